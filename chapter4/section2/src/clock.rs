@@ -0,0 +1,118 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Instant;
+
+/// Emulated time per instruction on the virtual clock: a 10 MHz CPU running one
+/// instruction per cycle.
+pub const NANOS_PER_INSTRUCTION: u64 = 100;
+
+/// What emulated time follows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockMode {
+    /// The number of instructions executed plus the time skipped in HLT, so runs are
+    /// deterministic.
+    Virtual,
+    /// The host's monotonic clock, so guest time keeps pace with real time.
+    Host,
+}
+
+#[derive(Debug)]
+struct ClockState {
+    mode: Cell<ClockMode>,
+    instructions: Cell<u64>,
+    /// Virtual time skipped over by `advance_to`.
+    skipped: Cell<u64>,
+    start: Instant,
+}
+
+/// Emulated time in nanoseconds since the emulator was created, shared between the
+/// CPU counting instructions and the devices reading it. Clones refer to the same
+/// clock.
+#[derive(Clone, Debug)]
+pub struct Clock(Rc<ClockState>);
+
+impl Clock {
+    pub fn new(mode: ClockMode) -> Clock {
+        Clock(Rc::new(ClockState {
+            mode: Cell::new(mode),
+            instructions: Cell::new(0),
+            skipped: Cell::new(0),
+            start: Instant::now(),
+        }))
+    }
+
+    pub fn mode(&self) -> ClockMode {
+        self.0.mode.get()
+    }
+
+    /// Switches what the clock follows. Time jumps unless this is done before the
+    /// first instruction.
+    pub fn set_mode(&self, mode: ClockMode) {
+        self.0.mode.set(mode);
+    }
+
+    /// Counts one executed instruction.
+    pub fn tick(&self) {
+        self.0.instructions.set(self.0.instructions.get() + 1);
+    }
+
+    /// Instructions executed so far.
+    pub fn instructions(&self) -> u64 {
+        self.0.instructions.get()
+    }
+
+    /// Current emulated time in nanoseconds.
+    pub fn now(&self) -> u64 {
+        match self.mode() {
+            ClockMode::Virtual => {
+                self.instructions() * NANOS_PER_INSTRUCTION + self.0.skipped.get()
+            }
+            ClockMode::Host => self.0.start.elapsed().as_nanos() as u64,
+        }
+    }
+
+    /// Moves virtual time forward to `time` without executing anything, as when the
+    /// CPU sits in HLT. The host clock cannot be moved, so this does nothing there.
+    pub fn advance_to(&self, time: u64) {
+        if self.mode() == ClockMode::Virtual {
+            let now = self.now();
+            if time > now {
+                self.0.skipped.set(self.0.skipped.get() + time - now);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtual_time_counts_instructions_and_skips() {
+        let clock = Clock::new(ClockMode::Virtual);
+        clock.tick();
+        clock.tick();
+        assert_eq!(clock.now(), 2 * NANOS_PER_INSTRUCTION);
+
+        clock.advance_to(1_000);
+        assert_eq!(clock.now(), 1_000);
+        // Time never runs backwards.
+        clock.advance_to(500);
+        assert_eq!(clock.now(), 1_000);
+
+        clock.tick();
+        assert_eq!(clock.now(), 1_000 + NANOS_PER_INSTRUCTION);
+        assert_eq!(clock.instructions(), 3);
+
+        // Clones share the time.
+        clock.clone().advance_to(5_000);
+        assert_eq!(clock.now(), 5_000);
+    }
+
+    #[test]
+    fn the_host_clock_cannot_be_advanced() {
+        let clock = Clock::new(ClockMode::Host);
+        clock.advance_to(u64::MAX / 2);
+        assert!(clock.now() < 60 * 1_000_000_000);
+    }
+}
@@ -0,0 +1,124 @@
+use crate::device::{HostInput, SerialBackend};
+
+use std::io;
+use std::io::{stdout, Write};
+use std::mem::MaybeUninit;
+use std::sync::OnceLock;
+
+/// Terminal settings saved by `HostConsole::open`, for the signal handler to restore.
+static SAVED_TERMIOS: OnceLock<libc::termios> = OnceLock::new();
+
+const RESTORED_SIGNALS: [libc::c_int; 4] =
+    [libc::SIGINT, libc::SIGTERM, libc::SIGQUIT, libc::SIGHUP];
+
+extern "C" fn restore_and_reraise(signal: libc::c_int) {
+    // tcsetattr, signal and raise are async-signal-safe.
+    unsafe {
+        if let Some(termios) = SAVED_TERMIOS.get() {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios);
+        }
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+/// The host terminal the emulator runs in.
+///
+/// While it lives a terminal on stdin is in raw mode, so keystrokes reach the guest one
+/// at a time and unechoed. A background thread reads them into `input`. Ctrl-C and the
+/// other terminal signals still stop the emulator; the terminal is restored on drop
+/// and when such a signal arrives.
+pub struct HostConsole {
+    input: HostInput,
+    saved: Option<libc::termios>,
+}
+
+impl HostConsole {
+    /// Puts stdin in raw mode if it is a terminal and starts reading it.
+    pub fn open() -> io::Result<HostConsole> {
+        let saved = if unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 {
+            Some(enter_raw_mode()?)
+        } else {
+            None
+        };
+
+        let input = HostInput::spawn(io::stdin());
+        Ok(HostConsole { input, saved })
+    }
+
+    /// Queue the keystrokes arrive in.
+    pub fn input(&self) -> HostInput {
+        self.input.clone()
+    }
+
+    /// Serial backend writing to stdout and reading the console keystrokes.
+    pub fn serial(&self) -> ConsoleSerial {
+        ConsoleSerial {
+            input: self.input(),
+        }
+    }
+}
+
+impl Drop for HostConsole {
+    fn drop(&mut self) {
+        if let Some(termios) = &self.saved {
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios);
+            }
+        }
+    }
+}
+
+/// Switches stdin to raw mode and returns the settings it had before.
+///
+/// Output processing stays on, so a bare LF still starts a new line, and so does ISIG,
+/// so Ctrl-C still interrupts; suspending with Ctrl-Z is disabled, as it would leave
+/// the terminal raw.
+fn enter_raw_mode() -> io::Result<libc::termios> {
+    let mut termios = MaybeUninit::uninit();
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let saved = unsafe { termios.assume_init() };
+
+    let mut raw = saved;
+    raw.c_iflag &= !(libc::BRKINT | libc::ICRNL | libc::INPCK | libc::ISTRIP | libc::IXON);
+    raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN);
+    raw.c_cflag |= libc::CS8;
+    raw.c_cc[libc::VMIN] = 1;
+    raw.c_cc[libc::VTIME] = 0;
+    raw.c_cc[libc::VSUSP] = 0;
+
+    let _ = SAVED_TERMIOS.set(saved);
+    for signal in RESTORED_SIGNALS {
+        unsafe {
+            libc::signal(
+                signal,
+                restore_and_reraise as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+    }
+
+    if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(saved)
+}
+
+/// Serial line on the host console, from `HostConsole::serial`.
+#[derive(Debug)]
+pub struct ConsoleSerial {
+    input: HostInput,
+}
+
+impl SerialBackend for ConsoleSerial {
+    fn transmit(&mut self, byte: u8) -> io::Result<()> {
+        let mut stdout = stdout();
+        stdout.write_all(&[byte])?;
+        stdout.flush()
+    }
+
+    fn receive(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input.pop())
+    }
+}
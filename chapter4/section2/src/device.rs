@@ -0,0 +1,17 @@
+mod ide;
+mod keyboard;
+mod pic;
+mod pit;
+mod rtc;
+mod serial;
+mod uart;
+
+pub use ide::{IdeChannel, IdeDisk};
+pub use keyboard::{A20Gate, KeyboardController, SystemControlPortA};
+pub use pic::PicPair;
+pub use pit::{Pit, SystemControlPort, PIT_FREQUENCY};
+pub use rtc::{BootDevice, Rtc};
+pub use serial::{
+    HostInput, NullSerial, PtySerial, SerialBackend, StdioSerial, StreamSerial, UnixSocketSerial,
+};
+pub use uart::Uart16550;
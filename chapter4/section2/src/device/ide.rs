@@ -0,0 +1,810 @@
+use crate::error::EmulatorError;
+use crate::io_bus::PortDevice;
+use crate::irq::IrqLine;
+use crate::scheduler::{EventId, Scheduler};
+
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::rc::{Rc, Weak};
+
+const SECTOR_SIZE: usize = 512;
+
+/// Time a command takes before its first sector is ready, and each sector after it,
+/// in nanoseconds.
+const SECTOR_TIME: u64 = 100_000;
+
+// Status register.
+const STATUS_ERROR: u8 = 1;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_SEEK_COMPLETE: u8 = 1 << 4;
+const STATUS_READY: u8 = 1 << 6;
+const STATUS_BUSY: u8 = 1 << 7;
+
+// Error register.
+const ERROR_DIAGNOSTIC_PASSED: u8 = 1;
+const ERROR_ABORTED: u8 = 1 << 2;
+const ERROR_ID_NOT_FOUND: u8 = 1 << 4;
+const ERROR_UNCORRECTABLE: u8 = 1 << 6;
+
+// Device register.
+const DEVICE_HEAD: u8 = 0x0F;
+const DEVICE_SLAVE: u8 = 1 << 4;
+const DEVICE_LBA: u8 = 1 << 6;
+
+// Device control register.
+const CONTROL_DISABLE_INTERRUPT: u8 = 1 << 1;
+const CONTROL_RESET: u8 = 1 << 2;
+/// Reads of the task file registers return the bytes written before the last ones.
+const CONTROL_HIGH_ORDER: u8 = 1 << 7;
+
+// Commands.
+const READ_SECTORS: u8 = 0x20;
+const READ_SECTORS_NO_RETRY: u8 = 0x21;
+const READ_SECTORS_EXT: u8 = 0x24;
+const WRITE_SECTORS: u8 = 0x30;
+const WRITE_SECTORS_NO_RETRY: u8 = 0x31;
+const WRITE_SECTORS_EXT: u8 = 0x34;
+const READ_VERIFY_SECTORS: u8 = 0x40;
+const READ_VERIFY_SECTORS_NO_RETRY: u8 = 0x41;
+const READ_VERIFY_SECTORS_EXT: u8 = 0x42;
+const EXECUTE_DEVICE_DIAGNOSTIC: u8 = 0x90;
+const INITIALIZE_DEVICE_PARAMETERS: u8 = 0x91;
+const FLUSH_CACHE: u8 = 0xE7;
+const FLUSH_CACHE_EXT: u8 = 0xEA;
+const IDENTIFY_DEVICE: u8 = 0xEC;
+const SET_FEATURES: u8 = 0xEF;
+
+/// Geometry reported for CHS addressing.
+const HEADS: u64 = 16;
+const SECTORS_PER_TRACK: u64 = 63;
+
+/// Largest sector count LBA28 commands and CHS can address.
+const LBA28_SECTORS: u64 = 0x0FFF_FFFF;
+
+/// A hard disk backed by a raw image file, one 512-byte sector after another. A last
+/// partial sector reads as if padded with zeros.
+#[derive(Debug)]
+pub struct IdeDisk {
+    file: File,
+    sectors: u64,
+    read_only: bool,
+}
+
+impl IdeDisk {
+    /// Opens the image for reading and writing, or only for reading if it cannot be
+    /// written.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<IdeDisk> {
+        let path = path.as_ref();
+        let (file, read_only) = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => (file, false),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => (File::open(path)?, true),
+            Err(e) => return Err(e),
+        };
+        let sectors = file.metadata()?.len().div_ceil(SECTOR_SIZE as u64);
+        Ok(IdeDisk {
+            file,
+            sectors,
+            read_only,
+        })
+    }
+
+    pub fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_sector(&mut self, lba: u64, buffer: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(lba * SECTOR_SIZE as u64))?;
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match self.file.read(&mut buffer[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        buffer[filled..].fill(0);
+        Ok(())
+    }
+
+    fn write_sector(&mut self, lba: u64, buffer: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(lba * SECTOR_SIZE as u64))?;
+        self.file.write_all(buffer)
+    }
+
+    /// The 256 words IDENTIFY DEVICE returns.
+    fn identify(&self, drive: usize) -> [u16; 256] {
+        let mut words = [0u16; 256];
+        let cylinders = (self.sectors / (HEADS * SECTORS_PER_TRACK)).min(16_383) as u16;
+        let lba28 = self.sectors.min(LBA28_SECTORS) as u32;
+        let chs = (cylinders as u32 * HEADS as u32 * SECTORS_PER_TRACK as u32).min(lba28);
+
+        // A fixed disk.
+        words[0] = 0x0040;
+        words[1] = cylinders;
+        words[3] = HEADS as u16;
+        words[6] = SECTORS_PER_TRACK as u16;
+        put_string(&mut words[10..20], &format!("PX86-{:04}", drive));
+        put_string(&mut words[23..27], "1.0");
+        put_string(&mut words[27..47], "PX86 HARDDISK");
+        // LBA supported.
+        words[49] = 1 << 9;
+        // Words 54-58 are valid.
+        words[53] = 1;
+        words[54] = cylinders;
+        words[55] = HEADS as u16;
+        words[56] = SECTORS_PER_TRACK as u16;
+        words[57] = chs as u16;
+        words[58] = (chs >> 16) as u16;
+        words[60] = lba28 as u16;
+        words[61] = (lba28 >> 16) as u16;
+        // ATA-1 to ATA-6.
+        words[80] = 0x007E;
+        // The 48-bit address feature set and FLUSH CACHE, supported and enabled.
+        words[82] = 0x4000;
+        words[83] = 0x4000 | 1 << 10 | 1 << 12 | 1 << 13;
+        words[84] = 0x4000;
+        words[85] = 0x4000;
+        words[86] = 1 << 10 | 1 << 12 | 1 << 13;
+        words[87] = 0x4000;
+        for (i, word) in words[100..104].iter_mut().enumerate() {
+            *word = (self.sectors >> (i * 16)) as u16;
+        }
+        words
+    }
+}
+
+/// Stores an IDENTIFY string: padded with spaces, two characters per word with the
+/// first in the high byte.
+fn put_string(words: &mut [u16], text: &str) {
+    let mut bytes = text.bytes().chain(std::iter::repeat(b' '));
+    for word in words {
+        let high = bytes.next().unwrap();
+        let low = bytes.next().unwrap();
+        *word = (high as u16) << 8 | low as u16;
+    }
+}
+
+/// What the data register transfers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Transfer {
+    None,
+    /// The buffer is read out, with `remaining` sectors after it starting at `lba`.
+    Read {
+        lba: u64,
+        remaining: u64,
+    },
+    /// The buffer is filled, to be written to `lba` with `remaining` sectors after it.
+    Write {
+        lba: u64,
+        remaining: u64,
+    },
+}
+
+/// IDE channel with up to two ATA disks, such as the primary channel on ports
+/// 0x1F0-0x1F7 and 0x3F6 with IRQ14. The control block register is told apart from
+/// the command block by bit 9 of the port.
+///
+/// Commands transfer with PIO through a one-sector buffer. The disk is busy for a
+/// while before each sector is ready, timed by events on the scheduler, and raises
+/// its interrupt as each sector is ready and when a command completes.
+#[derive(Debug)]
+pub struct IdeChannel {
+    scheduler: Scheduler,
+    irq: IrqLine,
+    /// Handle the scheduled events reach the channel through.
+    this: Weak<RefCell<IdeChannel>>,
+    drives: [Option<IdeDisk>; 2],
+    /// Task file registers that hold two bytes for 48-bit commands: the last byte
+    /// written in the low half and the one before it in the high half.
+    features: u16,
+    sector_count: u16,
+    lba_low: u16,
+    lba_mid: u16,
+    lba_high: u16,
+    device: u8,
+    error: u8,
+    status: u8,
+    control: u8,
+    interrupt_pending: bool,
+    buffer: Vec<u8>,
+    position: usize,
+    transfer: Transfer,
+    /// End of the busy period in progress.
+    event: Option<EventId>,
+}
+
+impl IdeChannel {
+    pub fn new(scheduler: Scheduler, irq: IrqLine) -> Rc<RefCell<IdeChannel>> {
+        Rc::new_cyclic(|this| {
+            RefCell::new(IdeChannel {
+                scheduler,
+                irq,
+                this: this.clone(),
+                drives: [None, None],
+                features: 0,
+                sector_count: 1,
+                lba_low: 1,
+                lba_mid: 0,
+                lba_high: 0,
+                device: 0,
+                error: ERROR_DIAGNOSTIC_PASSED,
+                status: STATUS_READY | STATUS_SEEK_COMPLETE,
+                control: 0,
+                interrupt_pending: false,
+                buffer: vec![0; SECTOR_SIZE],
+                position: 0,
+                transfer: Transfer::None,
+                event: None,
+            })
+        })
+    }
+
+    /// Connects `disk` as the master (0) or the slave (1).
+    pub fn attach(&mut self, drive: usize, disk: IdeDisk) {
+        self.drives[drive] = Some(disk);
+    }
+
+    fn selected(&self) -> usize {
+        (self.device & DEVICE_SLAVE != 0) as usize
+    }
+
+    fn update_irq(&self) {
+        self.irq
+            .set(self.interrupt_pending && self.control & CONTROL_DISABLE_INTERRUPT == 0);
+    }
+
+    fn interrupt(&mut self) {
+        self.interrupt_pending = true;
+        self.update_irq();
+    }
+
+    /// Runs `action` on the channel `delay` nanoseconds from now, replacing any event
+    /// scheduled before.
+    fn schedule(&mut self, delay: u64, action: fn(&mut IdeChannel)) {
+        self.cancel_event();
+        let this = self.this.clone();
+        self.event = Some(self.scheduler.schedule_in(delay, move || {
+            if let Some(channel) = this.upgrade() {
+                let mut channel = channel.borrow_mut();
+                channel.event = None;
+                action(&mut channel);
+            }
+        }));
+    }
+
+    fn cancel_event(&mut self) {
+        if let Some(event) = self.event.take() {
+            self.scheduler.cancel(event);
+        }
+    }
+
+    /// Ends the command with an error.
+    fn fail(&mut self, error: u8) {
+        self.error = error;
+        self.status = STATUS_READY | STATUS_SEEK_COMPLETE | STATUS_ERROR;
+        self.transfer = Transfer::None;
+        self.interrupt();
+    }
+
+    /// Ends the command successfully.
+    fn complete(&mut self) {
+        self.status = STATUS_READY | STATUS_SEEK_COMPLETE;
+        self.transfer = Transfer::None;
+        self.interrupt();
+    }
+
+    /// Loads the task file with the signature of an ATA device, as after a reset.
+    fn set_signature(&mut self) {
+        self.sector_count = 1;
+        self.lba_low = 1;
+        self.lba_mid = 0;
+        self.lba_high = 0;
+        self.device = 0;
+        self.error = ERROR_DIAGNOSTIC_PASSED;
+        self.status = STATUS_READY | STATUS_SEEK_COMPLETE;
+        self.transfer = Transfer::None;
+    }
+
+    fn reset_done(&mut self) {
+        self.set_signature();
+    }
+
+    /// Starting sector and sector count of a 28-bit command, in LBA or CHS form.
+    fn address28(&self) -> (u64, u64) {
+        let count = match self.sector_count as u8 {
+            0 => 256,
+            count => count as u64,
+        };
+        let lba = if self.device & DEVICE_LBA != 0 {
+            ((self.device & DEVICE_HEAD) as u64) << 24
+                | (self.lba_high as u8 as u64) << 16
+                | (self.lba_mid as u8 as u64) << 8
+                | self.lba_low as u8 as u64
+        } else {
+            let cylinder = (self.lba_high as u8 as u64) << 8 | self.lba_mid as u8 as u64;
+            let head = (self.device & DEVICE_HEAD) as u64;
+            let sector = self.lba_low as u8 as u64;
+            if sector == 0 {
+                // Sectors count from 1; this one cannot exist.
+                return (u64::MAX, count);
+            }
+            (cylinder * HEADS + head) * SECTORS_PER_TRACK + sector - 1
+        };
+        (lba, count)
+    }
+
+    /// Starting sector and sector count of a 48-bit command.
+    fn address48(&self) -> (u64, u64) {
+        let count = match self.sector_count {
+            0 => 65_536,
+            count => count as u64,
+        };
+        let lba = [self.lba_low, self.lba_mid, self.lba_high]
+            .iter()
+            .enumerate()
+            .fold(0u64, |lba, (i, &register)| {
+                lba | (register as u8 as u64) << (i * 8) | ((register >> 8) as u64) << (24 + i * 8)
+            });
+        (lba, count)
+    }
+
+    /// Whether the sectors fit on the selected disk and in the addressing used.
+    fn in_range(&self, lba: u64, count: u64, limit: u64) -> bool {
+        let sectors = self.drives[self.selected()]
+            .as_ref()
+            .map_or(0, |disk| disk.sectors().min(limit));
+        lba.checked_add(count).is_some_and(|end| end <= sectors)
+    }
+
+    fn write_command(&mut self, command: u8) {
+        let drive = self.selected();
+        if self.drives[drive].is_none() || self.status & STATUS_BUSY != 0 {
+            return;
+        }
+        self.cancel_event();
+        self.interrupt_pending = false;
+        self.update_irq();
+        self.error = 0;
+
+        match command {
+            IDENTIFY_DEVICE => {
+                let words = self.drives[drive].as_ref().unwrap().identify(drive);
+                for (bytes, word) in self.buffer.chunks_mut(2).zip(words) {
+                    bytes.copy_from_slice(&word.to_le_bytes());
+                }
+                self.status = STATUS_BUSY;
+                self.schedule(SECTOR_TIME, IdeChannel::buffer_ready);
+            }
+            READ_SECTORS | READ_SECTORS_NO_RETRY | READ_SECTORS_EXT => {
+                let ((lba, count), limit) = if command == READ_SECTORS_EXT {
+                    (self.address48(), u64::MAX)
+                } else {
+                    (self.address28(), LBA28_SECTORS)
+                };
+                if !self.in_range(lba, count, limit) {
+                    return self.fail(ERROR_ID_NOT_FOUND | ERROR_ABORTED);
+                }
+                self.transfer = Transfer::Read {
+                    lba,
+                    remaining: count,
+                };
+                self.status = STATUS_BUSY;
+                self.schedule(SECTOR_TIME, IdeChannel::read_sector);
+            }
+            WRITE_SECTORS | WRITE_SECTORS_NO_RETRY | WRITE_SECTORS_EXT => {
+                let ((lba, count), limit) = if command == WRITE_SECTORS_EXT {
+                    (self.address48(), u64::MAX)
+                } else {
+                    (self.address28(), LBA28_SECTORS)
+                };
+                if self.drives[drive].as_ref().unwrap().is_read_only() {
+                    return self.fail(ERROR_ABORTED);
+                }
+                if !self.in_range(lba, count, limit) {
+                    return self.fail(ERROR_ID_NOT_FOUND | ERROR_ABORTED);
+                }
+                // The first sector is taken without an interrupt.
+                self.transfer = Transfer::Write {
+                    lba,
+                    remaining: count,
+                };
+                self.position = 0;
+                self.status = STATUS_READY | STATUS_SEEK_COMPLETE | STATUS_DRQ;
+            }
+            READ_VERIFY_SECTORS | READ_VERIFY_SECTORS_NO_RETRY | READ_VERIFY_SECTORS_EXT => {
+                let ((lba, count), limit) = if command == READ_VERIFY_SECTORS_EXT {
+                    (self.address48(), u64::MAX)
+                } else {
+                    (self.address28(), LBA28_SECTORS)
+                };
+                if !self.in_range(lba, count, limit) {
+                    return self.fail(ERROR_ID_NOT_FOUND | ERROR_ABORTED);
+                }
+                self.status = STATUS_BUSY;
+                self.schedule(SECTOR_TIME, IdeChannel::complete);
+            }
+            FLUSH_CACHE | FLUSH_CACHE_EXT => {
+                let flushed = self.drives[drive].as_mut().unwrap().file.sync_data();
+                self.status = STATUS_BUSY;
+                if flushed.is_err() {
+                    self.schedule(SECTOR_TIME, |channel| channel.fail(ERROR_ABORTED));
+                } else {
+                    self.schedule(SECTOR_TIME, IdeChannel::complete);
+                }
+            }
+            EXECUTE_DEVICE_DIAGNOSTIC => {
+                self.status = STATUS_BUSY;
+                self.schedule(SECTOR_TIME, |channel| {
+                    channel.set_signature();
+                    channel.interrupt();
+                });
+            }
+            // Recalibrate, and settings that have nothing to set up here.
+            0x10..=0x1F | INITIALIZE_DEVICE_PARAMETERS | SET_FEATURES => {
+                self.status = STATUS_BUSY;
+                self.schedule(SECTOR_TIME, IdeChannel::complete);
+            }
+            _ => self.fail(ERROR_ABORTED),
+        }
+    }
+
+    /// The buffer holds the next sector for the host to read.
+    fn buffer_ready(&mut self) {
+        self.position = 0;
+        self.status = STATUS_READY | STATUS_SEEK_COMPLETE | STATUS_DRQ;
+        self.interrupt();
+    }
+
+    fn read_sector(&mut self) {
+        let (lba, remaining) = match self.transfer {
+            Transfer::Read { lba, remaining } => (lba, remaining),
+            _ => return,
+        };
+        let drive = self.selected();
+        let disk = match self.drives[drive].as_mut() {
+            Some(disk) => disk,
+            None => return,
+        };
+        if disk.read_sector(lba, &mut self.buffer).is_err() {
+            return self.fail(ERROR_UNCORRECTABLE);
+        }
+        self.transfer = Transfer::Read {
+            lba: lba + 1,
+            remaining: remaining - 1,
+        };
+        self.buffer_ready();
+    }
+
+    fn write_sector(&mut self) {
+        let (lba, remaining) = match self.transfer {
+            Transfer::Write { lba, remaining } => (lba, remaining),
+            _ => return,
+        };
+        let drive = self.selected();
+        let disk = match self.drives[drive].as_mut() {
+            Some(disk) => disk,
+            None => return,
+        };
+        if disk.write_sector(lba, &self.buffer).is_err() {
+            return self.fail(ERROR_ABORTED);
+        }
+        if remaining == 1 {
+            return self.complete();
+        }
+        self.transfer = Transfer::Write {
+            lba: lba + 1,
+            remaining: remaining - 1,
+        };
+        self.buffer_ready();
+    }
+
+    /// The host has read or written the whole buffer.
+    fn buffer_done(&mut self) {
+        match self.transfer {
+            Transfer::Read { remaining: 0, .. } | Transfer::None => {
+                self.status = STATUS_READY | STATUS_SEEK_COMPLETE;
+                self.transfer = Transfer::None;
+            }
+            Transfer::Read { .. } => {
+                self.status = STATUS_BUSY;
+                self.schedule(SECTOR_TIME, IdeChannel::read_sector);
+            }
+            Transfer::Write { .. } => {
+                self.status = STATUS_BUSY;
+                self.schedule(SECTOR_TIME, IdeChannel::write_sector);
+            }
+        }
+    }
+
+    /// Takes the next byte of the buffer. The data register moves a byte at a time
+    /// when accessed with 8-bit I/O.
+    fn read_data8(&mut self) -> u8 {
+        if self.status & STATUS_DRQ == 0 || matches!(self.transfer, Transfer::Write { .. }) {
+            return 0xFF;
+        }
+        let byte = self.buffer[self.position];
+        self.position += 1;
+        if self.position == SECTOR_SIZE {
+            self.buffer_done();
+        }
+        byte
+    }
+
+    fn write_data8(&mut self, value: u8) {
+        if self.status & STATUS_DRQ == 0 || !matches!(self.transfer, Transfer::Write { .. }) {
+            return;
+        }
+        self.buffer[self.position] = value;
+        self.position += 1;
+        if self.position == SECTOR_SIZE {
+            self.buffer_done();
+        }
+    }
+
+    fn read_data(&mut self) -> u16 {
+        u16::from_le_bytes([self.read_data8(), self.read_data8()])
+    }
+
+    fn write_data(&mut self, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.write_data8(low);
+        self.write_data8(high);
+    }
+
+    /// Device control register. Software reset takes effect when SRST is cleared
+    /// again.
+    fn write_control(&mut self, value: u8) {
+        let resetting = self.control & CONTROL_RESET != 0;
+        self.control = value;
+        if value & CONTROL_RESET != 0 {
+            self.cancel_event();
+            self.status = STATUS_BUSY;
+            self.transfer = Transfer::None;
+            self.interrupt_pending = false;
+        } else if resetting {
+            self.schedule(SECTOR_TIME, IdeChannel::reset_done);
+        }
+        self.update_irq();
+    }
+
+    fn read_register(&mut self, register: u16) -> u8 {
+        let high_order = self.control & CONTROL_HIGH_ORDER != 0;
+        let pick = |value: u16| {
+            if high_order {
+                (value >> 8) as u8
+            } else {
+                value as u8
+            }
+        };
+        match register {
+            0 => self.read_data8(),
+            1 => self.error,
+            2 => pick(self.sector_count),
+            3 => pick(self.lba_low),
+            4 => pick(self.lba_mid),
+            5 => pick(self.lba_high),
+            6 => self.device | 0xA0,
+            _ => {
+                self.interrupt_pending = false;
+                self.update_irq();
+                self.status
+            }
+        }
+    }
+
+    fn write_register(&mut self, register: u16, value: u8) {
+        if register == 7 {
+            return self.write_command(value);
+        }
+        if register == 0 {
+            return self.write_data8(value);
+        }
+        // The task file cannot be written while a command runs.
+        if self.status & (STATUS_BUSY | STATUS_DRQ) != 0 {
+            return;
+        }
+        // Writing any of these clears HOB.
+        self.control &= !CONTROL_HIGH_ORDER;
+        let push = |register: &mut u16| *register = *register << 8 | value as u16;
+        match register {
+            1 => push(&mut self.features),
+            2 => push(&mut self.sector_count),
+            3 => push(&mut self.lba_low),
+            4 => push(&mut self.lba_mid),
+            5 => push(&mut self.lba_high),
+            _ => self.device = value,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.drives.iter().all(Option::is_none)
+    }
+
+    /// Registers of a drive that is not there read as 0, and a channel with no drives
+    /// at all floats high.
+    fn absent_value(&self) -> Option<u8> {
+        if self.is_empty() {
+            Some(0xFF)
+        } else if self.drives[self.selected()].is_none() {
+            Some(0)
+        } else {
+            None
+        }
+    }
+}
+
+impl PortDevice for IdeChannel {
+    fn read8(&mut self, port: u16) -> Result<u8, EmulatorError> {
+        let control = port & 0x200 != 0;
+        let register = port & 7;
+        // The device register stays readable so the host can select the other drive.
+        if control || register != 6 {
+            if let Some(value) = self.absent_value() {
+                return Ok(value);
+            }
+        }
+        if control {
+            // Alternate status: the status without acknowledging the interrupt.
+            return Ok(self.status);
+        }
+        Ok(self.read_register(register))
+    }
+
+    fn write8(&mut self, port: u16, value: u8) -> Result<(), EmulatorError> {
+        if port & 0x200 != 0 {
+            self.write_control(value);
+        } else {
+            self.write_register(port & 7, value);
+        }
+        Ok(())
+    }
+
+    fn read16(&mut self, port: u16) -> Result<u16, EmulatorError> {
+        if port & 0x207 != 0 {
+            return Ok(self.read8(port)? as u16 | 0xFF00);
+        }
+        if let Some(value) = self.absent_value() {
+            return Ok(u16::from_le_bytes([value, value]));
+        }
+        Ok(self.read_data())
+    }
+
+    fn write16(&mut self, port: u16, value: u16) -> Result<(), EmulatorError> {
+        if port & 0x207 != 0 {
+            return self.write8(port, value as u8);
+        }
+        self.write_data(value);
+        Ok(())
+    }
+
+    fn read32(&mut self, port: u16) -> Result<u32, EmulatorError> {
+        let low = self.read16(port)? as u32;
+        let high = self.read16(port)? as u32;
+        Ok(low | high << 16)
+    }
+
+    fn write32(&mut self, port: u16, value: u32) -> Result<(), EmulatorError> {
+        self.write16(port, value as u16)?;
+        self.write16(port, (value >> 16) as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ClockMode};
+    use crate::irq::IrqLines;
+
+    const DATA: u16 = 0x1F0;
+    const STATUS: u16 = 0x1F7;
+    const CONTROL: u16 = 0x3F6;
+
+    /// A channel with a master disk of `sectors` sectors, byte `i` of the image
+    /// holding `i % 251`.
+    fn channel(name: &str, sectors: usize) -> (Rc<RefCell<IdeChannel>>, Scheduler) {
+        let path = std::env::temp_dir().join(format!("px86-{}-{}.img", name, std::process::id()));
+        let image: Vec<u8> = (0..sectors * SECTOR_SIZE)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        std::fs::write(&path, image).unwrap();
+        let disk = IdeDisk::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let scheduler = Scheduler::new(Clock::new(ClockMode::Virtual));
+        let channel = IdeChannel::new(scheduler.clone(), IrqLines::new().line(14));
+        channel.borrow_mut().attach(0, disk);
+        (channel, scheduler)
+    }
+
+    /// Runs the next scheduled event, as if the host waited for it.
+    fn wait(scheduler: &Scheduler) {
+        if let Some(time) = scheduler.next_event() {
+            scheduler.clock().advance_to(time);
+            scheduler.run_due();
+        }
+    }
+
+    fn write(channel: &Rc<RefCell<IdeChannel>>, port: u16, value: u8) {
+        channel.borrow_mut().write8(port, value).unwrap();
+    }
+
+    fn read(channel: &Rc<RefCell<IdeChannel>>, port: u16) -> u8 {
+        channel.borrow_mut().read8(port).unwrap()
+    }
+
+    /// Starts READ SECTORS of one sector at LBA `lba`.
+    fn read_sectors(channel: &Rc<RefCell<IdeChannel>>, lba: u8) {
+        write(channel, 0x1F2, 1);
+        write(channel, 0x1F3, lba);
+        write(channel, 0x1F4, 0);
+        write(channel, 0x1F5, 0);
+        write(channel, 0x1F6, 0xE0);
+        write(channel, STATUS, READ_SECTORS);
+    }
+
+    #[test]
+    fn byte_reads_of_the_data_register() {
+        let (channel, scheduler) = channel("ide-byte-reads", 2);
+        read_sectors(&channel, 1);
+        wait(&scheduler);
+        assert_eq!(read(&channel, STATUS) & STATUS_DRQ, STATUS_DRQ);
+
+        // Sector 1 starts at byte 512 of the image, which holds 512 % 251 = 10.
+        assert_eq!(read(&channel, DATA), 10);
+        assert_eq!(read(&channel, DATA), 11);
+        assert_eq!(channel.borrow_mut().read16(DATA).unwrap(), 0x0D0C);
+        for _ in 4..SECTOR_SIZE {
+            read(&channel, DATA);
+        }
+        assert_eq!(read(&channel, STATUS) & (STATUS_BUSY | STATUS_DRQ), 0);
+    }
+
+    #[test]
+    fn reset_drops_the_command_in_progress() {
+        let (channel, scheduler) = channel("ide-reset", 1);
+        read_sectors(&channel, 0);
+        assert_eq!(read(&channel, STATUS), STATUS_BUSY);
+
+        write(&channel, CONTROL, CONTROL_RESET);
+        assert_eq!(scheduler.next_event(), None);
+        write(&channel, CONTROL, 0);
+        wait(&scheduler);
+        assert_eq!(scheduler.next_event(), None);
+        assert_eq!(read(&channel, STATUS), STATUS_READY | STATUS_SEEK_COMPLETE);
+        assert_eq!(read(&channel, 0x1F2), 1);
+        assert_eq!(read(&channel, 0x1F3), 1);
+    }
+
+    #[test]
+    fn lba48_registers_read_back_with_hob() {
+        let (channel, _scheduler) = channel("ide-hob", 1);
+        // Previous bytes first, then current ones, as for READ SECTORS EXT.
+        for (port, previous, current) in [
+            (0x1F2, 0x12, 0x34),
+            (0x1F3, 0x56, 0x78),
+            (0x1F4, 0x9A, 0xBC),
+            (0x1F5, 0xDE, 0xF0),
+        ] {
+            write(&channel, port, previous);
+            write(&channel, port, current);
+        }
+        assert_eq!(channel.borrow().address48(), (0xDE9A_56F0_BC78, 0x1234));
+
+        let registers = |channel: &Rc<RefCell<IdeChannel>>| {
+            [0x1F2, 0x1F3, 0x1F4, 0x1F5].map(|port| read(channel, port))
+        };
+        assert_eq!(registers(&channel), [0x34, 0x78, 0xBC, 0xF0]);
+        write(&channel, CONTROL, CONTROL_HIGH_ORDER);
+        assert_eq!(registers(&channel), [0x12, 0x56, 0x9A, 0xDE]);
+
+        // Writing a task file register clears HOB again.
+        write(&channel, 0x1F2, 0x01);
+        assert_eq!(registers(&channel), [0x01, 0x78, 0xBC, 0xF0]);
+    }
+}
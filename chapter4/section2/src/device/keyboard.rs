@@ -0,0 +1,850 @@
+use crate::device::HostInput;
+use crate::error::EmulatorError;
+use crate::io_bus::PortDevice;
+use crate::irq::IrqLine;
+use crate::scheduler::{EventId, Scheduler};
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::{Rc, Weak};
+
+/// Time the keyboard takes to send one byte to the controller, in nanoseconds: 11
+/// bits at about 10 kHz.
+const BYTE_TIME: u64 = 1_100_000;
+
+/// Longest escape sequence held back waiting for its final byte. Longer ones are
+/// dropped.
+const MAX_ESCAPE: usize = 16;
+
+// Status register.
+const STATUS_OUTPUT_FULL: u8 = 1;
+const STATUS_SYSTEM: u8 = 1 << 2;
+/// The last write was to the command port.
+const STATUS_COMMAND: u8 = 1 << 3;
+/// The keyboard is not inhibited by the keylock.
+const STATUS_UNLOCKED: u8 = 1 << 4;
+
+// Command byte.
+const COMMAND_INTERRUPT: u8 = 1;
+const COMMAND_SYSTEM: u8 = 1 << 2;
+const COMMAND_DISABLE_KEYBOARD: u8 = 1 << 4;
+const COMMAND_DISABLE_AUX: u8 = 1 << 5;
+const COMMAND_TRANSLATE: u8 = 1 << 6;
+
+// Output port.
+const OUTPUT_RESET: u8 = 1;
+const OUTPUT_A20: u8 = 1 << 1;
+const OUTPUT_FULL: u8 = 1 << 4;
+
+// Keyboard responses.
+const ACK: u8 = 0xFA;
+const RESEND: u8 = 0xFE;
+const SELF_TEST_PASSED: u8 = 0xAA;
+const ECHO: u8 = 0xEE;
+/// Prefix of a break code in scan code set 2.
+const BREAK: u8 = 0xF0;
+/// Prefix of the keys added by the enhanced keyboard.
+const EXTENDED: u8 = 0xE0;
+
+// Keys, by their scan code set 1 make codes.
+const KEY_ESCAPE: u8 = 0x01;
+const KEY_BACKSPACE: u8 = 0x0E;
+const KEY_TAB: u8 = 0x0F;
+const KEY_ENTER: u8 = 0x1C;
+const KEY_CTRL: u8 = 0x1D;
+const KEY_SHIFT: u8 = 0x2A;
+const KEY_ALT: u8 = 0x38;
+const KEY_SPACE: u8 = 0x39;
+const KEY_F1: u8 = 0x3B;
+const KEY_F11: u8 = 0x57;
+const KEY_F12: u8 = 0x58;
+const KEY_HOME: u8 = 0x47;
+const KEY_UP: u8 = 0x48;
+const KEY_PAGE_UP: u8 = 0x49;
+const KEY_LEFT: u8 = 0x4B;
+const KEY_RIGHT: u8 = 0x4D;
+const KEY_END: u8 = 0x4F;
+const KEY_DOWN: u8 = 0x50;
+const KEY_PAGE_DOWN: u8 = 0x51;
+const KEY_INSERT: u8 = 0x52;
+const KEY_DELETE: u8 = 0x53;
+
+/// Scan code set 2 make code of each set 1 make code. The controller translates with
+/// the same table the other way round.
+const SET1_TO_SET2: [u8; 0x59] = [
+    0x00, 0x76, 0x16, 0x1E, 0x26, 0x25, 0x2E, 0x36, 0x3D, 0x3E, 0x46, 0x45, 0x4E, 0x55, 0x66, 0x0D,
+    0x15, 0x1D, 0x24, 0x2D, 0x2C, 0x35, 0x3C, 0x43, 0x44, 0x4D, 0x54, 0x5B, 0x5A, 0x14, 0x1C, 0x1B,
+    0x23, 0x2B, 0x34, 0x33, 0x3B, 0x42, 0x4B, 0x4C, 0x52, 0x0E, 0x12, 0x5D, 0x1A, 0x22, 0x21, 0x2A,
+    0x32, 0x31, 0x3A, 0x41, 0x49, 0x4A, 0x59, 0x7C, 0x11, 0x29, 0x58, 0x05, 0x06, 0x04, 0x0C, 0x03,
+    0x0B, 0x83, 0x0A, 0x01, 0x09, 0x77, 0x7E, 0x6C, 0x75, 0x7D, 0x7B, 0x6B, 0x73, 0x74, 0x79, 0x69,
+    0x72, 0x7A, 0x70, 0x71, 0x84, 0x00, 0x61, 0x78, 0x07,
+];
+
+/// Rows of the US layout: the set 1 code of the first key and the characters typed
+/// without and with shift.
+const LAYOUT: [(u8, &[u8], &[u8]); 4] = [
+    (0x02, b"1234567890-=", b"!@#$%^&*()_+"),
+    (0x10, b"qwertyuiop[]", b"QWERTYUIOP{}"),
+    (0x1E, b"asdfghjkl;'`", b"ASDFGHJKL:\"~"),
+    (0x2B, b"\\zxcvbnm,./", b"|ZXCVBNM<>?"),
+];
+
+/// A key of the keyboard, with the modifier it is typed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Key {
+    /// Scan code set 1 make code.
+    code: u8,
+    /// Sent with the E0 prefix.
+    extended: bool,
+    modifier: Option<u8>,
+}
+
+impl Key {
+    fn plain(code: u8) -> Key {
+        Key {
+            code,
+            extended: false,
+            modifier: None,
+        }
+    }
+
+    fn with(code: u8, modifier: u8) -> Key {
+        Key {
+            code,
+            extended: false,
+            modifier: Some(modifier),
+        }
+    }
+}
+
+/// The key a byte from a terminal in raw mode stands for.
+fn ascii_key(byte: u8) -> Option<Key> {
+    match byte {
+        b' ' => return Some(Key::plain(KEY_SPACE)),
+        b'\t' => return Some(Key::plain(KEY_TAB)),
+        b'\r' | b'\n' => return Some(Key::plain(KEY_ENTER)),
+        0x08 | 0x7F => return Some(Key::plain(KEY_BACKSPACE)),
+        0x1B => return Some(Key::plain(KEY_ESCAPE)),
+        // Ctrl-A to Ctrl-Z.
+        0x01..=0x1A => return ascii_key(byte + 0x60).map(|key| Key::with(key.code, KEY_CTRL)),
+        _ => (),
+    }
+
+    LAYOUT.iter().find_map(|&(first, plain, shifted)| {
+        if let Some(i) = plain.iter().position(|&c| c == byte) {
+            Some(Key::plain(first + i as u8))
+        } else {
+            let i = shifted.iter().position(|&c| c == byte)?;
+            Some(Key::with(first + i as u8, KEY_SHIFT))
+        }
+    })
+}
+
+/// The key the final byte of a CSI or SS3 escape sequence stands for, or with `~`
+/// the key its first parameter stands for. The second parameter is xterm's modifier:
+/// 1 plus a bit each for Shift, Alt and Ctrl. A key holds one modifier, so Ctrl wins
+/// over Alt and Alt over Shift.
+fn escape_key(number: u32, modifier: u32, last: u8) -> Option<Key> {
+    let (code, extended) = match (number, last) {
+        (_, b'A') => (KEY_UP, true),
+        (_, b'B') => (KEY_DOWN, true),
+        (_, b'C') => (KEY_RIGHT, true),
+        (_, b'D') => (KEY_LEFT, true),
+        (_, b'H') | (1, b'~') => (KEY_HOME, true),
+        (_, b'F') | (4, b'~') => (KEY_END, true),
+        (2, b'~') => (KEY_INSERT, true),
+        (3, b'~') => (KEY_DELETE, true),
+        (5, b'~') => (KEY_PAGE_UP, true),
+        (6, b'~') => (KEY_PAGE_DOWN, true),
+        (_, b'P'..=b'S') => (KEY_F1 + (last - b'P'), false),
+        (11..=15, b'~') => (KEY_F1 + (number - 11) as u8, false),
+        (17..=21, b'~') => (KEY_F1 + 5 + (number - 17) as u8, false),
+        (23, b'~') => (KEY_F11, false),
+        (24, b'~') => (KEY_F12, false),
+        _ => return None,
+    };
+
+    let held = modifier.saturating_sub(1);
+    let modifier = if held & 4 != 0 {
+        Some(KEY_CTRL)
+    } else if held & 2 != 0 {
+        Some(KEY_ALT)
+    } else if held & 1 != 0 {
+        Some(KEY_SHIFT)
+    } else {
+        None
+    };
+    Some(Key {
+        code,
+        extended,
+        modifier,
+    })
+}
+
+/// The key a complete CSI sequence stands for, from its parameter bytes and final
+/// byte. Sequences with anything but `;`-separated decimal numbers have none.
+fn csi_key(parameters: &[u8], last: u8) -> Option<Key> {
+    let mut numbers = [0u32; 2];
+    for (i, parameter) in parameters.split(|&byte| byte == b';').enumerate() {
+        if !parameter.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        if let Some(number) = numbers.get_mut(i) {
+            *number = parameter.iter().fold(0u32, |n, &digit| {
+                n.saturating_mul(10).saturating_add((digit - b'0') as u32)
+            });
+        }
+    }
+    escape_key(numbers[0], numbers[1], last)
+}
+
+/// State of the A20 address line, shared between the keyboard controller, port 0x92
+/// and the memory accesses it affects. Clones refer to the same gate.
+#[derive(Clone, Debug)]
+pub struct A20Gate(Rc<Cell<bool>>);
+
+impl A20Gate {
+    pub fn new(enabled: bool) -> A20Gate {
+        A20Gate(Rc::new(Cell::new(enabled)))
+    }
+
+    /// Whether physical addresses keep bit 20. While it is off they wrap at 1 MiB.
+    pub fn is_enabled(&self) -> bool {
+        self.0.get()
+    }
+
+    pub fn set(&self, enabled: bool) {
+        self.0.set(enabled);
+    }
+}
+
+/// The keyboard at the end of the controller's keyboard port.
+#[derive(Debug)]
+struct Keyboard {
+    /// Scan code set 1 or 2.
+    scan_set: u8,
+    scanning: bool,
+    /// Command waiting for its argument byte.
+    command: Option<u8>,
+    leds: u8,
+    /// Bytes not yet sent to the controller.
+    queue: VecDeque<u8>,
+    last_sent: u8,
+}
+
+impl Keyboard {
+    fn new() -> Keyboard {
+        Keyboard {
+            scan_set: 2,
+            scanning: true,
+            command: None,
+            leds: 0,
+            queue: VecDeque::new(),
+            last_sent: 0,
+        }
+    }
+
+    fn send(&mut self, bytes: &[u8]) {
+        self.queue.extend(bytes);
+    }
+
+    /// Sends the make or break code of `code` in the current scan code set.
+    fn send_code(&mut self, code: u8, extended: bool, make: bool) {
+        if extended {
+            self.send(&[EXTENDED]);
+        }
+        if self.scan_set == 1 {
+            self.send(&[if make { code } else { code | 0x80 }]);
+        } else if make {
+            self.send(&[SET1_TO_SET2[code as usize]]);
+        } else {
+            self.send(&[BREAK, SET1_TO_SET2[code as usize]]);
+        }
+    }
+
+    /// Presses and releases `key`, holding down its modifier around it.
+    fn type_key(&mut self, key: Key) {
+        if let Some(modifier) = key.modifier {
+            self.send_code(modifier, false, true);
+        }
+        self.send_code(key.code, key.extended, true);
+        self.send_code(key.code, key.extended, false);
+        if let Some(modifier) = key.modifier {
+            self.send_code(modifier, false, false);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.scan_set = 2;
+        self.scanning = true;
+        self.command = None;
+        self.leds = 0;
+        self.queue.clear();
+    }
+
+    /// A byte the controller passed on from the data port.
+    fn write(&mut self, value: u8) {
+        if let Some(command) = self.command.take() {
+            match command {
+                0xED => self.leds = value & 7,
+                0xF0 if value == 0 => {
+                    let set = self.scan_set;
+                    self.send(&[ACK, set]);
+                    return;
+                }
+                0xF0 if value == 1 || value == 2 => self.scan_set = value,
+                // Scan code set 3 is not supported.
+                0xF0 => {
+                    self.send(&[RESEND]);
+                    return;
+                }
+                // Typematic rate and delay.
+                _ => (),
+            }
+            self.send(&[ACK]);
+            return;
+        }
+
+        match value {
+            0xED | 0xF0 | 0xF3 => {
+                self.command = Some(value);
+                self.send(&[ACK]);
+            }
+            0xEE => self.send(&[ECHO]),
+            // An MF2 keyboard.
+            0xF2 => self.send(&[ACK, 0xAB, 0x83]),
+            0xF4 => {
+                self.scanning = true;
+                self.send(&[ACK]);
+            }
+            0xF5 => {
+                self.scanning = false;
+                self.send(&[ACK]);
+            }
+            0xFE => {
+                let last = self.last_sent;
+                self.send(&[last]);
+            }
+            0xFF => {
+                self.reset();
+                self.send(&[ACK, SELF_TEST_PASSED]);
+            }
+            _ => self.send(&[ACK]),
+        }
+    }
+}
+
+/// 8042 keyboard controller on ports 0x60 (data) and 0x64 (status and command), with
+/// a keyboard on IRQ1 typing the keystrokes of a `HostInput`.
+///
+/// The keyboard starts in scan code set 2, which the controller translates to set 1
+/// while bit 6 of its command byte is set, as the BIOS leaves it. Bytes reach the
+/// output buffer one at a time, each a byte time after the last was read, so every
+/// one raises its own IRQ1. Bit 1 of the output port drives the A20 gate. Pulsing the
+/// reset line is ignored, as the CPU cannot be reset.
+#[derive(Debug)]
+pub struct KeyboardController {
+    scheduler: Scheduler,
+    irq: IrqLine,
+    a20: A20Gate,
+    /// Handle the transfer events reach the controller through.
+    this: Weak<RefCell<KeyboardController>>,
+    input: Option<HostInput>,
+    /// Bytes of an escape sequence held back until the rest arrives.
+    escape: Vec<u8>,
+    keyboard: Keyboard,
+    /// Output buffer. It keeps its byte after being read.
+    output: u8,
+    output_full: bool,
+    command_byte: u8,
+    /// Controller command waiting for its byte on the data port.
+    command: Option<u8>,
+    last_write_command: bool,
+    /// A set 2 break prefix was swallowed by translation.
+    translated_break: bool,
+    transfer: Option<EventId>,
+}
+
+impl KeyboardController {
+    pub fn new(scheduler: Scheduler, irq: IrqLine, a20: A20Gate) -> Rc<RefCell<Self>> {
+        Rc::new_cyclic(|this| {
+            RefCell::new(KeyboardController {
+                scheduler,
+                irq,
+                a20,
+                this: this.clone(),
+                input: None,
+                escape: Vec::new(),
+                keyboard: Keyboard::new(),
+                output: 0,
+                output_full: false,
+                command_byte: COMMAND_INTERRUPT | COMMAND_SYSTEM | COMMAND_TRANSLATE,
+                command: None,
+                last_write_command: false,
+                translated_break: false,
+                transfer: None,
+            })
+        })
+    }
+
+    /// Types the keystrokes arriving on `input` on the keyboard.
+    pub fn connect(&mut self, input: HostInput) {
+        self.input = Some(input);
+    }
+
+    /// Keyboard LEDs as last set by the guest: scroll lock, num lock and caps lock in
+    /// bits 0 to 2.
+    pub fn leds(&self) -> u8 {
+        self.keyboard.leds
+    }
+
+    /// Types the keys the next keystrokes on the input stand for. An escape sequence
+    /// is only taken once it is complete, a CSI sequence at its final byte in
+    /// 0x40-0x7E, and is dropped whole if it stands for no key. An escape with nothing
+    /// after it is the Escape key.
+    fn take_input(&mut self) {
+        let input = match &self.input {
+            Some(input) => input.clone(),
+            None => return,
+        };
+
+        while let Some(byte) = input.pop() {
+            self.escape.push(byte);
+            match self.escape.as_slice() {
+                [0x1B] if input.is_empty() => {
+                    self.keyboard.type_key(Key::plain(KEY_ESCAPE));
+                }
+                [0x1B] | [0x1B, b'[' | b'O'] => continue,
+                [0x1B, b'O', last] => {
+                    if let Some(key) = escape_key(0, 0, *last) {
+                        self.keyboard.type_key(key);
+                    }
+                }
+                [0x1B, b'[', parameters @ .., last @ 0x40..=0x7E] => {
+                    if let Some(key) = csi_key(parameters, *last) {
+                        self.keyboard.type_key(key);
+                    }
+                }
+                [0x1B, b'[', ..] if self.escape.len() < MAX_ESCAPE => continue,
+                [0x1B, other] => {
+                    let other = *other;
+                    self.keyboard.type_key(Key::plain(KEY_ESCAPE));
+                    if let Some(key) = ascii_key(other) {
+                        self.keyboard.type_key(key);
+                    }
+                }
+                [byte] => {
+                    if let Some(key) = ascii_key(*byte) {
+                        self.keyboard.type_key(key);
+                    }
+                }
+                // Sequences this keyboard has no key for.
+                _ => (),
+            }
+            self.escape.clear();
+            if !self.keyboard.queue.is_empty() {
+                return;
+            }
+        }
+    }
+
+    fn update_irq(&self) {
+        self.irq
+            .set(self.output_full && self.command_byte & COMMAND_INTERRUPT != 0);
+    }
+
+    /// Puts a byte from the controller itself in the output buffer.
+    fn respond(&mut self, value: u8) {
+        self.output = value;
+        self.output_full = true;
+        self.update_irq();
+    }
+
+    /// Schedules the next byte from the keyboard, if it has one and the output buffer
+    /// and the keyboard interface are free.
+    fn schedule_transfer(&mut self) {
+        if self.transfer.is_some()
+            || self.output_full
+            || self.command_byte & COMMAND_DISABLE_KEYBOARD != 0
+            || self.keyboard.queue.is_empty()
+        {
+            return;
+        }
+        let this = self.this.clone();
+        self.transfer = Some(self.scheduler.schedule_in(BYTE_TIME, move || {
+            if let Some(controller) = this.upgrade() {
+                controller.borrow_mut().receive();
+            }
+        }));
+    }
+
+    /// Moves the next byte from the keyboard to the output buffer, translating it to
+    /// set 1 if enabled.
+    fn receive(&mut self) {
+        self.transfer = None;
+        while !self.output_full {
+            let byte = match self.keyboard.queue.pop_front() {
+                Some(byte) => byte,
+                None => break,
+            };
+            self.keyboard.last_sent = byte;
+
+            if self.command_byte & COMMAND_TRANSLATE == 0 {
+                self.output = byte;
+                self.output_full = true;
+            } else if byte == BREAK {
+                self.translated_break = true;
+            } else {
+                let translated = if byte < 0x80 || byte == 0x83 || byte == 0x84 {
+                    SET1_TO_SET2
+                        .iter()
+                        .position(|&code| code == byte)
+                        .map_or(byte, |code| code as u8)
+                } else {
+                    byte
+                };
+                let release = std::mem::take(&mut self.translated_break);
+                self.output = if release {
+                    translated | 0x80
+                } else {
+                    translated
+                };
+                self.output_full = true;
+            }
+        }
+        self.update_irq();
+        self.schedule_transfer();
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = STATUS_UNLOCKED;
+        if self.output_full {
+            status |= STATUS_OUTPUT_FULL;
+        }
+        if self.command_byte & COMMAND_SYSTEM != 0 {
+            status |= STATUS_SYSTEM;
+        }
+        if self.last_write_command {
+            status |= STATUS_COMMAND;
+        }
+        status
+    }
+
+    fn output_port(&self) -> u8 {
+        let mut value = OUTPUT_RESET;
+        if self.a20.is_enabled() {
+            value |= OUTPUT_A20;
+        }
+        if self.output_full {
+            value |= OUTPUT_FULL;
+        }
+        value
+    }
+
+    /// A command written to port 0x64.
+    fn write_command(&mut self, value: u8) {
+        match value {
+            0x20 => self.respond(self.command_byte),
+            0x60 | 0xD1 | 0xD2 => self.command = Some(value),
+            0xA7 => self.command_byte |= COMMAND_DISABLE_AUX,
+            0xA8 => self.command_byte &= !COMMAND_DISABLE_AUX,
+            // No mouse is attached: its clock line is stuck low.
+            0xA9 => self.respond(0x01),
+            0xAA => self.respond(0x55),
+            0xAB => self.respond(0x00),
+            0xAD => self.command_byte |= COMMAND_DISABLE_KEYBOARD,
+            0xAE => self.command_byte &= !COMMAND_DISABLE_KEYBOARD,
+            // Input port: the keyboard is not inhibited.
+            0xC0 => self.respond(0x80),
+            0xD0 => self.respond(self.output_port()),
+            0xDD => self.a20.set(false),
+            0xDF => self.a20.set(true),
+            // Pulses of the output port lines, including reset.
+            _ => (),
+        }
+    }
+
+    /// A byte written to port 0x60: the argument of a controller command, or a byte
+    /// for the keyboard.
+    fn write_data(&mut self, value: u8) {
+        match self.command.take() {
+            Some(0x60) => {
+                self.command_byte = value;
+                self.update_irq();
+            }
+            Some(0xD1) => self.a20.set(value & OUTPUT_A20 != 0),
+            // As if the keyboard had sent it.
+            Some(_) => self.respond(value),
+            None => {
+                self.command_byte &= !COMMAND_DISABLE_KEYBOARD;
+                self.keyboard.write(value);
+            }
+        }
+    }
+}
+
+impl PortDevice for KeyboardController {
+    fn read8(&mut self, port: u16) -> Result<u8, EmulatorError> {
+        if port & 4 != 0 {
+            return Ok(self.status());
+        }
+        self.output_full = false;
+        self.update_irq();
+        self.schedule_transfer();
+        Ok(self.output)
+    }
+
+    fn write8(&mut self, port: u16, value: u8) -> Result<(), EmulatorError> {
+        self.last_write_command = port & 4 != 0;
+        if self.last_write_command {
+            self.write_command(value);
+        } else {
+            self.write_data(value);
+        }
+        self.schedule_transfer();
+        Ok(())
+    }
+
+    /// Types waiting host keystrokes once the keyboard has sent everything before
+    /// them.
+    fn poll(&mut self) -> Result<(), EmulatorError> {
+        if self.keyboard.scanning && self.keyboard.queue.is_empty() {
+            self.take_input();
+            self.schedule_transfer();
+        }
+        Ok(())
+    }
+}
+
+/// Port 0x92 of PS/2 systems, system control port A: "fast A20" in bit 1. Setting
+/// bit 0 requests a CPU reset, which is ignored like the keyboard controller's.
+#[derive(Debug)]
+pub struct SystemControlPortA {
+    a20: A20Gate,
+    value: u8,
+}
+
+impl SystemControlPortA {
+    pub fn new(a20: A20Gate) -> SystemControlPortA {
+        SystemControlPortA { a20, value: 0 }
+    }
+}
+
+impl PortDevice for SystemControlPortA {
+    fn read8(&mut self, _port: u16) -> Result<u8, EmulatorError> {
+        let a20 = if self.a20.is_enabled() { OUTPUT_A20 } else { 0 };
+        Ok((self.value & !(OUTPUT_A20 | OUTPUT_RESET)) | a20)
+    }
+
+    fn write8(&mut self, _port: u16, value: u8) -> Result<(), EmulatorError> {
+        self.a20.set(value & OUTPUT_A20 != 0);
+        self.value = value;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ClockMode};
+    use crate::emulator::{Emulator, OperandSize::Byte};
+    use crate::irq::IrqLines;
+
+    const DATA: u16 = 0x60;
+    const STATUS: u16 = 0x64;
+
+    fn controller() -> (Rc<RefCell<KeyboardController>>, Scheduler, IrqLines) {
+        let scheduler = Scheduler::new(Clock::new(ClockMode::Virtual));
+        let irq = IrqLines::new();
+        let controller =
+            KeyboardController::new(scheduler.clone(), irq.line(1), A20Gate::new(false));
+        (controller, scheduler, irq)
+    }
+
+    fn write(controller: &Rc<RefCell<KeyboardController>>, port: u16, value: u8) {
+        controller.borrow_mut().write8(port, value).unwrap();
+    }
+
+    fn read(controller: &Rc<RefCell<KeyboardController>>, port: u16) -> u8 {
+        controller.borrow_mut().read8(port).unwrap()
+    }
+
+    /// Types `keystrokes` and returns the bytes read from the data port, reading each
+    /// as soon as it arrives.
+    fn type_bytes(
+        controller: &Rc<RefCell<KeyboardController>>,
+        scheduler: &Scheduler,
+        keystrokes: &[u8],
+    ) -> Vec<u8> {
+        let input = HostInput::new();
+        for &byte in keystrokes {
+            input.push(byte);
+        }
+        controller.borrow_mut().connect(input);
+
+        let mut bytes = Vec::new();
+        loop {
+            controller.borrow_mut().poll().unwrap();
+            match scheduler.next_event() {
+                Some(time) => {
+                    scheduler.clock().advance_to(time);
+                    scheduler.run_due();
+                }
+                None => return bytes,
+            }
+            if read(controller, STATUS) & STATUS_OUTPUT_FULL != 0 {
+                bytes.push(read(controller, DATA));
+            }
+        }
+    }
+
+    /// The set 1 bytes `keystrokes` arrive as.
+    fn scan_codes(keystrokes: &[u8]) -> Vec<u8> {
+        let (controller, scheduler, _) = controller();
+        type_bytes(&controller, &scheduler, keystrokes)
+    }
+
+    #[test]
+    fn escape_sequences_are_taken_whole() {
+        assert_eq!(scan_codes(b"\x1b[A"), [0xE0, 0x48, 0xE0, 0xC8]);
+        assert_eq!(scan_codes(b"\x1bOP"), [0x3B, 0xBB]);
+        // F5 and F12
+        assert_eq!(scan_codes(b"\x1b[15~\x1b[24~"), [0x3F, 0xBF, 0x58, 0xD8]);
+        // Ctrl+Up and Shift+Delete
+        assert_eq!(
+            scan_codes(b"\x1b[1;5A"),
+            [0x1D, 0xE0, 0x48, 0xE0, 0xC8, 0x9D]
+        );
+        assert_eq!(
+            scan_codes(b"\x1b[3;2~"),
+            [0x2A, 0xE0, 0x53, 0xE0, 0xD3, 0xAA]
+        );
+    }
+
+    #[test]
+    fn sequences_without_a_key_are_dropped_whole() {
+        // Bracketed paste start and a device attributes reply, then x and z.
+        assert_eq!(scan_codes(b"\x1b[200~x"), [0x2D, 0xAD]);
+        assert_eq!(scan_codes(b"\x1b[?1;2cz"), [0x2C, 0xAC]);
+
+        // A lone escape is the Escape key; one followed by a character is Escape
+        // then that character.
+        assert_eq!(scan_codes(b"\x1b"), [0x01, 0x81]);
+        assert_eq!(scan_codes(b"\x1bx"), [0x01, 0x81, 0x2D, 0xAD]);
+    }
+
+    #[test]
+    fn output_port_drives_a20() {
+        let mut emu = Emulator::new_real_mode(0x0011_0000, 0, 0x7C00);
+        let a20 = |emu: &mut Emulator, enabled: bool| {
+            emu.io.write(0x64, 0xD1, Byte).unwrap();
+            let output = OUTPUT_RESET | if enabled { OUTPUT_A20 } else { 0 };
+            emu.io.write(0x60, output as u32, Byte).unwrap();
+            emu.io.write(0x64, 0xD0, Byte).unwrap();
+            assert_eq!(
+                emu.io.read(0x60, Byte).unwrap() as u8 & OUTPUT_A20 != 0,
+                enabled
+            );
+        };
+
+        // With A20 off, 1 MiB wraps around to 0.
+        emu.set_memory8(0x0010_0000, 0xAA).unwrap();
+        assert_eq!(emu.memory[0], 0xAA);
+
+        a20(&mut emu, true);
+        emu.set_memory8(0x0010_0000, 0xBB).unwrap();
+        assert_eq!((emu.memory[0], emu.memory[0x0010_0000]), (0xAA, 0xBB));
+
+        a20(&mut emu, false);
+        assert_eq!(emu.get_memory8(0x0010_0000).unwrap(), 0xAA);
+    }
+
+    #[test]
+    fn port_92_bit_1_drives_a20() {
+        let mut emu = Emulator::new_real_mode(0x0011_0000, 0, 0x7C00);
+        emu.memory[0x0010_0000] = 0xBB;
+
+        emu.io.write(0x92, OUTPUT_A20 as u32, Byte).unwrap();
+        assert!(emu.a20.is_enabled());
+        assert_eq!(emu.io.read(0x92, Byte).unwrap(), OUTPUT_A20 as u32);
+        assert_eq!(emu.get_memory8(0x0010_0000).unwrap(), 0xBB);
+
+        emu.io.write(0x92, 0, Byte).unwrap();
+        assert!(!emu.a20.is_enabled());
+        assert_eq!(emu.io.read(0x92, Byte).unwrap(), 0);
+        assert_eq!(emu.get_memory8(0x0010_0000).unwrap(), emu.memory[0]);
+    }
+
+    #[test]
+    fn command_byte_round_trips() {
+        let (controller, _, _) = controller();
+
+        write(&controller, STATUS, 0x20);
+        assert_eq!(
+            read(&controller, STATUS),
+            STATUS_UNLOCKED | STATUS_COMMAND | STATUS_SYSTEM | STATUS_OUTPUT_FULL
+        );
+        assert_eq!(
+            read(&controller, DATA),
+            COMMAND_INTERRUPT | COMMAND_SYSTEM | COMMAND_TRANSLATE
+        );
+
+        write(&controller, STATUS, 0x60);
+        write(&controller, DATA, COMMAND_TRANSLATE | COMMAND_DISABLE_AUX);
+        assert_eq!(read(&controller, STATUS), STATUS_UNLOCKED);
+        write(&controller, STATUS, 0x20);
+        assert_eq!(
+            read(&controller, DATA),
+            COMMAND_TRANSLATE | COMMAND_DISABLE_AUX
+        );
+    }
+
+    #[test]
+    fn translation_selects_the_scan_code_set() {
+        assert_eq!(scan_codes(b"a"), [0x1E, 0x9E]);
+        assert_eq!(scan_codes(b"A"), [0x2A, 0x1E, 0x9E, 0xAA]);
+
+        let (controller, scheduler, _) = controller();
+        write(&controller, STATUS, 0x60);
+        write(&controller, DATA, COMMAND_INTERRUPT | COMMAND_SYSTEM);
+        assert_eq!(
+            type_bytes(&controller, &scheduler, b"a"),
+            [0x1C, BREAK, 0x1C]
+        );
+    }
+
+    #[test]
+    fn irq1_follows_the_command_byte() {
+        let (controller, scheduler, irq) = controller();
+        write(&controller, STATUS, 0x60);
+        write(&controller, DATA, COMMAND_SYSTEM | COMMAND_TRANSLATE);
+
+        let input = HostInput::new();
+        input.push(b'a');
+        controller.borrow_mut().connect(input);
+        controller.borrow_mut().poll().unwrap();
+        scheduler.clock().advance_to(BYTE_TIME);
+        scheduler.run_due();
+        assert_eq!(
+            read(&controller, STATUS) & STATUS_OUTPUT_FULL,
+            STATUS_OUTPUT_FULL
+        );
+        assert!(!irq.is_raised(1));
+
+        write(&controller, STATUS, 0x60);
+        write(
+            &controller,
+            DATA,
+            COMMAND_INTERRUPT | COMMAND_SYSTEM | COMMAND_TRANSLATE,
+        );
+        assert!(irq.is_raised(1));
+        assert_eq!(read(&controller, DATA), 0x1E);
+        assert!(!irq.is_raised(1));
+
+        // The break code raises IRQ1 again once it arrives.
+        scheduler.clock().advance_to(2 * BYTE_TIME);
+        scheduler.run_due();
+        assert!(irq.is_raised(1));
+        assert_eq!(read(&controller, DATA), 0x9E);
+    }
+}
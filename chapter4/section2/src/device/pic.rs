@@ -0,0 +1,461 @@
+use crate::error::EmulatorError;
+use crate::io_bus::PortDevice;
+
+/// Input of the master the slave's INT output is wired to, as on the PC/AT.
+const CASCADE_IRQ: u8 = 2;
+
+// ICW1
+const ICW1_ICW4: u8 = 1;
+const ICW1_SINGLE: u8 = 1 << 1;
+const ICW1_LEVEL: u8 = 1 << 3;
+const ICW1: u8 = 1 << 4;
+
+const ICW4_AUTO_EOI: u8 = 1 << 1;
+
+// OCW3
+const OCW3: u8 = 1 << 3;
+const OCW3_READ_ISR: u8 = 1;
+const OCW3_READ_REGISTER: u8 = 1 << 1;
+const OCW3_POLL: u8 = 1 << 2;
+const OCW3_SPECIAL_MASK: u8 = 1 << 5;
+const OCW3_SET_SPECIAL_MASK: u8 = 1 << 6;
+
+/// Initialization word the data port expects next.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Init {
+    Icw2,
+    Icw3,
+    Icw4,
+    Done,
+}
+
+/// One 8259A.
+#[derive(Debug)]
+struct Pic {
+    irr: u8,
+    isr: u8,
+    imr: u8,
+    /// Vector of IR0, from ICW2.
+    vector_base: u8,
+    /// Inputs with a slave on them (master) or the slave's ID (slave), from ICW3.
+    cascade: u8,
+    init: Init,
+    icw4: bool,
+    single: bool,
+    level_triggered: bool,
+    auto_eoi: bool,
+    rotate_on_auto_eoi: bool,
+    special_mask: bool,
+    /// The command port reads ISR instead of IRR.
+    read_isr: bool,
+    /// The next read of the command port is a poll.
+    poll: bool,
+    /// Input with the lowest priority. The one after it has the highest.
+    lowest_priority: u8,
+    /// Input levels at the last sample, for edge detection.
+    levels: u8,
+}
+
+impl Pic {
+    fn new(vector_base: u8, cascade: u8, imr: u8) -> Pic {
+        Pic {
+            irr: 0,
+            isr: 0,
+            imr,
+            vector_base,
+            cascade,
+            init: Init::Done,
+            icw4: true,
+            single: false,
+            level_triggered: false,
+            auto_eoi: false,
+            rotate_on_auto_eoi: false,
+            special_mask: false,
+            read_isr: false,
+            poll: false,
+            lowest_priority: 7,
+            levels: 0,
+        }
+    }
+
+    /// Inputs from the highest priority to the lowest.
+    fn by_priority(&self) -> impl Iterator<Item = u8> {
+        let lowest = self.lowest_priority;
+        (1..=8).map(move |i| (lowest + i) & 7)
+    }
+
+    fn highest(&self, bits: u8) -> Option<u8> {
+        self.by_priority().find(|&irq| bits & (1 << irq) != 0)
+    }
+
+    /// Latches requests from the input levels: on rising edges, or while the input is
+    /// high when level triggered. `pulses` are inputs that went low and high again
+    /// since the last sample.
+    fn sample(&mut self, levels: u8, pulses: u8) {
+        if self.level_triggered {
+            self.irr = levels | pulses;
+        } else {
+            self.irr |= (levels & !self.levels) | pulses;
+        }
+        self.levels = levels;
+    }
+
+    /// The input whose request would be acknowledged next. Outside special mask mode a
+    /// request only interrupts the service of lower-priority inputs.
+    fn pending(&self) -> Option<u8> {
+        let requests = self.irr & !self.imr;
+        if self.special_mask {
+            return self.highest(requests & !self.isr);
+        }
+
+        for irq in self.by_priority() {
+            if self.isr & (1 << irq) != 0 {
+                return None;
+            }
+            if requests & (1 << irq) != 0 {
+                return Some(irq);
+            }
+        }
+        None
+    }
+
+    /// Moves the request on `irq` into service, as the INTA cycle does.
+    fn acknowledge(&mut self, irq: u8) {
+        let bit = 1 << irq;
+        self.irr &= !bit;
+        if !self.auto_eoi {
+            self.isr |= bit;
+        } else if self.rotate_on_auto_eoi {
+            self.lowest_priority = irq;
+        }
+    }
+
+    fn write_command(&mut self, value: u8) {
+        if value & ICW1 != 0 {
+            self.icw4 = value & ICW1_ICW4 != 0;
+            self.single = value & ICW1_SINGLE != 0;
+            self.level_triggered = value & ICW1_LEVEL != 0;
+            self.imr = 0;
+            self.isr = 0;
+            self.irr = 0;
+            self.auto_eoi = false;
+            self.rotate_on_auto_eoi = false;
+            self.special_mask = false;
+            self.read_isr = false;
+            self.poll = false;
+            self.lowest_priority = 7;
+            self.init = Init::Icw2;
+        } else if value & OCW3 != 0 {
+            if value & OCW3_READ_REGISTER != 0 {
+                self.read_isr = value & OCW3_READ_ISR != 0;
+            }
+            self.poll = value & OCW3_POLL != 0;
+            if value & OCW3_SET_SPECIAL_MASK != 0 {
+                self.special_mask = value & OCW3_SPECIAL_MASK != 0;
+            }
+        } else {
+            self.write_ocw2(value);
+        }
+    }
+
+    /// OCW2: the R, SL and EOI bits select the command, bits 0-2 the input.
+    fn write_ocw2(&mut self, value: u8) {
+        let level = value & 7;
+        let in_service = if self.special_mask {
+            self.isr & !self.imr
+        } else {
+            self.isr
+        };
+
+        match value >> 5 {
+            // Non-specific EOI, and with rotation.
+            0b001 | 0b101 => {
+                if let Some(irq) = self.highest(in_service) {
+                    self.isr &= !(1 << irq);
+                    if value >> 5 == 0b101 {
+                        self.lowest_priority = irq;
+                    }
+                }
+            }
+            // Specific EOI, and with rotation.
+            0b011 | 0b111 => {
+                self.isr &= !(1 << level);
+                if value >> 5 == 0b111 {
+                    self.lowest_priority = level;
+                }
+            }
+            0b100 => self.rotate_on_auto_eoi = true,
+            0b000 => self.rotate_on_auto_eoi = false,
+            0b110 => self.lowest_priority = level,
+            _ => (),
+        }
+    }
+
+    fn write_data(&mut self, value: u8) {
+        self.init = match self.init {
+            Init::Icw2 => {
+                self.vector_base = value & 0xF8;
+                if !self.single {
+                    Init::Icw3
+                } else if self.icw4 {
+                    Init::Icw4
+                } else {
+                    Init::Done
+                }
+            }
+            Init::Icw3 => {
+                self.cascade = value;
+                if self.icw4 {
+                    Init::Icw4
+                } else {
+                    Init::Done
+                }
+            }
+            Init::Icw4 => {
+                self.auto_eoi = value & ICW4_AUTO_EOI != 0;
+                Init::Done
+            }
+            Init::Done => {
+                self.imr = value;
+                Init::Done
+            }
+        };
+    }
+
+    fn read_command(&self) -> u8 {
+        if self.read_isr {
+            self.isr
+        } else {
+            self.irr
+        }
+    }
+}
+
+/// The master and slave 8259A of the PC/AT on ports 0x20-0x21 and 0xA0-0xA1, with the
+/// slave on IR2 of the master. IRQ 0-7 go to the master and IRQ 8-15 to the slave.
+///
+/// Both start out as the BIOS leaves them: IRQ 0-7 on vectors 08h-0Fh, IRQ 8-15 on
+/// 70h-77h, and everything but the cascade masked.
+#[derive(Debug)]
+pub struct PicPair {
+    master: Pic,
+    slave: Pic,
+}
+
+impl Default for PicPair {
+    fn default() -> Self {
+        PicPair::new()
+    }
+}
+
+impl PicPair {
+    pub fn new() -> PicPair {
+        PicPair {
+            master: Pic::new(0x08, 1 << CASCADE_IRQ, !(1 << CASCADE_IRQ)),
+            slave: Pic::new(0x70, CASCADE_IRQ, 0xFF),
+        }
+    }
+
+    /// Samples the IRQ lines, bit n holding IRQ n, along with the lines pulsed since
+    /// the last sample. The slave's INT output takes the place of IRQ2 at the master.
+    pub fn update(&mut self, levels: u16, pulses: u16) {
+        self.slave.sample((levels >> 8) as u8, (pulses >> 8) as u8);
+
+        let cascade = if self.slave.pending().is_some() {
+            1 << CASCADE_IRQ
+        } else {
+            0
+        };
+        let inputs = !(1 << CASCADE_IRQ);
+        self.master
+            .sample((levels as u8 & inputs) | cascade, pulses as u8 & inputs);
+    }
+
+    /// Whether an interrupt is waiting to be acknowledged.
+    pub fn has_interrupt(&self) -> bool {
+        self.master.pending().is_some()
+    }
+
+    fn is_cascaded(&self, irq: u8) -> bool {
+        !self.master.single && self.master.cascade & (1 << irq) != 0
+    }
+
+    /// Acknowledges the highest-priority request and returns its vector, as the INTA
+    /// cycles do. If the slave's request went away in the meantime its spurious IRQ 7
+    /// vector is returned without putting anything in service.
+    pub fn acknowledge(&mut self) -> Option<u8> {
+        let irq = self.master.pending()?;
+        self.master.acknowledge(irq);
+
+        if !self.is_cascaded(irq) {
+            return Some(self.master.vector_base + irq);
+        }
+        match self.slave.pending() {
+            Some(slave_irq) => {
+                self.slave.acknowledge(slave_irq);
+                Some(self.slave.vector_base + slave_irq)
+            }
+            None => Some(self.slave.vector_base + 7),
+        }
+    }
+
+    /// Poll command read: acknowledges the highest-priority request of one chip and
+    /// returns 80h with its input, or 0 if there is none.
+    fn poll(&mut self, slave: bool) -> u8 {
+        let pic = if slave {
+            &mut self.slave
+        } else {
+            &mut self.master
+        };
+        pic.poll = false;
+        match pic.pending() {
+            Some(irq) => {
+                pic.acknowledge(irq);
+                0x80 | irq
+            }
+            None => 0,
+        }
+    }
+}
+
+impl PortDevice for PicPair {
+    fn read8(&mut self, port: u16) -> Result<u8, EmulatorError> {
+        let slave = port & 0x80 != 0;
+        let pic = if slave { &self.slave } else { &self.master };
+        Ok(if port & 1 != 0 {
+            pic.imr
+        } else if pic.poll {
+            self.poll(slave)
+        } else {
+            pic.read_command()
+        })
+    }
+
+    fn write8(&mut self, port: u16, value: u8) -> Result<(), EmulatorError> {
+        let pic = if port & 0x80 != 0 {
+            &mut self.slave
+        } else {
+            &mut self.master
+        };
+        if port & 1 != 0 {
+            pic.write_data(value);
+        } else {
+            pic.write_command(value);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(pic: &mut PicPair, port: u16, value: u8) {
+        pic.write8(port, value).unwrap();
+    }
+
+    fn read(pic: &mut PicPair, port: u16) -> u8 {
+        pic.read8(port).unwrap()
+    }
+
+    /// A pair with every master input unmasked.
+    fn unmasked() -> PicPair {
+        let mut pic = PicPair::new();
+        write(&mut pic, 0x21, 0x00);
+        pic
+    }
+
+    #[test]
+    fn initialization_words() {
+        let mut pic = PicPair::new();
+        for (port, value) in [(0x20, 0x11), (0x21, 0x20), (0x21, 0x04), (0x21, 0x01)] {
+            write(&mut pic, port, value);
+        }
+        for (port, value) in [(0xA0, 0x11), (0xA1, 0x28), (0xA1, 0x02), (0xA1, 0x01)] {
+            write(&mut pic, port, value);
+        }
+        // ICW1 clears the masks; the next data write is OCW1 again.
+        assert_eq!(read(&mut pic, 0x21), 0x00);
+        write(&mut pic, 0xA1, 0x01);
+        assert_eq!(read(&mut pic, 0xA1), 0x01);
+
+        pic.update(1 << 0 | 1 << 9, 0);
+        assert_eq!(pic.acknowledge(), Some(0x20));
+        // IRQ9 comes in through IR2, below IRQ0 in service.
+        assert!(!pic.has_interrupt());
+        write(&mut pic, 0x20, 0x20);
+        pic.update(1 << 0 | 1 << 9, 0);
+        assert_eq!(pic.acknowledge(), Some(0x29));
+        // IRQ8 is masked on the slave.
+        write(&mut pic, 0xA0, 0x20);
+        write(&mut pic, 0x20, 0x20);
+        pic.update(1 << 8 | 1 << 9, 0);
+        assert!(!pic.has_interrupt());
+    }
+
+    #[test]
+    fn requests_latch_on_edges_and_pulses() {
+        let mut pic = unmasked();
+        pic.update(1 << 1, 0);
+        assert_eq!(pic.acknowledge(), Some(0x09));
+        write(&mut pic, 0x20, 0x20);
+
+        // Staying high is not a new request.
+        pic.update(1 << 1, 0);
+        assert!(!pic.has_interrupt());
+        pic.update(0, 0);
+        pic.update(1 << 1, 0);
+        assert!(pic.has_interrupt());
+        assert_eq!(pic.acknowledge(), Some(0x09));
+        write(&mut pic, 0x20, 0x20);
+
+        pic.update(0, 1 << 3);
+        assert!(pic.has_interrupt());
+    }
+
+    #[test]
+    fn eoi_and_register_reads() {
+        let mut pic = unmasked();
+        pic.update(1 << 3 | 1 << 4, 0);
+        assert_eq!(read(&mut pic, 0x20), 0x18);
+        assert_eq!(pic.acknowledge(), Some(0x0B));
+
+        // OCW3 selects ISR or IRR for reads of the command port.
+        write(&mut pic, 0x20, 0x0B);
+        assert_eq!(read(&mut pic, 0x20), 0x08);
+        write(&mut pic, 0x20, 0x0A);
+        assert_eq!(read(&mut pic, 0x20), 0x10);
+
+        // IRQ4 waits for IRQ3's EOI; a specific EOI for another input leaves it waiting.
+        assert!(!pic.has_interrupt());
+        write(&mut pic, 0x20, 0x65);
+        assert!(!pic.has_interrupt());
+        write(&mut pic, 0x20, 0x63);
+        assert_eq!(pic.acknowledge(), Some(0x0C));
+
+        // A non-specific EOI ends the highest-priority service.
+        write(&mut pic, 0x20, 0x0B);
+        assert_eq!(read(&mut pic, 0x20), 0x10);
+        write(&mut pic, 0x20, 0x20);
+        assert_eq!(read(&mut pic, 0x20), 0x00);
+    }
+
+    #[test]
+    fn auto_eoi_and_poll() {
+        let mut pic = PicPair::new();
+        for (port, value) in [(0x20, 0x11), (0x21, 0x08), (0x21, 0x04), (0x21, 0x03)] {
+            write(&mut pic, port, value);
+        }
+        pic.update(1 << 5, 0);
+        assert_eq!(pic.acknowledge(), Some(0x0D));
+        write(&mut pic, 0x20, 0x0B);
+        assert_eq!(read(&mut pic, 0x20), 0x00);
+
+        // A poll acknowledges like INTA but returns the input.
+        pic.update(1 << 6, 0);
+        write(&mut pic, 0x20, 0x0C);
+        assert_eq!(read(&mut pic, 0x20), 0x86);
+        write(&mut pic, 0x20, 0x0C);
+        assert_eq!(read(&mut pic, 0x20), 0x00);
+    }
+}
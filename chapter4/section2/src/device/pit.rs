@@ -0,0 +1,603 @@
+use crate::error::EmulatorError;
+use crate::io_bus::PortDevice;
+use crate::irq::IrqLine;
+use crate::scheduler::{EventId, Scheduler};
+
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+
+/// Input clock of every channel, in Hz.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// Period of the DRAM refresh toggle in bit 4 of port 0x61, in nanoseconds.
+const REFRESH_PERIOD: u64 = 15_085;
+
+// Port 0x61
+const GATE2: u8 = 1;
+const SPEAKER_DATA: u8 = 1 << 1;
+const REFRESH: u8 = 1 << 4;
+const OUT2: u8 = 1 << 5;
+
+fn to_bcd(value: u16) -> u16 {
+    ((value / 1000 % 10) << 12)
+        | ((value / 100 % 10) << 8)
+        | ((value / 10 % 10) << 4)
+        | (value % 10)
+}
+
+fn from_bcd(value: u16) -> u16 {
+    ((value >> 12) & 0xF) * 1000
+        + ((value >> 8) & 0xF) * 100
+        + ((value >> 4) & 0xF) * 10
+        + (value & 0xF)
+}
+
+/// One counter of the 8254.
+///
+/// The counter is not stepped clock by clock. It remembers how many input clocks it
+/// has counted and when it last started, and its count and OUT are worked out from
+/// that when they are looked at.
+#[derive(Debug)]
+struct Channel {
+    /// 0 to 5; modes 6 and 7 are stored as 2 and 3.
+    mode: u8,
+    /// RW field of the control word: 1 LSB only, 2 MSB only, 3 LSB then MSB.
+    access: u8,
+    bcd: bool,
+    /// Initial count in binary. 0 stands for the maximum.
+    reload: u16,
+    /// A count has been written since the control word.
+    loaded: bool,
+    /// The control word or a new count has not yet reached the counter.
+    null_count: bool,
+    /// LSB of a two-byte count, waiting for the MSB.
+    written_low: Option<u8>,
+    /// The next two-byte read of the live count returns the MSB.
+    read_high: bool,
+    /// Latched count bytes in read order.
+    latched: Vec<u8>,
+    status: Option<u8>,
+    gate: bool,
+    /// Modes 1 and 5 have been triggered by the gate.
+    armed: bool,
+    /// Input clocks counted before `since`.
+    counted: u64,
+    /// Clock tick the counter has been counting from, or `None` while it is stopped.
+    since: Option<u64>,
+    /// Periods or strobes seen by the last `drive_irq`.
+    pulses: u64,
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            mode: 0,
+            access: 3,
+            bcd: false,
+            reload: 0,
+            loaded: false,
+            null_count: true,
+            written_low: None,
+            read_high: false,
+            latched: Vec::new(),
+            status: None,
+            gate: true,
+            armed: false,
+            counted: 0,
+            since: None,
+            pulses: 0,
+        }
+    }
+
+    fn modulus(&self) -> u64 {
+        if self.bcd {
+            10_000
+        } else {
+            65_536
+        }
+    }
+
+    fn period(&self) -> u64 {
+        match self.reload {
+            0 => self.modulus(),
+            reload => reload as u64,
+        }
+    }
+
+    fn elapsed(&self, now: u64) -> u64 {
+        self.counted + self.since.map_or(0, |since| now.saturating_sub(since))
+    }
+
+    /// Counting is under way: a count is loaded and, in modes 1 and 5, triggered.
+    fn is_counting(&self) -> bool {
+        self.loaded && (self.armed || !matches!(self.mode, 1 | 5))
+    }
+
+    fn count(&self, now: u64) -> u16 {
+        if !self.is_counting() {
+            return self.reload;
+        }
+
+        let n = self.period();
+        let e = self.elapsed(now);
+        let value = match self.mode {
+            2 => n - e % n,
+            // Decrements by two, through each half of the square wave.
+            3 => n - 2 * (e % n.div_ceil(2)),
+            // Keeps counting down through zero after the terminal count.
+            _ => (n + self.modulus() - e % self.modulus()) % self.modulus(),
+        };
+        (value % self.modulus()) as u16
+    }
+
+    fn out(&self, now: u64) -> bool {
+        if !self.is_counting() {
+            return self.mode != 0;
+        }
+
+        let n = self.period();
+        let e = self.elapsed(now);
+        match self.mode {
+            0 | 1 => e >= n,
+            2 => !self.gate || e % n != n - 1,
+            3 => !self.gate || e % n < n.div_ceil(2),
+            _ => e != n,
+        }
+    }
+
+    /// Periods completed (modes 2 and 3) or strobes given (modes 4 and 5) so far.
+    fn pulses(&self, now: u64) -> u64 {
+        if !self.is_counting() {
+            return 0;
+        }
+        match self.mode {
+            2 | 3 => self.elapsed(now) / self.period(),
+            4 | 5 => (self.elapsed(now) >= self.period()) as u64,
+            _ => 0,
+        }
+    }
+
+    /// Drives an interrupt request line from OUT. Modes 0 and 1 drive OUT itself. In
+    /// the other modes OUT is only low briefly, so the line stays high and is pulsed
+    /// for each period or strobe instead.
+    fn drive_irq(&mut self, now: u64, irq: &IrqLine) {
+        if matches!(self.mode, 0 | 1) {
+            irq.set(self.out(now));
+            return;
+        }
+        irq.raise();
+        let pulses = self.pulses(now);
+        if pulses != self.pulses {
+            self.pulses = pulses;
+            irq.pulse();
+        }
+    }
+
+    /// Input clock at which OUT next changes in a way `drive_irq` reports: the
+    /// terminal count in modes 0 and 1, the next strobe in modes 4 and 5, and the
+    /// end of the current period in modes 2 and 3.
+    fn next_event(&self, now: u64) -> Option<u64> {
+        if !self.is_counting() || self.since.is_none() {
+            return None;
+        }
+        let n = self.period();
+        let e = self.elapsed(now);
+        match self.mode {
+            2 | 3 => Some(now + n - e % n),
+            _ if e < n => Some(now + n - e),
+            _ => None,
+        }
+    }
+
+    fn status_byte(&self, now: u64) -> u8 {
+        let mut status = self.access << 4 | self.mode << 1 | self.bcd as u8;
+        if self.out(now) {
+            status |= 0x80;
+        }
+        if self.null_count {
+            status |= 0x40;
+        }
+        status
+    }
+
+    fn encoded_count(&self, now: u64) -> u16 {
+        let count = self.count(now);
+        if self.bcd {
+            to_bcd(count)
+        } else {
+            count
+        }
+    }
+
+    fn latch_count(&mut self, now: u64) {
+        if !self.latched.is_empty() {
+            return;
+        }
+        let [low, high] = self.encoded_count(now).to_le_bytes();
+        self.latched = match self.access {
+            1 => vec![low],
+            2 => vec![high],
+            _ => vec![low, high],
+        };
+    }
+
+    fn latch_status(&mut self, now: u64) {
+        if self.status.is_none() {
+            self.status = Some(self.status_byte(now));
+        }
+    }
+
+    fn set_control(&mut self, access: u8, mode: u8, bcd: bool) {
+        *self = Channel {
+            mode: if mode >= 6 { mode - 4 } else { mode },
+            access,
+            bcd,
+            gate: self.gate,
+            ..Channel::new()
+        };
+    }
+
+    fn load(&mut self, raw: u16, now: u64) {
+        self.reload = if self.bcd { from_bcd(raw) } else { raw };
+        self.loaded = true;
+        self.null_count = false;
+        self.counted = 0;
+        self.pulses = 0;
+        self.armed = false;
+        // Modes 1 and 5 wait for the gate to trigger them. A new count in modes 2 and 3
+        // restarts the current period rather than waiting for its end.
+        self.since = if self.gate && !matches!(self.mode, 1 | 5) {
+            Some(now)
+        } else {
+            None
+        };
+    }
+
+    fn write(&mut self, value: u8, now: u64) {
+        match self.access {
+            1 => self.load(value as u16, now),
+            2 => self.load((value as u16) << 8, now),
+            _ => match self.written_low.take() {
+                Some(low) => self.load(low as u16 | (value as u16) << 8, now),
+                None => self.written_low = Some(value),
+            },
+        }
+    }
+
+    fn read(&mut self, now: u64) -> u8 {
+        if let Some(status) = self.status.take() {
+            return status;
+        }
+        if !self.latched.is_empty() {
+            return self.latched.remove(0);
+        }
+
+        let [low, high] = self.encoded_count(now).to_le_bytes();
+        match self.access {
+            1 => low,
+            2 => high,
+            _ => {
+                self.read_high = !self.read_high;
+                if self.read_high {
+                    low
+                } else {
+                    high
+                }
+            }
+        }
+    }
+
+    /// Gate low pauses modes 0 and 4 and stops modes 2 and 3, which start a new period
+    /// when it rises again. A rising gate triggers modes 1 and 5.
+    fn set_gate(&mut self, gate: bool, now: u64) {
+        if gate == self.gate {
+            return;
+        }
+        self.gate = gate;
+
+        match self.mode {
+            0 | 4 => {
+                if gate {
+                    if self.loaded {
+                        self.since = Some(now);
+                    }
+                } else {
+                    self.counted = self.elapsed(now);
+                    self.since = None;
+                }
+            }
+            2 | 3 => {
+                self.counted = 0;
+                self.pulses = 0;
+                self.since = if gate && self.loaded { Some(now) } else { None };
+            }
+            _ => {
+                if gate && self.loaded {
+                    self.armed = true;
+                    self.counted = 0;
+                    self.pulses = 0;
+                    self.since = Some(now);
+                }
+            }
+        }
+    }
+}
+
+/// 8254 programmable interval timer on ports 0x40-0x43, clocked from `Clock`.
+///
+/// Channel 0 drives IRQ0, from an event scheduled for each time its OUT changes.
+/// Channel 1, which refreshed DRAM, is free-running. Channel 2 is gated by bit 0 of
+/// port 0x61 and feeds the speaker; see `SystemControlPort`.
+#[derive(Debug)]
+pub struct Pit {
+    channels: [Channel; 3],
+    scheduler: Scheduler,
+    irq: IrqLine,
+    speaker_data: bool,
+    /// Handle the scheduled event reaches the timer through.
+    this: Weak<RefCell<Pit>>,
+    /// Next change of channel 0's OUT.
+    event: Option<EventId>,
+}
+
+impl Pit {
+    /// Creates the timer as the BIOS leaves it: channel 0 in mode 3 with the maximum
+    /// count, ticking at 18.2 Hz, and channel 1 in mode 2 with a count of 18.
+    pub fn new(scheduler: Scheduler, irq: IrqLine) -> Rc<RefCell<Pit>> {
+        let pit = Rc::new_cyclic(|this| {
+            RefCell::new(Pit {
+                channels: [Channel::new(), Channel::new(), Channel::new()],
+                scheduler,
+                irq,
+                speaker_data: false,
+                this: this.clone(),
+                event: None,
+            })
+        });
+
+        {
+            let mut pit = pit.borrow_mut();
+            let now = pit.now();
+            pit.channels[0].set_control(3, 3, false);
+            pit.channels[0].load(0, now);
+            pit.channels[1].set_control(1, 2, false);
+            pit.channels[1].load(18, now);
+            pit.update_irq();
+        }
+        pit
+    }
+
+    /// Input clocks since the emulator was created.
+    fn now(&self) -> u64 {
+        (self.scheduler.clock().now() as u128 * PIT_FREQUENCY as u128 / 1_000_000_000) as u64
+    }
+
+    /// Drives IRQ0 from channel 0 and schedules the next change of its OUT, replacing
+    /// any event scheduled before.
+    fn update_irq(&mut self) {
+        let now = self.now();
+        self.channels[0].drive_irq(now, &self.irq);
+
+        if let Some(event) = self.event.take() {
+            self.scheduler.cancel(event);
+        }
+        if let Some(tick) = self.channels[0].next_event(now) {
+            // Rounded up, so that the clock has reached the tick when the event runs.
+            let time = (tick as u128 * 1_000_000_000).div_ceil(PIT_FREQUENCY as u128) as u64;
+            let this = self.this.clone();
+            self.event = Some(self.scheduler.schedule_at(time, move || {
+                if let Some(pit) = this.upgrade() {
+                    pit.borrow_mut().update_irq();
+                }
+            }));
+        }
+    }
+
+    /// Frequency the speaker sounds at, if channel 2 is gated on, its output reaches
+    /// the speaker and it generates a square wave.
+    pub fn speaker_frequency(&self) -> Option<f64> {
+        let channel = &self.channels[2];
+        if !channel.gate || !self.speaker_data || channel.mode != 3 || !channel.loaded {
+            return None;
+        }
+        Some(PIT_FREQUENCY as f64 / channel.period() as f64)
+    }
+
+    /// Control word on port 0x43.
+    fn write_control(&mut self, value: u8) {
+        let now = self.now();
+        let select = value >> 6;
+        let access = (value >> 4) & 3;
+
+        if select == 3 {
+            // Read-back: bits 1-3 select the channels, bits 5 and 4 (active low) the
+            // count and the status.
+            for (i, channel) in self.channels.iter_mut().enumerate() {
+                if value & (2 << i) == 0 {
+                    continue;
+                }
+                if value & 0x10 == 0 {
+                    channel.latch_status(now);
+                }
+                if value & 0x20 == 0 {
+                    channel.latch_count(now);
+                }
+            }
+        } else if access == 0 {
+            self.channels[select as usize].latch_count(now);
+        } else {
+            self.channels[select as usize].set_control(access, (value >> 1) & 7, value & 1 != 0);
+        }
+    }
+
+    fn read_port_b(&self) -> u8 {
+        let mut value = 0;
+        if self.channels[2].gate {
+            value |= GATE2;
+        }
+        if self.speaker_data {
+            value |= SPEAKER_DATA;
+        }
+        if (self.scheduler.clock().now() / REFRESH_PERIOD) & 1 != 0 {
+            value |= REFRESH;
+        }
+        if self.channels[2].out(self.now()) {
+            value |= OUT2;
+        }
+        value
+    }
+
+    fn write_port_b(&mut self, value: u8) {
+        let now = self.now();
+        self.channels[2].set_gate(value & GATE2 != 0, now);
+        self.speaker_data = value & SPEAKER_DATA != 0;
+    }
+}
+
+impl PortDevice for Pit {
+    fn read8(&mut self, port: u16) -> Result<u8, EmulatorError> {
+        let now = self.now();
+        Ok(match port & 3 {
+            3 => 0xFF,
+            channel => self.channels[channel as usize].read(now),
+        })
+    }
+
+    fn write8(&mut self, port: u16, value: u8) -> Result<(), EmulatorError> {
+        let now = self.now();
+        match port & 3 {
+            3 => self.write_control(value),
+            channel => self.channels[channel as usize].write(value, now),
+        }
+        self.update_irq();
+        Ok(())
+    }
+}
+
+/// Port 0x61 of the PC/AT: the gate of channel 2 and the speaker data enable in bits 0
+/// and 1, the refresh toggle and channel 2's OUT in bits 4 and 5.
+///
+/// It is a separate device sharing the timer, as the port lies outside the timer's
+/// own range.
+#[derive(Debug)]
+pub struct SystemControlPort(pub Rc<RefCell<Pit>>);
+
+impl PortDevice for SystemControlPort {
+    fn read8(&mut self, _port: u16) -> Result<u8, EmulatorError> {
+        Ok(self.0.borrow().read_port_b())
+    }
+
+    fn write8(&mut self, _port: u16, value: u8) -> Result<(), EmulatorError> {
+        self.0.borrow_mut().write_port_b(value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ClockMode};
+    use crate::irq::IrqLines;
+
+    /// A channel in `mode` counting from `reload`, started at clock 0.
+    fn loaded(mode: u8, reload: u16) -> Channel {
+        let mut channel = Channel::new();
+        channel.set_control(3, mode, false);
+        channel.load(reload, 0);
+        channel
+    }
+
+    #[test]
+    fn rate_generator_counts() {
+        let channel = loaded(2, 10);
+        let counts: Vec<u16> = (0..12).map(|now| channel.count(now)).collect();
+        assert_eq!(counts, [10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 10, 9]);
+        // OUT goes low for the one clock at a count of 1.
+        let out: Vec<bool> = (7..12).map(|now| channel.out(now)).collect();
+        assert_eq!(out, [true, true, false, true, true]);
+        assert_eq!(channel.pulses(25), 2);
+        assert_eq!(channel.next_event(25), Some(30));
+    }
+
+    #[test]
+    fn square_wave_counts() {
+        let channel = loaded(3, 10);
+        let counts: Vec<u16> = (0..11).map(|now| channel.count(now)).collect();
+        assert_eq!(counts, [10, 8, 6, 4, 2, 10, 8, 6, 4, 2, 10]);
+        let out: Vec<bool> = (0..11).map(|now| channel.out(now)).collect();
+        assert_eq!(
+            out,
+            [true, true, true, true, true, false, false, false, false, false, true]
+        );
+
+        // An odd count stays high one clock longer than low.
+        let channel = loaded(3, 5);
+        let out: Vec<bool> = (0..5).map(|now| channel.out(now)).collect();
+        assert_eq!(out, [true, true, true, false, false]);
+
+        // 0 stands for 65536.
+        let channel = loaded(3, 0);
+        assert_eq!(channel.count(1), 65_534);
+        assert_eq!(channel.next_event(0), Some(65_536));
+    }
+
+    #[test]
+    fn bcd_counts() {
+        let mut channel = Channel::new();
+        channel.set_control(3, 2, true);
+        channel.write(0x00, 0);
+        channel.write(0x10, 0);
+        assert_eq!(channel.period(), 1000);
+        assert_eq!([channel.read(1), channel.read(1)], [0x99, 0x09]);
+        assert_eq!(channel.count(1000), 1000);
+    }
+
+    fn pit() -> (Rc<RefCell<Pit>>, Scheduler) {
+        let scheduler = Scheduler::new(Clock::new(ClockMode::Virtual));
+        let pit = Pit::new(scheduler.clone(), IrqLines::new().line(0));
+        (pit, scheduler)
+    }
+
+    fn write(pit: &Rc<RefCell<Pit>>, port: u16, value: u8) {
+        pit.borrow_mut().write8(port, value).unwrap();
+    }
+
+    fn read(pit: &Rc<RefCell<Pit>>, port: u16) -> u8 {
+        pit.borrow_mut().read8(port).unwrap()
+    }
+
+    #[test]
+    fn latched_count_holds_until_read() {
+        let (pit, scheduler) = pit();
+        // Channel 0, LSB then MSB, mode 2, a count of 0x1000.
+        write(&pit, 0x43, 0x34);
+        write(&pit, 0x40, 0x00);
+        write(&pit, 0x40, 0x10);
+        write(&pit, 0x43, 0x00);
+        // A second latch before the first is read changes nothing.
+        scheduler.clock().advance_to(1_000_000);
+        write(&pit, 0x43, 0x00);
+        assert_eq!([read(&pit, 0x40), read(&pit, 0x40)], [0x00, 0x10]);
+
+        // The live count again.
+        let live = (0x1000 - pit.borrow().now() % 0x1000) as u16;
+        assert_eq!([read(&pit, 0x40), read(&pit, 0x40)], live.to_le_bytes());
+    }
+
+    #[test]
+    fn read_back_latches_status_and_count() {
+        let (pit, _scheduler) = pit();
+        write(&pit, 0x43, 0x34);
+        // Status of channel 0: OUT goes high on a mode 2 control word, which has no
+        // count yet.
+        write(&pit, 0x43, 0xE2);
+        assert_eq!(read(&pit, 0x40), 0x80 | 0x40 | 0x34);
+
+        write(&pit, 0x40, 0x34);
+        write(&pit, 0x40, 0x12);
+        // Status and count of channel 0, with the count loaded.
+        write(&pit, 0x43, 0xC2);
+        assert_eq!(read(&pit, 0x40), 0x80 | 0x34);
+        assert_eq!([read(&pit, 0x40), read(&pit, 0x40)], [0x34, 0x12]);
+    }
+}
@@ -0,0 +1,690 @@
+use crate::error::EmulatorError;
+use crate::io_bus::PortDevice;
+use crate::irq::IrqLine;
+use crate::scheduler::{EventId, Scheduler};
+
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// Frequency of the time base crystal, in Hz.
+const TIME_BASE: u64 = 32_768;
+
+/// How long before an update cycle UIP is set, in nanoseconds.
+const UPDATE_WARNING: u64 = 244_000;
+
+// Registers.
+const SECONDS: u8 = 0x00;
+const SECONDS_ALARM: u8 = 0x01;
+const MINUTES: u8 = 0x02;
+const MINUTES_ALARM: u8 = 0x03;
+const HOURS: u8 = 0x04;
+const HOURS_ALARM: u8 = 0x05;
+const DAY_OF_WEEK: u8 = 0x06;
+const DAY_OF_MONTH: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+const STATUS_C: u8 = 0x0C;
+const STATUS_D: u8 = 0x0D;
+
+// NVRAM, laid out as the PC/AT BIOS and its successors use it.
+const BASE_MEMORY: u8 = 0x15;
+const EXTENDED_MEMORY: u8 = 0x17;
+const CHECKSUM: u8 = 0x2E;
+const EXTENDED_MEMORY_COPY: u8 = 0x30;
+const CENTURY: u8 = 0x32;
+const MEMORY_ABOVE_16M: u8 = 0x34;
+/// Third boot device in the high nibble.
+const BOOT_ORDER_THIRD: u8 = 0x38;
+/// First boot device in the low nibble, second in the high one.
+const BOOT_ORDER: u8 = 0x3D;
+
+// Status register A.
+const A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const A_DIVIDER: u8 = 7 << 4;
+/// The divider setting for a 32.768 kHz time base, the only one the clock runs with.
+const A_DIVIDER_RUNNING: u8 = 2 << 4;
+const A_RATE: u8 = 0x0F;
+
+// Status register B.
+const B_SET: u8 = 1 << 7;
+const B_PERIODIC: u8 = 1 << 6;
+const B_ALARM: u8 = 1 << 5;
+const B_UPDATE: u8 = 1 << 4;
+const B_BINARY: u8 = 1 << 2;
+const B_24_HOUR: u8 = 1 << 1;
+
+// Status register C. The flags line up with their enable bits in B.
+const C_IRQ: u8 = 1 << 7;
+const C_FLAGS: u8 = B_PERIODIC | B_ALARM | B_UPDATE;
+
+const D_VALID_RAM: u8 = 1 << 7;
+
+/// Alarm values from C0h up match every value.
+const ALARM_DONT_CARE: u8 = 0xC0;
+
+const PM: u8 = 1 << 7;
+
+/// Devices the BIOS can boot from, as numbered in the boot order bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootDevice {
+    Floppy = 1,
+    HardDisk = 2,
+    CdRom = 3,
+}
+
+/// Days since 1970-01-01 to a proleptic Gregorian (year, month, day).
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month as u8, day as u8)
+}
+
+fn is_leap_year(year: u16) -> bool {
+    year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400))
+}
+
+fn days_in_month(month: u8, year: u16) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Calendar time as the update cycle keeps it, in binary and 24-hour form.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Time {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    /// 1 for Sunday to 7 for Saturday.
+    day_of_week: u8,
+    day: u8,
+    month: u8,
+    year: u16,
+}
+
+impl Time {
+    fn from_system_time(time: SystemTime) -> Time {
+        let seconds = match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_secs() as i64,
+            Err(before) => -(before.duration().as_secs() as i64),
+        };
+        let days = seconds.div_euclid(86_400);
+        let second_of_day = seconds.rem_euclid(86_400);
+        let (year, month, day) = civil_from_days(days);
+        Time {
+            second: (second_of_day % 60) as u8,
+            minute: (second_of_day / 60 % 60) as u8,
+            hour: (second_of_day / 3600) as u8,
+            // 1970-01-01 was a Thursday.
+            day_of_week: ((days + 4).rem_euclid(7) + 1) as u8,
+            day,
+            month,
+            year: year.clamp(0, 9999) as u16,
+        }
+    }
+
+    /// Advances the time by one second, as the update cycle does. Out-of-range values
+    /// written by the guest roll over at the next carry.
+    fn advance(&mut self) {
+        self.second += 1;
+        if self.second < 60 {
+            return;
+        }
+        self.second = 0;
+        self.minute += 1;
+        if self.minute < 60 {
+            return;
+        }
+        self.minute = 0;
+        self.hour += 1;
+        if self.hour < 24 {
+            return;
+        }
+        self.hour = 0;
+        self.day_of_week = self.day_of_week % 7 + 1;
+        self.day += 1;
+        if self.day <= days_in_month(self.month, self.year) {
+            return;
+        }
+        self.day = 1;
+        self.month += 1;
+        if self.month <= 12 {
+            return;
+        }
+        self.month = 1;
+        self.year = (self.year + 1) % 10_000;
+    }
+}
+
+/// MC146818 real-time clock with its battery-backed RAM on ports 0x70 (index) and
+/// 0x71 (data), with IRQ8.
+///
+/// Writing the index port also sets the NMI mask in bit 7. The time advances from
+/// an update event scheduled every second, which raises the update and alarm
+/// interrupts; the periodic interrupt has its own event while it is enabled. The
+/// century is kept in register 32h as IBM defined it, and daylight saving time is
+/// not applied.
+#[derive(Debug)]
+pub struct Rtc {
+    scheduler: Scheduler,
+    irq: IrqLine,
+    /// Handle the scheduled events reach the clock through.
+    this: Weak<RefCell<Rtc>>,
+    index: u8,
+    nmi_disabled: bool,
+    time: Time,
+    /// Register bytes not held in `time`: the alarms, the status registers and the
+    /// NVRAM.
+    ram: [u8; 128],
+    /// Pending flags of register C.
+    flags: u8,
+    /// Emulated time the divider started counting at, which the periodic interrupt
+    /// is timed from.
+    divider_start: u64,
+    next_update: u64,
+    update_event: Option<EventId>,
+    periodic_event: Option<EventId>,
+    /// Periods completed when the periodic flag was last set or read.
+    periods_seen: u64,
+}
+
+impl Rtc {
+    /// Creates the clock set to `time`, with the NVRAM describing `memory_size` bytes
+    /// of RAM and booting from the hard disk, then the floppy.
+    pub fn new(
+        scheduler: Scheduler,
+        irq: IrqLine,
+        time: SystemTime,
+        memory_size: usize,
+    ) -> Rc<RefCell<Rtc>> {
+        let rtc = Rc::new_cyclic(|this| {
+            RefCell::new(Rtc {
+                scheduler,
+                irq,
+                this: this.clone(),
+                index: 0,
+                nmi_disabled: false,
+                time: Time::from_system_time(time),
+                ram: [0; 128],
+                flags: 0,
+                divider_start: 0,
+                next_update: 0,
+                update_event: None,
+                periodic_event: None,
+                periods_seen: 0,
+            })
+        });
+
+        {
+            let mut rtc = rtc.borrow_mut();
+            // 24-hour time in BCD, as the BIOS sets it up, and a 1024 Hz periodic rate.
+            rtc.ram[STATUS_B as usize] = B_24_HOUR;
+            rtc.ram[STATUS_D as usize] = D_VALID_RAM;
+            rtc.set_memory_size(memory_size);
+            rtc.set_boot_order(&[BootDevice::HardDisk, BootDevice::Floppy]);
+            rtc.write_status_a(A_DIVIDER_RUNNING | 6);
+        }
+        rtc
+    }
+
+    /// Sets the clock, so that runs on the virtual clock see the same dates.
+    pub fn set_time(&mut self, time: SystemTime) {
+        self.time = Time::from_system_time(time);
+    }
+
+    /// Whether the last index written had bit 7 set, masking NMI.
+    pub fn nmi_disabled(&self) -> bool {
+        self.nmi_disabled
+    }
+
+    /// Byte `index` of the NVRAM, or of a register.
+    pub fn nvram(&self, index: u8) -> u8 {
+        self.ram[(index & 0x7F) as usize]
+    }
+
+    /// Stores a byte of the NVRAM from 0Eh up, keeping the checksum over 10h-2Dh
+    /// valid.
+    pub fn set_nvram(&mut self, index: u8, value: u8) {
+        let index = index & 0x7F;
+        assert!(index > STATUS_D, "CMOS byte {:02X}h is not NVRAM", index);
+        self.ram[index as usize] = value;
+        self.update_checksum();
+    }
+
+    fn set_word(&mut self, index: u8, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.set_nvram(index, low);
+        self.set_nvram(index + 1, high);
+    }
+
+    /// Fills in the base memory in KiB below 640 KiB, the extended memory in KiB above
+    /// 1 MiB, and the memory above 16 MiB in 64 KiB blocks.
+    pub fn set_memory_size(&mut self, bytes: usize) {
+        let base = (bytes.min(640 << 10) >> 10) as u16;
+        let extended = (bytes.saturating_sub(1 << 20) >> 10).min(0xFFFF) as u16;
+        let above_16m = (bytes.saturating_sub(16 << 20) >> 16).min(0xFFFF) as u16;
+        self.set_word(BASE_MEMORY, base);
+        self.set_word(EXTENDED_MEMORY, extended);
+        self.set_word(EXTENDED_MEMORY_COPY, extended);
+        self.set_word(MEMORY_ABOVE_16M, above_16m);
+    }
+
+    /// Fills in the boot order bytes with up to three devices, first to last.
+    pub fn set_boot_order(&mut self, devices: &[BootDevice]) {
+        assert!(devices.len() <= 3, "only three boot devices fit in CMOS");
+        let device = |i: usize| devices.get(i).map_or(0, |&device| device as u8);
+        self.set_nvram(BOOT_ORDER, device(0) | (device(1) << 4));
+        let third = self.ram[BOOT_ORDER_THIRD as usize] & 0x0F;
+        self.set_nvram(BOOT_ORDER_THIRD, third | (device(2) << 4));
+    }
+
+    fn update_checksum(&mut self) {
+        let sum = self.ram[0x10..CHECKSUM as usize]
+            .iter()
+            .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        let [high, low] = sum.to_be_bytes();
+        self.ram[CHECKSUM as usize] = high;
+        self.ram[CHECKSUM as usize + 1] = low;
+    }
+
+    fn now(&self) -> u64 {
+        self.scheduler.clock().now()
+    }
+
+    fn status_b(&self) -> u8 {
+        self.ram[STATUS_B as usize]
+    }
+
+    fn is_running(&self) -> bool {
+        self.ram[STATUS_A as usize] & A_DIVIDER == A_DIVIDER_RUNNING
+    }
+
+    /// A time register value in the format selected in register B.
+    fn encode(&self, value: u8) -> u8 {
+        if self.status_b() & B_BINARY != 0 {
+            value
+        } else {
+            ((value / 10) << 4) | (value % 10)
+        }
+    }
+
+    fn decode(&self, value: u8) -> u8 {
+        if self.status_b() & B_BINARY != 0 {
+            value
+        } else {
+            (value >> 4) * 10 + (value & 0x0F)
+        }
+    }
+
+    fn encode_hour(&self, hour: u8) -> u8 {
+        if self.status_b() & B_24_HOUR != 0 {
+            return self.encode(hour);
+        }
+        let pm = if hour >= 12 { PM } else { 0 };
+        match hour % 12 {
+            0 => self.encode(12) | pm,
+            hour => self.encode(hour) | pm,
+        }
+    }
+
+    fn decode_hour(&self, value: u8) -> u8 {
+        if self.status_b() & B_24_HOUR != 0 {
+            return self.decode(value);
+        }
+        let hour = self.decode(value & !PM) % 12;
+        if value & PM != 0 {
+            hour + 12
+        } else {
+            hour
+        }
+    }
+
+    fn read_time(&self, index: u8) -> u8 {
+        let time = &self.time;
+        match index {
+            SECONDS => self.encode(time.second),
+            MINUTES => self.encode(time.minute),
+            HOURS => self.encode_hour(time.hour),
+            DAY_OF_WEEK => self.encode(time.day_of_week),
+            DAY_OF_MONTH => self.encode(time.day),
+            MONTH => self.encode(time.month),
+            YEAR => self.encode((time.year % 100) as u8),
+            _ => self.encode((time.year / 100) as u8),
+        }
+    }
+
+    fn write_time(&mut self, index: u8, value: u8) {
+        let decoded = self.decode(value);
+        let hour = self.decode_hour(value);
+        let time = &mut self.time;
+        match index {
+            SECONDS => time.second = decoded,
+            MINUTES => time.minute = decoded,
+            HOURS => time.hour = hour,
+            DAY_OF_WEEK => time.day_of_week = decoded,
+            DAY_OF_MONTH => time.day = decoded,
+            MONTH => time.month = decoded,
+            YEAR => time.year = time.year / 100 * 100 + decoded as u16 % 100,
+            _ => time.year = decoded as u16 % 100 * 100 + time.year % 100,
+        }
+    }
+
+    /// Length of a period of the periodic interrupt in time base cycles, or `None` if
+    /// it is off. Rates 1 and 2 repeat rates 8 and 9.
+    fn period(&self) -> Option<u64> {
+        match self.ram[STATUS_A as usize] & A_RATE {
+            0 => None,
+            rate @ 1..=2 => Some(1 << (rate + 6)),
+            rate => Some(1 << (rate - 1)),
+        }
+    }
+
+    /// Periods completed since the divider started.
+    fn periods(&self, now: u64) -> u64 {
+        match self.period() {
+            Some(period) if self.is_running() => {
+                let elapsed = now.saturating_sub(self.divider_start) as u128;
+                (elapsed * TIME_BASE as u128 / (period as u128 * NANOS_PER_SECOND as u128)) as u64
+            }
+            _ => 0,
+        }
+    }
+
+    fn update_irq(&mut self) {
+        self.irq.set(self.flags & self.status_b() & C_FLAGS != 0);
+    }
+
+    fn schedule_update(&mut self) {
+        if let Some(event) = self.update_event.take() {
+            self.scheduler.cancel(event);
+        }
+        if !self.is_running() {
+            return;
+        }
+        let this = self.this.clone();
+        self.update_event = Some(self.scheduler.schedule_at(self.next_update, move || {
+            if let Some(rtc) = this.upgrade() {
+                rtc.borrow_mut().update();
+            }
+        }));
+    }
+
+    /// The update cycle: advances the time unless SET holds it, and checks the alarm.
+    fn update(&mut self) {
+        self.update_event = None;
+        self.next_update += NANOS_PER_SECOND;
+        self.schedule_update();
+
+        if self.status_b() & B_SET != 0 {
+            return;
+        }
+        self.time.advance();
+        self.flags |= B_UPDATE;
+
+        let matches = |alarm: u8, value: u8| alarm >= ALARM_DONT_CARE || alarm == value;
+        if matches(self.ram[SECONDS_ALARM as usize], self.read_time(SECONDS))
+            && matches(self.ram[MINUTES_ALARM as usize], self.read_time(MINUTES))
+            && matches(self.ram[HOURS_ALARM as usize], self.read_time(HOURS))
+        {
+            self.flags |= B_ALARM;
+        }
+        self.update_irq();
+    }
+
+    /// Schedules the next periodic interrupt while it is enabled in register B. While
+    /// it is not, register C works out from the time whether a period has passed.
+    fn schedule_periodic(&mut self) {
+        if let Some(event) = self.periodic_event.take() {
+            self.scheduler.cancel(event);
+        }
+        let period = match self.period() {
+            Some(period) if self.is_running() && self.status_b() & B_PERIODIC != 0 => period,
+            _ => return,
+        };
+
+        let next = (self.periods(self.now()) + 1) as u128 * period as u128;
+        let time = self.divider_start
+            + (next * NANOS_PER_SECOND as u128).div_ceil(TIME_BASE as u128) as u64;
+        let this = self.this.clone();
+        self.periodic_event = Some(self.scheduler.schedule_at(time, move || {
+            if let Some(rtc) = this.upgrade() {
+                rtc.borrow_mut().periodic();
+            }
+        }));
+    }
+
+    fn periodic(&mut self) {
+        self.periodic_event = None;
+        self.periods_seen = self.periods(self.now());
+        self.flags |= B_PERIODIC;
+        self.update_irq();
+        self.schedule_periodic();
+    }
+
+    /// A divider leaving reset starts the first update cycle half a second later.
+    fn write_status_a(&mut self, value: u8) {
+        let was_running = self.is_running();
+        self.ram[STATUS_A as usize] = value & !A_UPDATE_IN_PROGRESS;
+        let now = self.now();
+        if self.is_running() && !was_running {
+            self.divider_start = now;
+            self.next_update = now + NANOS_PER_SECOND / 2;
+        }
+        self.periods_seen = self.periods(now);
+        self.schedule_update();
+        self.schedule_periodic();
+    }
+
+    /// Setting SET stops the update cycle from touching the time and clears UIE.
+    fn write_status_b(&mut self, value: u8) {
+        let value = if value & B_SET != 0 {
+            value & !B_UPDATE
+        } else {
+            value
+        };
+        self.ram[STATUS_B as usize] = value;
+        self.update_irq();
+        self.schedule_periodic();
+    }
+
+    fn read_status_a(&self) -> u8 {
+        let mut value = self.ram[STATUS_A as usize];
+        let now = self.now();
+        if self.is_running()
+            && self.status_b() & B_SET == 0
+            && self.next_update.saturating_sub(now) <= UPDATE_WARNING
+        {
+            value |= A_UPDATE_IN_PROGRESS;
+        }
+        value
+    }
+
+    /// Reading register C clears the flags and the interrupt request.
+    fn read_status_c(&mut self) -> u8 {
+        let periods = self.periods(self.now());
+        if periods > self.periods_seen {
+            self.flags |= B_PERIODIC;
+        }
+        self.periods_seen = periods;
+
+        let mut value = self.flags;
+        if value & self.status_b() & C_FLAGS != 0 {
+            value |= C_IRQ;
+        }
+        self.flags = 0;
+        self.update_irq();
+        value
+    }
+}
+
+impl PortDevice for Rtc {
+    fn read8(&mut self, port: u16) -> Result<u8, EmulatorError> {
+        if port & 1 == 0 {
+            // The index port is write-only.
+            return Ok(0xFF);
+        }
+        Ok(match self.index {
+            SECONDS | MINUTES | HOURS | DAY_OF_WEEK..=YEAR | CENTURY => self.read_time(self.index),
+            STATUS_A => self.read_status_a(),
+            STATUS_C => self.read_status_c(),
+            index => self.ram[index as usize],
+        })
+    }
+
+    fn write8(&mut self, port: u16, value: u8) -> Result<(), EmulatorError> {
+        if port & 1 == 0 {
+            self.index = value & 0x7F;
+            self.nmi_disabled = value & 0x80 != 0;
+            return Ok(());
+        }
+        match self.index {
+            SECONDS | MINUTES | HOURS | DAY_OF_WEEK..=YEAR | CENTURY => {
+                self.write_time(self.index, value)
+            }
+            STATUS_A => self.write_status_a(value),
+            STATUS_B => self.write_status_b(value),
+            // C and D are read-only.
+            STATUS_C | STATUS_D => (),
+            index => self.ram[index as usize] = value,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ClockMode};
+    use crate::irq::IrqLines;
+    use std::time::Duration;
+
+    /// A clock set to 2024-02-29 23:59:58, a Thursday, with 1 MiB of memory.
+    fn rtc() -> (Rc<RefCell<Rtc>>, Scheduler) {
+        let scheduler = Scheduler::new(Clock::new(ClockMode::Virtual));
+        let time = UNIX_EPOCH + Duration::from_secs(1_709_251_198);
+        let rtc = Rtc::new(scheduler.clone(), IrqLines::new().line(8), time, 1 << 20);
+        (rtc, scheduler)
+    }
+
+    fn write(rtc: &Rc<RefCell<Rtc>>, index: u8, value: u8) {
+        let mut rtc = rtc.borrow_mut();
+        rtc.write8(0x70, index).unwrap();
+        rtc.write8(0x71, value).unwrap();
+    }
+
+    fn read(rtc: &Rc<RefCell<Rtc>>, index: u8) -> u8 {
+        let mut rtc = rtc.borrow_mut();
+        rtc.write8(0x70, index).unwrap();
+        rtc.read8(0x71).unwrap()
+    }
+
+    fn date(rtc: &Rc<RefCell<Rtc>>) -> [u8; 8] {
+        [
+            CENTURY,
+            YEAR,
+            MONTH,
+            DAY_OF_MONTH,
+            DAY_OF_WEEK,
+            HOURS,
+            MINUTES,
+            SECONDS,
+        ]
+        .map(|index| read(rtc, index))
+    }
+
+    #[test]
+    fn bcd_and_binary_time() {
+        let (rtc, _scheduler) = rtc();
+        assert_eq!(date(&rtc), [0x20, 0x24, 0x02, 0x29, 5, 0x23, 0x59, 0x58]);
+
+        write(&rtc, STATUS_B, B_24_HOUR | B_BINARY);
+        assert_eq!(date(&rtc), [20, 24, 2, 29, 5, 23, 59, 58]);
+
+        // Writes are taken in the current format too.
+        write(&rtc, MINUTES, 45);
+        write(&rtc, STATUS_B, B_24_HOUR);
+        assert_eq!(read(&rtc, MINUTES), 0x45);
+    }
+
+    #[test]
+    fn twelve_hour_time() {
+        let (rtc, _scheduler) = rtc();
+        write(&rtc, STATUS_B, 0);
+        assert_eq!(read(&rtc, HOURS), PM | 0x11);
+        write(&rtc, STATUS_B, B_BINARY);
+        assert_eq!(read(&rtc, HOURS), PM | 11);
+
+        // 12 AM is midnight and 12 PM noon.
+        write(&rtc, STATUS_B, 0);
+        write(&rtc, HOURS, 0x12);
+        assert_eq!(rtc.borrow().time.hour, 0);
+        write(&rtc, HOURS, PM | 0x12);
+        assert_eq!(rtc.borrow().time.hour, 12);
+        write(&rtc, HOURS, PM | 0x01);
+        write(&rtc, STATUS_B, B_24_HOUR);
+        assert_eq!(read(&rtc, HOURS), 0x13);
+    }
+
+    #[test]
+    fn update_cycle_rolls_over_the_leap_day() {
+        let (rtc, scheduler) = rtc();
+        // The first update comes half a second after the divider starts.
+        for second in 0..2 {
+            scheduler
+                .clock()
+                .advance_to(NANOS_PER_SECOND / 2 + second * NANOS_PER_SECOND);
+            scheduler.run_due();
+        }
+        assert_eq!(date(&rtc), [0x20, 0x24, 0x03, 0x01, 6, 0x00, 0x00, 0x00]);
+        // The alarm registers, still 0, match midnight.
+        let flags = read(&rtc, STATUS_C);
+        assert_eq!(flags & (C_IRQ | B_ALARM | B_UPDATE), B_ALARM | B_UPDATE);
+    }
+
+    #[test]
+    fn checksum_covers_10h_to_2dh() {
+        let (rtc, _scheduler) = rtc();
+        let sum = |rtc: &Rc<RefCell<Rtc>>| -> u16 {
+            (0x10..CHECKSUM).map(|index| read(rtc, index) as u16).sum()
+        };
+        let checksum = |rtc: &Rc<RefCell<Rtc>>| {
+            u16::from_be_bytes([read(rtc, CHECKSUM), read(rtc, CHECKSUM + 1)])
+        };
+
+        // 640 KiB of base memory and none above 1 MiB.
+        assert_eq!(
+            [read(&rtc, BASE_MEMORY), read(&rtc, BASE_MEMORY + 1)],
+            [0x80, 0x02]
+        );
+        assert_eq!(read(&rtc, EXTENDED_MEMORY), 0);
+        assert_eq!(checksum(&rtc), sum(&rtc));
+
+        rtc.borrow_mut().set_memory_size(32 << 20);
+        assert_eq!(checksum(&rtc), sum(&rtc));
+        rtc.borrow_mut().set_nvram(0x2D, 0xFF);
+        assert_eq!(checksum(&rtc), sum(&rtc));
+        // Bytes outside the range do not count.
+        rtc.borrow_mut().set_nvram(0x40, 0xFF);
+        assert_eq!(checksum(&rtc), sum(&rtc));
+    }
+}
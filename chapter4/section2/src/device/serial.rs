@@ -0,0 +1,300 @@
+use std::collections::VecDeque;
+use std::ffi::{CStr, OsStr};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{stdout, Read, Write};
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The host end of a serial line: where the bytes a UART transmits go and where the
+/// bytes it receives come from.
+pub trait SerialBackend {
+    /// Sends a byte the guest transmitted.
+    fn transmit(&mut self, byte: u8) -> io::Result<()>;
+
+    /// Returns the next received byte, or `None` without blocking if none is waiting.
+    fn receive(&mut self) -> io::Result<Option<u8>>;
+}
+
+/// Bytes arriving from the host, such as keystrokes or what a socket client sends,
+/// queued until a device takes them. Clones refer to the same queue.
+#[derive(Clone, Debug, Default)]
+pub struct HostInput(Arc<Mutex<VecDeque<u8>>>);
+
+impl HostInput {
+    pub fn new() -> HostInput {
+        HostInput::default()
+    }
+
+    /// Queues the bytes of `reader` on a background thread.
+    pub fn spawn<R: Read + Send + 'static>(reader: R) -> HostInput {
+        let input = HostInput::new();
+        let queue = input.clone();
+        thread::spawn(move || queue.read_from(reader));
+        input
+    }
+
+    /// Queues every byte of `reader` until it ends or fails. Returns false if it
+    /// stopped early because no other clone of the queue is left to take them.
+    fn read_from<R: Read>(&self, mut reader: R) -> bool {
+        let mut buf = [0u8; 256];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => return true,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return true,
+            };
+            if Arc::strong_count(&self.0) == 1 {
+                return false;
+            }
+            self.0.lock().unwrap().extend(&buf[..n]);
+        }
+    }
+
+    pub fn push(&self, byte: u8) {
+        self.0.lock().unwrap().push_back(byte);
+    }
+
+    /// Takes the oldest byte, if any.
+    pub fn pop(&self) -> Option<u8> {
+        self.0.lock().unwrap().pop_front()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+}
+
+/// Serial line with nothing attached: transmitted bytes are dropped and nothing is
+/// ever received.
+#[derive(Debug, Default)]
+pub struct NullSerial;
+
+impl SerialBackend for NullSerial {
+    fn transmit(&mut self, _byte: u8) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn receive(&mut self) -> io::Result<Option<u8>> {
+        Ok(None)
+    }
+}
+
+/// Serial line on the host's stdin and stdout. Stdin is read on a background thread,
+/// started on the first `receive`, so the guest never blocks waiting for input.
+#[derive(Debug, Default)]
+pub struct StdioSerial {
+    input: Option<HostInput>,
+}
+
+impl StdioSerial {
+    pub fn new() -> StdioSerial {
+        StdioSerial::default()
+    }
+}
+
+impl SerialBackend for StdioSerial {
+    fn transmit(&mut self, byte: u8) -> io::Result<()> {
+        let mut stdout = stdout();
+        stdout.write_all(&[byte])?;
+        stdout.flush()
+    }
+
+    fn receive(&mut self) -> io::Result<Option<u8>> {
+        let input = self
+            .input
+            .get_or_insert_with(|| HostInput::spawn(io::stdin()));
+        Ok(input.pop())
+    }
+}
+
+/// Serial line on a pair of byte streams, such as an input and an output file. The
+/// input is read on a background thread; once it ends the line stays idle.
+pub struct StreamSerial {
+    input: HostInput,
+    output: Box<dyn Write>,
+}
+
+impl StreamSerial {
+    pub fn new<R: Read + Send + 'static>(input: R, output: Box<dyn Write>) -> StreamSerial {
+        StreamSerial {
+            input: HostInput::spawn(input),
+            output,
+        }
+    }
+
+    /// Receives from the file at `input` and transmits to the file at `output`,
+    /// truncating it.
+    pub fn files<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q) -> io::Result<StreamSerial> {
+        let input = File::open(input)?;
+        let output = File::create(output)?;
+        Ok(StreamSerial::new(input, Box::new(output)))
+    }
+}
+
+impl SerialBackend for StreamSerial {
+    fn transmit(&mut self, byte: u8) -> io::Result<()> {
+        self.output.write_all(&[byte])?;
+        self.output.flush()
+    }
+
+    fn receive(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input.pop())
+    }
+}
+
+/// Serial line on a new pseudo-terminal. A terminal program opened on `path` talks to
+/// the guest.
+pub struct PtySerial {
+    stream: StreamSerial,
+    path: PathBuf,
+    /// Kept open so the master does not see a hangup whenever no program has the
+    /// slave open.
+    _slave: File,
+}
+
+impl PtySerial {
+    pub fn open() -> io::Result<PtySerial> {
+        let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+        if master < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = unsafe { File::from_raw_fd(master) };
+
+        let mut name = [0 as libc::c_char; 128];
+        let fd = master.as_raw_fd();
+        unsafe {
+            if libc::grantpt(fd) != 0
+                || libc::unlockpt(fd) != 0
+                || libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let path = PathBuf::from(OsStr::from_bytes(
+            unsafe { CStr::from_ptr(name.as_ptr()) }.to_bytes(),
+        ));
+
+        let slave = OpenOptions::new().read(true).write(true).open(&path)?;
+        set_raw(&slave)?;
+
+        Ok(PtySerial {
+            stream: StreamSerial::new(master.try_clone()?, Box::new(master)),
+            path,
+            _slave: slave,
+        })
+    }
+
+    /// Path of the slave side, such as /dev/pts/3.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Turns off echo and line editing on the slave, so bytes pass through unchanged
+/// until the program opening it sets its own mode.
+fn set_raw(slave: &File) -> io::Result<()> {
+    unsafe {
+        let mut termios = MaybeUninit::uninit();
+        if libc::tcgetattr(slave.as_raw_fd(), termios.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut termios = termios.assume_init();
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+impl SerialBackend for PtySerial {
+    fn transmit(&mut self, byte: u8) -> io::Result<()> {
+        self.stream.transmit(byte)
+    }
+
+    fn receive(&mut self) -> io::Result<Option<u8>> {
+        self.stream.receive()
+    }
+}
+
+/// Serial line served on a Unix socket. Connections are accepted one after another on
+/// a background thread; bytes the guest transmits while nobody is connected are
+/// dropped, as on an unplugged cable. The socket file is removed on drop.
+pub struct UnixSocketSerial {
+    input: HostInput,
+    client: Arc<Mutex<Option<UnixStream>>>,
+    path: PathBuf,
+}
+
+impl UnixSocketSerial {
+    /// Listens on a new socket at `path`. A socket left there by an earlier run is
+    /// replaced; any other file is an error.
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixSocketSerial> {
+        let path = path.as_ref().to_path_buf();
+        if let Ok(metadata) = fs::symlink_metadata(&path) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(&path)?;
+            }
+        }
+        let listener = UnixListener::bind(&path)?;
+        let input = HostInput::new();
+        let client = Arc::new(Mutex::new(None));
+
+        let queue = input.clone();
+        let current = Arc::clone(&client);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                match stream.try_clone() {
+                    Ok(writer) => *current.lock().unwrap() = Some(writer),
+                    Err(_) => continue,
+                }
+
+                if !queue.read_from(stream) {
+                    return;
+                }
+                *current.lock().unwrap() = None;
+            }
+        });
+
+        Ok(UnixSocketSerial {
+            input,
+            client,
+            path,
+        })
+    }
+}
+
+impl Drop for UnixSocketSerial {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl SerialBackend for UnixSocketSerial {
+    fn transmit(&mut self, byte: u8) -> io::Result<()> {
+        let mut client = self.client.lock().unwrap();
+        if let Some(stream) = client.as_mut() {
+            if stream.write_all(&[byte]).is_err() {
+                *client = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input.pop())
+    }
+}
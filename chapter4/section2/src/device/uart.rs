@@ -0,0 +1,538 @@
+use crate::device::SerialBackend;
+use crate::error::EmulatorError;
+use crate::io_bus::PortDevice;
+use crate::irq::IrqLine;
+use crate::scheduler::Scheduler;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::{Rc, Weak};
+
+// Interrupt enable register.
+const IER_RECEIVED_DATA: u8 = 1;
+const IER_THR_EMPTY: u8 = 1 << 1;
+const IER_LINE_STATUS: u8 = 1 << 2;
+const IER_MODEM_STATUS: u8 = 1 << 3;
+
+// Interrupt identification register. Bit 0 set means no interrupt is pending.
+const IIR_NONE: u8 = 0x01;
+const IIR_MODEM_STATUS: u8 = 0x00;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RECEIVED_DATA: u8 = 0x04;
+const IIR_LINE_STATUS: u8 = 0x06;
+const IIR_CHARACTER_TIMEOUT: u8 = 0x0C;
+const IIR_FIFOS_ENABLED: u8 = 0xC0;
+
+// FIFO control register.
+const FCR_ENABLE: u8 = 1;
+const FCR_CLEAR_RECEIVER: u8 = 1 << 1;
+
+// Line control register.
+const LCR_WORD_LENGTH: u8 = 3;
+const LCR_TWO_STOP_BITS: u8 = 1 << 2;
+const LCR_PARITY: u8 = 1 << 3;
+const LCR_DLAB: u8 = 1 << 7;
+
+// Modem control register.
+const MCR_DTR: u8 = 1;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT1: u8 = 1 << 2;
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+// Line status register.
+const LSR_DATA_READY: u8 = 1;
+const LSR_OVERRUN: u8 = 1 << 1;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+// Modem status register: deltas in the low nibble, lines in the high one.
+const MSR_CTS: u8 = 1 << 4;
+const MSR_DSR: u8 = 1 << 5;
+const MSR_RI: u8 = 1 << 6;
+const MSR_DCD: u8 = 1 << 7;
+const MSR_TRAILING_EDGE_RI: u8 = 1 << 2;
+
+const FIFO_SIZE: usize = 16;
+
+/// Baud rate with a divisor of 1: the 1.8432 MHz crystal over 16.
+const BASE_BAUD: u64 = 115_200;
+
+/// 16550A UART on eight consecutive ports, such as COM1 on 0x3F8-0x3FF with IRQ4.
+///
+/// Bytes reach the backend as soon as THR is written, but the transmitter stays busy
+/// for as long as sending them at the programmed baud rate takes, timed by events on
+/// the scheduler. The interrupt request reaches the line only while OUT2 is set in
+/// MCR, as on PC serial cards.
+pub struct Uart16550 {
+    backend: Box<dyn SerialBackend>,
+    irq: IrqLine,
+    scheduler: Scheduler,
+    /// Handle the transmit events reach the UART through.
+    this: Weak<RefCell<Uart16550>>,
+    /// Bytes in THR or the transmitter FIFO and the shift register that have not
+    /// finished sending.
+    transmitting: usize,
+    /// Receiver FIFO, or the receiver buffer register alone while FIFOs are disabled.
+    received: VecDeque<u8>,
+    divisor: u16,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scratch: u8,
+    fifos_enabled: bool,
+    /// Received bytes that raise a received data interrupt in FIFO mode.
+    trigger_level: usize,
+    overrun: bool,
+    /// A THR empty interrupt is pending. Reading IIR while it is the one reported, or
+    /// writing THR, clears it.
+    thr_empty_pending: bool,
+    /// Low nibble of MSR.
+    modem_status_deltas: u8,
+}
+
+impl Uart16550 {
+    pub fn new(
+        backend: Box<dyn SerialBackend>,
+        irq: IrqLine,
+        scheduler: Scheduler,
+    ) -> Rc<RefCell<Uart16550>> {
+        Rc::new_cyclic(|this| {
+            RefCell::new(Uart16550 {
+                backend,
+                irq,
+                scheduler,
+                this: this.clone(),
+                transmitting: 0,
+                received: VecDeque::with_capacity(FIFO_SIZE),
+                // 9600 baud.
+                divisor: 12,
+                ier: 0,
+                lcr: 0,
+                mcr: 0,
+                scratch: 0,
+                fifos_enabled: false,
+                trigger_level: 1,
+                overrun: false,
+                thr_empty_pending: false,
+                modem_status_deltas: 0,
+            })
+        })
+    }
+
+    fn capacity(&self) -> usize {
+        if self.fifos_enabled {
+            FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    /// Time one character takes on the line, in nanoseconds: a start bit, the data
+    /// bits, the parity bit and the stop bits.
+    fn character_time(&self) -> u64 {
+        let data = 5 + (self.lcr & LCR_WORD_LENGTH) as u64;
+        let parity = (self.lcr & LCR_PARITY != 0) as u64;
+        let stop = if self.lcr & LCR_TWO_STOP_BITS != 0 {
+            2
+        } else {
+            1
+        };
+        let divisor = self.divisor.max(1) as u64;
+        (1 + data + parity + stop) * divisor * 1_000_000_000 / BASE_BAUD
+    }
+
+    /// Sends a byte written to THR. It is dropped if THR or the transmitter FIFO is
+    /// still full.
+    fn transmit(&mut self, byte: u8) -> Result<(), EmulatorError> {
+        if self.transmitting > self.capacity() {
+            return Ok(());
+        }
+        self.backend.transmit(byte)?;
+        self.transmitting += 1;
+        if self.transmitting == 1 {
+            // The byte goes straight on to the idle shift register.
+            self.thr_empty_pending = true;
+            self.schedule_transmitted();
+        }
+        Ok(())
+    }
+
+    fn schedule_transmitted(&self) {
+        let this = self.this.clone();
+        self.scheduler.schedule_in(self.character_time(), move || {
+            if let Some(uart) = this.upgrade() {
+                uart.borrow_mut().transmitted();
+            }
+        });
+    }
+
+    /// The shift register has sent its byte and takes the next one, if any.
+    fn transmitted(&mut self) {
+        self.transmitting -= 1;
+        if self.transmitting > 0 {
+            if self.transmitting == 1 {
+                self.thr_empty_pending = true;
+            }
+            self.schedule_transmitted();
+        }
+        self.update_irq();
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.received.len() < self.capacity() {
+            self.received.push_back(byte);
+        } else {
+            self.overrun = true;
+        }
+    }
+
+    /// Modem lines in the high nibble of MSR. In loopback mode they mirror the MCR
+    /// outputs; otherwise a terminal is always connected and ready.
+    fn modem_lines(&self) -> u8 {
+        if self.mcr & MCR_LOOPBACK == 0 {
+            return MSR_CTS | MSR_DSR | MSR_DCD;
+        }
+
+        let mut lines = 0;
+        if self.mcr & MCR_RTS != 0 {
+            lines |= MSR_CTS;
+        }
+        if self.mcr & MCR_DTR != 0 {
+            lines |= MSR_DSR;
+        }
+        if self.mcr & MCR_OUT1 != 0 {
+            lines |= MSR_RI;
+        }
+        if self.mcr & MCR_OUT2 != 0 {
+            lines |= MSR_DCD;
+        }
+        lines
+    }
+
+    /// The highest-priority pending interrupt as reported in IIR.
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_LINE_STATUS != 0 && self.overrun {
+            IIR_LINE_STATUS
+        } else if self.ier & IER_RECEIVED_DATA != 0 && !self.received.is_empty() {
+            // There is no receive timing, so data below the trigger level times out
+            // at once.
+            if self.fifos_enabled && self.received.len() < self.trigger_level {
+                IIR_CHARACTER_TIMEOUT
+            } else {
+                IIR_RECEIVED_DATA
+            }
+        } else if self.ier & IER_THR_EMPTY != 0 && self.thr_empty_pending {
+            IIR_THR_EMPTY
+        } else if self.ier & IER_MODEM_STATUS != 0 && self.modem_status_deltas != 0 {
+            IIR_MODEM_STATUS
+        } else {
+            IIR_NONE
+        }
+    }
+
+    fn update_irq(&self) {
+        self.irq
+            .set(self.interrupt_id() != IIR_NONE && self.mcr & MCR_OUT2 != 0);
+    }
+
+    fn write_fcr(&mut self, value: u8) {
+        let enable = value & FCR_ENABLE != 0;
+        if enable != self.fifos_enabled || value & FCR_CLEAR_RECEIVER != 0 {
+            self.received.clear();
+        }
+        self.fifos_enabled = enable;
+        self.trigger_level = [1, 4, 8, 14][(value >> 6) as usize];
+    }
+
+    fn write_mcr(&mut self, value: u8) {
+        let before = self.modem_lines();
+        self.mcr = value & 0x1F;
+        let after = self.modem_lines();
+
+        let changed = (before ^ after) & (MSR_CTS | MSR_DSR | MSR_DCD);
+        self.modem_status_deltas |= changed >> 4;
+        if before & MSR_RI != 0 && after & MSR_RI == 0 {
+            self.modem_status_deltas |= MSR_TRAILING_EDGE_RI;
+        }
+    }
+}
+
+impl PortDevice for Uart16550 {
+    fn read8(&mut self, port: u16) -> Result<u8, EmulatorError> {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = match port & 7 {
+            0 if dlab => self.divisor as u8,
+            0 => self.received.pop_front().unwrap_or(0),
+            1 if dlab => (self.divisor >> 8) as u8,
+            1 => self.ier,
+            2 => {
+                let id = self.interrupt_id();
+                if id == IIR_THR_EMPTY {
+                    self.thr_empty_pending = false;
+                }
+                if self.fifos_enabled {
+                    id | IIR_FIFOS_ENABLED
+                } else {
+                    id
+                }
+            }
+            3 => self.lcr,
+            4 => self.mcr,
+            5 => {
+                let mut lsr = 0;
+                if self.transmitting <= 1 {
+                    lsr |= LSR_THR_EMPTY;
+                }
+                if self.transmitting == 0 {
+                    lsr |= LSR_TRANSMITTER_EMPTY;
+                }
+                if !self.received.is_empty() {
+                    lsr |= LSR_DATA_READY;
+                }
+                if self.overrun {
+                    lsr |= LSR_OVERRUN;
+                    self.overrun = false;
+                }
+                lsr
+            }
+            6 => {
+                let msr = self.modem_lines() | self.modem_status_deltas;
+                self.modem_status_deltas = 0;
+                msr
+            }
+            _ => self.scratch,
+        };
+
+        self.update_irq();
+        Ok(value)
+    }
+
+    fn write8(&mut self, port: u16, value: u8) -> Result<(), EmulatorError> {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match port & 7 {
+            0 if dlab => self.divisor = (self.divisor & 0xFF00) | value as u16,
+            0 => {
+                self.thr_empty_pending = false;
+                if self.mcr & MCR_LOOPBACK != 0 {
+                    self.receive(value);
+                    self.thr_empty_pending = true;
+                } else {
+                    self.transmit(value)?;
+                }
+            }
+            1 if dlab => self.divisor = (self.divisor & 0x00FF) | (value as u16) << 8,
+            1 => {
+                // Enabling the THR empty interrupt while THR is empty raises it.
+                if self.ier & IER_THR_EMPTY == 0
+                    && value & IER_THR_EMPTY != 0
+                    && self.transmitting <= 1
+                {
+                    self.thr_empty_pending = true;
+                }
+                self.ier = value & 0x0F;
+            }
+            2 => self.write_fcr(value),
+            3 => self.lcr = value,
+            4 => self.write_mcr(value),
+            // LSR and MSR are read-only.
+            5 | 6 => (),
+            _ => self.scratch = value,
+        }
+
+        self.update_irq();
+        Ok(())
+    }
+
+    /// Moves waiting host input into the receiver while it has room. The receiver is
+    /// cut off from the line in loopback mode.
+    fn poll(&mut self) -> Result<(), EmulatorError> {
+        if self.mcr & MCR_LOOPBACK == 0 {
+            while self.received.len() < self.capacity() {
+                match self.backend.receive()? {
+                    Some(byte) => self.received.push_back(byte),
+                    None => break,
+                }
+            }
+        }
+
+        self.update_irq();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ClockMode};
+    use crate::device::NullSerial;
+    use crate::irq::IrqLines;
+
+    const THR: u16 = 0x3F8;
+    const IER: u16 = 0x3F9;
+    const IIR: u16 = 0x3FA;
+    const LCR: u16 = 0x3FB;
+    const MCR: u16 = 0x3FC;
+    const LSR: u16 = 0x3FD;
+    const MSR: u16 = 0x3FE;
+
+    fn uart() -> (Rc<RefCell<Uart16550>>, Scheduler, IrqLines) {
+        let scheduler = Scheduler::new(Clock::new(ClockMode::Virtual));
+        let irq = IrqLines::new();
+        let uart = Uart16550::new(Box::new(NullSerial), irq.line(4), scheduler.clone());
+        (uart, scheduler, irq)
+    }
+
+    /// Runs every scheduled event, as if the host waited for the line to go idle.
+    fn drain(scheduler: &Scheduler) {
+        while let Some(time) = scheduler.next_event() {
+            scheduler.clock().advance_to(time);
+            scheduler.run_due();
+        }
+    }
+
+    fn write(uart: &Rc<RefCell<Uart16550>>, port: u16, value: u8) {
+        uart.borrow_mut().write8(port, value).unwrap();
+    }
+
+    fn read(uart: &Rc<RefCell<Uart16550>>, port: u16) -> u8 {
+        uart.borrow_mut().read8(port).unwrap()
+    }
+
+    #[test]
+    fn dlab_switches_thr_and_ier_to_the_divisor_latches() {
+        let (uart, _, _) = uart();
+
+        write(&uart, LCR, LCR_DLAB | 0x03);
+        assert_eq!((read(&uart, THR), read(&uart, IER)), (12, 0));
+        write(&uart, THR, 0x01);
+        write(&uart, IER, 0x02);
+        assert_eq!((read(&uart, THR), read(&uart, IER)), (0x01, 0x02));
+
+        write(&uart, LCR, 0x03);
+        write(&uart, IER, IER_RECEIVED_DATA);
+        assert_eq!(read(&uart, IER), IER_RECEIVED_DATA);
+        write(&uart, LCR, LCR_DLAB | 0x03);
+        assert_eq!(uart.borrow().divisor, 0x0201);
+    }
+
+    #[test]
+    fn lsr_follows_the_transmitter_and_receiver() {
+        let (uart, scheduler, _) = uart();
+        let idle = LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY;
+        assert_eq!(read(&uart, LSR), idle);
+
+        // The first byte moves on to the shift register, the second waits in THR.
+        write(&uart, THR, b'A');
+        assert_eq!(read(&uart, LSR), LSR_THR_EMPTY);
+        write(&uart, THR, b'B');
+        assert_eq!(read(&uart, LSR), 0);
+        drain(&scheduler);
+        assert_eq!(read(&uart, LSR), idle);
+
+        write(&uart, MCR, MCR_LOOPBACK);
+        write(&uart, THR, 0x55);
+        assert_eq!(read(&uart, LSR), idle | LSR_DATA_READY);
+        assert_eq!(read(&uart, THR), 0x55);
+        assert_eq!(read(&uart, LSR), idle);
+    }
+
+    #[test]
+    fn iir_reports_the_highest_priority_interrupt() {
+        let (uart, _, _) = uart();
+        write(&uart, MCR, MCR_LOOPBACK);
+        write(
+            &uart,
+            IER,
+            IER_RECEIVED_DATA | IER_THR_EMPTY | IER_LINE_STATUS,
+        );
+
+        // Reading IIR while it reports THR empty clears that interrupt.
+        assert_eq!(read(&uart, IIR), IIR_THR_EMPTY);
+        assert_eq!(read(&uart, IIR), IIR_NONE);
+
+        // The second byte overruns the receiver buffer register.
+        write(&uart, THR, 0x11);
+        write(&uart, THR, 0x22);
+        assert_eq!(read(&uart, IIR), IIR_LINE_STATUS);
+        assert_eq!(read(&uart, LSR) & LSR_OVERRUN, LSR_OVERRUN);
+        assert_eq!(read(&uart, IIR), IIR_RECEIVED_DATA);
+        assert_eq!(read(&uart, THR), 0x11);
+        assert_eq!(read(&uart, IIR), IIR_THR_EMPTY);
+        assert_eq!(read(&uart, IIR), IIR_NONE);
+    }
+
+    #[test]
+    fn fcr_enables_the_fifo_and_sets_the_trigger_level() {
+        let (uart, _, _) = uart();
+        write(&uart, MCR, MCR_LOOPBACK);
+        write(&uart, IER, IER_RECEIVED_DATA);
+
+        // Enable with a trigger level of 8.
+        write(&uart, IIR, 0x81);
+        assert_eq!(read(&uart, IIR), IIR_FIFOS_ENABLED | IIR_NONE);
+        for byte in 0..7 {
+            write(&uart, THR, byte);
+        }
+        assert_eq!(read(&uart, IIR), IIR_FIFOS_ENABLED | IIR_CHARACTER_TIMEOUT);
+        write(&uart, THR, 7);
+        assert_eq!(read(&uart, IIR), IIR_FIFOS_ENABLED | IIR_RECEIVED_DATA);
+
+        // The FIFO holds 16 bytes, in order.
+        for byte in 8..17 {
+            write(&uart, THR, byte);
+        }
+        assert_eq!(read(&uart, LSR) & LSR_OVERRUN, LSR_OVERRUN);
+        let received: Vec<u8> = (0..16).map(|_| read(&uart, THR)).collect();
+        assert_eq!(received, (0..16).collect::<Vec<u8>>());
+        assert_eq!(read(&uart, LSR) & LSR_DATA_READY, 0);
+
+        // Clearing the receiver empties the FIFO.
+        write(&uart, THR, 0x33);
+        write(&uart, IIR, FCR_ENABLE | FCR_CLEAR_RECEIVER);
+        assert_eq!(read(&uart, LSR) & LSR_DATA_READY, 0);
+
+        write(&uart, IIR, 0);
+        assert_eq!(read(&uart, IIR), IIR_NONE);
+    }
+
+    #[test]
+    fn loopback_echoes_thr_and_mcr() {
+        let (uart, _, _) = uart();
+        let connected = MSR_CTS | MSR_DSR | MSR_DCD;
+        assert_eq!(read(&uart, MSR), connected);
+
+        // DCD drops as OUT2 is clear.
+        write(&uart, MCR, MCR_LOOPBACK | MCR_DTR | MCR_RTS);
+        assert_eq!(read(&uart, MSR), MSR_CTS | MSR_DSR | (MSR_DCD >> 4));
+        assert_eq!(read(&uart, MSR), MSR_CTS | MSR_DSR);
+
+        write(&uart, MCR, MCR_LOOPBACK | MCR_OUT1 | MCR_OUT2);
+        assert_eq!(
+            read(&uart, MSR),
+            MSR_RI | MSR_DCD | ((MSR_CTS | MSR_DSR | MSR_DCD) >> 4)
+        );
+        write(&uart, MCR, MCR_LOOPBACK | MCR_OUT2);
+        assert_eq!(read(&uart, MSR), MSR_DCD | MSR_TRAILING_EDGE_RI);
+
+        write(&uart, THR, 0x5A);
+        assert_eq!(read(&uart, THR), 0x5A);
+    }
+
+    #[test]
+    fn irq4_follows_ier_and_out2() {
+        let (uart, _, irq) = uart();
+
+        write(&uart, IER, IER_THR_EMPTY);
+        assert!(!irq.is_raised(4));
+        write(&uart, MCR, MCR_OUT2);
+        assert!(irq.is_raised(4));
+        write(&uart, IER, 0);
+        assert!(!irq.is_raised(4));
+
+        write(&uart, IER, IER_THR_EMPTY);
+        assert!(irq.is_raised(4));
+        assert_eq!(read(&uart, IIR), IIR_THR_EMPTY);
+        assert!(!irq.is_raised(4));
+    }
+}
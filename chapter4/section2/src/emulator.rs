@@ -1,6 +1,19 @@
+use crate::clock::Clock;
+use crate::device::{A20Gate, IdeChannel, KeyboardController, PicPair, Pit, Rtc};
+use crate::instruction::{InstructionFunctions, InterruptHook};
+use crate::io_bus::IoBus;
+use crate::irq::IrqLines;
+use crate::memory_map::MemoryMap;
+use crate::paging::TlbEntry;
+use crate::scheduler::Scheduler;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 use strum_macros::EnumIter;
 use variant_count::VariantCount;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Copy, Debug, EnumIter, VariantCount)]
 pub enum Register32 {
     EAX,
@@ -13,6 +26,19 @@ pub enum Register32 {
     EDI,
 }
 
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, EnumIter, VariantCount)]
+pub enum Register16 {
+    AX,
+    CX,
+    DX,
+    BX,
+    SP,
+    BP,
+    SI,
+    DI,
+}
+
 #[derive(Clone, Copy, Debug, EnumIter, VariantCount)]
 pub enum Register8 {
     AL,
@@ -25,10 +51,186 @@ pub enum Register8 {
     BH,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, EnumIter, VariantCount)]
+pub enum SegmentRegister {
+    ES,
+    CS,
+    SS,
+    DS,
+    FS,
+    GS,
+}
+
+/// Selector of a segment register together with the base, limit and attributes the
+/// CPU caches when the selector is loaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub selector: u16,
+    pub base: u32,
+    pub limit: u32,
+    /// Byte 5 of the descriptor: present bit, DPL, S bit and type. 0 marks a null
+    /// selector, which faults on use.
+    pub access: u8,
+    /// D/B flag: 32-bit default operand and address size for CS, 32-bit stack pointer
+    /// for SS.
+    pub big: bool,
+}
+
+impl Segment {
+    pub fn is_present(&self) -> bool {
+        self.access & 0x80 != 0
+    }
+
+    /// S bit set and type bit 3 set.
+    pub fn is_code(&self) -> bool {
+        self.access & 0x18 == 0x18
+    }
+
+    /// Readable for code segments, writable for data segments.
+    pub fn is_readable_or_writable(&self) -> bool {
+        self.access & 0x02 != 0
+    }
+
+    pub fn dpl(&self) -> u8 {
+        (self.access >> 5) & 0x03
+    }
+}
+
+/// GDTR or IDTR.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DescriptorTable {
+    pub base: u32,
+    pub limit: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RepeatPrefix {
+    /// F3: REP, or REPE/REPZ for CMPS and SCAS.
+    Rep,
+    /// F2: REPNE/REPNZ.
+    Repne,
+}
+
+/// Prefix bytes seen in front of the opcode of the current instruction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Prefixes {
+    /// 66
+    pub operand_size_override: bool,
+    /// 67
+    pub address_size_override: bool,
+    /// 26, 2E, 36, 3E, 64 or 65
+    pub segment: Option<SegmentRegister>,
+    /// F0
+    pub lock: bool,
+    /// F2 or F3
+    pub repeat: Option<RepeatPrefix>,
+}
+
+/// Width of an instruction operand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperandSize {
+    Byte,
+    Word,
+    Dword,
+}
+
+impl OperandSize {
+    pub fn bits(&self) -> u32 {
+        match self {
+            OperandSize::Byte => 8,
+            OperandSize::Word => 16,
+            OperandSize::Dword => 32,
+        }
+    }
+
+    pub fn bytes(&self) -> u32 {
+        self.bits() / 8
+    }
+
+    pub fn mask(&self) -> u32 {
+        u32::MAX >> (32 - self.bits())
+    }
+
+    pub fn sign_bit(&self) -> u32 {
+        1 << (self.bits() - 1)
+    }
+}
+
+/// Why `Emulator::run` returned control to the caller.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// A HLT instruction was executed.
+    Halted,
+    /// EIP became 0, i.e. the program returned from its entry point.
+    EndOfProgram,
+    /// CS:EIP left the emulated memory.
+    OutOfMemory,
+    /// The opcode at EIP has no handler. Opcodes of the two-byte map are 0x0F00 plus
+    /// their second byte.
+    NotImplemented(u16),
+    /// The linear address of CS:EIP is registered in `Emulator::breakpoints`.
+    Breakpoint(u32),
+}
+
+/// Result of executing a single instruction with `Emulator::step`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepOutcome {
+    Continue,
+    Stop(StopReason),
+}
+
 #[derive(Debug)]
 pub struct Emulator {
     pub registers: [u32; Register32::VARIANT_COUNT],
+    pub segments: [Segment; SegmentRegister::VARIANT_COUNT],
     pub eflags: u16,
+    /// CR0 to CR4. CR1 is reserved and always 0.
+    pub control_registers: [u32; 5],
+    pub gdtr: DescriptorTable,
+    pub idtr: DescriptorTable,
+    /// Cached translations keyed by linear page number.
+    pub(crate) tlb: HashMap<u32, TlbEntry>,
+    /// RAM from physical address 0.
     pub memory: Vec<u8>,
+    /// ROM and MMIO regions, which take precedence over the RAM they overlap.
+    pub memory_map: MemoryMap,
+    /// While off, bit 20 of every physical address is held low.
+    pub a20: A20Gate,
+    /// Devices reachable with IN and OUT.
+    pub io: IoBus,
+    /// Interrupt request lines driven by the devices.
+    pub irq: IrqLines,
+    /// Interrupt controllers turning the IRQ lines into vectors. Also registered on
+    /// the I/O bus.
+    pub pic: Rc<RefCell<PicPair>>,
+    /// Emulated time, advanced by every step.
+    pub clock: Clock,
+    /// Device events on `clock`, run between instructions.
+    pub scheduler: Scheduler,
+    /// Interval timer on IRQ0. Also registered on the I/O bus.
+    pub pit: Rc<RefCell<Pit>>,
+    /// Real-time clock and CMOS RAM on IRQ8. Also registered on the I/O bus.
+    pub rtc: Rc<RefCell<Rtc>>,
+    /// 8042 keyboard controller and keyboard on IRQ1. Also registered on the I/O bus.
+    pub keyboard: Rc<RefCell<KeyboardController>>,
+    /// Primary IDE channel on IRQ14, with no disks until they are attached. Also
+    /// registered on the I/O bus.
+    pub ide: Rc<RefCell<IdeChannel>>,
     pub eip: u32,
+    /// HLT was executed. With IF set the CPU waits for an interrupt.
+    pub halted: bool,
+    /// STI set IF on the last instruction, so interrupts are held off for one more.
+    pub(crate) interrupt_shadow: bool,
+    /// A REP string instruction stopped between elements and resumes on the next step,
+    /// so its address is not a new instruction boundary for breakpoints.
+    pub(crate) repeating: bool,
+    /// Prefixes of the instruction being executed.
+    pub prefixes: Prefixes,
+    /// Offset of the first prefix or opcode byte of the instruction being executed.
+    pub instruction_start: u32,
+    pub breakpoints: HashSet<u32>,
+    /// Native BIOS services, indexed by interrupt vector.
+    pub(crate) interrupt_hooks: [Option<InterruptHook>; 256],
+    pub(crate) functions: InstructionFunctions,
+    pub(crate) two_byte_functions: InstructionFunctions,
 }
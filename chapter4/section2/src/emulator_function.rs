@@ -1,11 +1,58 @@
-use crate::emulator::{Emulator, Register32};
+use crate::clock::{Clock, ClockMode};
+use crate::device::{
+    A20Gate, IdeChannel, KeyboardController, NullSerial, PicPair, Pit, Rtc, SystemControlPort,
+    SystemControlPortA, Uart16550,
+};
+use crate::emulator::{
+    DescriptorTable, Emulator, OperandSize, Prefixes, Register32, Segment, SegmentRegister,
+    StepOutcome, StopReason,
+};
+use crate::error::{EmulatorError, ExecutionError};
+use crate::instruction::{InstructionFunctions, New};
+use crate::io_bus::{IoBus, PortDevice, UnmappedPortPolicy};
+use crate::irq::IrqLines;
+use crate::memory_map::MemoryMap;
+use crate::paging::Access;
+use crate::scheduler::Scheduler;
 
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
 use strum::IntoEnumIterator;
 
+/// Present, ring 0, read/write data, accessed.
+const DATA_SEGMENT_ACCESS: u8 = 0x93;
+/// Present, ring 0, execute/read code, accessed.
+const CODE_SEGMENT_ACCESS: u8 = 0x9B;
+
+const CR0_PE: u32 = 1;
+
+/// Unix time the RTC of a new emulator starts at: midnight on 1 January 2000 UTC.
+const RTC_START: u64 = 946_684_800;
+
+/// The address line the A20 gate holds low.
+const A20: u32 = 1 << 20;
+
+/// Longest a step in HLT waits on the host before input is polled again.
+const IDLE_WAIT: Duration = Duration::from_millis(1);
+
+enum SegmentAccess {
+    Read,
+    Write,
+    Execute,
+}
+
 enum Eflag {
     Carry,
+    Parity,
+    Adjust,
     Zero,
     Sign,
+    Interrupt,
+    Direction,
     Overflow,
 }
 
@@ -13,55 +60,467 @@ impl Eflag {
     fn map_to_u16(&self) -> u16 {
         match self {
             Eflag::Carry => 1,
+            Eflag::Parity => 1 << 2,
+            Eflag::Adjust => 1 << 4,
             Eflag::Zero => 1 << 6,
             Eflag::Sign => 1 << 7,
+            Eflag::Interrupt => 1 << 9,
+            Eflag::Direction => 1 << 10,
             Eflag::Overflow => 1 << 11,
         }
     }
 }
 
 impl Emulator {
+    /// Creates an emulator running flat 32-bit code: every segment has base 0, a 4 GiB
+    /// limit and 32-bit default sizes, so offsets are linear addresses. COM1 has
+    /// nothing attached; unregister 0x3F8-0x3FF and register another UART there to give
+    /// it a host end. The RTC starts at midnight on 1 January 2000, so that runs are
+    /// reproducible; `Rtc::set_time` moves it.
     pub fn new(size: usize, eip: u32, esp: u32) -> Emulator {
+        let flat = Segment {
+            selector: 0,
+            base: 0,
+            limit: u32::MAX,
+            access: DATA_SEGMENT_ACCESS,
+            big: true,
+        };
+
+        let clock = Clock::new(ClockMode::Virtual);
+        let scheduler = Scheduler::new(clock.clone());
+        let irq = IrqLines::new();
+        let pit = Pit::new(scheduler.clone(), irq.line(0));
+        let rtc_start = UNIX_EPOCH + Duration::from_secs(RTC_START);
+        let rtc = Rtc::new(scheduler.clone(), irq.line(8), rtc_start, size);
+        let a20 = A20Gate::new(true);
+        let keyboard = KeyboardController::new(scheduler.clone(), irq.line(1), a20.clone());
+        let ide = IdeChannel::new(scheduler.clone(), irq.line(14));
+
         let mut emu = Emulator {
             registers: [0; Register32::VARIANT_COUNT],
+            segments: [flat; SegmentRegister::VARIANT_COUNT],
             eflags: 0,
+            control_registers: [0; 5],
+            gdtr: DescriptorTable::default(),
+            idtr: DescriptorTable {
+                base: 0,
+                limit: 0x3FF,
+            },
+            tlb: HashMap::new(),
             memory: vec![0; size],
-            eip: eip,
+            memory_map: MemoryMap::new(),
+            a20,
+            io: IoBus::new(UnmappedPortPolicy::Error),
+            irq,
+            pic: Rc::new(RefCell::new(PicPair::new())),
+            clock,
+            scheduler,
+            pit,
+            rtc,
+            keyboard,
+            ide,
+            eip,
+            halted: false,
+            interrupt_shadow: false,
+            repeating: false,
+            prefixes: Prefixes::default(),
+            instruction_start: eip,
+            breakpoints: HashSet::new(),
+            interrupt_hooks: [None; 256],
+            functions: InstructionFunctions::new(),
+            two_byte_functions: InstructionFunctions::new_two_byte(),
         };
 
         emu.registers[Register32::ESP as usize] = esp;
+        emu.segments[SegmentRegister::CS as usize].access = CODE_SEGMENT_ACCESS;
+        let com1 = Uart16550::new(Box::new(NullSerial), emu.irq.line(4), emu.scheduler.clone());
+        let devices: [(RangeInclusive<u16>, Box<dyn PortDevice>); 11] = [
+            (0x03F8..=0x03FF, Box::new(com1)),
+            (0x0020..=0x0021, Box::new(emu.pic.clone())),
+            (0x00A0..=0x00A1, Box::new(emu.pic.clone())),
+            (0x0040..=0x0043, Box::new(emu.pit.clone())),
+            (
+                0x0061..=0x0061,
+                Box::new(SystemControlPort(emu.pit.clone())),
+            ),
+            (0x0060..=0x0060, Box::new(emu.keyboard.clone())),
+            (0x0064..=0x0064, Box::new(emu.keyboard.clone())),
+            (0x0070..=0x0071, Box::new(emu.rtc.clone())),
+            (
+                0x0092..=0x0092,
+                Box::new(SystemControlPortA::new(emu.a20.clone())),
+            ),
+            (0x01F0..=0x01F7, Box::new(emu.ide.clone())),
+            (0x03F6..=0x03F6, Box::new(emu.ide.clone())),
+        ];
+        for (ports, device) in devices {
+            emu.io
+                .register(ports, device)
+                .expect("built-in devices share an I/O port");
+        }
+        emu.install_bios_stubs();
+        emu.hook_interrupt(0x10, Some(Emulator::bios_video));
+
+        emu
+    }
+
+    /// Creates an emulator in real mode starting at `cs:ip`, as a BIOS leaves the CPU
+    /// when it jumps to a boot sector. The other segment registers and SP are 0, and
+    /// the A20 gate is off.
+    pub fn new_real_mode(size: usize, cs: u16, ip: u16) -> Emulator {
+        let mut emu = Emulator::new(size, ip as u32, 0);
+        emu.a20.set(false);
+
+        for segment in SegmentRegister::iter() {
+            let selector = if segment == SegmentRegister::CS {
+                cs
+            } else {
+                0
+            };
+            let cache = &mut emu.segments[segment as usize];
+            cache.selector = selector;
+            cache.base = (selector as u32) << 4;
+            cache.limit = 0xFFFF;
+            cache.big = false;
+        }
 
         emu
     }
 
+    /// Executes the instruction at EIP.
+    ///
+    /// If the instruction raises an error, EIP is rewound to its first byte and the
+    /// returned `ExecutionError` describes the guest state at that point.
+    pub fn step(&mut self) -> Result<StepOutcome, ExecutionError> {
+        // An unmapped page is left to the instruction fetch, which raises #PF.
+        let linear = self.linear_address(SegmentRegister::CS, self.eip);
+        if let Some(address) = self.peek_translate(linear) {
+            if !self.is_mapped_physical(address) {
+                return Ok(StepOutcome::Stop(StopReason::OutOfMemory));
+            }
+        }
+
+        self.clock.tick();
+        self.scheduler.run_due();
+
+        let eip = self.eip;
+        self.repeating = false;
+        if let Err(error) = self.io.poll() {
+            return Err(self.execution_error(error, eip));
+        }
+
+        if let Some(outcome) = self.deliver_interrupt() {
+            return outcome;
+        }
+        if self.halted {
+            self.idle();
+            return Ok(StepOutcome::Continue);
+        }
+
+        self.instruction_start = eip;
+
+        let code = match self.read_prefixes() {
+            Ok(code) => code,
+            Err(error) => return self.fault(error, eip),
+        };
+
+        let (opcode, function) = if code == 0x0F {
+            match self.get_code8(1) {
+                Ok(second) => (
+                    0x0F00 | second as u16,
+                    self.two_byte_functions[second as usize],
+                ),
+                Err(error) => return self.fault(error, eip),
+            }
+        } else {
+            (code as u16, self.functions[code as usize])
+        };
+        let function = match function {
+            Some(function) => function,
+            None => {
+                self.eip = eip;
+                return Ok(StepOutcome::Stop(StopReason::NotImplemented(opcode)));
+            }
+        };
+        if self.prefixes.lock {
+            if let Err(error) = self.check_lock(opcode) {
+                return self.fault(error, eip);
+            }
+        }
+        // Two-byte handlers start on the second opcode byte.
+        if code == 0x0F {
+            self.advance_eip(1);
+        }
+
+        let result = function(self);
+
+        if let Err(error) = result {
+            return self.fault(error, eip);
+        }
+
+        // Nothing can wake a CPU halted with IF clear.
+        if self.halted && !self.is_interrupt() {
+            self.halted = false;
+            Ok(StepOutcome::Stop(StopReason::Halted))
+        } else if self.eip == 0 {
+            Ok(StepOutcome::Stop(StopReason::EndOfProgram))
+        } else {
+            Ok(StepOutcome::Continue)
+        }
+    }
+
+    /// Samples the IRQ lines and, if IF is set and the interrupt controller has a
+    /// request, delivers its vector and wakes the CPU from HLT. Returns the outcome of
+    /// the step if an interrupt was delivered.
+    fn deliver_interrupt(&mut self) -> Option<Result<StepOutcome, ExecutionError>> {
+        let mut pic = self.pic.borrow_mut();
+        pic.update(self.irq.levels(), self.irq.take_pulses());
+
+        let shadow = std::mem::take(&mut self.interrupt_shadow);
+        if shadow || !self.is_interrupt() {
+            return None;
+        }
+        let vector = pic.acknowledge()?;
+        drop(pic);
+
+        self.halted = false;
+        let eip = self.eip;
+        Some(match self.interrupt(vector, None, false) {
+            Ok(()) => Ok(StepOutcome::Continue),
+            Err(error) => self.fault(error, eip),
+        })
+    }
+
+    /// Waits in HLT for the next scheduled event. The virtual clock skips straight to
+    /// it; on the host clock the thread sleeps until then. Either way the host is
+    /// slept on for at most `IDLE_WAIT` at a time so that host input keeps being
+    /// polled.
+    fn idle(&self) {
+        let next = self.scheduler.next_event();
+        let wait = match self.clock.mode() {
+            ClockMode::Virtual => match next {
+                Some(time) => {
+                    self.clock.advance_to(time);
+                    return;
+                }
+                None => IDLE_WAIT,
+            },
+            ClockMode::Host => match next {
+                Some(time) => {
+                    Duration::from_nanos(time.saturating_sub(self.clock.now())).min(IDLE_WAIT)
+                }
+                None => IDLE_WAIT,
+            },
+        };
+        thread::sleep(wait);
+    }
+
+    /// Executes instructions until one of the conditions in `StopReason` holds.
+    ///
+    /// The instruction at the current EIP is always executed, so calling `run` again
+    /// after stopping at a breakpoint resumes execution. A breakpoint on a REP string
+    /// instruction stops once, before its first element.
+    pub fn run(&mut self) -> Result<StopReason, ExecutionError> {
+        loop {
+            if let StepOutcome::Stop(reason) = self.step()? {
+                return Ok(reason);
+            }
+            if self.repeating {
+                continue;
+            }
+
+            let address = self.linear_address(SegmentRegister::CS, self.eip);
+            if self.breakpoints.contains(&address) {
+                return Ok(StopReason::Breakpoint(address));
+            }
+        }
+    }
+
+    /// Delivers the exception `error` stands for, restarting the instruction at `eip`
+    /// afterwards. Errors without an exception vector, and exceptions the guest cannot
+    /// handle, stop execution with `error`.
+    fn fault(&mut self, error: EmulatorError, eip: u32) -> Result<StepOutcome, ExecutionError> {
+        let (vector, error_code) = match error.exception() {
+            Some(exception) => exception,
+            None => return Err(self.execution_error(error, eip)),
+        };
+
+        let report = self.execution_error(error, eip);
+        match self.exception(vector, error_code) {
+            Ok(()) => Ok(StepOutcome::Continue),
+            Err(_) => Err(report),
+        }
+    }
+
+    fn execution_error(&mut self, error: EmulatorError, eip: u32) -> ExecutionError {
+        const MAX_INSTRUCTION_LENGTH: u32 = 15;
+
+        let length = self.eip.wrapping_sub(eip).clamp(1, MAX_INSTRUCTION_LENGTH);
+        let linear = self.linear_address(SegmentRegister::CS, eip);
+        let code = (0..length)
+            .map_while(|i| {
+                let address = self.peek_translate(linear.wrapping_add(i))?;
+                self.peek_memory8(address)
+            })
+            .collect();
+
+        self.eip = eip;
+
+        ExecutionError {
+            error,
+            cs: self.segments[SegmentRegister::CS as usize].selector,
+            eip,
+            code,
+            registers: self.registers,
+            eflags: self.eflags,
+        }
+    }
+
     pub fn dump_registers(&self) {
         for r in Register32::iter() {
             println!("{:?} = {:>08x}", &r, self.registers[r as usize]);
         }
 
         println!("EIP = {:>08x}", self.eip);
+        println!("EFLAGS = {:>04x}", self.eflags);
+
+        for s in SegmentRegister::iter() {
+            println!("{:?} = {:>04x}", &s, self.segments[s as usize].selector);
+        }
+    }
+
+    /// Linear address of `offset` in `segment`.
+    pub fn linear_address(&self, segment: SegmentRegister, offset: u32) -> u32 {
+        self.segments[segment as usize].base.wrapping_add(offset)
     }
 
-    pub fn get_code8(&self, index: i32) -> u8 {
-        self.memory[(self.eip + index as u32) as usize]
+    /// Moves EIP `length` bytes forward. In a 16-bit code segment it wraps around at
+    /// 64 KiB as IP does.
+    pub(crate) fn advance_eip(&mut self, length: u32) {
+        let mask = if self.segments[SegmentRegister::CS as usize].big {
+            u32::MAX
+        } else {
+            0xFFFF
+        };
+        self.eip = self.eip.wrapping_add(length) & mask;
     }
 
-    pub fn get_sign_code8(&self, index: i32) -> i8 {
-        self.memory[(self.eip + index as u32) as usize] as i8
+    /// CR0.PE
+    pub fn is_protected_mode(&self) -> bool {
+        self.control_registers[0] & CR0_PE != 0
     }
 
-    pub fn get_code32(&self, index: i32) -> u32 {
-        let mut ret = 0u32;
+    /// Current privilege level: the RPL of CS in protected mode, 0 in real mode.
+    pub fn cpl(&self) -> u8 {
+        if self.is_protected_mode() {
+            (self.segments[SegmentRegister::CS as usize].selector & 0x03) as u8
+        } else {
+            0
+        }
+    }
+
+    /// Checks `size` bytes at `offset` against the limit of `segment` and, in protected
+    /// mode, against its type, raising #GP(0) on a violation.
+    fn check_segment(
+        &self,
+        segment: SegmentRegister,
+        offset: u32,
+        size: OperandSize,
+        access: SegmentAccess,
+    ) -> Result<(), EmulatorError> {
+        let cache = &self.segments[segment as usize];
+
+        if self.is_protected_mode() {
+            let allowed = cache.is_present()
+                && match access {
+                    SegmentAccess::Read => !cache.is_code() || cache.is_readable_or_writable(),
+                    SegmentAccess::Write => !cache.is_code() && cache.is_readable_or_writable(),
+                    SegmentAccess::Execute => cache.is_code(),
+                };
+            if !allowed {
+                return Err(EmulatorError::GeneralProtection(0));
+            }
+        }
 
-        for i in 0..4 {
-            ret |= (self.get_code8(index + i) as u32) << (i * 8);
+        let first = offset as u64;
+        let last = first + size.bytes() as u64 - 1;
+        let inside = if !cache.is_code() && cache.access & 0x04 != 0 {
+            // Expand-down: valid offsets lie above the limit.
+            let upper = if cache.big { u32::MAX } else { 0xFFFF };
+            first > cache.limit as u64 && last <= upper as u64
+        } else {
+            last <= cache.limit as u64
+        };
+        if !inside {
+            return Err(EmulatorError::GeneralProtection(0));
         }
 
-        ret
+        Ok(())
+    }
+
+    /// Reads `size` bytes at `segment:offset`.
+    pub fn get_segmented(
+        &mut self,
+        segment: SegmentRegister,
+        offset: u32,
+        size: OperandSize,
+    ) -> Result<u32, EmulatorError> {
+        self.check_segment(segment, offset, size, SegmentAccess::Read)?;
+        self.get_linear(self.linear_address(segment, offset), size, Access::Read)
+    }
+
+    /// Writes `size` bytes at `segment:offset`.
+    pub fn set_segmented(
+        &mut self,
+        segment: SegmentRegister,
+        offset: u32,
+        value: u32,
+        size: OperandSize,
+    ) -> Result<(), EmulatorError> {
+        self.check_segment(segment, offset, size, SegmentAccess::Write)?;
+        self.set_linear(self.linear_address(segment, offset), value, size)
+    }
+
+    /// Reads `size` bytes of the instruction stream at CS:EIP + `index`.
+    fn fetch_code(&mut self, index: i32, size: OperandSize) -> Result<u32, EmulatorError> {
+        let offset = self.eip.wrapping_add(index as u32);
+        self.check_segment(SegmentRegister::CS, offset, size, SegmentAccess::Execute)?;
+        let linear = self.linear_address(SegmentRegister::CS, offset);
+        self.get_linear(linear, size, Access::Execute)
+    }
+
+    pub fn get_code8(&mut self, index: i32) -> Result<u8, EmulatorError> {
+        Ok(self.fetch_code(index, OperandSize::Byte)? as u8)
+    }
+
+    pub fn get_sign_code8(&mut self, index: i32) -> Result<i8, EmulatorError> {
+        Ok(self.get_code8(index)? as i8)
+    }
+
+    pub fn get_code32(&mut self, index: i32) -> Result<u32, EmulatorError> {
+        self.fetch_code(index, OperandSize::Dword)
+    }
+
+    pub fn get_sign_code32(&mut self, index: i32) -> Result<i32, EmulatorError> {
+        Ok(self.get_code32(index)? as i32)
+    }
+
+    pub fn get_code(&mut self, index: i32, size: OperandSize) -> Result<u32, EmulatorError> {
+        match size {
+            OperandSize::Byte => Ok(self.get_code8(index)? as u32),
+            OperandSize::Word => self.fetch_code(index, size),
+            OperandSize::Dword => self.get_code32(index),
+        }
     }
 
-    pub fn get_sign_code32(&self, index: i32) -> i32 {
-        self.get_code32(index) as i32
+    /// Sign-extends the low `size` bits of `value`.
+    pub fn sign_extend(value: u32, size: OperandSize) -> i64 {
+        let shift = 32 - size.bits();
+        ((value << shift) as i32 >> shift) as i64
+    }
+
+    pub fn get_sign_code(&mut self, index: i32, size: OperandSize) -> Result<u32, EmulatorError> {
+        Ok(Emulator::sign_extend(self.get_code(index, size)?, size) as u32)
     }
 
     pub fn get_register32(&self, index: i32) -> u32 {
@@ -72,64 +531,293 @@ impl Emulator {
         self.registers[index as usize] = value;
     }
 
+    pub fn get_register16(&self, index: i32) -> u16 {
+        self.get_register(index, OperandSize::Word) as u16
+    }
+
+    pub fn set_register16(&mut self, index: i32, value: u16) {
+        self.set_register(index, value as u32, OperandSize::Word);
+    }
+
+    /// Reads AL, CL, DL, BL, AH, CH, DH or BH. Only the low 3 bits of `index` are used,
+    /// as in the reg and r/m fields of an instruction.
     pub fn get_register8(&self, index: i32) -> u8 {
-        if (0 <= index) && (index < 4) {
+        let index = index & 0x07;
+        if index < 4 {
             (self.registers[index as usize] & 0xff) as u8
-        } else if (4 <= index) && (index < 8) {
-            ((self.registers[(index - 4) as usize] >> 8) & 0xff) as u8
         } else {
-            panic!()
+            ((self.registers[(index - 4) as usize] >> 8) & 0xff) as u8
         }
     }
 
+    /// Writes AL, CL, DL, BL, AH, CH, DH or BH. Only the low 3 bits of `index` are used,
+    /// as in the reg and r/m fields of an instruction.
     pub fn set_register8(&mut self, index: i32, value: u8) {
-        if (0 <= index) && (index < 4) {
+        let index = index & 0x07;
+        if index < 4 {
             let r = self.registers[index as usize] & 0xffffff00;
             self.registers[index as usize] = r | (value as u32);
-        } else if (4 <= index) && (index < 8) {
+        } else {
             let r = self.registers[(index - 4) as usize] & 0xffff00ff;
             self.registers[(index - 4) as usize] = r | ((value as u32) << 8);
+        }
+    }
+
+    pub fn get_register(&self, index: i32, size: OperandSize) -> u32 {
+        match size {
+            OperandSize::Byte => self.get_register8(index) as u32,
+            OperandSize::Word => self.get_register32(index) & 0xFFFF,
+            OperandSize::Dword => self.get_register32(index),
+        }
+    }
+
+    pub fn set_register(&mut self, index: i32, value: u32, size: OperandSize) {
+        match size {
+            OperandSize::Byte => self.set_register8(index, value as u8),
+            OperandSize::Word => {
+                let r = self.get_register32(index) & 0xFFFF0000;
+                self.set_register32(index, r | (value & 0xFFFF));
+            }
+            OperandSize::Dword => self.set_register32(index, value),
+        }
+    }
+
+    /// `address` as it reaches memory through the A20 gate.
+    fn gate_a20(&self, address: u32) -> u32 {
+        if self.a20.is_enabled() {
+            address
+        } else {
+            address & !A20
+        }
+    }
+
+    /// Whether an access of `size` bytes wraps around within itself because the A20
+    /// gate is off, so that its bytes are not consecutive.
+    fn is_split_by_a20(&self, address: u32, size: u32) -> bool {
+        !self.a20.is_enabled() && (address ^ address.wrapping_add(size - 1)) & A20 != 0
+    }
+
+    /// Whether physical `address` is backed by RAM or a region of the memory map.
+    pub fn is_mapped_physical(&self, address: u32) -> bool {
+        let address = self.gate_a20(address);
+        (address as usize) < self.memory.len() || self.memory_map.is_mapped(address)
+    }
+
+    /// Index into `memory` of an access that can bypass the memory map: plain RAM
+    /// below every mapped region, not split by the A20 gate.
+    fn ram_index(&self, address: u32, size: usize) -> Option<usize> {
+        if self.is_split_by_a20(address, size as u32) {
+            return None;
+        }
+        let address = self.gate_a20(address);
+        let start = address as usize;
+        if start + size <= self.memory.len()
+            && self.memory_map.is_below_regions(address, size as u32)
+        {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    /// Reads the byte at physical `address` for the debugger. MMIO regions are not
+    /// read, as reading a device register may change it.
+    pub fn peek_memory8(&self, address: u32) -> Option<u8> {
+        let address = self.gate_a20(address);
+        if self.memory_map.is_mapped(address) {
+            self.memory_map.peek(address)
         } else {
-            panic!()
+            self.memory.get(address as usize).copied()
         }
     }
 
-    pub fn get_memory8(&self, address: u32) -> u8 {
-        self.memory[address as usize]
+    /// Reads through the A20 gate and the memory map. An access no single region holds
+    /// whole is split into bytes, each served by its region or by RAM.
+    fn read_physical(&self, address: u32, size: OperandSize) -> Result<u32, EmulatorError> {
+        if !self.is_split_by_a20(address, size.bytes()) {
+            if let Some(result) = self.memory_map.read(self.gate_a20(address), size) {
+                return result;
+            }
+        }
+
+        let mut value = 0;
+        for offset in 0..size.bytes() {
+            let byte_address = self.gate_a20(address.wrapping_add(offset));
+            let byte = match self.memory_map.read(byte_address, OperandSize::Byte) {
+                Some(result) => result?,
+                None => match self.memory.get(byte_address as usize) {
+                    Some(&byte) => byte as u32,
+                    None => {
+                        return Err(EmulatorError::MemoryOutOfBounds {
+                            addr: address,
+                            size: size.bytes() as usize,
+                        })
+                    }
+                },
+            };
+            value |= byte << (offset * 8);
+        }
+        Ok(value)
+    }
+
+    /// Writes through the memory map, split into bytes like `read_physical`. Nothing is
+    /// written if any byte is unmapped.
+    fn write_physical(
+        &mut self,
+        address: u32,
+        value: u32,
+        size: OperandSize,
+    ) -> Result<(), EmulatorError> {
+        if !self.is_split_by_a20(address, size.bytes()) {
+            if let Some(result) = self.memory_map.write(self.gate_a20(address), value, size) {
+                return result;
+            }
+        }
+
+        if !(0..size.bytes()).all(|offset| self.is_mapped_physical(address.wrapping_add(offset))) {
+            return Err(EmulatorError::MemoryOutOfBounds {
+                addr: address,
+                size: size.bytes() as usize,
+            });
+        }
+
+        for offset in 0..size.bytes() {
+            let byte_address = self.gate_a20(address.wrapping_add(offset));
+            let byte = (value >> (offset * 8)) as u8;
+            match self
+                .memory_map
+                .write(byte_address, byte as u32, OperandSize::Byte)
+            {
+                Some(result) => result?,
+                None => self.memory[byte_address as usize] = byte,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_memory8(&self, address: u32) -> Result<u8, EmulatorError> {
+        match self.ram_index(address, 1) {
+            Some(start) => Ok(self.memory[start]),
+            None => Ok(self.read_physical(address, OperandSize::Byte)? as u8),
+        }
+    }
+
+    pub fn get_memory16(&self, address: u32) -> Result<u16, EmulatorError> {
+        match self.ram_index(address, 2) {
+            Some(start) => Ok(self.memory[start] as u16 | (self.memory[start + 1] as u16) << 8),
+            None => Ok(self.read_physical(address, OperandSize::Word)? as u16),
+        }
     }
 
-    pub fn get_memory32(&self, address: u32) -> u32 {
+    pub fn get_memory32(&self, address: u32) -> Result<u32, EmulatorError> {
+        let start = match self.ram_index(address, 4) {
+            Some(start) => start,
+            None => return self.read_physical(address, OperandSize::Dword),
+        };
         let mut ret = 0u32;
 
         for offset in 0..4 {
-            ret |= (self.get_memory8(address + offset) as u32) << (offset * 8);
+            ret |= (self.memory[start + offset] as u32) << (offset * 8);
         }
 
-        ret
+        Ok(ret)
+    }
+
+    pub fn set_memory8(&mut self, address: u32, value: u8) -> Result<(), EmulatorError> {
+        match self.ram_index(address, 1) {
+            Some(start) => self.memory[start] = value,
+            None => self.write_physical(address, value as u32, OperandSize::Byte)?,
+        }
+        Ok(())
     }
 
-    pub fn set_memory8(&mut self, address: u32, value: u8) {
-        self.memory[address as usize] = value;
+    pub fn set_memory16(&mut self, address: u32, value: u16) -> Result<(), EmulatorError> {
+        let start = match self.ram_index(address, 2) {
+            Some(start) => start,
+            None => return self.write_physical(address, value as u32, OperandSize::Word),
+        };
+        self.memory[start] = value as u8;
+        self.memory[start + 1] = (value >> 8) as u8;
+        Ok(())
     }
 
-    pub fn set_memory32(&mut self, address: u32, value: u32) {
+    pub fn set_memory32(&mut self, address: u32, value: u32) -> Result<(), EmulatorError> {
+        let start = match self.ram_index(address, 4) {
+            Some(start) => start,
+            None => return self.write_physical(address, value, OperandSize::Dword),
+        };
+
         for offset in 0..4 {
-            self.set_memory8(address + offset, ((value >> (offset * 8)) & 0xFF) as u8);
+            self.memory[start + offset] = ((value >> (offset * 8)) & 0xFF) as u8;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_memory(&self, address: u32, size: OperandSize) -> Result<u32, EmulatorError> {
+        match size {
+            OperandSize::Byte => Ok(self.get_memory8(address)? as u32),
+            OperandSize::Word => Ok(self.get_memory16(address)? as u32),
+            OperandSize::Dword => self.get_memory32(address),
+        }
+    }
+
+    pub fn set_memory(
+        &mut self,
+        address: u32,
+        value: u32,
+        size: OperandSize,
+    ) -> Result<(), EmulatorError> {
+        match size {
+            OperandSize::Byte => self.set_memory8(address, value as u8),
+            OperandSize::Word => self.set_memory16(address, value as u16),
+            OperandSize::Dword => self.set_memory32(address, value),
+        }
+    }
+
+    /// Width of the stack pointer: ESP when the B flag of SS is set, SP otherwise.
+    pub fn stack_size(&self) -> OperandSize {
+        if self.segments[SegmentRegister::SS as usize].big {
+            OperandSize::Dword
+        } else {
+            OperandSize::Word
         }
     }
 
-    pub fn push32(&mut self, value: u32) {
-        let address = self.get_register32(Register32::ESP as i32) - 4;
-        self.set_register32(Register32::ESP as i32, address);
-        self.set_memory32(address, value);
+    pub fn push(&mut self, value: u32, size: OperandSize) -> Result<(), EmulatorError> {
+        let stack_size = self.stack_size();
+        let address = self
+            .get_register(Register32::ESP as i32, stack_size)
+            .wrapping_sub(size.bytes())
+            & stack_size.mask();
+        self.set_segmented(SegmentRegister::SS, address, value, size)?;
+        self.set_register(Register32::ESP as i32, address, stack_size);
+        Ok(())
+    }
+
+    pub fn pop(&mut self, size: OperandSize) -> Result<u32, EmulatorError> {
+        let stack_size = self.stack_size();
+        let address = self.get_register(Register32::ESP as i32, stack_size);
+        let ret = self.get_segmented(SegmentRegister::SS, address, size)?;
+        let address = address.wrapping_add(size.bytes()) & stack_size.mask();
+        self.set_register(Register32::ESP as i32, address, stack_size);
+
+        Ok(ret)
+    }
+
+    /// Discards `bytes` bytes from the top of the stack.
+    pub fn release_stack(&mut self, bytes: u32) {
+        let stack_size = self.stack_size();
+        let esp = self.get_register(Register32::ESP as i32, stack_size);
+        self.set_register(Register32::ESP as i32, esp.wrapping_add(bytes), stack_size);
     }
 
-    pub fn pop32(&mut self) -> u32 {
-        let address = self.get_register32(Register32::ESP as i32);
-        let ret = self.get_memory32(address);
-        self.set_register32(Register32::ESP as i32, address + 4);
+    pub fn push32(&mut self, value: u32) -> Result<(), EmulatorError> {
+        self.push(value, OperandSize::Dword)
+    }
 
-        ret
+    pub fn pop32(&mut self) -> Result<u32, EmulatorError> {
+        self.pop(OperandSize::Dword)
     }
 
     pub fn set_carry(&mut self, is_carry: bool) {
@@ -140,6 +828,22 @@ impl Emulator {
         }
     }
 
+    pub fn set_parity(&mut self, is_parity: bool) {
+        if is_parity {
+            self.eflags |= Eflag::map_to_u16(&Eflag::Parity);
+        } else {
+            self.eflags &= !Eflag::map_to_u16(&Eflag::Parity);
+        }
+    }
+
+    pub fn set_adjust(&mut self, is_adjust: bool) {
+        if is_adjust {
+            self.eflags |= Eflag::map_to_u16(&Eflag::Adjust);
+        } else {
+            self.eflags &= !Eflag::map_to_u16(&Eflag::Adjust);
+        }
+    }
+
     pub fn set_zero(&mut self, is_zero: bool) {
         if is_zero {
             self.eflags |= Eflag::map_to_u16(&Eflag::Zero);
@@ -156,6 +860,22 @@ impl Emulator {
         }
     }
 
+    pub fn set_interrupt(&mut self, is_interrupt: bool) {
+        if is_interrupt {
+            self.eflags |= Eflag::map_to_u16(&Eflag::Interrupt);
+        } else {
+            self.eflags &= !Eflag::map_to_u16(&Eflag::Interrupt);
+        }
+    }
+
+    pub fn set_direction(&mut self, is_direction: bool) {
+        if is_direction {
+            self.eflags |= Eflag::map_to_u16(&Eflag::Direction);
+        } else {
+            self.eflags &= !Eflag::map_to_u16(&Eflag::Direction);
+        }
+    }
+
     pub fn set_overflow(&mut self, is_overflow: bool) {
         if is_overflow {
             self.eflags |= Eflag::map_to_u16(&Eflag::Overflow);
@@ -168,6 +888,14 @@ impl Emulator {
         (self.eflags & Eflag::map_to_u16(&Eflag::Carry)) == Eflag::map_to_u16(&Eflag::Carry)
     }
 
+    pub fn is_parity(&self) -> bool {
+        (self.eflags & Eflag::map_to_u16(&Eflag::Parity)) == Eflag::map_to_u16(&Eflag::Parity)
+    }
+
+    pub fn is_adjust(&self) -> bool {
+        (self.eflags & Eflag::map_to_u16(&Eflag::Adjust)) == Eflag::map_to_u16(&Eflag::Adjust)
+    }
+
     pub fn is_zero(&self) -> bool {
         (self.eflags & Eflag::map_to_u16(&Eflag::Zero)) == Eflag::map_to_u16(&Eflag::Zero)
     }
@@ -176,18 +904,283 @@ impl Emulator {
         (self.eflags & Eflag::map_to_u16(&Eflag::Sign)) == Eflag::map_to_u16(&Eflag::Sign)
     }
 
+    pub fn is_interrupt(&self) -> bool {
+        (self.eflags & Eflag::map_to_u16(&Eflag::Interrupt)) == Eflag::map_to_u16(&Eflag::Interrupt)
+    }
+
+    /// I/O privilege level, bits 12-13 of EFLAGS.
+    pub fn iopl(&self) -> u8 {
+        ((self.eflags >> 12) & 0x03) as u8
+    }
+
+    pub fn is_direction(&self) -> bool {
+        (self.eflags & Eflag::map_to_u16(&Eflag::Direction)) == Eflag::map_to_u16(&Eflag::Direction)
+    }
+
     pub fn is_overflow(&self) -> bool {
         (self.eflags & Eflag::map_to_u16(&Eflag::Overflow)) == Eflag::map_to_u16(&Eflag::Overflow)
     }
 
-    pub fn update_eflags_sub(&mut self, v1: u32, v2: u32, result: u64) {
-        let sign1 = (v1 >> 31) != 0;
-        let sign2 = (v2 >> 31) != 0;
-        let signr = ((result >> 31) & 1) != 0;
+    /// Evaluates the condition encoded in the low 4 bits of Jcc, SETcc and CMOVcc.
+    pub fn is_condition(&self, condition: u8) -> bool {
+        let result = match condition >> 1 {
+            0 => self.is_overflow(),
+            1 => self.is_carry(),
+            2 => self.is_zero(),
+            3 => self.is_carry() || self.is_zero(),
+            4 => self.is_sign(),
+            5 => self.is_parity(),
+            6 => self.is_sign() != self.is_overflow(),
+            _ => self.is_zero() || (self.is_sign() != self.is_overflow()),
+        };
+
+        // Odd conditions are the negation of the preceding even one.
+        result != (condition & 1 == 1)
+    }
+
+    /// Sets ZF, SF and PF from `result`, which are defined the same way for every
+    /// arithmetic and logic instruction.
+    fn update_eflags_result(&mut self, result: u32, size: OperandSize) {
+        self.set_zero(result & size.mask() == 0);
+        self.set_sign(result & size.sign_bit() != 0);
+        // PF only looks at the least significant byte, whatever the operand size.
+        self.set_parity((result as u8).count_ones() & 1 == 0);
+    }
+
+    /// Updates the flags after `result = v1 + v2 (+ carry)`, where `result` is computed
+    /// without truncation to `size`.
+    pub fn update_eflags_add(&mut self, v1: u32, v2: u32, result: u64, size: OperandSize) {
+        let sign_bit = size.sign_bit() as u64;
+        let (v1, v2) = (v1 as u64, v2 as u64);
+
+        self.set_carry(result > size.mask() as u64);
+        self.set_adjust((v1 ^ v2 ^ result) & 0x10 != 0);
+        self.set_overflow(!(v1 ^ v2) & (v1 ^ result) & sign_bit != 0);
+        self.update_eflags_result(result as u32, size);
+    }
+
+    /// Updates the flags after `result = v1 - v2 (- carry)`, where `result` is computed
+    /// with wrapping 64-bit arithmetic so that a borrow shows up above `size`.
+    pub fn update_eflags_sub(&mut self, v1: u32, v2: u32, result: u64, size: OperandSize) {
+        let sign_bit = size.sign_bit() as u64;
+        let (v1, v2) = (v1 as u64, v2 as u64);
+
+        self.set_carry(result > size.mask() as u64);
+        self.set_adjust((v1 ^ v2 ^ result) & 0x10 != 0);
+        self.set_overflow((v1 ^ v2) & (v1 ^ result) & sign_bit != 0);
+        self.update_eflags_result(result as u32, size);
+    }
+
+    /// Updates the flags after AND, OR, XOR and TEST. AF is undefined and cleared.
+    pub fn update_eflags_logic(&mut self, result: u32, size: OperandSize) {
+        self.set_carry(false);
+        self.set_adjust(false);
+        self.set_overflow(false);
+        self.update_eflags_result(result, size);
+    }
+
+    /// Updates the flags after INC. CF is left unchanged.
+    pub fn update_eflags_inc(&mut self, result: u32, size: OperandSize) {
+        let result = result & size.mask();
+
+        self.set_adjust(result & 0x0f == 0);
+        self.set_overflow(result == size.sign_bit());
+        self.update_eflags_result(result, size);
+    }
+
+    /// Updates the flags after DEC. CF is left unchanged.
+    pub fn update_eflags_dec(&mut self, result: u32, size: OperandSize) {
+        let result = result & size.mask();
+
+        self.set_adjust(result & 0x0f == 0x0f);
+        self.set_overflow(result == size.sign_bit() - 1);
+        self.update_eflags_result(result, size);
+    }
+
+    /// Updates the flags after SHL, SHR, SAL and SAR with a non-zero count. `carry` is
+    /// the last bit shifted out; `overflow` only has a defined meaning for a count of 1.
+    /// AF is undefined and cleared.
+    pub fn update_eflags_shift(
+        &mut self,
+        result: u32,
+        carry: bool,
+        overflow: bool,
+        size: OperandSize,
+    ) {
+        self.set_carry(carry);
+        self.set_adjust(false);
+        self.set_overflow(overflow);
+        self.update_eflags_result(result, size);
+    }
+
+    /// Updates the flags after MUL and IMUL. CF and OF are set when the upper half of the
+    /// product is significant; SF, ZF and PF are undefined and follow the lower half.
+    pub fn update_eflags_mul(&mut self, result: u32, overflow: bool, size: OperandSize) {
+        self.set_carry(overflow);
+        self.set_adjust(false);
+        self.set_overflow(overflow);
+        self.update_eflags_result(result, size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::OperandSize::{Byte, Dword, Word};
+
+    const ENTRY: u32 = 0x7C00;
+
+    fn emulator(code: &[u8]) -> Emulator {
+        let mut emu = Emulator::new(0x10000, ENTRY, ENTRY);
+        emu.memory[ENTRY as usize..ENTRY as usize + code.len()].copy_from_slice(code);
+        emu
+    }
+
+    #[test]
+    fn breakpoint_on_rep_stops_once() {
+        // mov ecx, 4; rep movsb; hlt
+        let mut emu = emulator(&[0xB9, 0x04, 0x00, 0x00, 0x00, 0xF3, 0xA4, 0xF4]);
+        emu.breakpoints.insert(ENTRY + 5);
+
+        assert_eq!(emu.run().unwrap(), StopReason::Breakpoint(ENTRY + 5));
+        assert_eq!(emu.run().unwrap(), StopReason::Halted);
+        assert_eq!(emu.get_register32(Register32::ECX as i32), 0);
+    }
+
+    #[test]
+    fn missing_opcodes_stop_in_both_maps() {
+        let mut emu = emulator(&[0xD6]);
+        assert_eq!(emu.run().unwrap(), StopReason::NotImplemented(0xD6));
+
+        let mut emu = emulator(&[0x0F, 0x0B]);
+        assert_eq!(emu.run().unwrap(), StopReason::NotImplemented(0x0F0B));
+        assert_eq!(emu.eip, ENTRY);
+    }
+
+    #[test]
+    fn hlt_skips_ahead_to_the_next_timer_interrupt() {
+        let mut emu = Emulator::new_real_mode(0x10000, 0, ENTRY as u16);
+        // Channel 0 in mode 0 with a count of 0x1000, set before the PIC first samples
+        // IRQ0 so that the BIOS square wave leaves no request behind.
+        emu.io.write(0x43, 0x30, Byte).unwrap();
+        emu.io.write(0x40, 0x00, Byte).unwrap();
+        emu.io.write(0x40, 0x10, Byte).unwrap();
+        let code = [
+            0xB0, 0xFE, 0xE6, 0x21, // mov al, 0xFE; out 0x21, al
+            0xFB, 0xF4, // sti; hlt
+        ];
+        emu.memory[ENTRY as usize..ENTRY as usize + code.len()].copy_from_slice(&code);
+        // IRQ0 (vector 8) goes to 0000:0600.
+        emu.memory[8 * 4..8 * 4 + 4].copy_from_slice(&0x0600u32.to_le_bytes());
+
+        for _ in 0..4 {
+            emu.step().unwrap();
+        }
+        assert!(emu.halted);
+        // 0x1000 ticks of 838 ns, far longer than the instructions took.
+        let due = emu.scheduler.next_event().unwrap();
+        assert!(due > 3_000_000);
+
+        emu.step().unwrap();
+        assert_eq!(emu.clock.now(), due);
+        assert_eq!(emu.eip, ENTRY + code.len() as u32);
+
+        emu.step().unwrap();
+        assert!(!emu.halted);
+        assert_eq!(emu.eip, 0x0600);
+        assert_eq!(emu.clock.instructions(), 6);
+    }
+
+    #[test]
+    fn the_rtc_starts_at_a_fixed_date() {
+        let mut emu = emulator(&[]);
+        let mut cmos = |index: u8| {
+            emu.io.write(0x70, index as u32, Byte).unwrap();
+            emu.io.read(0x71, Byte).unwrap()
+        };
+        // Seconds, hours, day of the month, month, year and century, in BCD.
+        let date: Vec<u32> = [0x00, 0x04, 0x07, 0x08, 0x09, 0x32]
+            .into_iter()
+            .map(&mut cmos)
+            .collect();
+        assert_eq!(date, [0x00, 0x00, 0x01, 0x01, 0x00, 0x20]);
+    }
+
+    #[test]
+    fn ip_wraps_in_real_mode() {
+        let mut emu = Emulator::new_real_mode(0x20000, 0x1000, 0xFFFF);
+        // nop
+        emu.memory[0x1FFFF] = 0x90;
+        emu.step().unwrap();
+        assert_eq!(emu.eip, 0);
+    }
+
+    /// `v1 + v2 + carry` truncated to `size`, with the flags updated.
+    fn add(emu: &mut Emulator, v1: u32, v2: u32, carry: bool, size: OperandSize) -> u32 {
+        let result = v1 as u64 + v2 as u64 + carry as u64;
+        emu.update_eflags_add(v1, v2, result, size);
+        result as u32 & size.mask()
+    }
+
+    /// `v1 - v2 - borrow` truncated to `size`, with the flags updated.
+    fn sub(emu: &mut Emulator, v1: u32, v2: u32, borrow: bool, size: OperandSize) -> u32 {
+        let result = (v1 as u64)
+            .wrapping_sub(v2 as u64)
+            .wrapping_sub(borrow as u64);
+        emu.update_eflags_sub(v1, v2, result, size);
+        result as u32 & size.mask()
+    }
+
+    #[test]
+    fn add_carry_and_overflow() {
+        let mut emu = emulator(&[]);
+        assert_eq!(add(&mut emu, 0xFF, 0x01, false, Byte), 0);
+        assert!(emu.is_carry() && emu.is_zero() && emu.is_adjust() && !emu.is_overflow());
+
+        assert_eq!(add(&mut emu, 0x7F, 0x01, false, Byte), 0x80);
+        assert!(!emu.is_carry() && emu.is_sign() && emu.is_overflow());
+
+        assert_eq!(add(&mut emu, 0x8000_0000, 0x8000_0000, false, Dword), 0);
+        assert!(emu.is_carry() && emu.is_overflow());
+
+        // The carry in counts towards both CF and OF.
+        assert_eq!(add(&mut emu, 0xFFFF, 0, true, Word), 0);
+        assert!(emu.is_carry() && !emu.is_overflow());
+        assert_eq!(add(&mut emu, 0x7FFE, 0x0001, true, Word), 0x8000);
+        assert!(!emu.is_carry() && emu.is_overflow());
+    }
+
+    #[test]
+    fn sub_borrow_and_overflow() {
+        let mut emu = emulator(&[]);
+        assert_eq!(sub(&mut emu, 0x00, 0x01, false, Byte), 0xFF);
+        assert!(emu.is_carry() && emu.is_sign() && emu.is_adjust() && !emu.is_overflow());
+
+        assert_eq!(sub(&mut emu, 0x80, 0x01, false, Byte), 0x7F);
+        assert!(!emu.is_carry() && emu.is_overflow());
+
+        assert_eq!(sub(&mut emu, 5, 5, false, Dword), 0);
+        assert!(!emu.is_carry() && emu.is_zero() && emu.is_parity());
+
+        assert_eq!(sub(&mut emu, 0, 0, true, Word), 0xFFFF);
+        assert!(emu.is_carry() && !emu.is_overflow());
+        assert_eq!(sub(&mut emu, 0x8000, 0x7FFF, true, Word), 0);
+        assert!(!emu.is_carry() && emu.is_zero() && emu.is_overflow());
+    }
+
+    #[test]
+    fn inc_and_dec_leave_carry_alone() {
+        let mut emu = emulator(&[]);
+        emu.set_carry(true);
+        emu.update_eflags_inc(0x80, Byte);
+        assert!(emu.is_carry() && emu.is_overflow() && emu.is_sign() && emu.is_adjust());
+        emu.update_eflags_inc(0x1_0000, Word);
+        assert!(emu.is_carry() && emu.is_zero() && !emu.is_overflow());
 
-        self.set_carry((result >> 32) != 0);
-        self.set_zero(result == 0);
-        self.set_sign(!signr);
-        self.set_overflow((sign1 != sign2) && (sign1 != signr));
+        emu.set_carry(false);
+        emu.update_eflags_dec(0x7FFF_FFFF, Dword);
+        assert!(!emu.is_carry() && emu.is_overflow() && emu.is_adjust());
+        emu.update_eflags_dec(0xFF, Byte);
+        assert!(!emu.is_carry() && !emu.is_overflow() && emu.is_sign());
     }
 }
@@ -0,0 +1,163 @@
+use crate::emulator::Register32;
+
+use std::fmt;
+use std::io;
+use strum::IntoEnumIterator;
+
+/// Errors raised while executing a guest instruction.
+#[derive(Debug)]
+pub enum EmulatorError {
+    /// The opcode (and, for group opcodes, the ModR/M reg field) is not a valid instruction.
+    /// Opcodes of the two-byte map are 0x0F00 plus their second byte.
+    InvalidOpcode { opcode: u16, modrm_reg: Option<u8> },
+    /// The ModR/M byte encodes an addressing form the emulator does not support.
+    UnsupportedModRM { m: u8, rm: u8 },
+    /// An access of `size` bytes at `addr` falls outside the emulated memory.
+    MemoryOutOfBounds { addr: u32, size: usize },
+    /// A write to ROM at the physical address under `RomWritePolicy::Error`.
+    RomWrite(u32),
+    /// DIV or IDIV with a zero divisor or a quotient too large for the destination.
+    DivideError,
+    /// #GP with its error code: a selector, or 0 for limit and type violations.
+    GeneralProtection(u16),
+    /// #PF on the linear `address`, with the P, W/R and U/S bits of the error code.
+    PageFault { address: u32, code: u32 },
+    /// #NP: the selector of a segment whose descriptor is not present.
+    SegmentNotPresent(u16),
+    /// A native BIOS service was called with a function number in AH it does not
+    /// implement.
+    UnhandledBiosFunction { vector: u8, function: u8 },
+    /// No device responds to the I/O port.
+    UnhandledPort(u16),
+    /// A device was registered for an I/O port another device already answers.
+    PortInUse(u16),
+    /// Reading from or writing to the host console failed.
+    HostIo(io::Error),
+}
+
+/// Shows an opcode as its bytes in hex: `XX`, or `0F XX` for the two-byte map.
+pub struct OpcodeName(pub u16);
+
+impl fmt::Display for OpcodeName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 > 0xFF {
+            write!(f, "{:02X} {:02X}", self.0 >> 8, self.0 & 0xFF)
+        } else {
+            write!(f, "{:02X}", self.0)
+        }
+    }
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulatorError::InvalidOpcode {
+                opcode,
+                modrm_reg: Some(reg),
+            } => write!(f, "invalid opcode: {} /{}", OpcodeName(*opcode), reg),
+            EmulatorError::InvalidOpcode {
+                opcode,
+                modrm_reg: None,
+            } => write!(f, "invalid opcode: {}", OpcodeName(*opcode)),
+            EmulatorError::UnsupportedModRM { m, rm } => {
+                write!(f, "unsupported ModRM: mod = {}, rm = {}", m, rm)
+            }
+            EmulatorError::MemoryOutOfBounds { addr, size } => {
+                write!(
+                    f,
+                    "memory access out of bounds: {} byte(s) at {:08X}",
+                    size, addr
+                )
+            }
+            EmulatorError::RomWrite(address) => write!(f, "write to ROM at {:08X}", address),
+            EmulatorError::DivideError => write!(f, "divide error"),
+            EmulatorError::GeneralProtection(code) => {
+                write!(f, "general protection fault (error code {:04X})", code)
+            }
+            EmulatorError::PageFault { address, code } => {
+                write!(f, "page fault at {:08X} (error code {:X})", address, code)
+            }
+            EmulatorError::SegmentNotPresent(selector) => {
+                write!(f, "segment not present: {:04X}", selector)
+            }
+            EmulatorError::UnhandledBiosFunction { vector, function } => {
+                write!(
+                    f,
+                    "unhandled BIOS function: INT {:02X} AH={:02X}",
+                    vector, function
+                )
+            }
+            EmulatorError::UnhandledPort(port) => write!(f, "unhandled I/O port: {:04X}", port),
+            EmulatorError::PortInUse(port) => write!(f, "I/O port already in use: {:04X}", port),
+            EmulatorError::HostIo(e) => write!(f, "host I/O error: {}", e),
+        }
+    }
+}
+
+impl EmulatorError {
+    /// The exception vector and error code the guest sees for this error, or `None`
+    /// for errors that stop the emulator instead.
+    pub fn exception(&self) -> Option<(u8, Option<u32>)> {
+        match self {
+            EmulatorError::DivideError => Some((0, None)),
+            EmulatorError::InvalidOpcode { .. } => Some((6, None)),
+            EmulatorError::SegmentNotPresent(selector) => Some((11, Some(*selector as u32))),
+            EmulatorError::GeneralProtection(code) => Some((13, Some(*code as u32))),
+            EmulatorError::PageFault { code, .. } => Some((14, Some(*code))),
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EmulatorError::HostIo(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for EmulatorError {
+    fn from(e: io::Error) -> Self {
+        EmulatorError::HostIo(e)
+    }
+}
+
+/// An `EmulatorError` together with the guest state at the faulting instruction.
+#[derive(Debug)]
+pub struct ExecutionError {
+    pub error: EmulatorError,
+    /// CS selector and EIP of the first byte of the faulting instruction.
+    pub cs: u16,
+    pub eip: u32,
+    /// Instruction bytes consumed before the error was raised.
+    pub code: Vec<u8>,
+    pub registers: [u32; Register32::VARIANT_COUNT],
+    pub eflags: u16,
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at CS:EIP = {:04X}:{:08X}, Code =",
+            self.error, self.cs, self.eip
+        )?;
+        for b in &self.code {
+            write!(f, " {:02X}", b)?;
+        }
+        writeln!(f)?;
+
+        for r in Register32::iter() {
+            writeln!(f, "{:?} = {:>08x}", r, self.registers[r as usize])?;
+        }
+        write!(f, "EFLAGS = {:>04x}", self.eflags)
+    }
+}
+
+impl std::error::Error for ExecutionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
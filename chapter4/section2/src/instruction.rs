@@ -1,381 +1,759 @@
+mod alu;
+mod bios;
+mod interrupt;
 mod io;
 mod modrm;
-mod bios;
-
-use crate::emulator::{Emulator, Register32, Register8};
-use io::{io_in8, io_out8};
+mod muldiv;
+mod prefix;
+mod segment;
+mod shift;
+mod string;
+mod system;
+mod two_byte;
+
+use crate::emulator::{Emulator, OperandSize, Register32, Register8, SegmentRegister};
+use crate::error::EmulatorError;
 use modrm::ModRM;
 
+pub use interrupt::InterruptHook;
+
 impl Emulator {
-    fn add_rm32_r32(&mut self) {
-        self.eip += 1;
-        let modrm = self.parse_modrm();
-        let r32 = self.get_r32(&modrm);
-        let rm32 = self.get_rm32(&modrm);
-        self.set_rm32(&modrm, rm32 + r32);
+    fn inc_r32(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let reg = self.get_code8(0)? - 0x40;
+        let value = self.get_register(reg as i32, size).wrapping_add(1);
+        self.set_register(reg as i32, value, size);
+        self.update_eflags_inc(value, size);
+        self.advance_eip(1);
+
+        Ok(())
     }
 
-    fn cmp_r32_rm32(&mut self) {
-        self.eip += 1;
-        let modrm = self.parse_modrm();
+    fn dec_r32(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let reg = self.get_code8(0)? - 0x48;
+        let value = self.get_register(reg as i32, size).wrapping_sub(1);
+        self.set_register(reg as i32, value, size);
+        self.update_eflags_dec(value, size);
+        self.advance_eip(1);
 
-        let r32 = self.get_r32(&modrm);
-        let rm32 = self.get_rm32(&modrm);
-        let result = (r32 as u64) - (rm32 as u64);
-        self.update_eflags_sub(r32, rm32, result);
+        Ok(())
     }
 
-    fn cmp_al_imm8(&mut self) {
-        let value = self.get_code8(1);
-        let al = self.get_register8(Register8::AL as i32);
-        let result = (al as u64).wrapping_sub(value as u64);
-        self.update_eflags_sub(al as u32, value as u32, result);
-        self.eip += 2;
+    fn mov_rm8_r8(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let r8 = self.get_r8(&modrm);
+        self.set_rm8(&modrm, r8)
     }
 
-    fn cmp_eax_imm32(&mut self) {
-        let value = self.get_code32(1);
-        let eax = self.get_register32(Register32::EAX as i32);
-        let result = (eax as u64).wrapping_sub(value as u64);
-        self.update_eflags_sub(eax, value, result);
-        self.eip += 5;
+    fn mov_rm32_r32(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let r = self.get_r(&modrm, size);
+        self.set_rm(&modrm, r, size)
     }
 
-    fn inc_r32(&mut self) {
-        let reg = self.get_code8(0) - 0x40;
-        self.set_register32(reg as i32, self.get_register32(reg as i32) + 1);
-        self.eip += 1;
-    }
+    fn mov_r8_rm8(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let rm8 = self.get_rm8(&modrm)?;
+        self.set_r8(&modrm, rm8);
 
-    fn sub_rm32_imm8(&mut self, modrm: &ModRM) {
-        let rm32 = self.get_rm32(&modrm);
-        let imm8 = self.get_sign_code8(0) as i32;
-        self.eip += 1;
-        self.set_rm32(modrm, rm32 - imm8 as u32);
+        Ok(())
     }
 
-    fn code_83(&mut self) {
-        self.eip += 1;
-        let mut modrm = self.parse_modrm();
+    fn push_r32(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let reg = self.get_code8(0)? - 0x50;
+        self.push(self.get_register(reg as i32, size), size)?;
+        self.advance_eip(1);
 
-        match unsafe { modrm.opereg.opecode } {
-            0 => {
-                self.add_rm32_imm8(&mut modrm);
-            }
-            5 => {
-                self.sub_rm32_imm8(&modrm);
-            }
-            _ => {
-                unimplemented!("not implemented: 83 {}", unsafe { modrm.opereg.opecode });
-            }
-        };
+        Ok(())
     }
 
-    fn mov_rm8_r8(&mut self) {
-        let reg = self.get_code8(0) - 0x40;
-        self.set_register32(reg as i32, self.get_register32(reg as i32) + 1);
-        self.eip += 1;
-    }
+    fn pop_r32(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let reg = self.get_code8(0)? - 0x58;
+        let value = self.pop(size)?;
+        self.set_register(reg as i32, value, size);
+        self.advance_eip(1);
 
-    fn mov_rm32_r32(&mut self) {
-        self.eip += 1;
-        let modrm = self.parse_modrm();
-        let r32 = self.get_r32(&modrm);
-        self.set_rm32(&modrm, r32);
+        Ok(())
     }
 
-    fn mov_r8_rm8(&mut self) {
-        self.eip += 1;
-        let modrm = self.parse_modrm();
-        let rm8 = self.get_rm8(&modrm);
-        self.set_r8(&modrm, rm8);
+    fn push_imm32(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let value = self.get_code(1, size)?;
+        self.push(value, size)?;
+        self.advance_eip(1 + size.bytes());
+
+        Ok(())
     }
 
-    fn push_r32(&mut self) {
-        let reg = self.get_code8(0) - 0x50;
-        self.push32(self.get_register32(reg as i32));
-        self.eip += 1;
+    fn push_imm8(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let value = self.get_sign_code(1, OperandSize::Byte)?;
+        self.push(value, size)?;
+        self.advance_eip(2);
+
+        Ok(())
     }
 
-    fn pop_r32(&mut self) {
-        let reg = self.get_code8(0) - 0x58;
-        let value = self.pop32();
-        self.set_register32(reg as u32 as i32, value);
-        self.eip += 1;
+    fn jcc_rel8(&mut self) -> Result<(), EmulatorError> {
+        let condition = self.get_code8(0)? & 0x0F;
+        let diff = if self.is_condition(condition) {
+            self.get_sign_code(1, OperandSize::Byte)?
+        } else {
+            0
+        };
+        self.jump_relative(2, diff);
+
+        Ok(())
     }
 
-    fn push_imm32(&mut self) {
-        let value = self.get_code32(1);
-        self.push32(value);
-        self.eip += 5;
+    fn mov_r32_rm32(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let rm = self.get_rm(&modrm, size)?;
+        self.set_r(&modrm, rm, size);
+
+        Ok(())
     }
 
-    fn push_imm8(&mut self) {
-        let value = self.get_code8(1);
-        self.push32(value as u32);
-        self.eip += 2;
+    fn mov_r8_imm8(&mut self) -> Result<(), EmulatorError> {
+        let reg = self.get_code8(0)? - 0xB0;
+        let value = self.get_code8(1)?;
+        self.set_register8(reg as i32, value);
+        self.advance_eip(2);
+
+        Ok(())
     }
 
-    fn jo(&mut self) {
-        let diff = if self.is_overflow() {
-            self.get_sign_code8(1)
-        } else {
-            0
-        };
-        self.eip = (self.eip + 2).wrapping_add(diff as i32 as u32);
+    fn mov_r32_imm32(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let reg = self.get_code8(0)? - 0xB8;
+        let value = self.get_code(1, size)?;
+
+        self.set_register(reg as i32, value, size);
+        self.advance_eip(1 + size.bytes());
+
+        Ok(())
     }
 
-    fn jno(&mut self) {
-        let diff = if self.is_overflow() {
-            0
-        } else {
-            self.get_sign_code8(1)
-        };
-        self.eip = (self.eip + 2).wrapping_add(diff as i32 as u32);
+    fn mov_rm8_imm8(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let value = self.get_code8(0)?;
+        self.advance_eip(1);
+        self.set_rm8(&modrm, value)
     }
 
-    fn jc(&mut self) {
-        let diff = if self.is_carry() {
-            self.get_sign_code8(1)
-        } else {
-            0
-        };
-        self.eip = (self.eip + 2).wrapping_add(diff as i32 as u32);
+    fn mov_rm32_imm32(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let value = self.get_code(0, size)?;
+        self.advance_eip(size.bytes());
+        self.set_rm(&modrm, value, size)
     }
 
-    fn jnc(&mut self) {
-        let diff = if self.is_carry() {
-            0
-        } else {
-            self.get_sign_code8(1)
-        };
-        self.eip = (self.eip + 2).wrapping_add(diff as i32 as u32);
+    /// LEA r16/32, m (8D): stores the offset of the memory operand without accessing it.
+    fn lea(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        if modrm.m == 3 {
+            return Err(EmulatorError::InvalidOpcode {
+                opcode: 0x8D,
+                modrm_reg: None,
+            });
+        }
+        let address = self.calc_memory_address(&modrm)?;
+        self.set_r(&modrm, address, size);
+
+        Ok(())
     }
 
-    fn jz(&mut self) {
-        let diff = if self.is_zero() {
-            self.get_sign_code8(1)
+    /// MOV AL/eAX, moffs (A0, A1) and MOV moffs, AL/eAX (A2, A3): the offset of the
+    /// memory operand follows the opcode directly, sized by the address size.
+    fn mov_moffs(&mut self) -> Result<(), EmulatorError> {
+        let code = self.get_code8(0)?;
+        let size = if code & 1 == 0 {
+            OperandSize::Byte
         } else {
-            0
+            self.operand_size()
         };
-        self.eip = (self.eip + 2).wrapping_add(diff as i32 as u32);
+        let segment = self.prefixes.segment.unwrap_or(SegmentRegister::DS);
+        let address_size = self.address_size();
+        let offset = self.get_code(1, address_size)?;
+        self.advance_eip(1 + address_size.bytes());
+
+        if code & 2 == 0 {
+            let value = self.get_segmented(segment, offset, size)?;
+            self.set_register(Register32::EAX as i32, value, size);
+            Ok(())
+        } else {
+            let value = self.get_register(Register32::EAX as i32, size);
+            self.set_segmented(segment, offset, value, size)
+        }
     }
 
-    fn jnz(&mut self) {
-        let diff = if self.is_zero() {
-            0
-        } else {
-            self.get_sign_code8(1)
-        };
-        self.eip = (self.eip + 2).wrapping_add(diff as i32 as u32);
+    /// Jumps `diff` bytes past the end of an instruction of `length` bytes. With a 16-bit
+    /// operand size the target is truncated to 16 bits.
+    fn jump_relative(&mut self, length: u32, diff: u32) {
+        let eip = self.eip.wrapping_add(length).wrapping_add(diff);
+        self.eip = eip & self.operand_size().mask();
     }
 
-    fn js(&mut self) {
-        let diff = if self.is_sign() {
-            self.get_sign_code8(1)
-        } else {
-            0
-        };
-        self.eip = (self.eip + 2).wrapping_add(diff as i32 as u32);
+    fn near_jump(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let diff = self.get_sign_code(1, size)?;
+        self.jump_relative(1 + size.bytes(), diff);
+
+        Ok(())
     }
 
-    fn jns(&mut self) {
-        let diff = if self.is_zero() {
-            0
-        } else {
-            self.get_sign_code8(1)
-        };
-        self.eip = (self.eip + 2).wrapping_add(diff as i32 as u32);
+    fn short_jump(&mut self) -> Result<(), EmulatorError> {
+        let diff = self.get_sign_code(1, OperandSize::Byte)?;
+        self.jump_relative(2, diff);
+
+        Ok(())
     }
 
-    fn jl(&mut self) {
-        let diff = if self.is_sign() != self.is_overflow() {
-            self.get_sign_code8(1)
-        } else {
-            0
-        };
-        self.eip = (self.eip + 2).wrapping_add(diff as i32 as u32);
+    fn inc_rm(&mut self, modrm: &ModRM, size: OperandSize) -> Result<(), EmulatorError> {
+        let value = self.get_rm(modrm, size)?.wrapping_add(1);
+        self.set_rm(modrm, value, size)?;
+        self.update_eflags_inc(value, size);
+
+        Ok(())
     }
 
-    fn jle(&mut self) {
-        let diff = if self.is_zero() || (self.is_sign() != self.is_overflow()) {
-            self.get_sign_code8(1)
-        } else {
-            0
-        };
-        self.eip = (self.eip + 2).wrapping_add(diff as i32 as u32);
+    fn dec_rm(&mut self, modrm: &ModRM, size: OperandSize) -> Result<(), EmulatorError> {
+        let value = self.get_rm(modrm, size)?.wrapping_sub(1);
+        self.set_rm(modrm, value, size)?;
+        self.update_eflags_dec(value, size);
+
+        Ok(())
     }
 
-    fn swi(&mut self) {
-        let int_index = self.get_code8(1);
-        self.eip += 2;
+    fn code_fe(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+
+        match modrm.reg {
+            0 => self.inc_rm(&modrm, OperandSize::Byte),
+            1 => self.dec_rm(&modrm, OperandSize::Byte),
+            reg => Err(EmulatorError::InvalidOpcode {
+                opcode: 0xFE,
+                modrm_reg: Some(reg),
+            }),
+        }
+    }
 
-        match int_index {
-            0x10 => self.bios_video(),
-            _ => println!("unknown interrupt: {:02x}", int_index),
+    fn code_ff(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+
+        let size = self.operand_size();
+        let reg = modrm.reg;
+        match reg {
+            0 => self.inc_rm(&modrm, size),
+            1 => self.dec_rm(&modrm, size),
+            2 => {
+                let target = self.get_rm(&modrm, size)?;
+                self.push(self.eip, size)?;
+                self.eip = target;
+                Ok(())
+            }
+            4 => {
+                self.eip = self.get_rm(&modrm, size)?;
+                Ok(())
+            }
+            3 | 5 if modrm.m != 3 => {
+                let address = self.calc_memory_address(&modrm)?;
+                let offset = self.get_segmented(modrm.segment, address, size)?;
+                let selector = address.wrapping_add(size.bytes());
+                let selector = self.get_segmented(modrm.segment, selector, OperandSize::Word)?;
+                if reg == 3 {
+                    self.call_far(selector as u16, offset)
+                } else {
+                    self.jump_far(selector as u16, offset)
+                }
+            }
+            6 => {
+                let value = self.get_rm(&modrm, size)?;
+                self.push(value, size)
+            }
+            reg => Err(EmulatorError::InvalidOpcode {
+                opcode: 0xFF,
+                modrm_reg: Some(reg),
+            }),
         }
     }
 
-    fn add_rm32_imm8(&mut self, modrm: &mut ModRM) {
-        let rm32 = self.get_rm32(&modrm);
-        let imm8 = self.get_sign_code8(0) as i32;
-        self.eip += 1;
-        self.set_rm32(&modrm, rm32 + imm8 as u32);
+    fn nop(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+
+        Ok(())
     }
 
-    fn mov_r32_rm32(&mut self) {
-        self.eip += 1;
-        let modrm = self.parse_modrm();
-        let rm32 = self.get_rm32(&modrm);
-        self.set_r32(&modrm, rm32);
+    fn hlt(&mut self) -> Result<(), EmulatorError> {
+        self.halted = true;
+        self.advance_eip(1);
+
+        Ok(())
     }
 
-    fn mov_r8_imm8(&mut self) {
-        let reg = self.get_code8(0) - 0xB0;
-        self.set_register8(reg as i32, self.get_code8(1));
-        self.eip += 2;
+    fn call_ref32(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let diff = self.get_sign_code(1, size)?;
+        self.push(self.eip.wrapping_add(1 + size.bytes()), size)?;
+        self.jump_relative(1 + size.bytes(), diff);
+
+        Ok(())
     }
 
-    fn mov_r32_imm32(&mut self) {
-        let reg = self.get_code8(0) - 0xB8;
-        let value = self.get_code32(1);
+    /// RET (C3) and RET imm16 (C2).
+    fn ret(&mut self) -> Result<(), EmulatorError> {
+        let release = if self.get_code8(0)? == 0xC2 {
+            self.get_code(1, OperandSize::Word)?
+        } else {
+            0
+        };
+        self.eip = self.pop(self.operand_size())?;
+        self.release_stack(release);
 
-        self.registers[reg as usize] = value;
-        self.eip += 5;
+        Ok(())
     }
 
-    fn mov_rm32_imm32(&mut self) {
-        self.eip += 1;
-        let modrm = self.parse_modrm();
-        let value = self.get_code32(0);
-        self.eip += 4;
-        self.set_rm32(&modrm, value);
+    fn leave(&mut self) -> Result<(), EmulatorError> {
+        let stack_size = self.stack_size();
+        let ebp = self.get_register(Register32::EBP as i32, stack_size);
+        self.set_register(Register32::ESP as i32, ebp, stack_size);
+
+        let size = self.operand_size();
+        let value = self.pop(size)?;
+        self.set_register(Register32::EBP as i32, value, size);
+        self.advance_eip(1);
+
+        Ok(())
     }
 
-    fn near_jump(&mut self) {
-        let diff = self.get_sign_code32(1) as u32;
-        self.eip = self.eip.wrapping_add(diff).wrapping_add(5);
+    /// XCHG r/m8, r8 (86) and XCHG r/m16/32, r16/32 (87).
+    fn xchg_rm_r(&mut self) -> Result<(), EmulatorError> {
+        let size = if self.get_code8(0)? == 0x86 {
+            OperandSize::Byte
+        } else {
+            self.operand_size()
+        };
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let r = self.get_r(&modrm, size);
+        let rm = self.get_rm(&modrm, size)?;
+        self.set_rm(&modrm, r, size)?;
+        self.set_r(&modrm, rm, size);
+
+        Ok(())
+    }
+
+    /// XCHG eAX, r16/32 (91-97).
+    fn xchg_eax_r32(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let reg = (self.get_code8(0)? - 0x90) as i32;
+        let eax = self.get_register(Register32::EAX as i32, size);
+        let value = self.get_register(reg, size);
+        self.set_register(Register32::EAX as i32, value, size);
+        self.set_register(reg, eax, size);
+        self.advance_eip(1);
+
+        Ok(())
+    }
+
+    /// POP r/m16/32 (8F /0). The address of the destination is computed with the
+    /// stack pointer after the pop, and the pop is undone if the write faults.
+    fn pop_rm32(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        if modrm.reg != 0 {
+            return Err(EmulatorError::InvalidOpcode {
+                opcode: 0x8F,
+                modrm_reg: Some(modrm.reg),
+            });
+        }
+
+        let esp = self.get_register32(Register32::ESP as i32);
+        let value = self.pop(size)?;
+        let result = self.set_rm(&modrm, value, size);
+        if result.is_err() {
+            self.set_register32(Register32::ESP as i32, esp);
+        }
+        result
     }
 
-    fn short_jump(&mut self) {
-        let diff = self.get_sign_code8(1) as u32;
-        self.eip = self.eip.wrapping_add(diff).wrapping_add(2);
+    /// PUSHA (60): pushes eAX, eCX, eDX, eBX, the original eSP, eBP, eSI and eDI.
+    fn pusha(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let values: Vec<u32> = (0..8).map(|reg| self.get_register(reg, size)).collect();
+        for value in values {
+            self.push(value, size)?;
+        }
+        self.advance_eip(1);
+
+        Ok(())
     }
 
-    fn in_al_dx(&mut self) {
-        let address = (self.get_register32(Register32::EDX as i32) & 0xffff) as u16;
-        let value = io_in8(address);
-        self.set_register8(Register8::AL as i32, value);
-        self.eip += 1;
+    /// POPA (61): pops what PUSHA pushed, discarding the saved eSP.
+    fn popa(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        for reg in (0..8).rev() {
+            if reg == Register32::ESP as i32 {
+                self.release_stack(size.bytes());
+            } else {
+                let value = self.pop(size)?;
+                self.set_register(reg, value, size);
+            }
+        }
+        self.advance_eip(1);
+
+        Ok(())
     }
 
-    fn out_dx_al(&mut self) {
-        let address = (self.get_register32(Register32::EDX as i32) & 0xffff) as u16;
-        let value = self.get_register8(Register8::AL as i32);
-        io_out8(address, value);
-        self.eip += 1;
+    /// CBW/CWDE (98): sign-extends AL into AX, or AX into EAX.
+    fn cbw(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let half = if size == OperandSize::Word {
+            OperandSize::Byte
+        } else {
+            OperandSize::Word
+        };
+        let value = self.get_register(Register32::EAX as i32, half);
+        let value = Emulator::sign_extend(value, half) as u32;
+        self.set_register(Register32::EAX as i32, value, size);
+        self.advance_eip(1);
+
+        Ok(())
     }
 
-    fn inc_rm32(&mut self, modrm: &mut ModRM) {
-        let value = self.get_rm32(&modrm);
-        self.set_rm32(&modrm, value + 1);
+    /// CWD/CDQ (99): fills DX or EDX with the sign bit of AX or EAX.
+    fn cwd(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let value = self.get_register(Register32::EAX as i32, size);
+        let fill = if value & size.sign_bit() != 0 {
+            u32::MAX
+        } else {
+            0
+        };
+        self.set_register(Register32::EDX as i32, fill, size);
+        self.advance_eip(1);
+
+        Ok(())
     }
 
-    fn code_ff(&mut self) {
-        self.eip += 1;
-        let mut modrm = self.parse_modrm();
+    /// SAHF (9E): loads SF, ZF, AF, PF and CF from AH.
+    fn sahf(&mut self) -> Result<(), EmulatorError> {
+        let ah = self.get_register8(Register8::AH as i32) as u16;
+        self.eflags = (self.eflags & !0xD5) | (ah & 0xD5);
+        self.advance_eip(1);
 
-        match unsafe { modrm.opereg.opecode } {
-            0 => {
-                self.inc_rm32(&mut modrm);
-            }
-            _ => {
-                unimplemented!("not implemented: FF {}", unsafe { modrm.opereg.opecode });
-            }
-        };
+        Ok(())
     }
 
-    fn call_ref32(&mut self) {
-        let diff = self.get_sign_code32(1);
-        self.push32(self.eip + 5);
-        self.eip = self.eip.wrapping_add(diff as u32).wrapping_add(5);
+    /// LAHF (9F): stores the low byte of FLAGS in AH. Bit 1 always reads as 1.
+    fn lahf(&mut self) -> Result<(), EmulatorError> {
+        self.set_register8(Register8::AH as i32, self.eflags as u8 | 0x02);
+        self.advance_eip(1);
+
+        Ok(())
     }
 
-    fn ret(&mut self) {
-        self.eip = self.pop32();
+    /// LOOPNE (E0), LOOPE (E1), LOOP (E2) and JCXZ/JECXZ (E3). The counter is CX or
+    /// ECX according to the address size.
+    fn loop_rel8(&mut self) -> Result<(), EmulatorError> {
+        let code = self.get_code8(0)?;
+        let size = self.address_size();
+        let mut count = self.get_register(Register32::ECX as i32, size);
+        if code != 0xE3 {
+            count = count.wrapping_sub(1) & size.mask();
+            self.set_register(Register32::ECX as i32, count, size);
+        }
+
+        let taken = match code {
+            0xE0 => count != 0 && !self.is_zero(),
+            0xE1 => count != 0 && self.is_zero(),
+            0xE2 => count != 0,
+            _ => count == 0,
+        };
+        let diff = if taken {
+            self.get_sign_code(1, OperandSize::Byte)?
+        } else {
+            0
+        };
+        self.jump_relative(2, diff);
+
+        Ok(())
     }
 
-    fn leave(&mut self) {
-        let ebp = self.get_register32(Register32::EBP as i32);
-        self.set_register32(Register32::ESP as i32, ebp);
+    /// CMC (F5), CLC (F8) and STC (F9).
+    fn carry_flag(&mut self) -> Result<(), EmulatorError> {
+        let carry = match self.get_code8(0)? {
+            0xF5 => !self.is_carry(),
+            0xF8 => false,
+            _ => true,
+        };
+        self.set_carry(carry);
+        self.advance_eip(1);
 
-        let value = self.pop32();
-        self.set_register32(Register32::EBP as i32, value);
-        self.eip += 1;
+        Ok(())
     }
 }
 
-pub type InstructionFunctions = [Option<fn(&mut Emulator)>; 256];
+pub type InstructionFunctions = [Option<fn(&mut Emulator) -> Result<(), EmulatorError>>; 256];
 
 pub trait New {
-    fn new() -> InstructionFunctions;
+    /// Builds the table for one-byte opcodes.
+    fn new() -> Self;
+    /// Builds the table for opcodes following the 0x0F escape byte. `Emulator::step`
+    /// looks them up itself, so the escape byte has no entry in the one-byte table.
+    fn new_two_byte() -> Self;
 }
 
 impl New for InstructionFunctions {
-    fn new() -> InstructionFunctions {
+    fn new() -> Self {
         let mut functions: InstructionFunctions = [None; 256];
 
         for f in functions.iter_mut() {
             *f = None;
         }
 
-        functions[0x01] = Some(Emulator::add_rm32_r32);
-        functions[0x3B] = Some(Emulator::cmp_r32_rm32);
-        functions[0x3C] = Some(Emulator::cmp_al_imm8);
-        functions[0x3D] = Some(Emulator::cmp_eax_imm32);
+        for i in 0..8 {
+            functions[i << 3] = Some(Emulator::alu_rm8_r8);
+            functions[(i << 3) + 1] = Some(Emulator::alu_rm32_r32);
+            functions[(i << 3) + 2] = Some(Emulator::alu_r8_rm8);
+            functions[(i << 3) + 3] = Some(Emulator::alu_r32_rm32);
+            functions[(i << 3) + 4] = Some(Emulator::alu_al_imm8);
+            functions[(i << 3) + 5] = Some(Emulator::alu_eax_imm32);
+        }
+        functions[0x06] = Some(Emulator::push_sreg);
+        functions[0x07] = Some(Emulator::pop_sreg);
+        functions[0x0E] = Some(Emulator::push_sreg);
+        functions[0x16] = Some(Emulator::push_sreg);
+        functions[0x17] = Some(Emulator::pop_sreg);
+        functions[0x1E] = Some(Emulator::push_sreg);
+        functions[0x1F] = Some(Emulator::pop_sreg);
         for i in 0..8 {
             functions[0x40 + i] = Some(Emulator::inc_r32);
         }
+        for i in 0..8 {
+            functions[0x48 + i] = Some(Emulator::dec_r32);
+        }
         for i in 0..8 {
             functions[0x50 + i] = Some(Emulator::push_r32);
         }
         for i in 0..8 {
             functions[0x58 + i] = Some(Emulator::pop_r32);
         }
+        functions[0x60] = Some(Emulator::pusha);
+        functions[0x61] = Some(Emulator::popa);
         functions[0x68] = Some(Emulator::push_imm32);
+        functions[0x69] = Some(Emulator::imul_r32_rm32_imm32);
         functions[0x6A] = Some(Emulator::push_imm8);
-        functions[0x70] = Some(Emulator::jo);
-        functions[0x71] = Some(Emulator::jno);
-        functions[0x72] = Some(Emulator::jc);
-        functions[0x73] = Some(Emulator::jnc);
-        functions[0x74] = Some(Emulator::jz);
-        functions[0x75] = Some(Emulator::jnz);
-        functions[0x78] = Some(Emulator::js);
-        functions[0x79] = Some(Emulator::jns);
-        functions[0x7C] = Some(Emulator::jl);
-        functions[0x7E] = Some(Emulator::jle);
+        functions[0x6B] = Some(Emulator::imul_r32_rm32_imm8);
+        for i in 0..4 {
+            functions[0x6C + i] = Some(Emulator::string);
+        }
+        for i in 0..16 {
+            functions[0x70 + i] = Some(Emulator::jcc_rel8);
+        }
+        functions[0x80] = Some(Emulator::code_80);
+        functions[0x81] = Some(Emulator::code_81);
+        functions[0x82] = Some(Emulator::code_80);
         functions[0x83] = Some(Emulator::code_83);
+        functions[0x84] = Some(Emulator::test_rm8_r8);
+        functions[0x85] = Some(Emulator::test_rm32_r32);
+        functions[0x86] = Some(Emulator::xchg_rm_r);
+        functions[0x87] = Some(Emulator::xchg_rm_r);
         functions[0x88] = Some(Emulator::mov_rm8_r8);
         functions[0x89] = Some(Emulator::mov_rm32_r32);
         functions[0x8A] = Some(Emulator::mov_r8_rm8);
         functions[0x8B] = Some(Emulator::mov_r32_rm32);
+        functions[0x8C] = Some(Emulator::mov_rm16_sreg);
+        functions[0x8D] = Some(Emulator::lea);
+        functions[0x8E] = Some(Emulator::mov_sreg_rm16);
+        functions[0x8F] = Some(Emulator::pop_rm32);
+        functions[0x90] = Some(Emulator::nop);
+        for i in 1..8 {
+            functions[0x90 + i] = Some(Emulator::xchg_eax_r32);
+        }
+        functions[0x98] = Some(Emulator::cbw);
+        functions[0x99] = Some(Emulator::cwd);
+        functions[0x9A] = Some(Emulator::far_call);
+        functions[0x9C] = Some(Emulator::pushf);
+        functions[0x9D] = Some(Emulator::popf);
+        functions[0x9E] = Some(Emulator::sahf);
+        functions[0x9F] = Some(Emulator::lahf);
+        for i in 0..4 {
+            functions[0xA0 + i] = Some(Emulator::mov_moffs);
+        }
+        for i in 0..4 {
+            functions[0xA4 + i] = Some(Emulator::string);
+        }
+        functions[0xA8] = Some(Emulator::test_al_imm8);
+        functions[0xA9] = Some(Emulator::test_eax_imm32);
+        for i in 0..6 {
+            functions[0xAA + i] = Some(Emulator::string);
+        }
         for i in 0..8 {
             functions[0xB0 + i] = Some(Emulator::mov_r8_imm8);
         }
         for i in 0..8 {
             functions[0xB8 + i] = Some(Emulator::mov_r32_imm32);
         }
+        functions[0xC0] = Some(Emulator::code_c0);
+        functions[0xC1] = Some(Emulator::code_c1);
+        functions[0xC2] = Some(Emulator::ret);
         functions[0xC3] = Some(Emulator::ret);
+        functions[0xC4] = Some(Emulator::load_far_pointer);
+        functions[0xC5] = Some(Emulator::load_far_pointer);
+        functions[0xC6] = Some(Emulator::mov_rm8_imm8);
         functions[0xC7] = Some(Emulator::mov_rm32_imm32);
         functions[0xC9] = Some(Emulator::leave);
-        functions[0xCD] = Some(Emulator::swi);
+        functions[0xCA] = Some(Emulator::far_return);
+        functions[0xCB] = Some(Emulator::far_return);
+        functions[0xCC] = Some(Emulator::int3);
+        functions[0xCD] = Some(Emulator::int_imm8);
+        functions[0xCE] = Some(Emulator::into);
+        functions[0xCF] = Some(Emulator::iret);
+        functions[0xD0] = Some(Emulator::code_d0);
+        functions[0xD1] = Some(Emulator::code_d1);
+        functions[0xD2] = Some(Emulator::code_d2);
+        functions[0xD3] = Some(Emulator::code_d3);
+        for i in 0..4 {
+            functions[0xE0 + i] = Some(Emulator::loop_rel8);
+        }
+        functions[0xE4] = Some(Emulator::port_in);
+        functions[0xE5] = Some(Emulator::port_in);
+        functions[0xE6] = Some(Emulator::port_out);
+        functions[0xE7] = Some(Emulator::port_out);
         functions[0xE8] = Some(Emulator::call_ref32);
         functions[0xE9] = Some(Emulator::near_jump);
+        functions[0xEA] = Some(Emulator::far_jump);
         functions[0xEB] = Some(Emulator::short_jump);
-        functions[0xEC] = Some(Emulator::in_al_dx);
-        functions[0xEE] = Some(Emulator::out_dx_al);
+        functions[0xEC] = Some(Emulator::port_in);
+        functions[0xED] = Some(Emulator::port_in);
+        functions[0xEE] = Some(Emulator::port_out);
+        functions[0xEF] = Some(Emulator::port_out);
+        functions[0xF4] = Some(Emulator::hlt);
+        functions[0xF5] = Some(Emulator::carry_flag);
+        functions[0xF6] = Some(Emulator::code_f6);
+        functions[0xF7] = Some(Emulator::code_f7);
+        functions[0xF8] = Some(Emulator::carry_flag);
+        functions[0xF9] = Some(Emulator::carry_flag);
+        functions[0xFA] = Some(Emulator::cli);
+        functions[0xFB] = Some(Emulator::sti);
+        functions[0xFC] = Some(Emulator::cld);
+        functions[0xFD] = Some(Emulator::std);
+        functions[0xFE] = Some(Emulator::code_fe);
         functions[0xFF] = Some(Emulator::code_ff);
 
         functions
     }
+
+    fn new_two_byte() -> Self {
+        let mut functions: InstructionFunctions = [None; 256];
+
+        functions[0x01] = Some(Emulator::code_0f_01);
+        functions[0x20] = Some(Emulator::mov_r32_cr);
+        functions[0x22] = Some(Emulator::mov_cr_r32);
+        for i in 0..16 {
+            functions[0x40 + i] = Some(Emulator::cmovcc_r32_rm32);
+        }
+        for i in 0..16 {
+            functions[0x80 + i] = Some(Emulator::jcc_rel32);
+        }
+        for i in 0..16 {
+            functions[0x90 + i] = Some(Emulator::setcc_rm8);
+        }
+        functions[0xA0] = Some(Emulator::push_sreg);
+        functions[0xA1] = Some(Emulator::pop_sreg);
+        functions[0xA3] = Some(Emulator::bt_rm32_r32);
+        functions[0xA4] = Some(Emulator::shld_rm32_r32_imm8);
+        functions[0xA5] = Some(Emulator::shld_rm32_r32_cl);
+        functions[0xA8] = Some(Emulator::push_sreg);
+        functions[0xA9] = Some(Emulator::pop_sreg);
+        functions[0xAB] = Some(Emulator::bt_rm32_r32);
+        functions[0xAC] = Some(Emulator::shrd_rm32_r32_imm8);
+        functions[0xAD] = Some(Emulator::shrd_rm32_r32_cl);
+        functions[0xAF] = Some(Emulator::imul_r32_rm32);
+        functions[0xB3] = Some(Emulator::bt_rm32_r32);
+        functions[0xB6] = Some(Emulator::movzx_r32_rm8);
+        functions[0xB7] = Some(Emulator::movzx_r32_rm16);
+        functions[0xBA] = Some(Emulator::code_0f_ba);
+        functions[0xBB] = Some(Emulator::bt_rm32_r32);
+        functions[0xBC] = Some(Emulator::bsf_r32_rm32);
+        functions[0xBD] = Some(Emulator::bsr_r32_rm32);
+        functions[0xBE] = Some(Emulator::movsx_r32_rm8);
+        functions[0xBF] = Some(Emulator::movsx_r32_rm16);
+        for i in 0..8 {
+            functions[0xC8 + i] = Some(Emulator::bswap_r32);
+        }
+
+        functions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::{Emulator, Register16, SegmentRegister, StopReason};
+
+    #[test]
+    fn boot_sector_opcodes() {
+        let code = [
+            0xB9, 0x03, 0x00, // mov cx, 3
+            0x31, 0xC0, // xor ax, ax
+            0x40, // inc ax
+            0xE2, 0xFD, // loop -3
+            0xBB, 0x34, 0x12, // mov bx, 0x1234
+            0x93, // xchg ax, bx
+            0x86, 0xDF, // xchg bh, bl
+            0xB0, 0x80, // mov al, 0x80
+            0x98, // cbw
+            0x99, // cwd
+            0x60, // pusha
+            0x31, 0xFF, // xor di, di
+            0x61, // popa
+            0xF9, // stc
+            0x9F, // lahf
+            0xF8, // clc
+            0xF5, // cmc
+            0xC7, 0x06, 0x00, 0x06, 0x78, 0x56, // mov word [0x600], 0x5678
+            0xC7, 0x06, 0x02, 0x06, 0x00, 0x01, // mov word [0x602], 0x0100
+            0xC4, 0x36, 0x00, 0x06, // les si, [0x600]
+            0x68, 0x21, 0x43, // push 0x4321
+            0x8F, 0x06, 0x04, 0x06, // pop word [0x604]
+            0xE3, 0x01, // jcxz +1
+            0xF4, // hlt
+            0xF4, // hlt
+        ];
+        let mut emu = Emulator::new_real_mode(0x10000, 0, 0x7C00);
+        emu.memory[0x7C00..0x7C00 + code.len()].copy_from_slice(&code);
+        emu.set_register16(Register16::DI as i32, 0xABCD);
+
+        assert_eq!(emu.run().unwrap(), StopReason::Halted);
+        assert_eq!(emu.eip, 0x7C00 + code.len() as u32);
+        assert_eq!(emu.get_register16(Register16::CX as i32), 0);
+        assert_eq!(emu.get_register16(Register16::BX as i32), 0x0300);
+        // CBW gave FF80, then LAHF stored ZF and PF from XOR and CF from STC.
+        assert_eq!(emu.get_register16(Register16::AX as i32), 0x4780);
+        assert_eq!(emu.get_register16(Register16::DX as i32), 0xFFFF);
+        assert!(emu.is_carry());
+        assert_eq!(emu.get_register16(Register16::DI as i32), 0xABCD);
+        assert_eq!(emu.get_register16(Register16::SP as i32), 0);
+        assert_eq!(emu.get_register16(Register16::SI as i32), 0x5678);
+        assert_eq!(emu.segments[SegmentRegister::ES as usize].selector, 0x0100);
+        assert_eq!(emu.memory[0x604..0x606], [0x21, 0x43]);
+    }
 }
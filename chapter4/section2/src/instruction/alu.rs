@@ -0,0 +1,211 @@
+use crate::emulator::{Emulator, OperandSize, Register32};
+use crate::error::EmulatorError;
+
+// ALU operations, numbered as in bits 3-5 of opcodes 00-3F and the reg field of 80-83.
+pub const ADD: u8 = 0;
+pub const OR: u8 = 1;
+pub const ADC: u8 = 2;
+pub const SBB: u8 = 3;
+pub const AND: u8 = 4;
+pub const SUB: u8 = 5;
+pub const XOR: u8 = 6;
+pub const CMP: u8 = 7;
+
+impl Emulator {
+    /// Computes `v1 <operation> v2` truncated to `size` and updates the flags.
+    pub fn alu(&mut self, operation: u8, v1: u32, v2: u32, size: OperandSize) -> u32 {
+        let (v1, v2) = (v1 & size.mask(), v2 & size.mask());
+        let carry = self.is_carry() as u64;
+
+        let result = match operation {
+            ADD | ADC => {
+                let carry = if operation == ADC { carry } else { 0 };
+                let result = v1 as u64 + v2 as u64 + carry;
+                self.update_eflags_add(v1, v2, result, size);
+                result as u32
+            }
+            SUB | SBB | CMP => {
+                let borrow = if operation == SBB { carry } else { 0 };
+                let result = (v1 as u64).wrapping_sub(v2 as u64).wrapping_sub(borrow);
+                self.update_eflags_sub(v1, v2, result, size);
+                result as u32
+            }
+            OR | AND | XOR => {
+                let result = match operation {
+                    OR => v1 | v2,
+                    AND => v1 & v2,
+                    _ => v1 ^ v2,
+                };
+                self.update_eflags_logic(result, size);
+                result
+            }
+            _ => unreachable!("ALU operation is a 3-bit field: {}", operation),
+        };
+
+        result & size.mask()
+    }
+
+    fn alu_rm_r(&mut self, size: OperandSize) -> Result<(), EmulatorError> {
+        let operation = (self.get_code8(0)? >> 3) & 0x07;
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let r = self.get_r(&modrm, size);
+        let rm = self.get_rm(&modrm, size)?;
+        let result = self.alu(operation, rm, r, size);
+
+        if operation != CMP {
+            self.set_rm(&modrm, result, size)?;
+        }
+
+        Ok(())
+    }
+
+    fn alu_r_rm(&mut self, size: OperandSize) -> Result<(), EmulatorError> {
+        let operation = (self.get_code8(0)? >> 3) & 0x07;
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let r = self.get_r(&modrm, size);
+        let rm = self.get_rm(&modrm, size)?;
+        let result = self.alu(operation, r, rm, size);
+
+        if operation != CMP {
+            self.set_r(&modrm, result, size);
+        }
+
+        Ok(())
+    }
+
+    fn alu_accumulator_imm(&mut self, size: OperandSize) -> Result<(), EmulatorError> {
+        let operation = (self.get_code8(0)? >> 3) & 0x07;
+        let value = self.get_code(1, size)?;
+        let accumulator = self.get_register(Register32::EAX as i32, size);
+        let result = self.alu(operation, accumulator, value, size);
+
+        if operation != CMP {
+            self.set_register(Register32::EAX as i32, result, size);
+        }
+
+        self.advance_eip(1 + size.bytes());
+        Ok(())
+    }
+
+    pub fn alu_rm8_r8(&mut self) -> Result<(), EmulatorError> {
+        self.alu_rm_r(OperandSize::Byte)
+    }
+
+    pub fn alu_rm32_r32(&mut self) -> Result<(), EmulatorError> {
+        self.alu_rm_r(self.operand_size())
+    }
+
+    pub fn alu_r8_rm8(&mut self) -> Result<(), EmulatorError> {
+        self.alu_r_rm(OperandSize::Byte)
+    }
+
+    pub fn alu_r32_rm32(&mut self) -> Result<(), EmulatorError> {
+        self.alu_r_rm(self.operand_size())
+    }
+
+    pub fn alu_al_imm8(&mut self) -> Result<(), EmulatorError> {
+        self.alu_accumulator_imm(OperandSize::Byte)
+    }
+
+    pub fn alu_eax_imm32(&mut self) -> Result<(), EmulatorError> {
+        self.alu_accumulator_imm(self.operand_size())
+    }
+
+    fn alu_rm_imm(&mut self, size: OperandSize, sign_extend: bool) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let operation = modrm.reg;
+        let rm = self.get_rm(&modrm, size)?;
+
+        let imm = if sign_extend {
+            self.get_sign_code8(0)? as i32 as u32
+        } else {
+            self.get_code(0, size)?
+        };
+        self.advance_eip(if sign_extend { 1 } else { size.bytes() });
+
+        let result = self.alu(operation, rm, imm, size);
+
+        if operation != CMP {
+            self.set_rm(&modrm, result, size)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn code_80(&mut self) -> Result<(), EmulatorError> {
+        self.alu_rm_imm(OperandSize::Byte, false)
+    }
+
+    pub fn code_81(&mut self) -> Result<(), EmulatorError> {
+        self.alu_rm_imm(self.operand_size(), false)
+    }
+
+    pub fn code_83(&mut self) -> Result<(), EmulatorError> {
+        self.alu_rm_imm(self.operand_size(), true)
+    }
+
+    fn test_rm_r(&mut self, size: OperandSize) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let r = self.get_r(&modrm, size);
+        let rm = self.get_rm(&modrm, size)?;
+        self.update_eflags_logic(rm & r, size);
+
+        Ok(())
+    }
+
+    fn test_accumulator_imm(&mut self, size: OperandSize) -> Result<(), EmulatorError> {
+        let value = self.get_code(1, size)?;
+        let accumulator = self.get_register(Register32::EAX as i32, size);
+        self.update_eflags_logic(accumulator & value, size);
+        self.advance_eip(1 + size.bytes());
+
+        Ok(())
+    }
+
+    pub fn test_rm8_r8(&mut self) -> Result<(), EmulatorError> {
+        self.test_rm_r(OperandSize::Byte)
+    }
+
+    pub fn test_rm32_r32(&mut self) -> Result<(), EmulatorError> {
+        self.test_rm_r(self.operand_size())
+    }
+
+    pub fn test_al_imm8(&mut self) -> Result<(), EmulatorError> {
+        self.test_accumulator_imm(OperandSize::Byte)
+    }
+
+    pub fn test_eax_imm32(&mut self) -> Result<(), EmulatorError> {
+        self.test_accumulator_imm(self.operand_size())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::{Emulator, Register32, StopReason};
+
+    const ENTRY: u32 = 0x7C00;
+
+    #[test]
+    fn immediates_carry_chains_and_compare() {
+        // mov eax, 0xFFFFFFFF; mov edx, 1; add eax, 1; adc edx, 0; cmp eax, 0;
+        // xor ebx, ebx; sub ebx, -1
+        let code = [
+            0xB8, 0xFF, 0xFF, 0xFF, 0xFF, 0xBA, 0x01, 0x00, 0x00, 0x00, 0x83, 0xC0, 0x01, 0x83,
+            0xD2, 0x00, 0x83, 0xF8, 0x00, 0x31, 0xDB, 0x83, 0xEB, 0xFF, 0xF4,
+        ];
+        let mut emu = Emulator::new(0x10000, ENTRY, ENTRY);
+        let start = ENTRY as usize;
+        emu.memory[start..start + code.len()].copy_from_slice(&code);
+        assert_eq!(emu.run().unwrap(), StopReason::Halted);
+
+        assert_eq!(emu.get_register32(Register32::EAX as i32), 0);
+        assert_eq!(emu.get_register32(Register32::EDX as i32), 2);
+        // The imm8 of 83 is sign-extended, so 0 - 0xFFFFFFFF borrows.
+        assert_eq!(emu.get_register32(Register32::EBX as i32), 1);
+        assert!(emu.is_carry() && !emu.is_zero());
+    }
+}
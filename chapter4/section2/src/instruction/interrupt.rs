@@ -0,0 +1,367 @@
+use crate::emulator::{Emulator, OperandSize, Register32, SegmentRegister};
+use crate::error::EmulatorError;
+use crate::paging::Access;
+
+/// Segment of the BIOS stubs. The IVT starts out pointing vector n at F000:n, where a
+/// single IRET byte in the BIOS ROM stands in for the BIOS handler.
+pub(crate) const BIOS_SEGMENT: u16 = 0xF000;
+
+/// Native handler run in place of a BIOS interrupt service.
+pub type InterruptHook = fn(&mut Emulator) -> Result<(), EmulatorError>;
+
+const DOUBLE_FAULT: u8 = 8;
+const PAGE_FAULT: u8 = 14;
+
+/// #DE, #TS, #NP, #SS and #GP.
+fn is_contributory(vector: u8) -> bool {
+    matches!(vector, 0 | 10..=13)
+}
+
+impl Emulator {
+    /// Maps the 64 KiB BIOS ROM holding the stubs and points every IVT entry at its
+    /// stub. The IVT is left alone if the RAM does not hold it.
+    pub(crate) fn install_bios_stubs(&mut self) {
+        let mut rom = vec![0; 0x10000];
+        rom[..256].fill(0xCF);
+        self.memory_map.map_rom((BIOS_SEGMENT as u32) << 4, rom);
+
+        if self.memory.len() < 256 * 4 {
+            return;
+        }
+        for vector in 0..256 {
+            let entry = ((BIOS_SEGMENT as u32) << 16) | vector as u32;
+            self.memory[vector * 4..vector * 4 + 4].copy_from_slice(&entry.to_le_bytes());
+        }
+    }
+
+    /// Serves real-mode interrupt `vector` with `hook` while its IVT entry still points
+    /// at the BIOS stub, or removes the hook with `None`.
+    pub fn hook_interrupt(&mut self, vector: u8, hook: Option<InterruptHook>) {
+        self.interrupt_hooks[vector as usize] = hook;
+    }
+
+    /// Raises exception `vector` with EIP holding the address to return to.
+    ///
+    /// In protected mode a fault while delivering it raises #DF if both are
+    /// contributory or the first is #PF, and is delivered instead otherwise. A fault
+    /// while delivering #DF is a triple fault and is returned, as is any fault while
+    /// delivering in real mode.
+    pub fn exception(&mut self, vector: u8, error_code: Option<u32>) -> Result<(), EmulatorError> {
+        let error = match self.interrupt(vector, error_code, false) {
+            Ok(()) => return Ok(()),
+            Err(error) if self.is_protected_mode() => error,
+            Err(error) => return Err(error),
+        };
+
+        let (second, second_code) = match error.exception() {
+            Some(exception) if vector != DOUBLE_FAULT => exception,
+            _ => return Err(error),
+        };
+
+        let double = (is_contributory(vector) || vector == PAGE_FAULT)
+            && (is_contributory(second) || (vector == PAGE_FAULT && second == PAGE_FAULT));
+        if double {
+            self.exception(DOUBLE_FAULT, Some(0))
+        } else {
+            self.exception(second, second_code)
+        }
+    }
+
+    /// Transfers control to the handler of `vector` through the IVT in real mode or
+    /// the IDT in protected mode. `software` marks INT n, INT3 and INTO, which are
+    /// subject to the DPL of the gate.
+    pub fn interrupt(
+        &mut self,
+        vector: u8,
+        error_code: Option<u32>,
+        software: bool,
+    ) -> Result<(), EmulatorError> {
+        if self.is_protected_mode() {
+            self.interrupt_protected(vector, error_code, software)
+        } else {
+            self.interrupt_real(vector)
+        }
+    }
+
+    /// Pushes the words of an interrupt frame in order. If one of them faults, ESP is
+    /// put back so the fault is raised on the stack the interrupt found.
+    fn push_frame(&mut self, values: &[u32], size: OperandSize) -> Result<(), EmulatorError> {
+        let esp = self.get_register32(Register32::ESP as i32);
+        for &value in values {
+            if let Err(error) = self.push(value, size) {
+                self.set_register32(Register32::ESP as i32, esp);
+                return Err(error);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pushes FLAGS, CS and IP and jumps through the IVT. Real mode has no error codes.
+    ///
+    /// A vector whose IVT entry still points at its BIOS stub is served by its native
+    /// hook if it has one, and otherwise runs the stub's IRET. The frame is as wide as
+    /// the default operand size of CS, which is what a plain IRET pops.
+    fn interrupt_real(&mut self, vector: u8) -> Result<(), EmulatorError> {
+        let offset = vector as u32 * 4;
+        if offset + 3 > self.idtr.limit as u32 {
+            return Err(EmulatorError::GeneralProtection(0));
+        }
+        let address = self.idtr.base.wrapping_add(offset);
+        let entry = self.get_linear(address, OperandSize::Dword, Access::Read)?;
+
+        if entry == ((BIOS_SEGMENT as u32) << 16) | vector as u32 {
+            if let Some(hook) = self.interrupt_hooks[vector as usize] {
+                return hook(self);
+            }
+        }
+
+        let selector = (entry >> 16) as u16;
+        let target = entry & 0xFFFF;
+        let cache = self.code_segment(selector, target, 0)?;
+
+        let code = self.segments[SegmentRegister::CS as usize];
+        let size = if code.big {
+            OperandSize::Dword
+        } else {
+            OperandSize::Word
+        };
+        self.push_frame(&[self.eflags as u32, code.selector as u32, self.eip], size)?;
+
+        self.set_interrupt(false);
+        self.commit_segment(SegmentRegister::CS, cache)?;
+        self.eip = target;
+
+        Ok(())
+    }
+
+    /// Pushes EFLAGS, CS, EIP and the error code and jumps through an interrupt or trap
+    /// gate. Task gates, and gates to a more privileged level, which need a TSS, are
+    /// not supported and raise #GP.
+    fn interrupt_protected(
+        &mut self,
+        vector: u8,
+        error_code: Option<u32>,
+        software: bool,
+    ) -> Result<(), EmulatorError> {
+        // Error code naming the gate: the index with the IDT bit set.
+        let gate = (vector as u16) * 8 + 2;
+
+        let offset = vector as u32 * 8;
+        if offset + 7 > self.idtr.limit as u32 {
+            return Err(EmulatorError::GeneralProtection(gate));
+        }
+        let address = self.idtr.base.wrapping_add(offset);
+        let low = self.get_linear(address, OperandSize::Dword, Access::Read)?;
+        let high = self.get_linear(address.wrapping_add(4), OperandSize::Dword, Access::Read)?;
+
+        let (size, trap) = match (high >> 8) & 0x1F {
+            0x06 => (OperandSize::Word, false),
+            0x07 => (OperandSize::Word, true),
+            0x0E => (OperandSize::Dword, false),
+            0x0F => (OperandSize::Dword, true),
+            _ => return Err(EmulatorError::GeneralProtection(gate)),
+        };
+        let dpl = ((high >> 13) & 0x03) as u8;
+        if software && dpl < self.cpl() {
+            return Err(EmulatorError::GeneralProtection(gate));
+        }
+        if high & 0x8000 == 0 {
+            return Err(EmulatorError::SegmentNotPresent(gate));
+        }
+
+        let selector = (low >> 16) as u16;
+        let target = ((high & 0xFFFF_0000) | (low & 0xFFFF)) & size.mask();
+        let cache = self.code_segment(selector, target, self.cpl())?;
+
+        let cs = self.segments[SegmentRegister::CS as usize].selector as u32;
+        let frame = [self.eflags as u32, cs, self.eip, error_code.unwrap_or(0)];
+        let words = if error_code.is_some() { 4 } else { 3 };
+        self.push_frame(&frame[..words], size)?;
+
+        if !trap {
+            self.set_interrupt(false);
+        }
+        self.commit_segment(SegmentRegister::CS, cache)?;
+        self.eip = target;
+
+        Ok(())
+    }
+
+    /// Loads EFLAGS popped by POPF or IRET. IOPL only changes at CPL 0 and IF only
+    /// when CPL <= IOPL; real mode runs at CPL 0.
+    fn load_flags(&mut self, value: u32) {
+        // CF, PF, AF, ZF, SF, TF, DF, OF and NT.
+        let mut mask: u16 = 0x4DD5;
+        if self.cpl() == 0 {
+            mask |= 0x3000;
+        }
+        if self.cpl() <= self.iopl() {
+            mask |= 0x0200;
+        }
+        self.eflags = (self.eflags & !mask) | (value as u16 & mask);
+    }
+
+    /// CLI and STI fault with #GP(0) when CPL > IOPL.
+    fn check_iopl(&self) -> Result<(), EmulatorError> {
+        if self.cpl() > self.iopl() {
+            Err(EmulatorError::GeneralProtection(0))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// INT imm8 (CD).
+    pub fn int_imm8(&mut self) -> Result<(), EmulatorError> {
+        let vector = self.get_code8(1)?;
+        self.advance_eip(2);
+        self.interrupt(vector, None, true)
+    }
+
+    /// INT3 (CC).
+    pub fn int3(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        self.interrupt(3, None, true)
+    }
+
+    /// INTO (CE): INT 4 if OF is set.
+    pub fn into(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        if self.is_overflow() {
+            self.interrupt(4, None, true)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// IRET (CF). Task returns through NT are not supported.
+    pub fn iret(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let offset = self.pop(size)?;
+        let selector = self.pop(size)? as u16;
+        let flags = self.pop(size)?;
+        self.load_flags(flags);
+        self.return_far(selector, offset & size.mask(), 0)
+    }
+
+    pub fn pushf(&mut self) -> Result<(), EmulatorError> {
+        self.push(self.eflags as u32, self.operand_size())?;
+        self.advance_eip(1);
+
+        Ok(())
+    }
+
+    pub fn popf(&mut self) -> Result<(), EmulatorError> {
+        let flags = self.pop(self.operand_size())?;
+        self.load_flags(flags);
+        self.advance_eip(1);
+
+        Ok(())
+    }
+
+    pub fn cli(&mut self) -> Result<(), EmulatorError> {
+        self.check_iopl()?;
+        self.set_interrupt(false);
+        self.advance_eip(1);
+
+        Ok(())
+    }
+
+    /// STI (FB). Interrupts are recognized only after the next instruction, so
+    /// `STI; HLT` cannot miss the interrupt it waits for.
+    pub fn sti(&mut self) -> Result<(), EmulatorError> {
+        self.check_iopl()?;
+        self.interrupt_shadow = !self.is_interrupt();
+        self.set_interrupt(true);
+        self.advance_eip(1);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::{DescriptorTable, Emulator, Register32, SegmentRegister, StopReason};
+    use crate::error::EmulatorError;
+
+    /// Runs `int 0x80; int 0x81; hlt` at 0x7C00, with vector 0x80 pointing at
+    /// `handler` at 0x7D00 and vector 0x81 left on its BIOS stub.
+    fn round_trip(emu: &mut Emulator, handler: &[u8]) {
+        emu.memory[0x7C00..0x7C05].copy_from_slice(&[0xCD, 0x80, 0xCD, 0x81, 0xF4]);
+        emu.memory[0x7D00..0x7D00 + handler.len()].copy_from_slice(handler);
+        emu.memory[0x200..0x204].copy_from_slice(&0x7D00u32.to_le_bytes());
+
+        assert_eq!(emu.run().unwrap(), StopReason::Halted);
+        assert_eq!(emu.eip, 0x7C05);
+        assert_eq!(emu.get_register32(Register32::EBX as i32), 0x12345678);
+    }
+
+    #[test]
+    fn int_and_iret_in_real_mode() {
+        let mut emu = Emulator::new_real_mode(0x10000, 0, 0x7C00);
+        // mov ebx, 0x12345678; iret
+        round_trip(&mut emu, &[0x66, 0xBB, 0x78, 0x56, 0x34, 0x12, 0xCF]);
+        assert_eq!(emu.get_register32(Register32::ESP as i32), 0);
+    }
+
+    #[test]
+    fn int_and_iret_with_32_bit_code_segment() {
+        let mut emu = Emulator::new(0x10000, 0x7C00, 0x7C00);
+        // mov ebx, 0x12345678; iretd
+        round_trip(&mut emu, &[0xBB, 0x78, 0x56, 0x34, 0x12, 0xCF]);
+        assert_eq!(emu.get_register32(Register32::ESP as i32), 0x7C00);
+    }
+
+    #[test]
+    fn a_fault_while_pushing_the_frame_leaves_esp_alone() {
+        // int 0x80 with SP = 2: FLAGS fits, CS wraps past the 4 KiB stack limit.
+        let mut emu = Emulator::new_real_mode(0x10000, 0, 0x7C00);
+        emu.memory[0x7C00..0x7C02].copy_from_slice(&[0xCD, 0x80]);
+        emu.memory[0x200..0x204].copy_from_slice(&0x7D00u32.to_le_bytes());
+        emu.segments[SegmentRegister::SS as usize].limit = 0x0FFF;
+        emu.set_register32(Register32::ESP as i32, 2);
+        let error = emu.step().unwrap_err();
+        assert!(matches!(error.error, EmulatorError::GeneralProtection(0)));
+        assert_eq!(emu.get_register32(Register32::ESP as i32), 2);
+        assert_eq!(emu.eip, 0x7C00);
+
+        // In protected mode the stack runs into a page that is not present after
+        // EFLAGS and CS; #PF, #DF and the triple fault all start from the same ESP.
+        let mut emu = Emulator::new(0x10000, 0x7C00, 0x7008);
+        // GDT at 0x1000: null and flat 32-bit code
+        emu.memory[0x1008..0x1010]
+            .copy_from_slice(&[0xFF, 0xFF, 0x00, 0x00, 0x00, 0x9A, 0xCF, 0x00]);
+        emu.gdtr = DescriptorTable {
+            base: 0x1000,
+            limit: 0x0F,
+        };
+        // IDT at 0x2000 with a 32-bit interrupt gate for vector 0x80 to 0008:3000
+        emu.idtr = DescriptorTable {
+            base: 0x2000,
+            limit: 0x7FF,
+        };
+        emu.memory[0x2400..0x2408]
+            .copy_from_slice(&[0x00, 0x30, 0x08, 0x00, 0x00, 0x8E, 0x00, 0x00]);
+        // Identity-mapped page table at 0x5000 without the page at 0x6000
+        emu.memory[0x4000..0x4004].copy_from_slice(&0x5007u32.to_le_bytes());
+        for page in 0..16u32 {
+            let pte = if page == 6 { 0 } else { (page << 12) | 0x07 };
+            let at = 0x5000 + page as usize * 4;
+            emu.memory[at..at + 4].copy_from_slice(&pte.to_le_bytes());
+        }
+        emu.control_registers[3] = 0x4000;
+        emu.control_registers[0] |= 0x8000_0001;
+        emu.memory[0x7C00..0x7C02].copy_from_slice(&[0xCD, 0x80]);
+
+        let error = emu.step().unwrap_err();
+        assert!(matches!(
+            error.error,
+            EmulatorError::PageFault {
+                address: 0x6FFC,
+                code: 0x02
+            }
+        ));
+        assert_eq!(emu.get_register32(Register32::ESP as i32), 0x7008);
+        assert_eq!(emu.eip, 0x7C00);
+    }
+}
@@ -1,23 +1,65 @@
-use std::io;
-use std::io::{stdout, Write};
-
-pub fn io_in8(address: u16) -> u8 {
-    match address {
-        0x03f8 => {
-            let mut guess = String::new();
-            io::stdin().read_line(&mut guess).expect("Input error!");
-            guess.chars().next().unwrap() as u8
+use crate::emulator::{Emulator, OperandSize, Register16, Register32};
+use crate::error::EmulatorError;
+
+impl Emulator {
+    /// In protected mode IN and OUT fault with #GP(0) when CPL > IOPL. There is no TSS,
+    /// so there is no I/O permission bitmap to consult.
+    pub(crate) fn check_io_privilege(&self) -> Result<(), EmulatorError> {
+        if self.is_protected_mode() && self.cpl() > self.iopl() {
+            Err(EmulatorError::GeneralProtection(0))
+        } else {
+            Ok(())
         }
-        _ => panic!(),
     }
-}
 
-pub fn io_out8(address: u16, value: u8) {
-    match address {
-        0x03f8 => {
-            print!("{}", value as char);
-            stdout().flush().unwrap();
+    pub fn io_in(&mut self, port: u16, size: OperandSize) -> Result<u32, EmulatorError> {
+        self.io.read(port, size)
+    }
+
+    pub fn io_out(
+        &mut self,
+        port: u16,
+        value: u32,
+        size: OperandSize,
+    ) -> Result<(), EmulatorError> {
+        self.io.write(port, value, size)
+    }
+
+    /// Port and operand size of IN and OUT: an imm8 port for E4-E7, DX for EC-EF.
+    fn port_operands(&mut self) -> Result<(u16, OperandSize, u32), EmulatorError> {
+        let code = self.get_code8(0)?;
+        let size = if code & 1 == 0 {
+            OperandSize::Byte
+        } else {
+            self.operand_size()
+        };
+
+        if code & 0x08 == 0 {
+            Ok((self.get_code8(1)? as u16, size, 2))
+        } else {
+            Ok((self.get_register16(Register16::DX as i32), size, 1))
         }
-        _ => (),
+    }
+
+    /// IN AL/eAX, imm8 (E4, E5) and IN AL/eAX, DX (EC, ED).
+    pub fn port_in(&mut self) -> Result<(), EmulatorError> {
+        let (port, size, length) = self.port_operands()?;
+        self.check_io_privilege()?;
+        let value = self.io_in(port, size)?;
+        self.set_register(Register32::EAX as i32, value, size);
+        self.advance_eip(length);
+
+        Ok(())
+    }
+
+    /// OUT imm8, AL/eAX (E6, E7) and OUT DX, AL/eAX (EE, EF).
+    pub fn port_out(&mut self) -> Result<(), EmulatorError> {
+        let (port, size, length) = self.port_operands()?;
+        self.check_io_privilege()?;
+        let value = self.get_register(Register32::EAX as i32, size);
+        self.io_out(port, value, size)?;
+        self.advance_eip(length);
+
+        Ok(())
     }
 }
@@ -1,132 +1,348 @@
-use crate::emulator::Emulator;
-
-pub union OpeReg {
-    pub opecode: u8,
-    reg_index: u8,
-}
-
-pub union Disp {
-    disp8: i8,
-    disp32: u32,
-}
+use crate::emulator::{Emulator, OperandSize, Register16, SegmentRegister};
+use crate::error::EmulatorError;
 
+/// A decoded ModR/M byte together with the SIB byte and displacement following it.
 pub struct ModRM {
     pub m: u8,
-    pub opereg: OpeReg,
+    /// reg field: a register index, or an opcode extension for group opcodes.
+    pub reg: u8,
     rm: u8,
     sib: u8,
-    disp: Disp,
+    /// Displacement, sign-extended to 32 bits.
+    disp: u32,
+    /// Segment the memory operand lives in.
+    pub segment: SegmentRegister,
+    address_size: OperandSize,
+}
+
+impl ModRM {
+    /// Base register of a 32-bit memory operand, or `None` for the disp32-only forms.
+    fn base(&self) -> Option<u8> {
+        match (self.m, self.rm) {
+            (0, 5) => None,
+            (0, 4) if self.sib & 0x07 == 5 => None,
+            (_, 4) => Some(self.sib & 0x07),
+            (_, rm) => Some(rm),
+        }
+    }
 }
 
 impl Emulator {
-    pub fn parse_modrm(&mut self) -> ModRM {
-        let code = self.get_code8(0);
+    pub fn parse_modrm(&mut self) -> Result<ModRM, EmulatorError> {
+        let code = self.get_code8(0)?;
         let mut modrm = ModRM {
             m: (code & 0xC0) >> 6,
-            opereg: OpeReg {
-                opecode: (code & 0x38) >> 3,
-            },
+            reg: (code & 0x38) >> 3,
             rm: code & 0x07,
             sib: 0,
-            disp: Disp { disp32: 0 },
+            disp: 0,
+            segment: SegmentRegister::DS,
+            address_size: self.address_size(),
         };
 
-        self.eip += 1;
+        self.advance_eip(1);
 
-        if modrm.m != 3 && modrm.rm == 4 {
-            modrm.sib = self.get_code8(0);
-            self.eip += 1;
+        if modrm.m == 3 {
+            return Ok(modrm);
         }
 
-        if (modrm.m == 0 && modrm.rm == 5) || modrm.m == 2 {
-            modrm.disp.disp32 = self.get_code32(0);
-            self.eip += 4;
-        } else if modrm.m == 1 {
-            modrm.disp.disp8 = self.get_sign_code8(0);
-            self.eip += 1;
+        if modrm.address_size == OperandSize::Word {
+            if (modrm.m == 0 && modrm.rm == 6) || modrm.m == 2 {
+                modrm.disp = self.get_code(0, OperandSize::Word)?;
+                self.advance_eip(2);
+            } else if modrm.m == 1 {
+                modrm.disp = self.get_sign_code8(0)? as i32 as u32;
+                self.advance_eip(1);
+            }
+
+            // Forms based on BP address the stack segment.
+            if modrm.rm == 2 || modrm.rm == 3 || (modrm.rm == 6 && modrm.m != 0) {
+                modrm.segment = SegmentRegister::SS;
+            }
+        } else {
+            if modrm.rm == 4 {
+                modrm.sib = self.get_code8(0)?;
+                self.advance_eip(1);
+            }
+
+            if modrm.m == 2 || modrm.base().is_none() {
+                modrm.disp = self.get_code32(0)?;
+                self.advance_eip(4);
+            } else if modrm.m == 1 {
+                modrm.disp = self.get_sign_code8(0)? as i32 as u32;
+                self.advance_eip(1);
+            }
+
+            // Forms based on EBP or ESP address the stack segment.
+            if matches!(modrm.base(), Some(4) | Some(5)) {
+                modrm.segment = SegmentRegister::SS;
+            }
         }
 
-        modrm
+        if let Some(segment) = self.prefixes.segment {
+            modrm.segment = segment;
+        }
+
+        Ok(modrm)
     }
 
-    pub fn calc_memory_address(&self, modrm: &ModRM) -> u32 {
-        match modrm.m {
-            0 => match modrm.rm {
-                4 => {
-                    unimplemented!("not implemented ModRM mod = 0, rm = 4");
-                }
-                5 => unsafe { modrm.disp.disp32 },
-                _ => self.get_register32(modrm.rm as u32 as i32),
-            },
-            1 => match modrm.m {
-                4 => {
-                    unimplemented!("not implemented ModRM mod = 1, rm = 4");
-                }
-                _ => self
-                    .get_register32(modrm.rm as u32 as i32)
-                    .wrapping_add(unsafe { modrm.disp.disp8 } as i32 as u32),
-            },
-            2 => match modrm.m {
-                4 => {
-                    unimplemented!("not implemented ModRM mod = 2, rm = 4");
-                }
-                _ => self.get_register32(modrm.rm as u32 as i32) + unsafe { modrm.disp.disp32 },
-            },
-            _ => {
-                unimplemented!("not implemented ModRM mod = 3");
-            }
+    /// Offset of the memory operand within `modrm.segment`, wrapped to the address size.
+    pub fn calc_memory_address(&self, modrm: &ModRM) -> Result<u32, EmulatorError> {
+        if modrm.m == 3 {
+            return Err(EmulatorError::UnsupportedModRM {
+                m: modrm.m,
+                rm: modrm.rm,
+            });
+        }
+
+        if modrm.address_size == OperandSize::Word {
+            return Ok(self.calc_memory_address16(modrm));
         }
+
+        let base = match modrm.base() {
+            Some(base) => self.get_register32(base as i32),
+            None => 0,
+        };
+        let index = match modrm.rm {
+            4 if (modrm.sib >> 3) & 0x07 != 4 => {
+                let scale = modrm.sib >> 6;
+                self.get_register32(((modrm.sib >> 3) & 0x07) as i32) << scale
+            }
+            _ => 0,
+        };
+
+        Ok(base.wrapping_add(index).wrapping_add(modrm.disp))
+    }
+
+    /// Offset of a memory operand encoded with a 16-bit ModR/M byte.
+    fn calc_memory_address16(&self, modrm: &ModRM) -> u32 {
+        let bx = self.get_register16(Register16::BX as i32) as u32;
+        let bp = self.get_register16(Register16::BP as i32) as u32;
+        let si = self.get_register16(Register16::SI as i32) as u32;
+        let di = self.get_register16(Register16::DI as i32) as u32;
+
+        let base = match modrm.rm {
+            0 => bx + si,
+            1 => bx + di,
+            2 => bp + si,
+            3 => bp + di,
+            4 => si,
+            5 => di,
+            6 if modrm.m == 0 => 0,
+            6 => bp,
+            _ => bx,
+        };
+
+        base.wrapping_add(modrm.disp) & 0xFFFF
     }
 
-    pub fn get_rm32(&self, modrm: &ModRM) -> u32 {
+    pub fn get_rm32(&mut self, modrm: &ModRM) -> Result<u32, EmulatorError> {
         if modrm.m == 3 {
-            self.get_register32(modrm.rm as u32 as i32)
+            Ok(self.get_register32(modrm.rm as u32 as i32))
         } else {
-            self.get_memory32(self.calc_memory_address(modrm))
+            let address = self.calc_memory_address(modrm)?;
+            self.get_segmented(modrm.segment, address, OperandSize::Dword)
         }
     }
 
-    pub fn set_rm32(&mut self, modrm: &ModRM, value: u32) {
+    pub fn set_rm32(&mut self, modrm: &ModRM, value: u32) -> Result<(), EmulatorError> {
         if modrm.m == 3 {
             self.set_register32(modrm.rm as u32 as i32, value);
+            Ok(())
         } else {
-            self.set_memory32(self.calc_memory_address(modrm), value);
+            let address = self.calc_memory_address(modrm)?;
+            self.set_segmented(modrm.segment, address, value, OperandSize::Dword)
         }
     }
 
     pub fn get_r32(&self, modrm: &ModRM) -> u32 {
-        self.get_register32(unsafe { modrm.opereg.reg_index } as u32 as i32)
+        self.get_register32(modrm.reg as u32 as i32)
     }
 
     pub fn set_r32(&mut self, modrm: &ModRM, value: u32) {
-        self.set_register32(unsafe { modrm.opereg.reg_index } as u32 as i32, value);
+        self.set_register32(modrm.reg as u32 as i32, value);
     }
 
-    #[warn(dead_code)]
-    pub fn set_rm8(&mut self, modrm: &ModRM, value: u8) {
+    pub fn set_rm8(&mut self, modrm: &ModRM, value: u8) -> Result<(), EmulatorError> {
         if modrm.m == 3 {
             self.set_register8(modrm.rm as i32, value);
+            Ok(())
         } else {
-            let address = self.calc_memory_address(modrm);
-            self.set_memory8(address, value);
+            let address = self.calc_memory_address(modrm)?;
+            self.set_segmented(modrm.segment, address, value as u32, OperandSize::Byte)
         }
     }
 
-    pub fn get_rm8(&mut self, modrm: &ModRM) -> u8 {
+    pub fn get_rm8(&mut self, modrm: &ModRM) -> Result<u8, EmulatorError> {
         if modrm.m == 3 {
-            self.get_register8(modrm.rm as i32)
+            Ok(self.get_register8(modrm.rm as i32))
         } else {
-            let address = self.calc_memory_address(modrm);
-            self.get_memory8(address)
+            let address = self.calc_memory_address(modrm)?;
+            Ok(self.get_segmented(modrm.segment, address, OperandSize::Byte)? as u8)
         }
     }
 
     pub fn set_r8(&mut self, modrm: &ModRM, value: u8) {
-        self.set_register8(unsafe { modrm.opereg.reg_index } as i32, value);
+        self.set_register8(modrm.reg as i32, value);
+    }
+
+    pub fn get_r8(&self, modrm: &ModRM) -> u8 {
+        self.get_register8(modrm.reg as i32)
+    }
+
+    pub fn get_rm(&mut self, modrm: &ModRM, size: OperandSize) -> Result<u32, EmulatorError> {
+        match size {
+            OperandSize::Byte => Ok(self.get_rm8(modrm)? as u32),
+            OperandSize::Word => {
+                if modrm.m == 3 {
+                    Ok(self.get_register(modrm.rm as i32, size))
+                } else {
+                    let address = self.calc_memory_address(modrm)?;
+                    self.get_segmented(modrm.segment, address, size)
+                }
+            }
+            OperandSize::Dword => self.get_rm32(modrm),
+        }
+    }
+
+    pub fn set_rm(
+        &mut self,
+        modrm: &ModRM,
+        value: u32,
+        size: OperandSize,
+    ) -> Result<(), EmulatorError> {
+        match size {
+            OperandSize::Byte => self.set_rm8(modrm, value as u8),
+            OperandSize::Word => {
+                if modrm.m == 3 {
+                    self.set_register(modrm.rm as i32, value, size);
+                    Ok(())
+                } else {
+                    let address = self.calc_memory_address(modrm)?;
+                    self.set_segmented(modrm.segment, address, value, size)
+                }
+            }
+            OperandSize::Dword => self.set_rm32(modrm, value),
+        }
+    }
+
+    pub fn get_r(&self, modrm: &ModRM, size: OperandSize) -> u32 {
+        self.get_register(modrm.reg as i32, size)
+    }
+
+    pub fn set_r(&mut self, modrm: &ModRM, value: u32, size: OperandSize) {
+        self.set_register(modrm.reg as i32, value, size);
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Register32;
+    use SegmentRegister::{DS, ES, SS};
+
+    /// Decodes the ModR/M bytes `code` at CS:EIP, returning the memory operand's
+    /// segment and offset and how many bytes were consumed.
+    fn decode(emu: &mut Emulator, code: &[u8]) -> (SegmentRegister, u32, u32) {
+        let eip = emu.eip;
+        let start = emu.linear_address(SegmentRegister::CS, eip) as usize;
+        emu.memory[start..start + code.len()].copy_from_slice(code);
+
+        let modrm = emu.parse_modrm().unwrap();
+        let address = emu.calc_memory_address(&modrm).unwrap();
+        let length = emu.eip - eip;
+        emu.eip = eip;
+        (modrm.segment, address, length)
+    }
+
+    fn flat() -> Emulator {
+        let mut emu = Emulator::new(0x10000, 0x7C00, 0);
+        for (register, value) in [
+            (Register32::EAX, 0x0000_0100),
+            (Register32::ECX, 0x0000_0020),
+            (Register32::ESP, 0x0000_8000),
+            (Register32::EBP, 0x0000_9000),
+        ] {
+            emu.set_register32(register as i32, value);
+        }
+        emu
+    }
+
+    #[test]
+    fn sib_without_base() {
+        let mut emu = flat();
+        // [ecx*4 + 0x1000]: mod=00 with SIB base=101 takes a disp32 instead of EBP.
+        assert_eq!(
+            decode(&mut emu, &[0x04, 0x8D, 0x00, 0x10, 0x00, 0x00]),
+            (DS, 0x1080, 6)
+        );
+        // [0x1000]: no base and no index.
+        assert_eq!(
+            decode(&mut emu, &[0x04, 0x25, 0x00, 0x10, 0x00, 0x00]),
+            (DS, 0x1000, 6)
+        );
+        // [0x1000] without a SIB byte.
+        assert_eq!(
+            decode(&mut emu, &[0x05, 0x00, 0x10, 0x00, 0x00]),
+            (DS, 0x1000, 5)
+        );
+        // [ebp + ecx + 0x10]: with mod=01 base=101 is EBP again.
+        assert_eq!(decode(&mut emu, &[0x44, 0x0D, 0x10]), (SS, 0x9030, 3));
+    }
+
+    #[test]
+    fn esp_and_ebp_bases_default_to_ss() {
+        let mut emu = flat();
+        // [ebp + 8]
+        assert_eq!(decode(&mut emu, &[0x45, 0x08]), (SS, 0x9008, 2));
+        // [esp]
+        assert_eq!(decode(&mut emu, &[0x04, 0x24]), (SS, 0x8000, 2));
+        // [esp + eax*2 + 4]
+        assert_eq!(decode(&mut emu, &[0x44, 0x44, 0x04]), (SS, 0x8204, 3));
+        // [eax + ebp]: EBP as the index does not.
+        assert_eq!(decode(&mut emu, &[0x04, 0x28]), (DS, 0x9100, 2));
+
+        // A segment override wins.
+        emu.prefixes.segment = Some(ES);
+        assert_eq!(decode(&mut emu, &[0x45, 0x08]), (ES, 0x9008, 2));
+    }
+
+    #[test]
+    fn sixteen_bit_addressing() {
+        let mut emu = Emulator::new_real_mode(0x20000, 0x0000, 0x7C00);
+        for (register, value) in [
+            (Register16::BX, 0x1000),
+            (Register16::BP, 0x2000),
+            (Register16::SI, 0x0100),
+            (Register16::DI, 0x0010),
+        ] {
+            emu.set_register16(register as i32, value);
+        }
+
+        // Every rm with mod=01 and a disp8 of 1.
+        let table = [
+            (DS, 0x1101),
+            (DS, 0x1011),
+            (SS, 0x2101),
+            (SS, 0x2011),
+            (DS, 0x0101),
+            (DS, 0x0011),
+            (SS, 0x2001),
+            (DS, 0x1001),
+        ];
+        for (rm, (segment, address)) in table.into_iter().enumerate() {
+            assert_eq!(
+                decode(&mut emu, &[0x40 | rm as u8, 0x01]),
+                (segment, address, 2),
+                "rm={}",
+                rm
+            );
+        }
 
-    #[warn(dead_code)]
-    pub fn get_r8(&mut self, modrm: &ModRM) -> u8 {
-        self.get_register8(unsafe { modrm.opereg.reg_index } as i32)
+        // [0x1234]: mod=00 rm=110 is a bare disp16, in DS.
+        assert_eq!(decode(&mut emu, &[0x06, 0x34, 0x12]), (DS, 0x1234, 3));
+        // [bp - 2] with a sign-extended disp8, and [bx + si + disp16] wrapping at 64 KiB.
+        assert_eq!(decode(&mut emu, &[0x46, 0xFE]), (SS, 0x1FFE, 2));
+        assert_eq!(decode(&mut emu, &[0x80, 0x00, 0xF0]), (DS, 0x0100, 3));
     }
 }
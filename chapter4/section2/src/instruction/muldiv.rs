@@ -0,0 +1,261 @@
+use crate::emulator::{Emulator, OperandSize, Register32};
+use crate::error::EmulatorError;
+use crate::instruction::modrm::ModRM;
+
+impl Emulator {
+    /// Reads the implicit double-width dividend: AX, DX:AX or EDX:EAX.
+    fn get_dividend(&self, size: OperandSize) -> u64 {
+        match size {
+            OperandSize::Byte => (self.get_register32(Register32::EAX as i32) & 0xFFFF) as u64,
+            _ => {
+                let high = self.get_register(Register32::EDX as i32, size) as u64;
+                let low = self.get_register(Register32::EAX as i32, size) as u64;
+                high << size.bits() | low
+            }
+        }
+    }
+
+    /// Writes the implicit double-width product: AX, DX:AX or EDX:EAX.
+    fn set_product(&mut self, product: u64, size: OperandSize) {
+        match size {
+            OperandSize::Byte => {
+                self.set_register(Register32::EAX as i32, product as u32, OperandSize::Word);
+            }
+            _ => {
+                self.set_register(Register32::EAX as i32, product as u32, size);
+                let high = (product >> size.bits()) as u32;
+                self.set_register(Register32::EDX as i32, high, size);
+            }
+        }
+    }
+
+    /// Stores the quotient in AL, AX or EAX and the remainder in AH, DX or EDX.
+    fn set_quotient(&mut self, quotient: u32, remainder: u32, size: OperandSize) {
+        match size {
+            OperandSize::Byte => {
+                let ax = (remainder & 0xFF) << 8 | (quotient & 0xFF);
+                self.set_register(Register32::EAX as i32, ax, OperandSize::Word);
+            }
+            _ => {
+                self.set_register(Register32::EAX as i32, quotient, size);
+                self.set_register(Register32::EDX as i32, remainder, size);
+            }
+        }
+    }
+
+    fn mul(&mut self, value: u32, size: OperandSize) {
+        let accumulator = self.get_register(Register32::EAX as i32, size) as u64;
+        let product = accumulator * value as u64;
+        self.set_product(product, size);
+        self.update_eflags_mul(product as u32, product >> size.bits() != 0, size);
+    }
+
+    fn imul(&mut self, value: u32, size: OperandSize) {
+        let accumulator =
+            Emulator::sign_extend(self.get_register(Register32::EAX as i32, size), size);
+        let product = accumulator * Emulator::sign_extend(value, size);
+        let low = product as u32 & size.mask();
+        self.set_product(product as u64, size);
+        self.update_eflags_mul(low, Emulator::sign_extend(low, size) != product, size);
+    }
+
+    fn div(&mut self, value: u32, size: OperandSize) -> Result<(), EmulatorError> {
+        if value == 0 {
+            return Err(EmulatorError::DivideError);
+        }
+
+        let dividend = self.get_dividend(size);
+        let quotient = dividend / value as u64;
+        if quotient > size.mask() as u64 {
+            return Err(EmulatorError::DivideError);
+        }
+
+        let remainder = dividend % value as u64;
+        self.set_quotient(quotient as u32, remainder as u32, size);
+        Ok(())
+    }
+
+    fn idiv(&mut self, value: u32, size: OperandSize) -> Result<(), EmulatorError> {
+        if value == 0 {
+            return Err(EmulatorError::DivideError);
+        }
+
+        let shift = 128 - 2 * size.bits();
+        let dividend = (self.get_dividend(size) as i128) << shift >> shift;
+        let divisor = Emulator::sign_extend(value, size) as i128;
+        let quotient = dividend / divisor;
+
+        let half = size.sign_bit() as i128;
+        if quotient < -half || quotient >= half {
+            return Err(EmulatorError::DivideError);
+        }
+
+        let remainder = dividend % divisor;
+        self.set_quotient(quotient as u32, remainder as u32, size);
+        Ok(())
+    }
+
+    fn unary_group(&mut self, opcode: u8, size: OperandSize) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let value = self.get_rm(&modrm, size)?;
+
+        match modrm.reg {
+            0 | 1 => {
+                let imm = self.get_code(0, size)?;
+                self.advance_eip(size.bytes());
+                self.update_eflags_logic(value & imm, size);
+                Ok(())
+            }
+            2 => self.set_rm(&modrm, !value & size.mask(), size),
+            3 => {
+                let result = (value as u64).wrapping_neg();
+                self.update_eflags_sub(0, value, result, size);
+                self.set_rm(&modrm, result as u32 & size.mask(), size)
+            }
+            4 => {
+                self.mul(value, size);
+                Ok(())
+            }
+            5 => {
+                self.imul(value, size);
+                Ok(())
+            }
+            6 => self.div(value, size),
+            7 => self.idiv(value, size),
+            _ => Err(EmulatorError::InvalidOpcode {
+                opcode: opcode as u16,
+                modrm_reg: Some(modrm.reg),
+            }),
+        }
+    }
+
+    pub fn code_f6(&mut self) -> Result<(), EmulatorError> {
+        self.unary_group(0xF6, OperandSize::Byte)
+    }
+
+    pub fn code_f7(&mut self) -> Result<(), EmulatorError> {
+        self.unary_group(0xF7, self.operand_size())
+    }
+
+    /// Multiplies two signed values and stores the truncated product in the reg operand.
+    fn imul_r(&mut self, modrm: &ModRM, v1: u32, v2: u32, size: OperandSize) {
+        let product = Emulator::sign_extend(v1, size) * Emulator::sign_extend(v2, size);
+        let result = product as u32 & size.mask();
+        self.set_r(modrm, result, size);
+        self.update_eflags_mul(result, Emulator::sign_extend(result, size) != product, size);
+    }
+
+    pub fn imul_r32_rm32(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let r = self.get_r(&modrm, size);
+        let rm = self.get_rm(&modrm, size)?;
+        self.imul_r(&modrm, r, rm, size);
+
+        Ok(())
+    }
+
+    pub fn imul_r32_rm32_imm32(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let rm = self.get_rm(&modrm, size)?;
+        let imm = self.get_code(0, size)?;
+        self.advance_eip(size.bytes());
+        self.imul_r(&modrm, rm, imm, size);
+
+        Ok(())
+    }
+
+    pub fn imul_r32_rm32_imm8(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let rm = self.get_rm(&modrm, size)?;
+        let imm8 = self.get_sign_code8(0)? as i32 as u32;
+        self.advance_eip(1);
+        self.imul_r(&modrm, rm, imm8, size);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::OperandSize::{Byte, Dword, Word};
+
+    fn emulator(edx: u32, eax: u32) -> Emulator {
+        let mut emu = Emulator::new(0x1000, 0, 0);
+        emu.set_register32(Register32::EDX as i32, edx);
+        emu.set_register32(Register32::EAX as i32, eax);
+        emu
+    }
+
+    fn registers(emu: &Emulator) -> (u32, u32) {
+        (
+            emu.get_register32(Register32::EDX as i32),
+            emu.get_register32(Register32::EAX as i32),
+        )
+    }
+
+    /// Divides and checks for #DE, with the registers left as they were.
+    fn assert_divide_error(
+        divide: fn(&mut Emulator, u32, OperandSize) -> Result<(), EmulatorError>,
+        (edx, eax): (u32, u32),
+        divisor: u32,
+        size: OperandSize,
+    ) {
+        let mut emu = emulator(edx, eax);
+        let result = divide(&mut emu, divisor, size);
+        assert!(
+            matches!(result, Err(EmulatorError::DivideError)),
+            "{:?}",
+            result
+        );
+        assert_eq!(registers(&emu), (edx, eax));
+    }
+
+    #[test]
+    fn div_faults_on_zero_and_on_quotient_overflow() {
+        assert_divide_error(Emulator::div, (0, 0x1234), 0, Byte);
+        assert_divide_error(Emulator::div, (0, 0x1234), 0, Dword);
+        assert_divide_error(Emulator::div, (0, 0x0100), 1, Byte);
+        assert_divide_error(Emulator::div, (0x0001, 0x0000), 1, Word);
+        assert_divide_error(Emulator::div, (0x0001, 0x0000), 1, Dword);
+
+        // The largest quotient that fits.
+        let mut emu = emulator(0, 0x01FF);
+        emu.div(2, Byte).unwrap();
+        assert_eq!(registers(&emu), (0, 0x01FF));
+        let mut emu = emulator(0x0000_0001, 0x0000_0005);
+        emu.div(2, Dword).unwrap();
+        assert_eq!(registers(&emu), (1, 0x8000_0002));
+    }
+
+    #[test]
+    fn idiv_faults_outside_the_signed_range() {
+        assert_divide_error(Emulator::idiv, (0, 0x1234), 0, Word);
+        // -128 / -1 = 128 does not fit in AL.
+        assert_divide_error(Emulator::idiv, (0, 0xFF80), 0xFF, Byte);
+        assert_divide_error(Emulator::idiv, (0, 0xFF7F), 1, Byte);
+        assert_divide_error(Emulator::idiv, (0xFFFF, 0x8000), 0xFFFF, Word);
+        assert_divide_error(
+            Emulator::idiv,
+            (0xFFFF_FFFF, 0x8000_0000),
+            0xFFFF_FFFF,
+            Dword,
+        );
+
+        // -128 / 1 does.
+        let mut emu = emulator(0, 0xFF80);
+        emu.idiv(1, Byte).unwrap();
+        assert_eq!(registers(&emu), (0, 0x0080));
+        // The quotient rounds towards zero and the remainder takes the dividend's sign.
+        let mut emu = emulator(0, 0xFFF9);
+        emu.idiv(2, Byte).unwrap();
+        assert_eq!(registers(&emu), (0, 0xFFFD));
+    }
+}
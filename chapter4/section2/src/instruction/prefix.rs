@@ -0,0 +1,188 @@
+use crate::emulator::{Emulator, OperandSize, Prefixes, RepeatPrefix, SegmentRegister};
+use crate::error::EmulatorError;
+
+/// An instruction, prefixes included, is at most 15 bytes long.
+const MAX_PREFIXES: u32 = 14;
+
+impl Emulator {
+    /// Consumes the prefix bytes at EIP into `self.prefixes` and returns the opcode byte,
+    /// leaving EIP on it.
+    pub fn read_prefixes(&mut self) -> Result<u8, EmulatorError> {
+        self.prefixes = Prefixes::default();
+
+        loop {
+            let code = self.get_code8(0)?;
+
+            match code {
+                0x66 => self.prefixes.operand_size_override = true,
+                0x67 => self.prefixes.address_size_override = true,
+                0x26 => self.prefixes.segment = Some(SegmentRegister::ES),
+                0x2E => self.prefixes.segment = Some(SegmentRegister::CS),
+                0x36 => self.prefixes.segment = Some(SegmentRegister::SS),
+                0x3E => self.prefixes.segment = Some(SegmentRegister::DS),
+                0x64 => self.prefixes.segment = Some(SegmentRegister::FS),
+                0x65 => self.prefixes.segment = Some(SegmentRegister::GS),
+                0xF0 => self.prefixes.lock = true,
+                0xF2 => self.prefixes.repeat = Some(RepeatPrefix::Repne),
+                0xF3 => self.prefixes.repeat = Some(RepeatPrefix::Rep),
+                _ => return Ok(code),
+            }
+
+            if self.eip.wrapping_sub(self.instruction_start) >= MAX_PREFIXES {
+                return Err(EmulatorError::InvalidOpcode {
+                    opcode: code as u16,
+                    modrm_reg: None,
+                });
+            }
+            self.advance_eip(1);
+        }
+    }
+
+    /// Raises #UD for a LOCK prefix on anything but the read-modify-write forms with a
+    /// memory destination. `opcode` is the full opcode, with EIP on its first byte.
+    pub fn check_lock(&mut self, opcode: u16) -> Result<(), EmulatorError> {
+        let length = if opcode > 0xFF { 2 } else { 1 };
+        let modrm = self.get_code8(length)?;
+        let reg = (modrm >> 3) & 0x07;
+
+        let lockable = modrm >> 6 != 3
+            && match opcode {
+                // ADD, OR, ADC, SBB, AND, SUB and XOR r/m, r; not CMP.
+                0x00..=0x37 => opcode & 0x07 <= 1,
+                0x80 | 0x81 | 0x83 => reg != 7,
+                0x86 | 0x87 => true,
+                // NOT and NEG.
+                0xF6 | 0xF7 => reg == 2 || reg == 3,
+                // INC and DEC.
+                0xFE | 0xFF => reg <= 1,
+                // BTS, BTR and BTC, CMPXCHG, XADD and CMPXCHG8B.
+                0x0FAB | 0x0FB3 | 0x0FBB | 0x0FB0 | 0x0FB1 | 0x0FC0 | 0x0FC1 => true,
+                0x0FBA => reg >= 5,
+                0x0FC7 => reg == 1,
+                _ => false,
+            };
+
+        if lockable {
+            Ok(())
+        } else {
+            Err(EmulatorError::InvalidOpcode {
+                opcode,
+                modrm_reg: None,
+            })
+        }
+    }
+
+    /// Operand size of the current instruction: the default size of CS, switched by
+    /// the 66 prefix.
+    pub fn operand_size(&self) -> OperandSize {
+        let big = self.segments[SegmentRegister::CS as usize].big;
+        if big == self.prefixes.operand_size_override {
+            OperandSize::Word
+        } else {
+            OperandSize::Dword
+        }
+    }
+
+    /// Address size of the current instruction: the default size of CS, switched by
+    /// the 67 prefix.
+    pub fn address_size(&self) -> OperandSize {
+        let big = self.segments[SegmentRegister::CS as usize].big;
+        if big == self.prefixes.address_size_override {
+            OperandSize::Word
+        } else {
+            OperandSize::Dword
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{Register16, Register32, StepOutcome};
+
+    const ENTRY: u32 = 0x7C00;
+    /// Where the #UD handler is put.
+    const HANDLER: u32 = 0x0600;
+
+    /// A real-mode emulator with `code` at 0000:7C00 and #UD going to 0000:0600.
+    fn real_mode(code: &[u8]) -> Emulator {
+        let mut emu = Emulator::new_real_mode(0x10000, 0, ENTRY as u16);
+        let start = ENTRY as usize;
+        emu.memory[start..start + code.len()].copy_from_slice(code);
+        emu.memory[6 * 4..6 * 4 + 4].copy_from_slice(&HANDLER.to_le_bytes());
+        emu
+    }
+
+    /// Reads the prefixes of `code` at EIP and returns the operand and address sizes.
+    fn sizes(emu: &mut Emulator, code: &[u8]) -> (OperandSize, OperandSize) {
+        let start = emu.linear_address(SegmentRegister::CS, emu.eip) as usize;
+        emu.memory[start..start + code.len()].copy_from_slice(code);
+        emu.instruction_start = emu.eip;
+        let eip = emu.eip;
+        emu.read_prefixes().unwrap();
+        emu.eip = eip;
+        (emu.operand_size(), emu.address_size())
+    }
+
+    #[test]
+    fn size_prefixes_switch_from_the_code_segment_default() {
+        use OperandSize::{Dword, Word};
+
+        let mut emu = real_mode(&[]);
+        assert_eq!(sizes(&mut emu, &[0x90]), (Word, Word));
+        assert_eq!(sizes(&mut emu, &[0x66, 0x90]), (Dword, Word));
+        assert_eq!(sizes(&mut emu, &[0x67, 0x90]), (Word, Dword));
+        assert_eq!(sizes(&mut emu, &[0x66, 0x67, 0x90]), (Dword, Dword));
+
+        let mut emu = Emulator::new(0x10000, ENTRY, ENTRY);
+        assert_eq!(sizes(&mut emu, &[0x90]), (Dword, Dword));
+        assert_eq!(sizes(&mut emu, &[0x66, 0x90]), (Word, Dword));
+        assert_eq!(sizes(&mut emu, &[0x67, 0x90]), (Dword, Word));
+        // Repeating a prefix does not switch back.
+        assert_eq!(sizes(&mut emu, &[0x66, 0x66, 0x90]), (Word, Dword));
+
+        // mov eax, 0x12345678 in 16-bit code takes a 32-bit immediate.
+        let mut emu = real_mode(&[0x66, 0xB8, 0x78, 0x56, 0x34, 0x12]);
+        emu.step().unwrap();
+        assert_eq!(emu.eip, ENTRY + 6);
+        assert_eq!(emu.get_register32(Register32::EAX as i32), 0x1234_5678);
+    }
+
+    #[test]
+    fn instructions_are_at_most_15_bytes() {
+        let mut code = vec![0x66; 14];
+        code.push(0x90);
+        let mut emu = real_mode(&code);
+        assert_eq!(emu.step().unwrap(), StepOutcome::Continue);
+        assert_eq!(emu.eip, ENTRY + 15);
+
+        let mut code = vec![0x66; 15];
+        code.push(0x90);
+        let mut emu = real_mode(&code);
+        emu.step().unwrap();
+        assert_eq!(emu.eip, HANDLER);
+    }
+
+    #[test]
+    fn lock_is_only_allowed_on_read_modify_write_memory() {
+        // lock add [bx], ax
+        let mut emu = real_mode(&[0xF0, 0x01, 0x07]);
+        emu.set_register16(Register16::BX as i32, 0x0500);
+        emu.set_register16(Register16::AX as i32, 0x0102);
+        emu.step().unwrap();
+        assert_eq!(emu.eip, ENTRY + 3);
+        assert_eq!(emu.memory[0x0500..0x0502], [0x02, 0x01]);
+
+        // lock add ax, bx; lock mov [bx], ax; lock cmp [bx], ax; lock nop
+        for code in [
+            [0xF0, 0x01, 0xD8],
+            [0xF0, 0x89, 0x07],
+            [0xF0, 0x39, 0x07],
+            [0xF0, 0x90, 0x90],
+        ] {
+            let mut emu = real_mode(&code);
+            emu.step().unwrap();
+            assert_eq!(emu.eip, HANDLER, "{:02X?}", code);
+        }
+    }
+}
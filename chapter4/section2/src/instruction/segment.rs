@@ -0,0 +1,510 @@
+use crate::emulator::{Emulator, OperandSize, Register32, Segment, SegmentRegister};
+use crate::error::EmulatorError;
+use crate::paging::Access;
+
+impl Emulator {
+    /// Reads the GDT descriptor `selector` refers to. Local descriptor tables are not
+    /// supported, so selectors with the TI bit set fault like ones past the GDT limit.
+    fn read_descriptor(&mut self, selector: u16) -> Result<Segment, EmulatorError> {
+        let index = (selector & 0xFFF8) as u32;
+        if selector & 0x04 != 0 || index + 7 > self.gdtr.limit as u32 {
+            return Err(EmulatorError::GeneralProtection(selector & 0xFFFC));
+        }
+
+        let address = self.gdtr.base.wrapping_add(index);
+        let low = self.get_linear(address, OperandSize::Dword, Access::Read)?;
+        let high = self.get_linear(address.wrapping_add(4), OperandSize::Dword, Access::Read)?;
+
+        let mut limit = (low & 0xFFFF) | (high & 0x000F_0000);
+        if high & (1 << 23) != 0 {
+            limit = (limit << 12) | 0xFFF;
+        }
+
+        Ok(Segment {
+            selector,
+            base: (low >> 16) | ((high & 0xFF) << 16) | (high & 0xFF00_0000),
+            limit,
+            access: (high >> 8) as u8,
+            big: high & (1 << 22) != 0,
+        })
+    }
+
+    /// Stores a checked descriptor in the cache of `segment` and sets the accessed bit
+    /// of its GDT entry.
+    pub(crate) fn commit_segment(
+        &mut self,
+        segment: SegmentRegister,
+        mut cache: Segment,
+    ) -> Result<(), EmulatorError> {
+        if cache.access != 0 && cache.access & 0x01 == 0 {
+            cache.access |= 0x01;
+            let address = self
+                .gdtr
+                .base
+                .wrapping_add((cache.selector & 0xFFF8) as u32 + 5);
+            self.set_linear(address, cache.access as u32, OperandSize::Byte)?;
+        }
+        self.segments[segment as usize] = cache;
+
+        Ok(())
+    }
+
+    /// Loads `selector` into a data or stack segment register.
+    ///
+    /// In real mode the base is the selector shifted left by 4, and the limit and
+    /// default size stay as they are. In protected mode the descriptor is read from the
+    /// GDT and its type and privilege are checked.
+    pub fn load_segment(
+        &mut self,
+        segment: SegmentRegister,
+        selector: u16,
+    ) -> Result<(), EmulatorError> {
+        if !self.is_protected_mode() {
+            let cache = &mut self.segments[segment as usize];
+            cache.selector = selector;
+            cache.base = (selector as u32) << 4;
+            return Ok(());
+        }
+
+        let fault = EmulatorError::GeneralProtection(selector & 0xFFFC);
+        let cpl = self.cpl();
+        let rpl = (selector & 0x03) as u8;
+
+        if selector & 0xFFFC == 0 {
+            if segment == SegmentRegister::SS {
+                return Err(EmulatorError::GeneralProtection(0));
+            }
+            // A null selector can be loaded, but any access through it faults.
+            self.segments[segment as usize] = Segment {
+                selector,
+                base: 0,
+                limit: 0,
+                access: 0,
+                big: false,
+            };
+            return Ok(());
+        }
+
+        let cache = self.read_descriptor(selector)?;
+        let system = cache.access & 0x10 == 0;
+        let conforming = cache.is_code() && cache.access & 0x04 != 0;
+
+        let allowed = if segment == SegmentRegister::SS {
+            !system
+                && !cache.is_code()
+                && cache.is_readable_or_writable()
+                && rpl == cpl
+                && cache.dpl() == cpl
+        } else {
+            !system
+                && (!cache.is_code() || cache.is_readable_or_writable())
+                && (conforming || cache.dpl() >= cpl.max(rpl))
+        };
+        if !allowed {
+            return Err(fault);
+        }
+        if !cache.is_present() {
+            return Err(EmulatorError::SegmentNotPresent(selector & 0xFFFC));
+        }
+
+        self.commit_segment(segment, cache)
+    }
+
+    /// Checks a far transfer to `selector:offset` that will run at privilege level
+    /// `privilege` and returns the new CS cache. Call gates and task switches are not
+    /// supported.
+    pub(crate) fn code_segment(
+        &mut self,
+        selector: u16,
+        offset: u32,
+        privilege: u8,
+    ) -> Result<Segment, EmulatorError> {
+        if !self.is_protected_mode() {
+            let mut cache = self.segments[SegmentRegister::CS as usize];
+            cache.selector = selector;
+            cache.base = (selector as u32) << 4;
+            return Ok(cache);
+        }
+
+        if selector & 0xFFFC == 0 {
+            return Err(EmulatorError::GeneralProtection(0));
+        }
+
+        let mut cache = self.read_descriptor(selector)?;
+        let conforming = cache.access & 0x04 != 0;
+        let allowed = cache.access & 0x10 != 0
+            && cache.is_code()
+            && if conforming {
+                cache.dpl() <= privilege
+            } else {
+                cache.dpl() == privilege
+            };
+        if !allowed {
+            return Err(EmulatorError::GeneralProtection(selector & 0xFFFC));
+        }
+        if !cache.is_present() {
+            return Err(EmulatorError::SegmentNotPresent(selector & 0xFFFC));
+        }
+        if offset > cache.limit {
+            return Err(EmulatorError::GeneralProtection(0));
+        }
+
+        cache.selector = (selector & 0xFFFC) | privilege as u16;
+        Ok(cache)
+    }
+
+    /// JMP ptr16:16/32 (EA).
+    pub fn far_jump(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let offset = self.get_code(1, size)?;
+        let selector = self.get_code(1 + size.bytes() as i32, OperandSize::Word)? as u16;
+        self.advance_eip(3 + size.bytes());
+        self.jump_far(selector, offset)
+    }
+
+    /// CALL ptr16:16/32 (9A).
+    pub fn far_call(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let offset = self.get_code(1, size)?;
+        let selector = self.get_code(1 + size.bytes() as i32, OperandSize::Word)? as u16;
+        self.advance_eip(3 + size.bytes());
+        self.call_far(selector, offset)
+    }
+
+    pub fn jump_far(&mut self, selector: u16, offset: u32) -> Result<(), EmulatorError> {
+        if self.is_protected_mode() && (selector & 0x03) as u8 > self.cpl() {
+            return Err(EmulatorError::GeneralProtection(selector & 0xFFFC));
+        }
+        let cache = self.code_segment(selector, offset, self.cpl())?;
+        self.commit_segment(SegmentRegister::CS, cache)?;
+        self.eip = offset;
+
+        Ok(())
+    }
+
+    pub fn call_far(&mut self, selector: u16, offset: u32) -> Result<(), EmulatorError> {
+        if self.is_protected_mode() && (selector & 0x03) as u8 > self.cpl() {
+            return Err(EmulatorError::GeneralProtection(selector & 0xFFFC));
+        }
+        let cache = self.code_segment(selector, offset, self.cpl())?;
+
+        let size = self.operand_size();
+        let cs = self.segments[SegmentRegister::CS as usize].selector as u32;
+        self.push(cs, size)?;
+        self.push(self.eip, size)?;
+
+        self.commit_segment(SegmentRegister::CS, cache)?;
+        self.eip = offset;
+
+        Ok(())
+    }
+
+    /// RETF (CB) and RETF imm16 (CA).
+    pub fn far_return(&mut self) -> Result<(), EmulatorError> {
+        let release = if self.get_code8(0)? == 0xCA {
+            self.get_code(1, OperandSize::Word)?
+        } else {
+            0
+        };
+
+        let size = self.operand_size();
+        let offset = self.pop(size)?;
+        let selector = self.pop(size)? as u16;
+        self.return_far(selector, offset, release)
+    }
+
+    /// Continues at `selector:offset` popped by RETF or IRET, discarding `release`
+    /// bytes of parameters. A return to an outer privilege level also pops ESP and
+    /// SS, and clears data segment registers the outer level may not use.
+    pub(crate) fn return_far(
+        &mut self,
+        selector: u16,
+        offset: u32,
+        release: u32,
+    ) -> Result<(), EmulatorError> {
+        let cpl = self.cpl();
+        let rpl = if self.is_protected_mode() {
+            (selector & 0x03) as u8
+        } else {
+            0
+        };
+        if rpl < cpl {
+            return Err(EmulatorError::GeneralProtection(selector & 0xFFFC));
+        }
+        let cache = self.code_segment(selector, offset, rpl)?;
+        self.release_stack(release);
+
+        if rpl > cpl {
+            let size = self.operand_size();
+            let esp = self.pop(size)?;
+            let ss = self.pop(size)? as u16;
+            self.commit_segment(SegmentRegister::CS, cache)?;
+            self.load_segment(SegmentRegister::SS, ss)?;
+            self.set_register(Register32::ESP as i32, esp, self.stack_size());
+            self.release_stack(release);
+
+            for segment in [
+                SegmentRegister::ES,
+                SegmentRegister::DS,
+                SegmentRegister::FS,
+                SegmentRegister::GS,
+            ] {
+                let data = self.segments[segment as usize];
+                let conforming = data.is_code() && data.access & 0x04 != 0;
+                if !conforming && data.dpl() < rpl {
+                    self.segments[segment as usize].access = 0;
+                }
+            }
+        } else {
+            self.commit_segment(SegmentRegister::CS, cache)?;
+        }
+        self.eip = offset;
+
+        Ok(())
+    }
+
+    /// Decodes the segment register named by bits 3-5 of `code`, an opcode or the
+    /// ModR/M byte following `opcode`.
+    fn segment_register(opcode: u16, code: u8) -> Result<SegmentRegister, EmulatorError> {
+        match (code >> 3) & 0x07 {
+            0 => Ok(SegmentRegister::ES),
+            1 => Ok(SegmentRegister::CS),
+            2 => Ok(SegmentRegister::SS),
+            3 => Ok(SegmentRegister::DS),
+            4 => Ok(SegmentRegister::FS),
+            5 => Ok(SegmentRegister::GS),
+            reg => Err(EmulatorError::InvalidOpcode {
+                opcode,
+                modrm_reg: Some(reg),
+            }),
+        }
+    }
+
+    pub fn mov_rm16_sreg(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let segment = Emulator::segment_register(0x8C, self.get_code8(0)?)?;
+        let modrm = self.parse_modrm()?;
+        let selector = self.segments[segment as usize].selector as u32;
+        // A register destination is written with the operand size, memory always with 16 bits.
+        let size = if modrm.m == 3 {
+            self.operand_size()
+        } else {
+            OperandSize::Word
+        };
+        self.set_rm(&modrm, selector, size)
+    }
+
+    pub fn mov_sreg_rm16(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let code = self.get_code8(0)?;
+        let segment = match Emulator::segment_register(0x8E, code)? {
+            SegmentRegister::CS => {
+                return Err(EmulatorError::InvalidOpcode {
+                    opcode: 0x8E,
+                    modrm_reg: Some(1),
+                })
+            }
+            segment => segment,
+        };
+        let modrm = self.parse_modrm()?;
+        let selector = self.get_rm(&modrm, OperandSize::Word)? as u16;
+        self.load_segment(segment, selector)
+    }
+
+    /// LES (C4) and LDS (C5): loads a far pointer from memory into ES or DS and a
+    /// general register.
+    pub fn load_far_pointer(&mut self) -> Result<(), EmulatorError> {
+        let code = self.get_code8(0)?;
+        let size = self.operand_size();
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        if modrm.m == 3 {
+            return Err(EmulatorError::InvalidOpcode {
+                opcode: code as u16,
+                modrm_reg: None,
+            });
+        }
+
+        let address = self.calc_memory_address(&modrm)?;
+        let offset = self.get_segmented(modrm.segment, address, size)?;
+        let selector = address.wrapping_add(size.bytes());
+        let selector = self.get_segmented(modrm.segment, selector, OperandSize::Word)?;
+        let segment = if code == 0xC4 {
+            SegmentRegister::ES
+        } else {
+            SegmentRegister::DS
+        };
+        self.load_segment(segment, selector as u16)?;
+        self.set_r(&modrm, offset, size);
+
+        Ok(())
+    }
+
+    /// PUSH ES/CS/SS/DS (06, 0E, 16, 1E) and PUSH FS/GS (0F A0, 0F A8).
+    pub fn push_sreg(&mut self) -> Result<(), EmulatorError> {
+        let code = self.get_code8(0)?;
+        let segment = Emulator::segment_register(code as u16, code)?;
+        let selector = self.segments[segment as usize].selector as u32;
+        self.push(selector, self.operand_size())?;
+        self.advance_eip(1);
+
+        Ok(())
+    }
+
+    /// POP ES/SS/DS (07, 17, 1F) and POP FS/GS (0F A1, 0F A9).
+    pub fn pop_sreg(&mut self) -> Result<(), EmulatorError> {
+        let code = self.get_code8(0)?;
+        let segment = Emulator::segment_register(code as u16, code)?;
+        let selector = self.pop(self.operand_size())? as u16;
+        self.load_segment(segment, selector)?;
+        self.advance_eip(1);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{DescriptorTable, Register16};
+
+    const ENTRY: u32 = 0x7C00;
+    const GDT: u32 = 0x1000;
+    /// Flat 32-bit readable code.
+    const CODE32: u16 = 0x08;
+    /// Writable data at 0x2000 with a byte-granular limit of 0xFF.
+    const DATA: u16 = 0x10;
+    /// Flat 32-bit execute-only code.
+    const EXECUTE_ONLY: u16 = 0x18;
+
+    fn write_gdt(emu: &mut Emulator) {
+        let gdt: [u8; 32] = [
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+            0xFF, 0xFF, 0x00, 0x00, 0x00, 0x9A, 0xCF, 0x00, //
+            0xFF, 0x00, 0x00, 0x20, 0x00, 0x92, 0x00, 0x00, //
+            0xFF, 0xFF, 0x00, 0x00, 0x00, 0x98, 0xCF, 0x00, //
+        ];
+        let start = GDT as usize;
+        emu.memory[start..start + gdt.len()].copy_from_slice(&gdt);
+    }
+
+    /// A ring 0 protected-mode emulator with `code` at ENTRY and the GDT above loaded.
+    /// The IDT is empty, so a fault escalates and `step` reports the first one.
+    fn protected(code: &[u8]) -> Emulator {
+        let mut emu = Emulator::new(0x10000, ENTRY, ENTRY);
+        write_gdt(&mut emu);
+        emu.gdtr = DescriptorTable {
+            base: GDT,
+            limit: 0x1F,
+        };
+        emu.idtr = DescriptorTable::default();
+        emu.control_registers[0] |= 0x01;
+        let start = ENTRY as usize;
+        emu.memory[start..start + code.len()].copy_from_slice(code);
+        emu
+    }
+
+    fn general_protection(result: Result<(), EmulatorError>) -> u16 {
+        match result {
+            Err(EmulatorError::GeneralProtection(code)) => code,
+            other => panic!("expected #GP, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn far_jump_enters_protected_mode() {
+        let mut emu = Emulator::new_real_mode(0x10000, 0, ENTRY as u16);
+        write_gdt(&mut emu);
+        // GDTR image at 0x0800: limit 0x1F, base 0x1000
+        emu.memory[0x0800..0x0806].copy_from_slice(&[0x1F, 0x00, 0x00, 0x10, 0x00, 0x00]);
+        let code = [
+            0x0F, 0x01, 0x16, 0x00, 0x08, // lgdt [0x0800]
+            0x0F, 0x20, 0xC0, // mov eax, cr0
+            0x0C, 0x01, // or al, 1
+            0x0F, 0x22, 0xC0, // mov cr0, eax
+            0xEA, 0x20, 0x7C, 0x08, 0x00, // jmp 0x0008:0x7C20
+        ];
+        let start = ENTRY as usize;
+        emu.memory[start..start + code.len()].copy_from_slice(&code);
+        // mov eax, 0x12345678 takes a 32-bit immediate in the new code segment.
+        emu.memory[0x7C20..0x7C25].copy_from_slice(&[0xB8, 0x78, 0x56, 0x34, 0x12]);
+
+        for _ in 0..5 {
+            emu.step().unwrap();
+        }
+        assert!(emu.is_protected_mode());
+        assert_eq!(emu.eip, 0x7C20);
+        let cs = emu.segments[SegmentRegister::CS as usize];
+        assert_eq!(cs.selector, CODE32);
+        assert_eq!(cs.base, 0);
+        assert_eq!(cs.limit, 0xFFFF_FFFF);
+        assert!(cs.big);
+        // The accessed bit is set in the GDT entry.
+        assert_eq!(emu.memory[GDT as usize + 8 + 5], 0x9B);
+
+        emu.step().unwrap();
+        assert_eq!(emu.eip, 0x7C25);
+        assert_eq!(emu.get_register32(Register32::EAX as i32), 0x1234_5678);
+    }
+
+    #[test]
+    fn selectors_past_the_gdt_limit_fault() {
+        // mov ds, ax
+        let mut emu = protected(&[0x8E, 0xD8]);
+        emu.set_register16(Register16::AX as i32, 0x0023);
+        let error = emu.step().unwrap_err();
+        assert!(matches!(
+            error.error,
+            EmulatorError::GeneralProtection(0x20)
+        ));
+        assert_eq!(error.eip, ENTRY);
+
+        // Local descriptor tables are not supported. The error code keeps the TI bit.
+        let result = emu.load_segment(SegmentRegister::DS, DATA | 0x04);
+        assert_eq!(general_protection(result), DATA | 0x04);
+    }
+
+    #[test]
+    fn code_selectors_need_readable_data_segments() {
+        let mut emu = protected(&[]);
+
+        let result = emu.load_segment(SegmentRegister::DS, EXECUTE_ONLY);
+        assert_eq!(general_protection(result), EXECUTE_ONLY);
+        let result = emu.load_segment(SegmentRegister::SS, CODE32);
+        assert_eq!(general_protection(result), CODE32);
+        let result = emu.load_segment(SegmentRegister::SS, 0);
+        assert_eq!(general_protection(result), 0);
+
+        // Readable code can be read through DS, but not written.
+        emu.load_segment(SegmentRegister::DS, CODE32).unwrap();
+        assert!(emu
+            .get_segmented(SegmentRegister::DS, 0, OperandSize::Byte)
+            .is_ok());
+        let result = emu.set_segmented(SegmentRegister::DS, 0, 0, OperandSize::Byte);
+        assert_eq!(general_protection(result), 0);
+    }
+
+    #[test]
+    fn accesses_past_the_segment_limit_fault() {
+        // mov al, [0x00FF]; mov ax, [0x00FF]
+        let mut emu = protected(&[
+            0xA0, 0xFF, 0x00, 0x00, 0x00, 0x66, 0xA1, 0xFF, 0x00, 0x00, 0x00,
+        ]);
+        emu.load_segment(SegmentRegister::DS, DATA).unwrap();
+        let ds = emu.segments[SegmentRegister::DS as usize];
+        assert_eq!((ds.base, ds.limit, ds.big), (0x2000, 0xFF, false));
+        emu.memory[0x20FF] = 0x5A;
+
+        emu.step().unwrap();
+        assert_eq!(emu.get_register8(Register32::EAX as i32), 0x5A);
+
+        let error = emu.step().unwrap_err();
+        assert!(matches!(error.error, EmulatorError::GeneralProtection(0)));
+        assert_eq!(error.eip, ENTRY + 5);
+
+        // A null selector loads into DS, but any access through it faults.
+        emu.load_segment(SegmentRegister::DS, 0).unwrap();
+        let result = emu.set_segmented(SegmentRegister::DS, 0, 0, OperandSize::Byte);
+        assert_eq!(general_protection(result), 0);
+    }
+}
@@ -0,0 +1,309 @@
+use crate::emulator::{Emulator, OperandSize, Register8};
+use crate::error::EmulatorError;
+use crate::instruction::modrm::ModRM;
+
+// Shift and rotate operations, numbered as in the reg field of C0, C1 and D0-D3.
+const ROL: u8 = 0;
+const ROR: u8 = 1;
+const RCL: u8 = 2;
+const RCR: u8 = 3;
+const SHL: u8 = 4;
+const SHR: u8 = 5;
+const SAL: u8 = 6;
+const SAR: u8 = 7;
+
+impl Emulator {
+    /// Shifts or rotates `value` by `count` (already masked to 5 bits, non-zero) and
+    /// updates the flags.
+    fn shift_rotate(&mut self, operation: u8, value: u32, count: u32, size: OperandSize) -> u32 {
+        let bits = size.bits();
+        let msb = |v: u32| v & size.sign_bit() != 0;
+
+        match operation {
+            ROL | ROR => {
+                let count = count % bits;
+                let result = if operation == ROL {
+                    (value << count | value >> ((bits - count) % bits)) & size.mask()
+                } else {
+                    (value >> count | value << ((bits - count) % bits)) & size.mask()
+                };
+
+                if operation == ROL {
+                    let carry = result & 1 != 0;
+                    self.set_carry(carry);
+                    self.set_overflow(msb(result) != carry);
+                } else {
+                    self.set_carry(msb(result));
+                    self.set_overflow(msb(result) != msb(result << 1));
+                }
+
+                result
+            }
+            RCL | RCR => {
+                let mut result = value;
+                let mut carry = self.is_carry();
+
+                if operation == RCR {
+                    self.set_overflow(msb(value) != carry);
+                }
+
+                for _ in 0..(count % (bits + 1)) {
+                    if operation == RCL {
+                        let out = msb(result);
+                        result = (result << 1 | carry as u32) & size.mask();
+                        carry = out;
+                    } else {
+                        let out = result & 1 != 0;
+                        result = result >> 1 | (carry as u32) << (bits - 1);
+                        carry = out;
+                    }
+                }
+
+                self.set_carry(carry);
+                if operation == RCL {
+                    self.set_overflow(msb(result) != carry);
+                }
+
+                result
+            }
+            SHL | SAL => {
+                let wide = (value as u64) << count;
+                let result = wide as u32 & size.mask();
+                let carry = (wide >> bits) & 1 != 0;
+                self.update_eflags_shift(result, carry, msb(result) != carry, size);
+                result
+            }
+            SHR => {
+                let result = value >> count;
+                let carry = (value >> (count - 1)) & 1 != 0;
+                self.update_eflags_shift(result, carry, msb(value), size);
+                result
+            }
+            SAR => {
+                let signed = ((value << (32 - bits)) as i32 >> (32 - bits)) as i64;
+                let result = (signed >> count) as u32 & size.mask();
+                let carry = (signed >> (count - 1)) & 1 != 0;
+                self.update_eflags_shift(result, carry, false, size);
+                result
+            }
+            _ => unreachable!("shift operation is a 3-bit field: {}", operation),
+        }
+    }
+
+    fn shift_rm(
+        &mut self,
+        modrm: &ModRM,
+        count: u8,
+        size: OperandSize,
+    ) -> Result<(), EmulatorError> {
+        let count = (count & 0x1F) as u32;
+        if count == 0 {
+            return Ok(());
+        }
+
+        let operation = modrm.reg;
+        let value = self.get_rm(modrm, size)?;
+        let result = self.shift_rotate(operation, value, count, size);
+        self.set_rm(modrm, result, size)
+    }
+
+    fn shift_rm_imm8(&mut self, size: OperandSize) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let count = self.get_code8(0)?;
+        self.advance_eip(1);
+        self.shift_rm(&modrm, count, size)
+    }
+
+    fn shift_rm_1(&mut self, size: OperandSize) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        self.shift_rm(&modrm, 1, size)
+    }
+
+    fn shift_rm_cl(&mut self, size: OperandSize) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let count = self.get_register8(Register8::CL as i32);
+        self.shift_rm(&modrm, count, size)
+    }
+
+    pub fn code_c0(&mut self) -> Result<(), EmulatorError> {
+        self.shift_rm_imm8(OperandSize::Byte)
+    }
+
+    pub fn code_c1(&mut self) -> Result<(), EmulatorError> {
+        self.shift_rm_imm8(self.operand_size())
+    }
+
+    pub fn code_d0(&mut self) -> Result<(), EmulatorError> {
+        self.shift_rm_1(OperandSize::Byte)
+    }
+
+    pub fn code_d1(&mut self) -> Result<(), EmulatorError> {
+        self.shift_rm_1(self.operand_size())
+    }
+
+    pub fn code_d2(&mut self) -> Result<(), EmulatorError> {
+        self.shift_rm_cl(OperandSize::Byte)
+    }
+
+    pub fn code_d3(&mut self) -> Result<(), EmulatorError> {
+        self.shift_rm_cl(self.operand_size())
+    }
+
+    /// Shifts the r/m operand left (SHLD) or right (SHRD), filling the vacated bits from
+    /// the register operand.
+    fn double_shift(
+        &mut self,
+        modrm: &ModRM,
+        count: u8,
+        left: bool,
+        size: OperandSize,
+    ) -> Result<(), EmulatorError> {
+        let count = (count & 0x1F) as u32;
+        if count == 0 {
+            return Ok(());
+        }
+
+        let bits = size.bits();
+        let dest = self.get_rm(modrm, size)? as u128;
+        let src = self.get_r(modrm, size) as u128;
+
+        let (result, carry) = if left {
+            let wide = (dest << bits | src) << count;
+            ((wide >> bits) as u32, (wide >> (2 * bits)) & 1 != 0)
+        } else {
+            let wide = (src << bits | dest) >> (count - 1);
+            ((wide >> 1) as u32, wide & 1 != 0)
+        };
+        let result = result & size.mask();
+
+        let overflow = (result ^ dest as u32) & size.sign_bit() != 0;
+        self.update_eflags_shift(result, carry, overflow, size);
+        self.set_rm(modrm, result, size)
+    }
+
+    pub fn shld_rm32_r32_imm8(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let count = self.get_code8(0)?;
+        self.advance_eip(1);
+        self.double_shift(&modrm, count, true, self.operand_size())
+    }
+
+    pub fn shld_rm32_r32_cl(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let count = self.get_register8(Register8::CL as i32);
+        self.double_shift(&modrm, count, true, self.operand_size())
+    }
+
+    pub fn shrd_rm32_r32_imm8(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let count = self.get_code8(0)?;
+        self.advance_eip(1);
+        self.double_shift(&modrm, count, false, self.operand_size())
+    }
+
+    pub fn shrd_rm32_r32_cl(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let count = self.get_register8(Register8::CL as i32);
+        self.double_shift(&modrm, count, false, self.operand_size())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::{Emulator, Register32, StopReason};
+
+    const ENTRY: u32 = 0x7C00;
+
+    /// Runs flat 32-bit `code` up to the HLT appended to it.
+    fn run(code: &[u8]) -> Emulator {
+        let mut emu = Emulator::new(0x10000, ENTRY, ENTRY);
+        let start = ENTRY as usize;
+        emu.memory[start..start + code.len()].copy_from_slice(code);
+        emu.memory[start + code.len()] = 0xF4;
+        assert_eq!(emu.run().unwrap(), StopReason::Halted);
+        emu
+    }
+
+    fn eax(emu: &Emulator) -> u32 {
+        emu.get_register32(Register32::EAX as i32)
+    }
+
+    #[test]
+    fn zero_count_changes_nothing() {
+        // stc; mov eax, 0x80000001; shl eax, 0; shl eax, 32; shld eax, edx, 32
+        let emu = run(&[
+            0xF9, 0xB8, 0x01, 0x00, 0x00, 0x80, 0xC1, 0xE0, 0x00, 0xC1, 0xE0, 0x20, 0x0F, 0xA4,
+            0xD0, 0x20,
+        ]);
+        assert_eq!(eax(&emu), 0x8000_0001);
+        assert!(emu.is_carry());
+    }
+
+    #[test]
+    fn count_is_masked_to_five_bits() {
+        // mov eax, 1; mov cl, 33; shl eax, cl
+        let emu = run(&[0xB8, 0x01, 0x00, 0x00, 0x00, 0xB1, 0x21, 0xD3, 0xE0]);
+        assert_eq!(eax(&emu), 2);
+        assert!(!emu.is_carry());
+
+        // Byte operands are not masked further: mov al, 0x80; shr al, 8
+        let emu = run(&[0xB0, 0x80, 0xC0, 0xE8, 0x08]);
+        assert_eq!(eax(&emu) & 0xFF, 0);
+        assert!(emu.is_carry() && emu.is_zero());
+    }
+
+    #[test]
+    fn rotate_through_carry_wraps_at_size_plus_one() {
+        // mov al, 0x81; rcl al, 9
+        let emu = run(&[0xB0, 0x81, 0xC0, 0xD0, 0x09]);
+        assert_eq!(eax(&emu) & 0xFF, 0x81);
+        assert!(!emu.is_carry());
+
+        // mov al, 0x81; rcl al, 10
+        let emu = run(&[0xB0, 0x81, 0xC0, 0xD0, 0x0A]);
+        assert_eq!(eax(&emu) & 0xFF, 0x02);
+        assert!(emu.is_carry());
+
+        // stc; mov ax, 0x1234; rcr ax, 17
+        let emu = run(&[0xF9, 0x66, 0xB8, 0x34, 0x12, 0x66, 0xC1, 0xD8, 0x11]);
+        assert_eq!(eax(&emu) & 0xFFFF, 0x1234);
+        assert!(emu.is_carry());
+
+        // stc; mov ax, 0x1234; rcr ax, 1
+        let emu = run(&[0xF9, 0x66, 0xB8, 0x34, 0x12, 0x66, 0xD1, 0xD8]);
+        assert_eq!(eax(&emu) & 0xFFFF, 0x891A);
+        assert!(!emu.is_carry() && emu.is_overflow());
+    }
+
+    #[test]
+    fn double_shifts_fill_from_the_register() {
+        // mov eax, 0x12345678; mov edx, 0x9ABCDEF0; shld eax, edx, 8
+        let shld = [
+            0xB8, 0x78, 0x56, 0x34, 0x12, 0xBA, 0xF0, 0xDE, 0xBC, 0x9A, 0x0F, 0xA4, 0xD0, 0x08,
+        ];
+        let emu = run(&shld);
+        assert_eq!(eax(&emu), 0x3456_789A);
+        assert!(!emu.is_carry());
+
+        // ...; shrd eax, edx, 4
+        let mut shrd = shld.to_vec();
+        shrd.extend([0x0F, 0xAC, 0xD0, 0x04]);
+        let emu = run(&shrd);
+        assert_eq!(eax(&emu), 0x0345_6789);
+        assert!(emu.is_carry());
+
+        // mov ax, 0x8000; mov dx, 0xFFFF; shrd ax, dx, 1, as a 16-bit operation.
+        let emu = run(&[
+            0x66, 0xB8, 0x00, 0x80, 0x66, 0xBA, 0xFF, 0xFF, 0x66, 0x0F, 0xAC, 0xD0, 0x01,
+        ]);
+        assert_eq!(eax(&emu) & 0xFFFF, 0xC000);
+        assert!(!emu.is_carry() && !emu.is_overflow());
+    }
+}
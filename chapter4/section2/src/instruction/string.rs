@@ -0,0 +1,203 @@
+use crate::emulator::{Emulator, OperandSize, Register32, RepeatPrefix, SegmentRegister};
+use crate::error::EmulatorError;
+use crate::instruction::alu::CMP;
+
+impl Emulator {
+    /// Amount SI/ESI and DI/EDI move by after each element: backwards when DF is set.
+    fn string_delta(&self, size: OperandSize) -> u32 {
+        if self.is_direction() {
+            size.bytes().wrapping_neg()
+        } else {
+            size.bytes()
+        }
+    }
+
+    /// Reads SI/ESI, DI/EDI or CX/ECX according to the address size.
+    fn get_string_register(&self, register: Register32) -> u32 {
+        self.get_register(register as i32, self.address_size())
+    }
+
+    fn advance_index(&mut self, register: Register32, size: OperandSize) {
+        let value = self.get_string_register(register);
+        let delta = self.string_delta(size);
+        self.set_register(
+            register as i32,
+            value.wrapping_add(delta),
+            self.address_size(),
+        );
+    }
+
+    /// Processes one element of INS, OUTS, MOVS, CMPS, STOS, LODS or SCAS without
+    /// moving EIP.
+    fn string_iteration(&mut self, code: u8) -> Result<(), EmulatorError> {
+        let size = if code & 1 == 0 {
+            OperandSize::Byte
+        } else {
+            self.operand_size()
+        };
+        // The source segment can be overridden, the destination is always ES.
+        let source = self.prefixes.segment.unwrap_or(SegmentRegister::DS);
+        let esi = self.get_string_register(Register32::ESI);
+        let edi = self.get_string_register(Register32::EDI);
+
+        match code {
+            // The port is in DX.
+            0x6C | 0x6D => {
+                self.check_io_privilege()?;
+                let port = self.get_register(Register32::EDX as i32, OperandSize::Word) as u16;
+                let value = self.io_in(port, size)?;
+                self.set_segmented(SegmentRegister::ES, edi, value, size)?;
+                self.advance_index(Register32::EDI, size);
+            }
+            0x6E | 0x6F => {
+                self.check_io_privilege()?;
+                let port = self.get_register(Register32::EDX as i32, OperandSize::Word) as u16;
+                let value = self.get_segmented(source, esi, size)?;
+                self.io_out(port, value, size)?;
+                self.advance_index(Register32::ESI, size);
+            }
+            0xA4 | 0xA5 => {
+                let value = self.get_segmented(source, esi, size)?;
+                self.set_segmented(SegmentRegister::ES, edi, value, size)?;
+                self.advance_index(Register32::ESI, size);
+                self.advance_index(Register32::EDI, size);
+            }
+            0xA6 | 0xA7 => {
+                let v1 = self.get_segmented(source, esi, size)?;
+                let v2 = self.get_segmented(SegmentRegister::ES, edi, size)?;
+                self.alu(CMP, v1, v2, size);
+                self.advance_index(Register32::ESI, size);
+                self.advance_index(Register32::EDI, size);
+            }
+            0xAA | 0xAB => {
+                let value = self.get_register(Register32::EAX as i32, size);
+                self.set_segmented(SegmentRegister::ES, edi, value, size)?;
+                self.advance_index(Register32::EDI, size);
+            }
+            0xAC | 0xAD => {
+                let value = self.get_segmented(source, esi, size)?;
+                self.set_register(Register32::EAX as i32, value, size);
+                self.advance_index(Register32::ESI, size);
+            }
+            _ => {
+                let value = self.get_register(Register32::EAX as i32, size);
+                let v2 = self.get_segmented(SegmentRegister::ES, edi, size)?;
+                self.alu(CMP, value, v2, size);
+                self.advance_index(Register32::EDI, size);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// INS, OUTS, MOVS, CMPS, STOS, LODS and SCAS, optionally with a REP/REPE/REPNE
+    /// prefix.
+    ///
+    /// A repeated instruction processes one element per step. Until the repetition
+    /// ends, EIP is moved back to the first prefix so the instruction resumes on the
+    /// next step, which lets execution stop between elements like a real CPU taking
+    /// an interrupt.
+    pub fn string(&mut self) -> Result<(), EmulatorError> {
+        let code = self.get_code8(0)?;
+
+        let repeat = match self.prefixes.repeat {
+            Some(repeat) => repeat,
+            None => {
+                self.string_iteration(code)?;
+                self.advance_eip(1);
+                return Ok(());
+            }
+        };
+
+        let count = self.get_string_register(Register32::ECX);
+        if count == 0 {
+            self.advance_eip(1);
+            return Ok(());
+        }
+
+        self.string_iteration(code)?;
+        let count = count - 1;
+        self.set_register(Register32::ECX as i32, count, self.address_size());
+
+        // Only CMPS and SCAS look at ZF: REPE stops when it is clear, REPNE when it is set.
+        let compares = matches!(code, 0xA6 | 0xA7 | 0xAE | 0xAF);
+        let stop_on_zf = compares && (self.is_zero() == (repeat == RepeatPrefix::Repne));
+
+        if count == 0 || stop_on_zf {
+            self.advance_eip(1);
+        } else {
+            self.eip = self.instruction_start;
+            self.repeating = true;
+        }
+
+        Ok(())
+    }
+
+    pub fn cld(&mut self) -> Result<(), EmulatorError> {
+        self.set_direction(false);
+        self.advance_eip(1);
+
+        Ok(())
+    }
+
+    pub fn std(&mut self) -> Result<(), EmulatorError> {
+        self.set_direction(true);
+        self.advance_eip(1);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::{Emulator, Register32, StopReason};
+
+    const ENTRY: u32 = 0x7C00;
+
+    /// Runs flat 32-bit `code` up to the HLT appended to it, with `data` at 0x1000.
+    fn run(code: &[u8], data: &[u8]) -> Emulator {
+        let mut emu = Emulator::new(0x10000, ENTRY, ENTRY);
+        let start = ENTRY as usize;
+        emu.memory[start..start + code.len()].copy_from_slice(code);
+        emu.memory[start + code.len()] = 0xF4;
+        emu.memory[0x1000..0x1000 + data.len()].copy_from_slice(data);
+        assert_eq!(emu.run().unwrap(), StopReason::Halted);
+        emu
+    }
+
+    fn register(emu: &Emulator, register: Register32) -> u32 {
+        emu.get_register32(register as i32)
+    }
+
+    #[test]
+    fn repe_cmps_stops_after_the_first_difference() {
+        // mov esi, 0x1000; mov edi, 0x1008; mov ecx, 8; repe cmpsb
+        let emu = run(
+            &[
+                0xBE, 0x00, 0x10, 0x00, 0x00, 0xBF, 0x08, 0x10, 0x00, 0x00, 0xB9, 0x08, 0x00, 0x00,
+                0x00, 0xF3, 0xA6,
+            ],
+            b"abcdXfghabcdYfgh",
+        );
+        assert_eq!(register(&emu, Register32::ECX), 3);
+        assert_eq!(register(&emu, Register32::ESI), 0x1005);
+        assert_eq!(register(&emu, Register32::EDI), 0x100D);
+        assert!(!emu.is_zero() && emu.is_carry());
+    }
+
+    #[test]
+    fn rep_movs_runs_backwards_with_df_set() {
+        // std; mov esi, 0x1003; mov edi, 0x2003; mov ecx, 4; rep movsb
+        let emu = run(
+            &[
+                0xFD, 0xBE, 0x03, 0x10, 0x00, 0x00, 0xBF, 0x03, 0x20, 0x00, 0x00, 0xB9, 0x04, 0x00,
+                0x00, 0x00, 0xF3, 0xA4,
+            ],
+            b"abcd",
+        );
+        assert_eq!(&emu.memory[0x2000..0x2004], b"abcd");
+        assert_eq!(register(&emu, Register32::ECX), 0);
+        assert_eq!(register(&emu, Register32::ESI), 0x0FFF);
+        assert_eq!(register(&emu, Register32::EDI), 0x1FFF);
+    }
+}
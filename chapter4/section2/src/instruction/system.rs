@@ -0,0 +1,190 @@
+use crate::emulator::{DescriptorTable, Emulator, OperandSize};
+use crate::error::EmulatorError;
+use crate::paging::CR0_PG;
+
+impl Emulator {
+    /// Instructions restricted to ring 0 raise #GP(0) at any other privilege level.
+    fn check_privileged(&self) -> Result<(), EmulatorError> {
+        if self.cpl() == 0 {
+            Ok(())
+        } else {
+            Err(EmulatorError::GeneralProtection(0))
+        }
+    }
+
+    /// SGDT, SIDT, LGDT, LIDT, SMSW, LMSW and INVLPG (0F 01 /0, /1, /2, /3, /4, /6, /7).
+    pub fn code_0f_01(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let reg = modrm.reg;
+        let invalid = EmulatorError::InvalidOpcode {
+            opcode: 0x0F01,
+            modrm_reg: Some(reg),
+        };
+
+        match reg {
+            0..=3 => {
+                if modrm.m == 3 {
+                    return Err(invalid);
+                }
+                let address = self.calc_memory_address(&modrm)?;
+                let base_address = address.wrapping_add(2);
+
+                if reg < 2 {
+                    let table = if reg == 0 { self.gdtr } else { self.idtr };
+                    let limit = table.limit as u32;
+                    self.set_segmented(modrm.segment, address, limit, OperandSize::Word)?;
+                    self.set_segmented(modrm.segment, base_address, table.base, OperandSize::Dword)
+                } else {
+                    self.check_privileged()?;
+                    let limit = self.get_segmented(modrm.segment, address, OperandSize::Word)?;
+                    let mut base =
+                        self.get_segmented(modrm.segment, base_address, OperandSize::Dword)?;
+                    // With a 16-bit operand size only 24 bits of the base are loaded.
+                    if self.operand_size() == OperandSize::Word {
+                        base &= 0x00FF_FFFF;
+                    }
+
+                    let table = DescriptorTable {
+                        base,
+                        limit: limit as u16,
+                    };
+                    if reg == 2 {
+                        self.gdtr = table;
+                    } else {
+                        self.idtr = table;
+                    }
+                    Ok(())
+                }
+            }
+            4 => {
+                let msw = self.control_registers[0] & 0xFFFF;
+                let size = if modrm.m == 3 {
+                    self.operand_size()
+                } else {
+                    OperandSize::Word
+                };
+                self.set_rm(&modrm, msw, size)
+            }
+            6 => {
+                self.check_privileged()?;
+                // LMSW can set PE but never clears it.
+                let msw = self.get_rm(&modrm, OperandSize::Word)? & 0x0F;
+                let cr0 = self.control_registers[0];
+                self.set_control_register(0, (cr0 & !0x0E) | msw | (cr0 & 0x01))
+            }
+            7 if modrm.m != 3 => {
+                self.check_privileged()?;
+                let address = self.calc_memory_address(&modrm)?;
+                self.invalidate_page(self.linear_address(modrm.segment, address));
+                Ok(())
+            }
+            _ => Err(invalid),
+        }
+    }
+
+    /// Writes CR0, CR2, CR3 or CR4. Writing CR0, CR3 or CR4 flushes the TLB, and
+    /// enabling paging outside protected mode raises #GP(0).
+    fn set_control_register(&mut self, index: u8, value: u32) -> Result<(), EmulatorError> {
+        if index == 0 && value & CR0_PG != 0 && value & 0x01 == 0 {
+            return Err(EmulatorError::GeneralProtection(0));
+        }
+
+        self.control_registers[index as usize] = value;
+        if index != 2 {
+            self.flush_tlb();
+        }
+
+        Ok(())
+    }
+
+    /// Decodes the ModR/M byte of MOV to or from a control register. The mod field is
+    /// ignored: the other operand is always a 32-bit general register.
+    fn control_register_operands(&mut self, opcode: u16) -> Result<(u8, i32), EmulatorError> {
+        let code = self.get_code8(0)?;
+        self.advance_eip(1);
+
+        let index = (code >> 3) & 0x07;
+        if !matches!(index, 0 | 2 | 3 | 4) {
+            return Err(EmulatorError::InvalidOpcode {
+                opcode,
+                modrm_reg: Some(index),
+            });
+        }
+        self.check_privileged()?;
+
+        Ok((index, (code & 0x07) as i32))
+    }
+
+    /// MOV r32, CRn (0F 20).
+    pub fn mov_r32_cr(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let (index, reg) = self.control_register_operands(0x0F20)?;
+        self.set_register32(reg, self.control_registers[index as usize]);
+
+        Ok(())
+    }
+
+    /// MOV CRn, r32 (0F 22).
+    pub fn mov_cr_r32(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let (index, reg) = self.control_register_operands(0x0F22)?;
+        self.set_control_register(index, self.get_register32(reg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTRY: usize = 0x7C00;
+
+    fn real_mode(code: &[u8]) -> Emulator {
+        let mut emu = Emulator::new_real_mode(0x10000, 0, ENTRY as u16);
+        emu.memory[ENTRY..ENTRY + code.len()].copy_from_slice(code);
+        emu
+    }
+
+    #[test]
+    fn descriptor_tables_round_trip() {
+        let mut emu = real_mode(&[
+            0x66, 0x0F, 0x01, 0x16, 0x00, 0x08, // lgdt [0x0800]
+            0x66, 0x0F, 0x01, 0x1E, 0x08, 0x08, // lidt [0x0808]
+            0x0F, 0x01, 0x06, 0x10, 0x08, // sgdt [0x0810]
+            0x0F, 0x01, 0x0E, 0x18, 0x08, // sidt [0x0818]
+        ]);
+        let gdtr = [0x27, 0x00, 0x78, 0x56, 0x34, 0x12];
+        let idtr = [0xFF, 0x07, 0x00, 0x00, 0x0F, 0x00];
+        emu.memory[0x0800..0x0806].copy_from_slice(&gdtr);
+        emu.memory[0x0808..0x080E].copy_from_slice(&idtr);
+
+        for _ in 0..4 {
+            emu.step().unwrap();
+        }
+        assert_eq!(
+            emu.gdtr,
+            DescriptorTable {
+                base: 0x1234_5678,
+                limit: 0x27
+            }
+        );
+        assert_eq!(
+            emu.idtr,
+            DescriptorTable {
+                base: 0x000F_0000,
+                limit: 0x07FF
+            }
+        );
+        assert_eq!(emu.memory[0x0810..0x0816], gdtr);
+        assert_eq!(emu.memory[0x0818..0x081E], idtr);
+    }
+
+    #[test]
+    fn a_16_bit_lgdt_loads_24_bits_of_base() {
+        // lgdt [0x0800]
+        let mut emu = real_mode(&[0x0F, 0x01, 0x16, 0x00, 0x08]);
+        emu.memory[0x0800..0x0806].copy_from_slice(&[0x27, 0x00, 0x78, 0x56, 0x34, 0x12]);
+        emu.step().unwrap();
+        assert_eq!(emu.gdtr.base, 0x0034_5678);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::emulator::{Emulator, OperandSize};
+use crate::error::EmulatorError;
+use crate::instruction::modrm::ModRM;
+
+// Bit test operations, numbered as in the reg field of 0F BA and bits 3-4 of 0F A3/AB/B3/BB.
+const BT: u8 = 4;
+const BTS: u8 = 5;
+const BTR: u8 = 6;
+const BTC: u8 = 7;
+
+impl Emulator {
+    pub fn jcc_rel32(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let condition = self.get_code8(0)? & 0x0F;
+        let diff = if self.is_condition(condition) {
+            self.get_sign_code(1, size)?
+        } else {
+            0
+        };
+        self.jump_relative(1 + size.bytes(), diff);
+
+        Ok(())
+    }
+
+    pub fn setcc_rm8(&mut self) -> Result<(), EmulatorError> {
+        let condition = self.get_code8(0)? & 0x0F;
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        self.set_rm8(&modrm, self.is_condition(condition) as u8)
+    }
+
+    pub fn cmovcc_r32_rm32(&mut self) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        let condition = self.get_code8(0)? & 0x0F;
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let rm = self.get_rm(&modrm, size)?;
+
+        if self.is_condition(condition) {
+            self.set_r(&modrm, rm, size);
+        }
+
+        Ok(())
+    }
+
+    fn movx(&mut self, size: OperandSize, sign_extend: bool) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let value = self.get_rm(&modrm, size)?;
+
+        let value = if sign_extend {
+            Emulator::sign_extend(value, size) as u32
+        } else {
+            value
+        };
+        self.set_r(&modrm, value, self.operand_size());
+
+        Ok(())
+    }
+
+    pub fn movzx_r32_rm8(&mut self) -> Result<(), EmulatorError> {
+        self.movx(OperandSize::Byte, false)
+    }
+
+    pub fn movzx_r32_rm16(&mut self) -> Result<(), EmulatorError> {
+        self.movx(OperandSize::Word, false)
+    }
+
+    pub fn movsx_r32_rm8(&mut self) -> Result<(), EmulatorError> {
+        self.movx(OperandSize::Byte, true)
+    }
+
+    pub fn movsx_r32_rm16(&mut self) -> Result<(), EmulatorError> {
+        self.movx(OperandSize::Word, true)
+    }
+
+    /// Copies the selected bit into CF and then sets, clears or complements it.
+    ///
+    /// With a register bit offset, a memory operand is treated as a bit string starting
+    /// at the effective address, so the offset may select a bit outside that dword.
+    fn bit_test(
+        &mut self,
+        modrm: &ModRM,
+        operation: u8,
+        offset: u32,
+        from_register: bool,
+    ) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+
+        let (value, address) = if modrm.m == 3 {
+            (self.get_rm(modrm, size)?, None)
+        } else {
+            let mut address = self.calc_memory_address(modrm)?;
+            if from_register {
+                let offset = Emulator::sign_extend(offset, size) as i32;
+                let bits = size.bits() as i32;
+                address = address.wrapping_add((offset.div_euclid(bits) * (bits / 8)) as u32);
+            }
+            (
+                self.get_segmented(modrm.segment, address, size)?,
+                Some(address),
+            )
+        };
+
+        let mask = 1 << (offset & (size.bits() - 1));
+        self.set_carry(value & mask != 0);
+
+        let result = match operation {
+            BTS => value | mask,
+            BTR => value & !mask,
+            BTC => value ^ mask,
+            _ => return Ok(()),
+        };
+
+        match address {
+            Some(address) => self.set_segmented(modrm.segment, address, result, size),
+            None => self.set_rm(modrm, result, size),
+        }
+    }
+
+    pub fn bt_rm32_r32(&mut self) -> Result<(), EmulatorError> {
+        let operation = ((self.get_code8(0)? >> 3) & 0x03) | 0x04;
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let offset = self.get_r(&modrm, self.operand_size());
+        self.bit_test(&modrm, operation, offset, true)
+    }
+
+    pub fn code_0f_ba(&mut self) -> Result<(), EmulatorError> {
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let offset = self.get_code8(0)? as u32;
+        self.advance_eip(1);
+
+        match modrm.reg {
+            operation @ (BT | BTS | BTR | BTC) => self.bit_test(&modrm, operation, offset, false),
+            reg => Err(EmulatorError::InvalidOpcode {
+                opcode: 0x0FBA,
+                modrm_reg: Some(reg),
+            }),
+        }
+    }
+
+    /// BSF and BSR: ZF is set and the destination left unchanged when the source is 0.
+    fn bit_scan(&mut self, reverse: bool) -> Result<(), EmulatorError> {
+        let size = self.operand_size();
+        self.advance_eip(1);
+        let modrm = self.parse_modrm()?;
+        let value = self.get_rm(&modrm, size)?;
+
+        self.set_zero(value == 0);
+        if value != 0 {
+            let index = if reverse {
+                31 - value.leading_zeros()
+            } else {
+                value.trailing_zeros()
+            };
+            self.set_r(&modrm, index, size);
+        }
+
+        Ok(())
+    }
+
+    pub fn bsf_r32_rm32(&mut self) -> Result<(), EmulatorError> {
+        self.bit_scan(false)
+    }
+
+    pub fn bsr_r32_rm32(&mut self) -> Result<(), EmulatorError> {
+        self.bit_scan(true)
+    }
+
+    pub fn bswap_r32(&mut self) -> Result<(), EmulatorError> {
+        let reg = self.get_code8(0)? - 0xC8;
+        let value = self.get_register32(reg as i32);
+        self.set_register32(reg as i32, value.swap_bytes());
+        self.advance_eip(1);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::{Emulator, Register32, Register8, StopReason};
+
+    const ENTRY: u32 = 0x7C00;
+
+    #[test]
+    fn extensions_setcc_and_near_jcc() {
+        // mov bl, 0x80; movsx eax, bl; movzx ecx, bl; cmp eax, ecx; setl dl;
+        // jne +1; hlt; mov dh, 7; hlt
+        let code = [
+            0xB3, 0x80, 0x0F, 0xBE, 0xC3, 0x0F, 0xB6, 0xCB, 0x39, 0xC8, 0x0F, 0x9C, 0xC2, 0x0F,
+            0x85, 0x01, 0x00, 0x00, 0x00, 0xF4, 0xB6, 0x07, 0xF4,
+        ];
+        let mut emu = Emulator::new(0x10000, ENTRY, ENTRY);
+        let start = ENTRY as usize;
+        emu.memory[start..start + code.len()].copy_from_slice(&code);
+        assert_eq!(emu.run().unwrap(), StopReason::Halted);
+
+        assert_eq!(emu.get_register32(Register32::EAX as i32), 0xFFFF_FF80);
+        assert_eq!(emu.get_register32(Register32::ECX as i32), 0x0000_0080);
+        assert_eq!(emu.get_register8(Register8::DL as i32), 1);
+        assert_eq!(emu.get_register8(Register8::DH as i32), 7);
+    }
+}
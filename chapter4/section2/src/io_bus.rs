@@ -0,0 +1,306 @@
+use crate::emulator::OperandSize;
+use crate::error::EmulatorError;
+
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+/// A device answering IN and OUT on the ports it is registered for.
+///
+/// Only the byte accessors are required. 16- and 32-bit accesses default to
+/// consecutive byte accesses on consecutive ports, lowest port first.
+pub trait PortDevice {
+    fn read8(&mut self, port: u16) -> Result<u8, EmulatorError>;
+
+    fn write8(&mut self, port: u16, value: u8) -> Result<(), EmulatorError>;
+
+    fn read16(&mut self, port: u16) -> Result<u16, EmulatorError> {
+        let low = self.read8(port)? as u16;
+        let high = self.read8(port.wrapping_add(1))? as u16;
+        Ok(low | (high << 8))
+    }
+
+    fn write16(&mut self, port: u16, value: u16) -> Result<(), EmulatorError> {
+        self.write8(port, value as u8)?;
+        self.write8(port.wrapping_add(1), (value >> 8) as u8)
+    }
+
+    fn read32(&mut self, port: u16) -> Result<u32, EmulatorError> {
+        let low = self.read16(port)? as u32;
+        let high = self.read16(port.wrapping_add(2))? as u32;
+        Ok(low | (high << 16))
+    }
+
+    fn write32(&mut self, port: u16, value: u32) -> Result<(), EmulatorError> {
+        self.write16(port, value as u16)?;
+        self.write16(port.wrapping_add(2), (value >> 16) as u16)
+    }
+
+    /// Called before every instruction, so the device can take in host input and
+    /// update its interrupt request line.
+    fn poll(&mut self) -> Result<(), EmulatorError> {
+        Ok(())
+    }
+}
+
+/// A device shared with the emulator, such as the interrupt controller, or registered
+/// for several port ranges.
+impl<T: PortDevice> PortDevice for Rc<RefCell<T>> {
+    fn read8(&mut self, port: u16) -> Result<u8, EmulatorError> {
+        self.borrow_mut().read8(port)
+    }
+
+    fn write8(&mut self, port: u16, value: u8) -> Result<(), EmulatorError> {
+        self.borrow_mut().write8(port, value)
+    }
+
+    fn read16(&mut self, port: u16) -> Result<u16, EmulatorError> {
+        self.borrow_mut().read16(port)
+    }
+
+    fn write16(&mut self, port: u16, value: u16) -> Result<(), EmulatorError> {
+        self.borrow_mut().write16(port, value)
+    }
+
+    fn read32(&mut self, port: u16) -> Result<u32, EmulatorError> {
+        self.borrow_mut().read32(port)
+    }
+
+    fn write32(&mut self, port: u16, value: u32) -> Result<(), EmulatorError> {
+        self.borrow_mut().write32(port, value)
+    }
+
+    fn poll(&mut self) -> Result<(), EmulatorError> {
+        self.borrow_mut().poll()
+    }
+}
+
+/// What an access to a port no device is registered for does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnmappedPortPolicy {
+    /// Reads return all ones and writes are dropped, like an empty ISA bus.
+    Ignore,
+    /// As `Ignore`, and the access is reported on stderr.
+    Log,
+    /// The access fails with `EmulatorError::UnhandledPort`.
+    Error,
+}
+
+struct Mapping {
+    ports: RangeInclusive<u16>,
+    device: Box<dyn PortDevice>,
+}
+
+/// The I/O port address space: devices registered for port ranges.
+pub struct IoBus {
+    mappings: Vec<Mapping>,
+    pub policy: UnmappedPortPolicy,
+}
+
+impl fmt::Debug for IoBus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IoBus")
+            .field(
+                "ports",
+                &self.mappings.iter().map(|m| &m.ports).collect::<Vec<_>>(),
+            )
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+impl IoBus {
+    pub fn new(policy: UnmappedPortPolicy) -> IoBus {
+        IoBus {
+            mappings: Vec::new(),
+            policy,
+        }
+    }
+
+    /// Registers `device` for `ports`. Fails with `EmulatorError::PortInUse` naming
+    /// the first shared port if another device is registered for any of them.
+    pub fn register(
+        &mut self,
+        ports: RangeInclusive<u16>,
+        device: Box<dyn PortDevice>,
+    ) -> Result<(), EmulatorError> {
+        if let Some(mapping) = self
+            .mappings
+            .iter()
+            .find(|m| m.ports.start() <= ports.end() && ports.start() <= m.ports.end())
+        {
+            let port = *ports.start().max(mapping.ports.start());
+            return Err(EmulatorError::PortInUse(port));
+        }
+        self.mappings.push(Mapping { ports, device });
+
+        Ok(())
+    }
+
+    /// Removes and returns the device registered for exactly `ports`, so another can
+    /// take its place.
+    pub fn unregister(&mut self, ports: RangeInclusive<u16>) -> Option<Box<dyn PortDevice>> {
+        let index = self.mappings.iter().position(|m| m.ports == ports)?;
+        Some(self.mappings.remove(index).device)
+    }
+
+    fn device(&mut self, port: u16) -> Option<&mut Box<dyn PortDevice>> {
+        self.mappings
+            .iter_mut()
+            .find(|m| m.ports.contains(&port))
+            .map(|m| &mut m.device)
+    }
+
+    fn unmapped(&self, port: u16, access: &str) -> Result<(), EmulatorError> {
+        match self.policy {
+            UnmappedPortPolicy::Ignore => Ok(()),
+            UnmappedPortPolicy::Log => {
+                eprintln!("unmapped I/O port {}: {:04X}", access, port);
+                Ok(())
+            }
+            UnmappedPortPolicy::Error => Err(EmulatorError::UnhandledPort(port)),
+        }
+    }
+
+    /// Polls every registered device.
+    pub fn poll(&mut self) -> Result<(), EmulatorError> {
+        for mapping in &mut self.mappings {
+            mapping.device.poll()?;
+        }
+        Ok(())
+    }
+
+    /// Reads `size` bytes from `port`. The device registered for the first port
+    /// serves the whole access.
+    pub fn read(&mut self, port: u16, size: OperandSize) -> Result<u32, EmulatorError> {
+        match self.device(port) {
+            Some(device) => match size {
+                OperandSize::Byte => Ok(device.read8(port)? as u32),
+                OperandSize::Word => Ok(device.read16(port)? as u32),
+                OperandSize::Dword => device.read32(port),
+            },
+            None => {
+                self.unmapped(port, "read")?;
+                Ok(size.mask())
+            }
+        }
+    }
+
+    /// Writes the low `size` bytes of `value` to `port`.
+    pub fn write(&mut self, port: u16, value: u32, size: OperandSize) -> Result<(), EmulatorError> {
+        match self.device(port) {
+            Some(device) => match size {
+                OperandSize::Byte => device.write8(port, value as u8),
+                OperandSize::Word => device.write16(port, value as u16),
+                OperandSize::Dword => device.write32(port, value),
+            },
+            None => self.unmapped(port, "write"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads return the low byte of the port and writes are recorded.
+    #[derive(Default)]
+    struct Recorder {
+        writes: Vec<(u16, u8)>,
+    }
+
+    impl PortDevice for Recorder {
+        fn read8(&mut self, port: u16) -> Result<u8, EmulatorError> {
+            Ok(port as u8)
+        }
+
+        fn write8(&mut self, port: u16, value: u8) -> Result<(), EmulatorError> {
+            self.writes.push((port, value));
+            Ok(())
+        }
+    }
+
+    fn bus_with_recorder() -> (IoBus, Rc<RefCell<Recorder>>) {
+        let mut bus = IoBus::new(UnmappedPortPolicy::Error);
+        let recorder = Rc::new(RefCell::new(Recorder::default()));
+        bus.register(0x0100..=0x0107, Box::new(recorder.clone()))
+            .unwrap();
+        (bus, recorder)
+    }
+
+    #[test]
+    fn accesses_of_every_width_reach_the_device() {
+        let (mut bus, recorder) = bus_with_recorder();
+
+        assert_eq!(bus.read(0x0105, OperandSize::Byte).unwrap(), 0x05);
+        assert_eq!(bus.read(0x0102, OperandSize::Word).unwrap(), 0x0302);
+        assert_eq!(bus.read(0x0104, OperandSize::Dword).unwrap(), 0x0706_0504);
+
+        bus.write(0x0107, 0x11, OperandSize::Byte).unwrap();
+        bus.write(0x0100, 0x3322, OperandSize::Word).unwrap();
+        bus.write(0x0104, 0x7766_5544, OperandSize::Dword).unwrap();
+        assert_eq!(
+            recorder.borrow().writes,
+            [
+                (0x0107, 0x11),
+                (0x0100, 0x22),
+                (0x0101, 0x33),
+                (0x0104, 0x44),
+                (0x0105, 0x55),
+                (0x0106, 0x66),
+                (0x0107, 0x77),
+            ]
+        );
+    }
+
+    #[test]
+    fn overlapping_registrations_are_rejected() {
+        let (mut bus, _) = bus_with_recorder();
+
+        for (ports, shared) in [
+            (0x0107..=0x0108, 0x0107),
+            (0x00F0..=0x0100, 0x0100),
+            (0x0102..=0x0103, 0x0102),
+            (0x0000..=0xFFFF, 0x0100),
+        ] {
+            match bus.register(ports, Box::new(Recorder::default())) {
+                Err(EmulatorError::PortInUse(port)) => assert_eq!(port, shared),
+                other => panic!("expected PortInUse, got {:?}", other),
+            }
+        }
+        bus.register(0x0108..=0x010F, Box::new(Recorder::default()))
+            .unwrap();
+
+        // A device is replaced by unregistering it first.
+        assert!(bus.unregister(0x0100..=0x0103).is_none());
+        assert!(bus.unregister(0x0100..=0x0107).is_some());
+        assert!(bus.read(0x0100, OperandSize::Byte).is_err());
+        bus.register(0x0100..=0x0107, Box::new(Recorder::default()))
+            .unwrap();
+        assert_eq!(bus.read(0x0100, OperandSize::Byte).unwrap(), 0x00);
+    }
+
+    #[test]
+    fn unmapped_ports_follow_the_policy() {
+        let (mut bus, _) = bus_with_recorder();
+
+        for policy in [UnmappedPortPolicy::Ignore, UnmappedPortPolicy::Log] {
+            bus.policy = policy;
+            assert_eq!(bus.read(0x0300, OperandSize::Byte).unwrap(), 0xFF);
+            assert_eq!(bus.read(0x0300, OperandSize::Word).unwrap(), 0xFFFF);
+            assert_eq!(bus.read(0x0300, OperandSize::Dword).unwrap(), 0xFFFF_FFFF);
+            bus.write(0x0300, 0, OperandSize::Byte).unwrap();
+        }
+
+        bus.policy = UnmappedPortPolicy::Error;
+        assert!(matches!(
+            bus.read(0x0300, OperandSize::Byte),
+            Err(EmulatorError::UnhandledPort(0x0300))
+        ));
+        assert!(matches!(
+            bus.write(0x0300, 0, OperandSize::Word),
+            Err(EmulatorError::UnhandledPort(0x0300))
+        ));
+    }
+}
@@ -0,0 +1,85 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+#[derive(Debug, Default)]
+struct Lines {
+    levels: Cell<u16>,
+    /// Pulses not yet seen by the interrupt controller.
+    pulses: Cell<u16>,
+}
+
+/// Levels of the 16 ISA interrupt request lines, shared between the devices driving
+/// them and the interrupt controller sampling them. Clones refer to the same lines.
+#[derive(Clone, Debug, Default)]
+pub struct IrqLines(Rc<Lines>);
+
+impl IrqLines {
+    pub fn new() -> IrqLines {
+        IrqLines::default()
+    }
+
+    /// Handle a device drives line `irq` (0 to 15) with.
+    pub fn line(&self, irq: u8) -> IrqLine {
+        assert!(irq < 16, "no IRQ line {}", irq);
+        IrqLine {
+            lines: self.clone(),
+            irq,
+        }
+    }
+
+    /// Current levels, bit n holding IRQ n.
+    pub fn levels(&self) -> u16 {
+        self.0.levels.get()
+    }
+
+    pub fn is_raised(&self, irq: u8) -> bool {
+        self.levels() & (1 << irq) != 0
+    }
+
+    /// Lines pulsed since the last call, bit n holding IRQ n.
+    pub fn take_pulses(&self) -> u16 {
+        self.0.pulses.take()
+    }
+}
+
+/// A single interrupt request line, driven by one device.
+#[derive(Clone, Debug)]
+pub struct IrqLine {
+    lines: IrqLines,
+    irq: u8,
+}
+
+impl IrqLine {
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+
+    pub fn set(&self, level: bool) {
+        let bit = 1 << self.irq;
+        let levels = &self.lines.0.levels;
+        levels.set(if level {
+            levels.get() | bit
+        } else {
+            levels.get() & !bit
+        });
+    }
+
+    pub fn raise(&self) {
+        self.set(true);
+    }
+
+    pub fn lower(&self) {
+        self.set(false);
+    }
+
+    /// Gives the controller a rising edge without changing the level, for outputs
+    /// that drop for a moment too short to sample, such as the timer's.
+    pub fn pulse(&self) {
+        let pulses = &self.lines.0.pulses;
+        pulses.set(pulses.get() | (1 << self.irq));
+    }
+
+    pub fn is_raised(&self) -> bool {
+        self.lines.is_raised(self.irq)
+    }
+}
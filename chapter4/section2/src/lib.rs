@@ -0,0 +1,31 @@
+mod clock;
+mod console;
+mod device;
+mod emulator;
+mod emulator_function;
+mod error;
+mod instruction;
+mod io_bus;
+mod irq;
+mod memory_map;
+mod paging;
+mod scheduler;
+
+pub use clock::{Clock, ClockMode, NANOS_PER_INSTRUCTION};
+pub use console::{ConsoleSerial, HostConsole};
+pub use device::{
+    A20Gate, BootDevice, HostInput, IdeChannel, IdeDisk, KeyboardController, NullSerial, PicPair,
+    Pit, PtySerial, Rtc, SerialBackend, StdioSerial, StreamSerial, SystemControlPort,
+    SystemControlPortA, Uart16550, UnixSocketSerial, PIT_FREQUENCY,
+};
+pub use emulator::{
+    DescriptorTable, Emulator, OperandSize, Prefixes, Register16, Register32, Register8,
+    RepeatPrefix, Segment, SegmentRegister, StepOutcome, StopReason,
+};
+pub use error::{EmulatorError, ExecutionError, OpcodeName};
+pub use instruction::{InstructionFunctions, InterruptHook, New};
+pub use io_bus::{IoBus, PortDevice, UnmappedPortPolicy};
+pub use irq::{IrqLine, IrqLines};
+pub use memory_map::{MemoryMap, MmioDevice, RomWritePolicy};
+pub use paging::Access;
+pub use scheduler::{EventCallback, EventId, Scheduler};
@@ -1,13 +1,48 @@
-mod emulator;
-mod emulator_function;
-mod instruction;
-
-use crate::instruction::New;
 use clap::{App, Arg};
-use emulator::Emulator;
-use instruction::InstructionFunctions;
+use px86::{
+    ClockMode, Emulator, HostConsole, IdeDisk, OpcodeName, PtySerial, Register32, SerialBackend,
+    StepOutcome, StopReason, StreamSerial, Uart16550, UnixSocketSerial, UnmappedPortPolicy,
+};
 use std::fs::File;
+use std::io;
 use std::io::{BufReader, Read};
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Opens the host end of COM1 named by `--serial`. The console is only opened, and the
+/// terminal only switched to raw mode, when COM1 or the keyboard uses it. With
+/// `keyboard` set the keystrokes go to the keyboard, and COM1 on stdio only writes.
+fn open_serial(
+    spec: &str,
+    keyboard: bool,
+    console: &mut Option<HostConsole>,
+) -> io::Result<Box<dyn SerialBackend>> {
+    if spec == "pty" {
+        let pty = PtySerial::open()?;
+        eprintln!("COM1 is on {}", pty.path().display());
+        Ok(Box::new(pty))
+    } else if let Some(path) = spec.strip_prefix("unix:") {
+        Ok(Box::new(UnixSocketSerial::bind(path)?))
+    } else if let Some(files) = spec.strip_prefix("file:") {
+        let (input, output) = files.split_once(',').ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "expected file:INPUT,OUTPUT")
+        })?;
+        Ok(Box::new(StreamSerial::files(input, output)?))
+    } else if spec == "stdio" && keyboard {
+        Ok(Box::new(StreamSerial::new(
+            io::empty(),
+            Box::new(io::stdout()),
+        )))
+    } else if spec == "stdio" {
+        let console = console.insert(HostConsole::open()?);
+        Ok(Box::new(console.serial()))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "expected stdio, pty, unix:PATH or file:INPUT,OUTPUT",
+        ))
+    }
+}
 
 fn main() {
     const MEMORY_SIZE: usize = 1_000_000;
@@ -23,6 +58,56 @@ fn main() {
                 .long("quiet")
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("real-mode")
+                .long("real-mode")
+                .help("Boots the image in real mode at 0000:7C00 like a BIOS")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("unmapped-ports")
+                .long("unmapped-ports")
+                .help("What accesses to I/O ports without a device do")
+                .possible_values(&["ignore", "log", "error"])
+                .default_value("error")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("clock")
+                .long("clock")
+                .help("What emulated time follows: executed instructions or the host clock")
+                .possible_values(&["virtual", "host"])
+                .default_value("virtual")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rtc-time")
+                .long("rtc-time")
+                .value_name("SECONDS")
+                .help("Unix time the real-time clock starts at instead of the host's time")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("serial")
+                .long("serial")
+                .value_name("BACKEND")
+                .help("Host end of COM1: stdio, pty, unix:PATH or file:INPUT,OUTPUT")
+                .default_value("stdio")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("keyboard")
+                .long("keyboard")
+                .help("Types the host's keystrokes on the PS/2 keyboard instead of COM1")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("disk")
+                .long("disk")
+                .value_name("IMAGE")
+                .help("Raw disk image attached as the master on the primary IDE channel")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("filename")
                 .value_name("FILE")
@@ -33,36 +118,106 @@ fn main() {
 
     let path = matches.value_of("filename").unwrap();
 
-    let mut emu = Emulator::new(MEMORY_SIZE, PROGRAM_HEAD as u32, PROGRAM_HEAD as u32);
+    let mut emu = if matches.is_present("real-mode") {
+        let mut emu = Emulator::new_real_mode(MEMORY_SIZE, 0, PROGRAM_HEAD as u16);
+        emu.set_register32(Register32::ESP as i32, PROGRAM_HEAD as u32);
+        emu
+    } else {
+        Emulator::new(MEMORY_SIZE, PROGRAM_HEAD as u32, PROGRAM_HEAD as u32)
+    };
 
-    let functions = InstructionFunctions::new();
+    emu.io.policy = match matches.value_of("unmapped-ports") {
+        Some("ignore") => UnmappedPortPolicy::Ignore,
+        Some("log") => UnmappedPortPolicy::Log,
+        _ => UnmappedPortPolicy::Error,
+    };
+
+    if matches.value_of("clock") == Some("host") {
+        emu.clock.set_mode(ClockMode::Host);
+    }
 
-    let f = File::open(path).expect(&format!("File {} not found", path));
+    let rtc_time = match matches.value_of("rtc-time") {
+        Some(seconds) => {
+            let seconds = seconds.parse().unwrap_or_else(|_| {
+                eprintln!("invalid --rtc-time: {}", seconds);
+                process::exit(1);
+            });
+            UNIX_EPOCH + Duration::from_secs(seconds)
+        }
+        None => SystemTime::now(),
+    };
+    emu.rtc.borrow_mut().set_time(rtc_time);
+
+    if let Some(image) = matches.value_of("disk") {
+        let disk = IdeDisk::open(image).unwrap_or_else(|e| {
+            eprintln!("cannot open disk image {}: {}", image, e);
+            process::exit(1);
+        });
+        emu.ide.borrow_mut().attach(0, disk);
+    }
+
+    let serial = matches.value_of("serial").unwrap();
+    let keyboard = matches.is_present("keyboard");
+    let mut console = None;
+    if keyboard {
+        let opened = HostConsole::open().unwrap_or_else(|e| {
+            eprintln!("cannot open the console: {}", e);
+            process::exit(1);
+        });
+        emu.keyboard.borrow_mut().connect(opened.input());
+        console = Some(opened);
+    }
+    let backend = open_serial(serial, keyboard, &mut console).unwrap_or_else(|e| {
+        eprintln!("cannot open serial backend {}: {}", serial, e);
+        process::exit(1);
+    });
+    let com1 = Uart16550::new(backend, emu.irq.line(4), emu.scheduler.clone());
+    emu.io.unregister(0x03F8..=0x03FF);
+    emu.io
+        .register(0x03F8..=0x03FF, Box::new(com1))
+        .expect("COM1 ports still in use");
+
+    let f = File::open(path).unwrap_or_else(|_| panic!("File {} not found", path));
     let mut reader = BufReader::new(f);
     let mut buf = [0u8; PROGRAM_SIZE];
 
-    reader
+    let size = reader
         .read(&mut buf)
-        .expect(&format!("File {} cannot read", path));
+        .unwrap_or_else(|_| panic!("File {} cannot read", path));
 
-    emu.memory[PROGRAM_HEAD..PROGRAM_HEAD + PROGRAM_SIZE].copy_from_slice(&buf);
+    emu.memory[PROGRAM_HEAD..PROGRAM_HEAD + size].copy_from_slice(&buf[..size]);
 
-    while emu.eip < MEMORY_SIZE as u32 {
-        let code = emu.get_code8(0);
-        if !matches.is_present("quiet") {
-            println!("EIP = {:X}, Code = {:>02X}", emu.eip, code);
-        }
+    let reason = if matches.is_present("quiet") {
+        emu.run()
+    } else {
+        loop {
+            // Nothing is executed while HLT waits for an interrupt.
+            if !emu.halted {
+                if let Ok(code) = emu.get_code8(0) {
+                    println!("EIP = {:X}, Code = {:>02X}", emu.eip, code);
+                }
+            }
 
-        if let Some(f) = functions[code as usize] {
-            f(&mut emu);
-        } else {
-            println!("\n\nNot Implemented: {:>02X}", code);
-            break;
+            match emu.step() {
+                Ok(StepOutcome::Continue) => (),
+                Ok(StepOutcome::Stop(reason)) => break Ok(reason),
+                Err(e) => break Err(e),
+            }
         }
+    };
 
-        if emu.eip == 0 {
-            println!("\n\nend of program.\n");
-            break;
+    match reason {
+        Ok(StopReason::NotImplemented(code)) => {
+            println!("\n\nNot Implemented: {}", OpcodeName(code))
+        }
+        Ok(StopReason::EndOfProgram) => println!("\n\nend of program.\n"),
+        Ok(StopReason::Halted) => println!("\n\nhalted.\n"),
+        Ok(_) => (),
+        Err(e) => {
+            println!("\n\n{}", e);
+            // process::exit skips destructors, so restore the terminal first.
+            drop(console);
+            process::exit(1);
         }
     }
 
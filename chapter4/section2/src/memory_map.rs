@@ -0,0 +1,336 @@
+use crate::emulator::OperandSize;
+use crate::error::EmulatorError;
+
+use std::cell::RefCell;
+use std::fmt;
+
+/// A device answering physical memory accesses inside the region it is mapped at.
+/// `offset` is relative to the start of the region.
+///
+/// Only the byte accessors are required. 16- and 32-bit accesses default to
+/// consecutive byte accesses, lowest address first.
+pub trait MmioDevice {
+    fn read8(&mut self, offset: u32) -> Result<u8, EmulatorError>;
+
+    fn write8(&mut self, offset: u32, value: u8) -> Result<(), EmulatorError>;
+
+    fn read16(&mut self, offset: u32) -> Result<u16, EmulatorError> {
+        let low = self.read8(offset)? as u16;
+        let high = self.read8(offset + 1)? as u16;
+        Ok(low | (high << 8))
+    }
+
+    fn write16(&mut self, offset: u32, value: u16) -> Result<(), EmulatorError> {
+        self.write8(offset, value as u8)?;
+        self.write8(offset + 1, (value >> 8) as u8)
+    }
+
+    fn read32(&mut self, offset: u32) -> Result<u32, EmulatorError> {
+        let low = self.read16(offset)? as u32;
+        let high = self.read16(offset + 2)? as u32;
+        Ok(low | (high << 16))
+    }
+
+    fn write32(&mut self, offset: u32, value: u32) -> Result<(), EmulatorError> {
+        self.write16(offset, value as u16)?;
+        self.write16(offset + 2, (value >> 16) as u16)
+    }
+}
+
+/// What a write to a ROM region does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RomWritePolicy {
+    /// The write is dropped, as on a real ROM.
+    Ignore,
+    /// As `Ignore`, and the write is reported on stderr.
+    Log,
+    /// The write fails with `EmulatorError::RomWrite`.
+    Error,
+}
+
+enum Backing {
+    /// Read-only contents. Writes follow `MemoryMap::rom_writes`.
+    Rom(Vec<u8>),
+    /// Accesses go to the device. A `RefCell` lets reads through `&Emulator` reach
+    /// devices whose registers change when read.
+    Mmio(RefCell<Box<dyn MmioDevice>>),
+}
+
+struct Region {
+    start: u32,
+    length: u32,
+    backing: Backing,
+}
+
+impl Region {
+    /// Offset of an access of `bytes` bytes at `address` if it lies wholly inside.
+    fn offset(&self, address: u32, bytes: u32) -> Option<u32> {
+        let offset = address.wrapping_sub(self.start);
+        if offset < self.length && bytes <= self.length - offset {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+}
+
+/// ROM and MMIO regions laid over the RAM in `Emulator::memory`. Physical addresses
+/// outside both RAM and every region are unmapped and fault with
+/// `EmulatorError::MemoryOutOfBounds`.
+pub struct MemoryMap {
+    regions: Vec<Region>,
+    /// Lowest address covered by a region. Accesses below it that fit in RAM take the
+    /// fast path straight to `Emulator::memory`.
+    lowest: u32,
+    pub rom_writes: RomWritePolicy,
+}
+
+impl fmt::Debug for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut list = f.debug_list();
+        for region in &self.regions {
+            let kind = match region.backing {
+                Backing::Rom(_) => "ROM",
+                Backing::Mmio(_) => "MMIO",
+            };
+            list.entry(&format_args!(
+                "{} {:08X}-{:08X}",
+                kind,
+                region.start,
+                region.start as u64 + region.length as u64 - 1
+            ));
+        }
+        list.finish()
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        MemoryMap::new()
+    }
+}
+
+impl MemoryMap {
+    pub fn new() -> MemoryMap {
+        MemoryMap {
+            regions: Vec::new(),
+            lowest: u32::MAX,
+            rom_writes: RomWritePolicy::Ignore,
+        }
+    }
+
+    fn map(&mut self, start: u32, length: u32, backing: Backing) {
+        if length == 0 {
+            return;
+        }
+        self.lowest = self.lowest.min(start);
+        self.regions.insert(
+            0,
+            Region {
+                start,
+                length,
+                backing,
+            },
+        );
+    }
+
+    /// Maps `data` read-only at `start`. Regions mapped later take precedence over
+    /// overlapping earlier ones.
+    pub fn map_rom(&mut self, start: u32, data: Vec<u8>) {
+        self.map(start, data.len() as u32, Backing::Rom(data));
+    }
+
+    /// Maps `device` at `length` bytes from `start`.
+    pub fn map_mmio(&mut self, start: u32, length: u32, device: Box<dyn MmioDevice>) {
+        self.map(start, length, Backing::Mmio(RefCell::new(device)));
+    }
+
+    /// Whether an access of `bytes` bytes at `address` can skip the region lookup.
+    pub(crate) fn is_below_regions(&self, address: u32, bytes: u32) -> bool {
+        (address as u64 + bytes as u64) <= self.lowest as u64
+    }
+
+    /// Whether a region covers `address`.
+    pub fn is_mapped(&self, address: u32) -> bool {
+        self.regions.iter().any(|r| r.offset(address, 1).is_some())
+    }
+
+    fn find(&self, address: u32) -> Option<&Region> {
+        self.regions.iter().find(|r| r.offset(address, 1).is_some())
+    }
+
+    /// The byte at `address` if a ROM holds it. MMIO registers are never peeked.
+    pub(crate) fn peek(&self, address: u32) -> Option<u8> {
+        let region = self.find(address)?;
+        match &region.backing {
+            Backing::Rom(data) => Some(data[(address - region.start) as usize]),
+            Backing::Mmio(_) => None,
+        }
+    }
+
+    /// Reads an access that lies wholly inside the region holding `address`, or
+    /// returns `None` if no region holds it whole.
+    pub(crate) fn read(
+        &self,
+        address: u32,
+        size: OperandSize,
+    ) -> Option<Result<u32, EmulatorError>> {
+        let region = self.find(address)?;
+        let offset = region.offset(address, size.bytes())?;
+
+        Some(match &region.backing {
+            Backing::Rom(data) => {
+                let mut value = 0;
+                for i in 0..size.bytes() {
+                    value |= (data[(offset + i) as usize] as u32) << (i * 8);
+                }
+                Ok(value)
+            }
+            Backing::Mmio(device) => {
+                let mut device = device.borrow_mut();
+                match size {
+                    OperandSize::Byte => device.read8(offset).map(|v| v as u32),
+                    OperandSize::Word => device.read16(offset).map(|v| v as u32),
+                    OperandSize::Dword => device.read32(offset),
+                }
+            }
+        })
+    }
+
+    /// Writes an access that lies wholly inside the region holding `address`, or
+    /// returns `None` if no region holds it whole.
+    pub(crate) fn write(
+        &mut self,
+        address: u32,
+        value: u32,
+        size: OperandSize,
+    ) -> Option<Result<(), EmulatorError>> {
+        let region = self.find(address)?;
+        let offset = region.offset(address, size.bytes())?;
+
+        Some(match &region.backing {
+            Backing::Rom(_) => match self.rom_writes {
+                RomWritePolicy::Ignore => Ok(()),
+                RomWritePolicy::Log => {
+                    eprintln!("ROM write: {:08X} = {:X}", address, value & size.mask());
+                    Ok(())
+                }
+                RomWritePolicy::Error => Err(EmulatorError::RomWrite(address)),
+            },
+            Backing::Mmio(device) => {
+                let mut device = device.borrow_mut();
+                match size {
+                    OperandSize::Byte => device.write8(offset, value as u8),
+                    OperandSize::Word => device.write16(offset, value as u16),
+                    OperandSize::Dword => device.write32(offset, value),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+
+    use std::rc::Rc;
+
+    /// Offsets and values of the bytes written to a `Recorder`.
+    type Writes = Rc<RefCell<Vec<(u32, u8)>>>;
+
+    /// Reads return the low byte of the offset and writes are recorded.
+    struct Recorder(Writes);
+
+    impl MmioDevice for Recorder {
+        fn read8(&mut self, offset: u32) -> Result<u8, EmulatorError> {
+            Ok(offset as u8)
+        }
+
+        fn write8(&mut self, offset: u32, value: u8) -> Result<(), EmulatorError> {
+            self.0.borrow_mut().push((offset, value));
+            Ok(())
+        }
+    }
+
+    /// 64 KiB of RAM with a recorder over 0x8000-0x80FF.
+    fn emulator_with_mmio() -> (Emulator, Writes) {
+        let mut emu = Emulator::new(0x10000, 0, 0);
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        emu.memory_map
+            .map_mmio(0x8000, 0x100, Box::new(Recorder(writes.clone())));
+        (emu, writes)
+    }
+
+    #[test]
+    fn rom_writes_follow_the_policy() {
+        let mut emu = Emulator::new(0x10000, 0, 0);
+        emu.memory_map
+            .map_rom(0x0002_0000, vec![0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(emu.get_memory32(0x0002_0000).unwrap(), 0x0403_0201);
+
+        for policy in [RomWritePolicy::Ignore, RomWritePolicy::Log] {
+            emu.memory_map.rom_writes = policy;
+            emu.set_memory16(0x0002_0001, 0xFFFF).unwrap();
+            assert_eq!(emu.get_memory32(0x0002_0000).unwrap(), 0x0403_0201);
+        }
+
+        emu.memory_map.rom_writes = RomWritePolicy::Error;
+        assert!(matches!(
+            emu.set_memory8(0x0002_0003, 0xFF),
+            Err(EmulatorError::RomWrite(0x0002_0003))
+        ));
+        assert_eq!(emu.get_memory8(0x0002_0003).unwrap(), 0x04);
+    }
+
+    #[test]
+    fn mmio_devices_see_offsets_into_their_region() {
+        let (mut emu, writes) = emulator_with_mmio();
+
+        assert_eq!(emu.get_memory8(0x8042).unwrap(), 0x42);
+        assert_eq!(emu.get_memory16(0x8010).unwrap(), 0x1110);
+        assert_eq!(emu.get_memory32(0x80FC).unwrap(), 0xFFFE_FDFC);
+
+        emu.set_memory16(0x8020, 0xBBAA).unwrap();
+        assert_eq!(*writes.borrow(), [(0x20, 0xAA), (0x21, 0xBB)]);
+        // The RAM under the region is left alone.
+        assert_eq!(emu.memory[0x8020], 0);
+    }
+
+    #[test]
+    fn accesses_straddling_ram_and_mmio_are_split() {
+        let (mut emu, writes) = emulator_with_mmio();
+
+        emu.set_memory32(0x7FFE, 0x4433_2211).unwrap();
+        assert_eq!(emu.memory[0x7FFE..0x8000], [0x11, 0x22]);
+        assert_eq!(*writes.borrow(), [(0x00, 0x33), (0x01, 0x44)]);
+        assert_eq!(emu.get_memory32(0x7FFE).unwrap(), 0x0100_2211);
+
+        emu.memory[0x8100] = 0x77;
+        assert_eq!(emu.get_memory16(0x80FF).unwrap(), 0x77FF);
+    }
+
+    #[test]
+    fn unmapped_accesses_fault() {
+        let (mut emu, _) = emulator_with_mmio();
+
+        assert!(matches!(
+            emu.get_memory8(0x0001_0000),
+            Err(EmulatorError::MemoryOutOfBounds {
+                addr: 0x0001_0000,
+                size: 1
+            })
+        ));
+        assert!(matches!(
+            emu.get_memory32(0xFFFE),
+            Err(EmulatorError::MemoryOutOfBounds {
+                addr: 0xFFFE,
+                size: 4
+            })
+        ));
+
+        // Nothing is written when part of the access is unmapped.
+        assert!(emu.set_memory32(0xFFFE, 0x4433_2211).is_err());
+        assert_eq!(emu.memory[0xFFFE..], [0, 0]);
+    }
+}
@@ -0,0 +1,406 @@
+use crate::emulator::{Emulator, OperandSize};
+use crate::error::EmulatorError;
+
+pub(crate) const CR0_WP: u32 = 1 << 16;
+pub(crate) const CR0_PG: u32 = 1 << 31;
+pub(crate) const CR4_PSE: u32 = 1 << 4;
+
+// Bits of page directory and page table entries.
+const PRESENT: u32 = 1;
+const WRITABLE: u32 = 1 << 1;
+const USER: u32 = 1 << 2;
+const ACCESSED: u32 = 1 << 5;
+const DIRTY: u32 = 1 << 6;
+const PAGE_SIZE: u32 = 1 << 7;
+
+/// Translation of one 4 KiB linear page cached by the TLB.
+#[derive(Clone, Copy, Debug)]
+pub struct TlbEntry {
+    frame: u32,
+    writable: bool,
+    user: bool,
+    /// The dirty bit is already set in memory, so writes need no page walk.
+    dirty: bool,
+    /// The page is part of a 4 MiB page.
+    large: bool,
+}
+
+/// Kind of memory access being translated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Emulator {
+    /// CR0.PG
+    pub fn is_paging(&self) -> bool {
+        self.control_registers[0] & CR0_PG != 0
+    }
+
+    /// Drops every cached translation, as writing CR3 does.
+    pub fn flush_tlb(&mut self) {
+        self.tlb.clear();
+    }
+
+    /// Drops the translation of the page holding `linear`, as INVLPG does.
+    pub fn invalidate_page(&mut self, linear: u32) {
+        let page = linear >> 12;
+        self.tlb
+            .retain(|&key, entry| key != page && !(entry.large && key >> 10 == page >> 10));
+    }
+
+    fn is_permitted(&self, writable: bool, user_page: bool, access: Access) -> bool {
+        let user = self.cpl() == 3;
+        if user && !user_page {
+            return false;
+        }
+        // Supervisor writes ignore the R/W bit unless CR0.WP is set.
+        access != Access::Write || writable || !(user || self.control_registers[0] & CR0_WP != 0)
+    }
+
+    /// Records a page fault on `linear` in CR2 and builds its error code.
+    fn page_fault(&mut self, linear: u32, present: bool, access: Access) -> EmulatorError {
+        self.control_registers[2] = linear;
+
+        let mut code = 0;
+        if present {
+            code |= PRESENT;
+        }
+        if access == Access::Write {
+            code |= WRITABLE;
+        }
+        if self.cpl() == 3 {
+            code |= USER;
+        }
+
+        EmulatorError::PageFault {
+            address: linear,
+            code,
+        }
+    }
+
+    /// Translates a linear address into a physical one, walking the page tables on a
+    /// TLB miss and setting the accessed and dirty bits the access implies.
+    pub fn translate(&mut self, linear: u32, access: Access) -> Result<u32, EmulatorError> {
+        if !self.is_paging() {
+            return Ok(linear);
+        }
+
+        let page = linear >> 12;
+        let offset = linear & 0xFFF;
+        if let Some(entry) = self.tlb.get(&page).copied() {
+            if self.is_permitted(entry.writable, entry.user, access)
+                && (access != Access::Write || entry.dirty)
+            {
+                return Ok(entry.frame | offset);
+            }
+        }
+
+        let pde_address = (self.control_registers[3] & 0xFFFF_F000) | ((linear >> 22) << 2);
+        let pde = self.get_memory32(pde_address)?;
+        if pde & PRESENT == 0 {
+            return Err(self.page_fault(linear, false, access));
+        }
+
+        let write = access == Access::Write;
+        let entry = if pde & PAGE_SIZE != 0 && self.control_registers[4] & CR4_PSE != 0 {
+            let writable = pde & WRITABLE != 0;
+            let user = pde & USER != 0;
+            if !self.is_permitted(writable, user, access) {
+                return Err(self.page_fault(linear, true, access));
+            }
+
+            let updated = pde | ACCESSED | if write { DIRTY } else { 0 };
+            if updated != pde {
+                self.set_memory32(pde_address, updated)?;
+            }
+
+            TlbEntry {
+                frame: (pde & 0xFFC0_0000) | (linear & 0x003F_F000),
+                writable,
+                user,
+                dirty: updated & DIRTY != 0,
+                large: true,
+            }
+        } else {
+            let pte_address = (pde & 0xFFFF_F000) | ((page & 0x3FF) << 2);
+            let pte = self.get_memory32(pte_address)?;
+            if pte & PRESENT == 0 {
+                return Err(self.page_fault(linear, false, access));
+            }
+
+            let writable = pde & pte & WRITABLE != 0;
+            let user = pde & pte & USER != 0;
+            if !self.is_permitted(writable, user, access) {
+                return Err(self.page_fault(linear, true, access));
+            }
+
+            if pde & ACCESSED == 0 {
+                self.set_memory32(pde_address, pde | ACCESSED)?;
+            }
+            let updated = pte | ACCESSED | if write { DIRTY } else { 0 };
+            if updated != pte {
+                self.set_memory32(pte_address, updated)?;
+            }
+
+            TlbEntry {
+                frame: pte & 0xFFFF_F000,
+                writable,
+                user,
+                dirty: updated & DIRTY != 0,
+                large: false,
+            }
+        };
+
+        self.tlb.insert(page, entry);
+        Ok(entry.frame | offset)
+    }
+
+    /// Translates `linear` for the debugger without touching the TLB, the accessed
+    /// and dirty bits or CR2. Returns `None` if the page is not mapped.
+    pub fn peek_translate(&self, linear: u32) -> Option<u32> {
+        if !self.is_paging() {
+            return Some(linear);
+        }
+
+        let pde_address = (self.control_registers[3] & 0xFFFF_F000) | ((linear >> 22) << 2);
+        let pde = self.get_memory32(pde_address).ok()?;
+        if pde & PRESENT == 0 {
+            return None;
+        }
+        if pde & PAGE_SIZE != 0 && self.control_registers[4] & CR4_PSE != 0 {
+            return Some((pde & 0xFFC0_0000) | (linear & 0x003F_FFFF));
+        }
+
+        let pte_address = (pde & 0xFFFF_F000) | (((linear >> 12) & 0x3FF) << 2);
+        let pte = self.get_memory32(pte_address).ok()?;
+        if pte & PRESENT == 0 {
+            return None;
+        }
+        Some((pte & 0xFFFF_F000) | (linear & 0xFFF))
+    }
+
+    /// Reads `size` bytes at a linear address. An access crossing a page boundary is
+    /// translated byte by byte.
+    pub fn get_linear(
+        &mut self,
+        linear: u32,
+        size: OperandSize,
+        access: Access,
+    ) -> Result<u32, EmulatorError> {
+        if !self.is_paging() || (linear & 0xFFF) + size.bytes() <= 0x1000 {
+            let address = self.translate(linear, access)?;
+            return self.get_memory(address, size);
+        }
+
+        let mut addresses = [0; 4];
+        for (i, address) in addresses.iter_mut().take(size.bytes() as usize).enumerate() {
+            *address = self.translate(linear.wrapping_add(i as u32), access)?;
+        }
+
+        let mut value = 0;
+        for (i, address) in addresses.iter().take(size.bytes() as usize).enumerate() {
+            value |= (self.get_memory8(*address)? as u32) << (i * 8);
+        }
+        Ok(value)
+    }
+
+    /// Writes `size` bytes at a linear address. Every page is translated before any
+    /// byte is written, so a fault on the second page leaves memory untouched.
+    pub fn set_linear(
+        &mut self,
+        linear: u32,
+        value: u32,
+        size: OperandSize,
+    ) -> Result<(), EmulatorError> {
+        if !self.is_paging() || (linear & 0xFFF) + size.bytes() <= 0x1000 {
+            let address = self.translate(linear, Access::Write)?;
+            return self.set_memory(address, value, size);
+        }
+
+        let mut addresses = [0; 4];
+        for (i, address) in addresses.iter_mut().take(size.bytes() as usize).enumerate() {
+            *address = self.translate(linear.wrapping_add(i as u32), Access::Write)?;
+        }
+
+        for (i, address) in addresses.iter().take(size.bytes() as usize).enumerate() {
+            self.set_memory8(*address, (value >> (i * 8)) as u8)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{Register32, SegmentRegister};
+
+    const DIRECTORY: u32 = 0x1000;
+    const TABLE: u32 = 0x2000;
+    const NOT_PRESENT: u32 = 0x5000;
+    const SUPERVISOR: u32 = 0x6000;
+    const READ_ONLY: u32 = 0x7000;
+
+    fn pte_address(linear: u32) -> u32 {
+        TABLE + ((linear >> 12) << 2)
+    }
+
+    /// A ring 0 emulator with paging on. The first 64 KiB are identity mapped as user
+    /// writable pages, except for the three pages above.
+    fn paged() -> Emulator {
+        let mut emu = Emulator::new(0x10000, 0x8000, 0x8000);
+        emu.set_memory32(DIRECTORY, TABLE | PRESENT | WRITABLE | USER)
+            .unwrap();
+        for page in 0..16 {
+            let linear = page << 12;
+            let pte = match linear {
+                NOT_PRESENT => 0,
+                SUPERVISOR => linear | PRESENT | WRITABLE,
+                READ_ONLY => linear | PRESENT | USER,
+                _ => linear | PRESENT | WRITABLE | USER,
+            };
+            emu.set_memory32(pte_address(linear), pte).unwrap();
+        }
+        emu.control_registers[0] |= CR0_PG | 0x01;
+        emu.control_registers[3] = DIRECTORY;
+        emu
+    }
+
+    fn set_cpl(emu: &mut Emulator, cpl: u16) {
+        emu.segments[SegmentRegister::CS as usize].selector = cpl;
+    }
+
+    /// Returns the error code of a page fault on `linear`, checking CR2.
+    fn fault_code(result: Result<u32, EmulatorError>, emu: &Emulator, linear: u32) -> u32 {
+        match result {
+            Err(EmulatorError::PageFault { address, code }) => {
+                assert_eq!(address, linear);
+                assert_eq!(emu.control_registers[2], linear);
+                code
+            }
+            other => panic!("expected #PF, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn faults_report_the_address_and_cause() {
+        let mut emu = paged();
+        let linear = NOT_PRESENT + 0x123;
+
+        let result = emu.translate(linear, Access::Read);
+        assert_eq!(fault_code(result, &emu, linear), 0);
+        let result = emu.translate(linear, Access::Write);
+        assert_eq!(fault_code(result, &emu, linear), WRITABLE);
+
+        set_cpl(&mut emu, 3);
+        let result = emu.translate(linear, Access::Write);
+        assert_eq!(fault_code(result, &emu, linear), USER | WRITABLE);
+        let result = emu.translate(SUPERVISOR, Access::Read);
+        assert_eq!(fault_code(result, &emu, SUPERVISOR), USER | PRESENT);
+
+        set_cpl(&mut emu, 0);
+        assert_eq!(emu.translate(SUPERVISOR, Access::Read).unwrap(), SUPERVISOR);
+    }
+
+    #[test]
+    fn supervisor_writes_to_read_only_pages_obey_cr0_wp() {
+        let mut emu = paged();
+        assert_eq!(emu.translate(READ_ONLY, Access::Write).unwrap(), READ_ONLY);
+
+        emu.control_registers[0] |= CR0_WP;
+        emu.flush_tlb();
+        let result = emu.translate(READ_ONLY, Access::Write);
+        assert_eq!(fault_code(result, &emu, READ_ONLY), PRESENT | WRITABLE);
+        assert_eq!(emu.translate(READ_ONLY, Access::Read).unwrap(), READ_ONLY);
+
+        // User writes fault whatever CR0.WP says.
+        emu.control_registers[0] &= !CR0_WP;
+        set_cpl(&mut emu, 3);
+        let result = emu.translate(READ_ONLY, Access::Write);
+        assert_eq!(
+            fault_code(result, &emu, READ_ONLY),
+            USER | PRESENT | WRITABLE
+        );
+    }
+
+    #[test]
+    fn accessed_and_dirty_bits_are_written_back() {
+        let mut emu = paged();
+        let linear = 0x3000;
+        emu.get_linear(linear, OperandSize::Dword, Access::Read)
+            .unwrap();
+        assert_eq!(emu.get_memory32(DIRECTORY).unwrap() & ACCESSED, ACCESSED);
+        let pte = emu.get_memory32(pte_address(linear)).unwrap();
+        assert_eq!(pte & (ACCESSED | DIRTY), ACCESSED);
+
+        // A write after a cached read still walks the tables to set the dirty bit.
+        emu.set_linear(linear, 1, OperandSize::Dword).unwrap();
+        let pte = emu.get_memory32(pte_address(linear)).unwrap();
+        assert_eq!(pte & (ACCESSED | DIRTY), ACCESSED | DIRTY);
+    }
+
+    #[test]
+    fn the_tlb_is_stale_until_invlpg_or_a_cr3_write() {
+        let mut emu = paged();
+        let linear = 0xC000;
+        emu.memory[0xC000] = 0x11;
+        emu.memory[0xD000] = 0x22;
+        emu.memory[0xE000] = 0x33;
+        let read = |emu: &mut Emulator| emu.get_linear(linear, OperandSize::Byte, Access::Read);
+        assert_eq!(read(&mut emu).unwrap(), 0x11);
+
+        emu.set_memory32(pte_address(linear), 0xD000 | PRESENT | WRITABLE)
+            .unwrap();
+        assert_eq!(read(&mut emu).unwrap(), 0x11);
+        emu.invalidate_page(linear + 0xFFF);
+        assert_eq!(read(&mut emu).unwrap(), 0x22);
+
+        emu.set_memory32(pte_address(linear), 0xE000 | PRESENT | WRITABLE)
+            .unwrap();
+        assert_eq!(read(&mut emu).unwrap(), 0x22);
+        // mov cr3, eax
+        emu.memory[0x8000..0x8003].copy_from_slice(&[0x0F, 0x22, 0xD8]);
+        emu.set_register32(Register32::EAX as i32, DIRECTORY);
+        emu.step().unwrap();
+        assert_eq!(read(&mut emu).unwrap(), 0x33);
+    }
+
+    #[test]
+    fn pse_maps_4_mib_pages() {
+        let mut emu = paged();
+        emu.set_memory32(DIRECTORY + 4, PAGE_SIZE | PRESENT | WRITABLE)
+            .unwrap();
+        let linear = 0x0040_1234;
+
+        emu.control_registers[4] |= CR4_PSE;
+        assert_eq!(emu.translate(linear, Access::Write).unwrap(), 0x1234);
+        assert_eq!(emu.translate(0x0040_5678, Access::Read).unwrap(), 0x5678);
+        let pde = emu.get_memory32(DIRECTORY + 4).unwrap();
+        assert_eq!(pde & (ACCESSED | DIRTY), ACCESSED | DIRTY);
+
+        // INVLPG anywhere in a large page drops all of it.
+        assert_eq!(emu.tlb.len(), 2);
+        emu.invalidate_page(0x007F_F000);
+        assert!(emu.tlb.is_empty());
+    }
+
+    #[test]
+    fn page_crossing_writes_are_all_or_nothing() {
+        let mut emu = paged();
+        let result = emu
+            .set_linear(NOT_PRESENT - 2, 0x4433_2211, OperandSize::Dword)
+            .map(|_| 0);
+        assert_eq!(fault_code(result, &emu, NOT_PRESENT), WRITABLE);
+        assert_eq!(emu.memory[0x4FFE..0x5000], [0, 0]);
+
+        emu.set_linear(0xAFFE, 0x4433_2211, OperandSize::Dword)
+            .unwrap();
+        assert_eq!(emu.memory[0xAFFE..0xB002], [0x11, 0x22, 0x33, 0x44]);
+        let value = emu
+            .get_linear(0xAFFE, OperandSize::Dword, Access::Read)
+            .unwrap();
+        assert_eq!(value, 0x4433_2211);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::clock::Clock;
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// Identifies a scheduled event, to cancel it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EventId {
+    time: u64,
+    sequence: u64,
+}
+
+impl EventId {
+    /// Emulated time the event is due at, in nanoseconds.
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+}
+
+/// Work a device asks to have done at a point in emulated time.
+pub type EventCallback = Box<dyn FnOnce()>;
+
+#[derive(Default)]
+struct Queue {
+    /// Ordered by time, then by the order they were scheduled in.
+    events: BTreeMap<EventId, EventCallback>,
+    next_sequence: u64,
+}
+
+/// Events due at points of emulated time on `Clock`, such as a timer expiring or a
+/// character finishing transmission. The emulator runs due events between
+/// instructions, and HLT skips ahead to the next one. Clones refer to the same queue.
+///
+/// Callbacks run with no device borrowed, so they can borrow the device that
+/// scheduled them through a weak handle.
+#[derive(Clone)]
+pub struct Scheduler {
+    clock: Clock,
+    queue: Rc<RefCell<Queue>>,
+}
+
+impl fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let queue = self.queue.borrow();
+        f.debug_struct("Scheduler")
+            .field("events", &queue.events.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Scheduler {
+    pub fn new(clock: Clock) -> Scheduler {
+        Scheduler {
+            clock,
+            queue: Rc::new(RefCell::new(Queue::default())),
+        }
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// Runs `callback` once emulated time reaches `time` nanoseconds. An event
+    /// scheduled in the past runs before the next instruction.
+    pub fn schedule_at<F: FnOnce() + 'static>(&self, time: u64, callback: F) -> EventId {
+        let mut queue = self.queue.borrow_mut();
+        let id = EventId {
+            time,
+            sequence: queue.next_sequence,
+        };
+        queue.next_sequence += 1;
+        queue.events.insert(id, Box::new(callback));
+        id
+    }
+
+    /// Runs `callback` `delay` nanoseconds from now.
+    pub fn schedule_in<F: FnOnce() + 'static>(&self, delay: u64, callback: F) -> EventId {
+        self.schedule_at(self.clock.now() + delay, callback)
+    }
+
+    /// Drops an event that has not run yet. Cancelling one that has is harmless.
+    pub fn cancel(&self, id: EventId) {
+        self.queue.borrow_mut().events.remove(&id);
+    }
+
+    /// Time of the earliest pending event.
+    pub fn next_event(&self) -> Option<u64> {
+        self.queue.borrow().events.keys().next().map(|id| id.time)
+    }
+
+    /// Runs every event due by now in time order, including those the callbacks
+    /// schedule for times already reached.
+    pub fn run_due(&self) {
+        loop {
+            let now = self.clock.now();
+            let callback = {
+                let mut queue = self.queue.borrow_mut();
+                match queue.events.first_key_value() {
+                    Some((id, _)) if id.time <= now => queue.events.pop_first().unwrap().1,
+                    _ => return,
+                }
+            };
+            callback();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ClockMode;
+
+    fn scheduler() -> (Scheduler, Rc<RefCell<Vec<u32>>>) {
+        (
+            Scheduler::new(Clock::new(ClockMode::Virtual)),
+            Rc::new(RefCell::new(Vec::new())),
+        )
+    }
+
+    /// Schedules an event at `time` that logs `value`.
+    fn log_at(
+        scheduler: &Scheduler,
+        log: &Rc<RefCell<Vec<u32>>>,
+        time: u64,
+        value: u32,
+    ) -> EventId {
+        let log = log.clone();
+        scheduler.schedule_at(time, move || log.borrow_mut().push(value))
+    }
+
+    #[test]
+    fn events_run_in_time_then_scheduling_order() {
+        let (scheduler, log) = scheduler();
+        log_at(&scheduler, &log, 200, 1);
+        log_at(&scheduler, &log, 100, 0);
+        log_at(&scheduler, &log, 200, 2);
+        log_at(&scheduler, &log, 300, 3);
+
+        scheduler.run_due();
+        assert!(log.borrow().is_empty());
+        assert_eq!(scheduler.next_event(), Some(100));
+
+        scheduler.clock().advance_to(200);
+        scheduler.run_due();
+        assert_eq!(*log.borrow(), [0, 1, 2]);
+        assert_eq!(scheduler.next_event(), Some(300));
+    }
+
+    #[test]
+    fn cancelled_events_never_run() {
+        let (scheduler, log) = scheduler();
+        let id = log_at(&scheduler, &log, 100, 0);
+        log_at(&scheduler, &log, 100, 1);
+        assert_eq!(id.time(), 100);
+
+        scheduler.cancel(id);
+        scheduler.clock().advance_to(100);
+        scheduler.run_due();
+        assert_eq!(*log.borrow(), [1]);
+
+        // Cancelling an event that has run, or twice, is harmless.
+        scheduler.cancel(id);
+        assert_eq!(scheduler.next_event(), None);
+    }
+
+    #[test]
+    fn callbacks_can_schedule_more_events() {
+        let (scheduler, log) = scheduler();
+        let inner = scheduler.clone();
+        let inner_log = log.clone();
+        scheduler.schedule_at(100, move || {
+            inner_log.borrow_mut().push(0);
+            // Already due, so it runs in the same `run_due`.
+            log_at(&inner, &inner_log, 50, 1);
+            log_at(&inner, &inner_log, 300, 2);
+        });
+
+        scheduler.clock().advance_to(100);
+        scheduler.run_due();
+        assert_eq!(*log.borrow(), [0, 1]);
+        assert_eq!(scheduler.next_event(), Some(300));
+    }
+}